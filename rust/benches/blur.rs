@@ -7,20 +7,76 @@
 #![feature(test)]
 extern crate test;
 extern crate hyperenvmap;
-use hyperenvmap::ltasgblur;
+extern crate cgmath;
+use cgmath::{Vector3, Vector4};
+use hyperenvmap::{cubemap, ltasgblur, pbr, separable};
+use hyperenvmap::container::CubeMap;
 
 fn run_single(b: &mut test::Bencher, size: usize, pass: usize) {
+    run_single_with_options(b, size, pass, ltasgblur::LtasgOptions::default())
+}
+
+fn run_single_with_options(
+    b: &mut test::Bencher,
+    size: usize,
+    pass: usize,
+    options: ltasgblur::LtasgOptions,
+) {
     let kernel = ltasgblur::gaussian_kernel(8, 4.0);
-    let count = size * size;
-    let src = vec![vec![0f32; count]; 6];
-    let mut dst = vec![vec![0f32; count]; 6];
+    let src = CubeMap::<f32>::zeros(size);
+    let mut dst = CubeMap::<f32>::zeros(size);
+    b.iter(move || {
+        ltasgblur::ltasg_single_with_options(
+            (&mut dst)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_mut_slice(),
+            (&src)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_slice(),
+            size,
+            &kernel,
+            0.5,
+            pass,
+            ltasgblur::StandardCubeMapTrait,
+            &options,
+        );
+    })
+}
+
+/// `run_single` with RGBA pixels.
+fn run_single_rgba(b: &mut test::Bencher, size: usize, pass: usize) {
+    let kernel = ltasgblur::gaussian_kernel(8, 4.0);
+    let src = CubeMap::<Vector4<f32>>::zeros(size);
+    let mut dst = CubeMap::<Vector4<f32>>::zeros(size);
     b.iter(move || {
         ltasgblur::ltasg_single(
-            dst.iter_mut()
-                .map(Vec::as_mut_slice)
+            &mut dst.faces_mut(),
+            &src.faces(),
+            size,
+            &kernel,
+            0.5,
+            pass,
+            ltasgblur::StandardCubeMapTrait,
+        );
+    })
+}
+
+/// `run_single` using `ltasg_single_in_place`, which needs two faces of
+/// temporary memory instead of a second set of six faces.
+fn run_single_in_place(b: &mut test::Bencher, size: usize, pass: usize) {
+    let kernel = ltasgblur::gaussian_kernel(8, 4.0);
+    let mut faces = CubeMap::<f32>::zeros(size);
+    b.iter(move || {
+        ltasgblur::ltasg_single_in_place(
+            (&mut faces)
+                .into_iter()
+                .map(|(_, image)| image)
                 .collect::<Vec<_>>()
                 .as_mut_slice(),
-            src.iter().map(Vec::as_slice).collect::<Vec<_>>().as_slice(),
             size,
             &kernel,
             0.5,
@@ -30,6 +86,50 @@ fn run_single(b: &mut test::Bencher, size: usize, pass: usize) {
     })
 }
 
+#[cfg(feature = "rayon")]
+fn run_single_par(
+    b: &mut test::Bencher,
+    size: usize,
+    pass: usize,
+    options: ltasgblur::LtasgOptions,
+) {
+    let kernel = ltasgblur::gaussian_kernel(8, 4.0);
+    let src = CubeMap::<f32>::zeros(size);
+    let mut dst = CubeMap::<f32>::zeros(size);
+    b.iter(move || {
+        ltasgblur::ltasg_single_par(
+            (&mut dst)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_mut_slice(),
+            (&src)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_slice(),
+            size,
+            &kernel,
+            0.5,
+            pass,
+            ltasgblur::StandardCubeMapTrait,
+            &options,
+        );
+    })
+}
+
+/// The options restricting the output to (a part of) +X, which is processed
+/// by the directional blur in the second phase.
+fn single_face_options() -> ltasgblur::LtasgOptions {
+    ltasgblur::LtasgOptions {
+        region: Some(cubemap::SphericalCap {
+            center_dir: Vector3::new(1.0, 0.0, 0.0),
+            half_angle: 0.7,
+        }),
+        ..Default::default()
+    }
+}
+
 #[bench]
 fn blur1_16(b: &mut test::Bencher) {
     run_single(b, 16, 0)
@@ -50,6 +150,11 @@ fn blur1_128(b: &mut test::Bencher) {
     run_single(b, 128, 0)
 }
 
+#[bench]
+fn blur1_128_rgba(b: &mut test::Bencher) {
+    run_single_rgba(b, 128, 0)
+}
+
 #[bench]
 fn blur2_16(b: &mut test::Bencher) {
     run_single(b, 16, 1)
@@ -70,6 +175,73 @@ fn blur2_128(b: &mut test::Bencher) {
     run_single(b, 128, 1)
 }
 
+#[bench]
+fn blur2_256(b: &mut test::Bencher) {
+    run_single(b, 256, 1)
+}
+
+#[cfg(feature = "rayon")]
+#[bench]
+fn blur2_256_par(b: &mut test::Bencher) {
+    run_single_par(b, 256, 1, Default::default())
+}
+
+#[bench]
+fn blur2_256_in_place(b: &mut test::Bencher) {
+    run_single_in_place(b, 256, 1)
+}
+
+#[bench]
+fn blur2_512(b: &mut test::Bencher) {
+    run_single(b, 512, 1)
+}
+
+#[cfg(feature = "rayon")]
+#[bench]
+fn blur2_512_par(b: &mut test::Bencher) {
+    run_single_par(b, 512, 1, Default::default())
+}
+
+#[bench]
+fn blur2_2048(b: &mut test::Bencher) {
+    run_single(b, 2048, 1)
+}
+
+#[bench]
+fn blur2_2048_in_place(b: &mut test::Bencher) {
+    run_single_in_place(b, 2048, 1)
+}
+
+#[bench]
+fn blur2_2048_tiled(b: &mut test::Bencher) {
+    run_single_with_options(
+        b,
+        2048,
+        1,
+        ltasgblur::LtasgOptions {
+            tile_size: Some(64),
+            ..Default::default()
+        },
+    )
+}
+
+#[bench]
+fn blur2_2048_single_face(b: &mut test::Bencher) {
+    run_single_with_options(b, 2048, 1, single_face_options())
+}
+
+#[cfg(feature = "rayon")]
+#[bench]
+fn blur2_2048_par(b: &mut test::Bencher) {
+    run_single_par(b, 2048, 1, Default::default())
+}
+
+#[cfg(feature = "rayon")]
+#[bench]
+fn blur2_2048_par_single_face(b: &mut test::Bencher) {
+    run_single_par(b, 2048, 1, single_face_options())
+}
+
 #[bench]
 fn blur3_16(b: &mut test::Bencher) {
     run_single(b, 16, 2)
@@ -90,6 +262,68 @@ fn blur3_128(b: &mut test::Bencher) {
     run_single(b, 128, 2)
 }
 
+/// Runs all three phases of `ltasg_single`. Compare with `run_separable`.
+fn run_all_phases(b: &mut test::Bencher, size: usize) {
+    let kernel = ltasgblur::gaussian_kernel(8, 4.0);
+    let src = CubeMap::<f32>::zeros(size);
+    let mut dst = CubeMap::<f32>::zeros(size);
+    b.iter(move || for pass in 0..3 {
+        ltasgblur::ltasg_single(
+            (&mut dst)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_mut_slice(),
+            (&src)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_slice(),
+            size,
+            &kernel,
+            1.0,
+            pass,
+            ltasgblur::StandardCubeMapTrait,
+        );
+    })
+}
+
+fn run_separable(b: &mut test::Bencher, size: usize) {
+    let kernel = ltasgblur::gaussian_kernel(8, 4.0);
+    let src = CubeMap::<f32>::zeros(size);
+    b.iter(move || {
+        separable::separable_blur(
+            (&src)
+                .into_iter()
+                .map(|(_, image)| image)
+                .collect::<Vec<_>>()
+                .as_slice(),
+            size,
+            &kernel,
+        )
+    })
+}
+
+#[bench]
+fn blur_all_phases_64(b: &mut test::Bencher) {
+    run_all_phases(b, 64)
+}
+
+#[bench]
+fn blur_all_phases_128(b: &mut test::Bencher) {
+    run_all_phases(b, 128)
+}
+
+#[bench]
+fn separable_64(b: &mut test::Bencher) {
+    run_separable(b, 64)
+}
+
+#[bench]
+fn separable_128(b: &mut test::Bencher) {
+    run_separable(b, 128)
+}
+
 #[bench]
 fn blur_mip_pyramid(b: &mut test::Bencher) {
     // Based on the parameters from ARcane's `context.rs`
@@ -99,56 +333,8 @@ fn blur_mip_pyramid(b: &mut test::Bencher) {
     static BLUR_KERNEL_SIGMA: f32 = 4.0;
     static BLUR_KERNEL_RATIO: f32 = 2.0;
 
-    let blur_table: Vec<(f32, usize)> = {
-        let mut last_variance = 0.0;
-        (0..5u8)
-            .map(|i| {
-                let size = SIZE >> i;
-                let sigma = (i as f32 - 5.0).exp2();
-
-                // The amount of ltasgblur applied on this stage
-                let res_sigma = (sigma * sigma - last_variance).sqrt();
-                last_variance = sigma * sigma;
-
-                // Upper bound of ltasgblur amount that can be applied by a single run of
-                // `ltasg_single(..., {0, 1, 2}, ...)`
-                let sigma_limit = 0.5 / BLUR_KERNEL_RATIO;
-                let num_passes = (res_sigma * res_sigma / (sigma_limit * sigma_limit)).ceil();
-
-                let level_sigma = (res_sigma * res_sigma / num_passes).sqrt() * size as f32 /
-                    BLUR_KERNEL_SIGMA;
-
-                (level_sigma, num_passes as usize)
-            })
-            .collect()
-    };
-
-    let kernel = ltasgblur::gaussian_kernel(
-        (BLUR_KERNEL_SIGMA * BLUR_KERNEL_RATIO) as usize,
-        BLUR_KERNEL_SIGMA,
-    );
-
-    let count = SIZE * SIZE;
-    let src = vec![vec![0f32; count]; 6];
-    let mut dst = vec![vec![0f32; count]; 6];
-    b.iter(move || for (i, &(kernel_scale, num_passes)) in
-        blur_table.iter().enumerate()
-    {
-        for _ in 0..num_passes {
-            for pass in 0..3 {
-                ltasgblur::ltasg_single(
-                    dst.iter_mut()
-                        .map(Vec::as_mut_slice)
-                        .collect::<Vec<_>>()
-                        .as_mut_slice(),
-                    src.iter().map(Vec::as_slice).collect::<Vec<_>>().as_slice(),
-                    SIZE >> i,
-                    &kernel,
-                    kernel_scale,
-                    pass,
-                    ltasgblur::StandardCubeMapTrait,
-                );
-            }
-        }
+    let src = CubeMap::<f32>::zeros(SIZE);
+    b.iter(move || {
+        pbr::generate_mip_pyramid(&src, 5, BLUR_KERNEL_SIGMA, BLUR_KERNEL_RATIO)
     })
 }
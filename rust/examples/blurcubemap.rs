@@ -9,21 +9,27 @@ extern crate image;
 extern crate hyperenvmap;
 extern crate cgmath;
 
-use std::mem::swap;
+use std::cmp;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 
 use cgmath::Vector4;
 
-use hyperenvmap::cubemap::CubeFace;
+use hyperenvmap::color;
+use hyperenvmap::container::{CubeMap, CubeMapError};
+use hyperenvmap::cubemap::{CubeFace, FaceOrientationOverride};
+use hyperenvmap::dds;
+use hyperenvmap::equirect;
+use hyperenvmap::ktx2;
 use hyperenvmap::ltasgblur;
-
-#[derive(Debug, Copy, Clone)]
-struct Image<T> {
-    pub data: T,
-    pub width: usize,
-    pub height: usize,
-}
+use hyperenvmap::mip;
+use hyperenvmap::pbr;
+use hyperenvmap::pixel;
+use hyperenvmap::raster::Image;
+use hyperenvmap::repair::{repair_faces, RepairPolicy};
 
 struct CubeMapPathSet {
     base: PathBuf,
@@ -32,11 +38,8 @@ struct CubeMapPathSet {
 
 impl CubeMapPathSet {
     pub fn from_one(name: &Path) -> Option<Self> {
-        let stem = name.file_stem();
-        if ["posx", "negx", "posy", "negy", "posz", "negz"]
-            .iter()
-            .all(|s| stem != Some(OsStr::new(s)))
-        {
+        let stem = name.file_stem().and_then(OsStr::to_str);
+        if stem.map(CubeFace::try_from).and_then(Result::ok).is_none() {
             return None;
         }
 
@@ -53,15 +56,13 @@ impl CubeMapPathSet {
         })
     }
 
+    /// Check whether the files exist.
+    pub fn exists(&self) -> bool {
+        self.paths().iter().all(|path| path.is_file())
+    }
+
     pub fn path(&self, face: CubeFace) -> PathBuf {
-        let mut name = OsStr::new(match face {
-            CubeFace::PositiveX => "posx",
-            CubeFace::NegativeX => "negx",
-            CubeFace::PositiveY => "posy",
-            CubeFace::NegativeY => "negy",
-            CubeFace::PositiveZ => "posz",
-            CubeFace::NegativeZ => "negz",
-        }).to_owned();
+        let mut name = OsStr::new(face.name()).to_owned();
         name.push(&self.ext);
         self.base.join(name)
     }
@@ -78,51 +79,480 @@ impl CubeMapPathSet {
     }
 }
 
+fn parse_orientation(value: &str) -> (CubeFace, FaceOrientationOverride) {
+    let mut parts = value.splitn(2, '=');
+    let face = match CubeFace::try_from(parts.next().unwrap()) {
+        Ok(face) => face,
+        Err(e) => panic!("{} — Try --help", e),
+    };
+    let orientation = match parts.next().unwrap_or("") {
+        "flip-h" => FaceOrientationOverride::FlipHorizontal,
+        "flip-v" => FaceOrientationOverride::FlipVertical,
+        "rotate90" => FaceOrientationOverride::Rotate90,
+        "rotate180" => FaceOrientationOverride::Rotate180,
+        "rotate270" => FaceOrientationOverride::Rotate270,
+        "transpose" => FaceOrientationOverride::Transpose,
+        "anti-transpose" => FaceOrientationOverride::AntiTranspose,
+        x => panic!("Invalid transform: '{}' — Try --help", x),
+    };
+    (face, orientation)
+}
+
+/// The upper bound of the values representable by `Encoding::Rgbm`.
+const RGBM_RANGE: f32 = 6.0;
+
+/// Specifies how the output images store the pixel values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    /// Gamma 2.0 with straight alpha (same as the input)
+    Gamma,
+    /// `color::encode_rgbm` with `RGBM_RANGE`. Alpha is discarded.
+    Rgbm,
+    /// `color::encode_rgbd`. Alpha is discarded.
+    Rgbd,
+}
+
+/// The standard deviation of the Gaussian kernel used by `mips`, measured in
+/// kernel taps.
+const MIP_KERNEL_SIGMA: f32 = 4.0;
+
+/// The ratio of the kernel radius to `MIP_KERNEL_SIGMA`.
+const MIP_KERNEL_RATIO: f32 = 2.0;
+
+/// The subcommands. Running the tool without one of them (the command line
+/// syntax before the introduction of subcommands) selects `blur`.
+const SUBCOMMANDS: &[&str] = &["blur", "convert", "help", "mips", "sh"];
+
 fn main() {
-    use clap::{App, Arg};
+    use clap::{App, AppSettings, Arg, SubCommand};
+
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let has_subcommand = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) => {
+            SUBCOMMANDS.contains(&arg) || ["-h", "--help", "-V", "--version"].contains(&arg)
+        }
+        None => true,
+    };
+    if !has_subcommand {
+        args.insert(1, OsString::from("blur"));
+    }
+
     let matches = App::new("blurcubemap")
         .author("yvt <i@yvt.jp>")
-        .arg(
-            Arg::with_name("input")
-                .required(true)
-                .index(1)
-                .value_name("INDIR")
-                .help(
-                    "Specifies the path to a cube map. \
-                     A cube map is composed of six image files named posx.EXT, \
-                     negx.EXT (EXT can be anything), and so forth, and one of \
-                     such files must be specified as the parameter.",
-                ),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("blur")
+                .about("Applies a spherical Gaussian blur to a cube map")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .index(1)
+                        .value_name("INDIR")
+                        .help(
+                            "Specifies the path to a cube map. \
+                             A cube map is composed of six image files named posx.EXT, \
+                             negx.EXT (EXT can be anything), and so forth, and one of \
+                             such files must be specified as the parameter.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .index(2)
+                        .value_name("OUTDIR")
+                        .help(
+                            "Specifies the path to save the generated cube map. \
+                             A cube map is composed of six image files named posx.EXT, \
+                             negx.EXT (EXT can be anything), and so forth, and one of \
+                             such files must be specified as the parameter.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("sigma")
+                        .short("s")
+                        .long("sigma")
+                        .value_name("SIGMA")
+                        .help("Specifies the standard deviation of the blur kernel.")
+                        .takes_value(true)
+                        .default_value("0.01"),
+                )
+                .arg(
+                    Arg::with_name("quality")
+                        .short("q")
+                        .long("quality")
+                        .value_name("QUALITY")
+                        .help(
+                            "Specifies the trade-off between the accuracy and the \
+                             number of passes. 'draft' uses the largest per-pass σ \
+                             allowed by the algorithm and therefore the fewest \
+                             passes, at the cost of a larger approximation error. \
+                             'high' halves the per-pass σ of 'normal', which \
+                             quadruples the number of passes.",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["draft", "normal", "high"])
+                        .default_value("normal"),
+                )
+                .arg(
+                    Arg::with_name("max-passes")
+                        .long("max-passes")
+                        .value_name("COUNT")
+                        .help(
+                            "Specifies the maximum number of passes. If σ requires \
+                             more passes (which usually means it was specified in \
+                             pixels instead of relative to the face size), the \
+                             blur is limited to what COUNT passes can achieve and \
+                             a warning is displayed.",
+                        )
+                        .takes_value(true)
+                        .default_value("256"),
+                )
+                .arg(
+                    Arg::with_name("orient")
+                        .long("orient")
+                        .value_name("FACE=TRANSFORM")
+                        .help(
+                            "Corrects the orientation of an input face authored with \
+                             a convention different from OpenGL's. FACE is one of \
+                             posx, negx, posy, negy, posz, and negz. TRANSFORM is one \
+                             of flip-h, flip-v, rotate90, rotate180, rotate270 \
+                             (clockwise), transpose, and anti-transpose. Can be \
+                             specified multiple times.",
+                        )
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("no-repair")
+                        .long("no-repair")
+                        .help(
+                            "Do not replace NaN and infinite input values with \
+                             the average of their neighbors before blurring.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("normalize")
+                        .short("n")
+                        .long("normalize")
+                        .help("Scale the output values to range [0, 1]"),
+                )
+                .arg(
+                    Arg::with_name("encode")
+                        .short("e")
+                        .long("encode")
+                        .value_name("ENCODING")
+                        .help(
+                            "Specifies how the output images store the linear \
+                             HDR values. 'gamma' applies gamma 2.0 (same as \
+                             the input) and clamps the values to [0, 1]. 'rgbm' \
+                             stores them as RGBM with a range of [0, 6]. \
+                             'rgbd' stores them as RGBD with a range of [0, 255]. \
+                             The alpha channel is discarded by 'rgbm' and 'rgbd'. \
+                             Ignored if the output images are Radiance HDR \
+                             (.hdr) files, which store the linear values as \
+                             they are.",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["gamma", "rgbm", "rgbd"])
+                        .default_value("gamma"),
+                )
+                .arg(
+                    Arg::with_name("preview")
+                        .long("preview")
+                        .value_name("FILE")
+                        .help(
+                            "Additionally saves a tone-mapped 8-bit copy of the \
+                             generated cube map for inspection. FILE is a cube map \
+                             path like OUTDIR. Unlike --normalize, this does not \
+                             affect the other outputs.",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tonemap")
+                        .long("tonemap")
+                        .value_name("OPERATOR")
+                        .help("Specifies the tone mapping operator used by --preview.")
+                        .takes_value(true)
+                        .possible_values(&["reinhard", "aces"])
+                        .default_value("aces"),
+                )
+                .arg(
+                    Arg::with_name("output-ktx2")
+                        .long("output-ktx2")
+                        .value_name("FILE")
+                        .help(
+                            "Additionally saves the generated cube map as a single \
+                             KTX 2.0 file storing the premultiplied, linear values \
+                             as RGBA32F, including the mip levels (see --levels).",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output-dds")
+                        .long("output-dds")
+                        .value_name("FILE")
+                        .help(
+                            "Additionally saves the generated cube map as a single \
+                             DDS file storing the premultiplied, linear values as \
+                             DXGI_FORMAT_R32G32B32A32_FLOAT, including the mip levels \
+                             (see --levels).",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("levels")
+                        .long("levels")
+                        .value_name("COUNT")
+                        .help(
+                            "Specifies the number of mip levels saved by --output-ktx2 \
+                             and --output-dds. Each level is produced by downsampling \
+                             the previous one with a 2×2 box filter. Defaults to the \
+                             full mip chain down to 1×1 (which requires the image size \
+                             to be a power of two; otherwise, the chain stops at the \
+                             first odd size).",
+                        )
+                        .takes_value(true),
+                )
         )
-        .arg(
-            Arg::with_name("output")
-                .required(true)
-                .index(2)
-                .value_name("OUTDIR")
-                .help(
-                    "Specifies the path to save the generated cube map. \
-                     A cube map is composed of six image files named posx.EXT, \
-                     negx.EXT (EXT can be anything), and so forth, and one of \
-                     such files must be specified as the parameter.",
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about(
+                    "Converts an equirectangular (latitude-longitude) image to a \
+                     cube map or vice versa",
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .index(1)
+                        .value_name("INPUT")
+                        .help(
+                            "Specifies the path to the input image. If it names one \
+                             of the six files of a cube map (posx.EXT, negx.EXT, and \
+                             so forth), the cube map is converted to an \
+                             equirectangular image. Otherwise, the image is treated \
+                             as an equirectangular image and converted to a cube map.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .index(2)
+                        .value_name("OUTPUT")
+                        .help(
+                            "Specifies the path to save the converted image. Must \
+                             name one of the six files of a cube map if INPUT is an \
+                             equirectangular image.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .value_name("SIZE")
+                        .help(
+                            "Specifies the face size of the output cube map. Defaults \
+                             to a quarter of the width of the equirectangular image. \
+                             When converting a cube map, specifies the height of the \
+                             equirectangular image (whose width is twice the height) \
+                             instead, which defaults to twice the face size.",
+                        )
+                        .takes_value(true),
                 ),
         )
-        .arg(
-            Arg::with_name("sigma")
-                .short("s")
-                .long("sigma")
-                .value_name("SIGMA")
-                .help("Specifies the standard deviation of the blur kernel.")
-                .takes_value(true)
-                .default_value("0.01"),
+        .subcommand(
+            SubCommand::with_name("mips")
+                .about(
+                    "Generates a mip pyramid of a cube map, each level blurred for \
+                     the roughness it represents",
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .index(1)
+                        .value_name("INDIR")
+                        .help(
+                            "Specifies the path to a cube map. \
+                             A cube map is composed of six image files named posx.EXT, \
+                             negx.EXT (EXT can be anything), and so forth, and one of \
+                             such files must be specified as the parameter.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .index(2)
+                        .value_name("FILE")
+                        .help(
+                            "Specifies the path to save the mip pyramid. The file \
+                             stores the premultiplied, linear values as RGBA32F in the \
+                             KTX 2.0 format, or in the DDS format if the path ends \
+                             with .dds.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("levels")
+                        .long("levels")
+                        .value_name("COUNT")
+                        .help(
+                            "Specifies the number of mip levels. The face size must be \
+                             divisible by 2^(COUNT - 1). Defaults to the full mip chain \
+                             down to 1×1 (or to the first odd size).",
+                        )
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::with_name("normalize")
-                .short("n")
-                .long("normalize")
-                .help("Scale the output values to range [0, 1]"),
+        .subcommand(
+            SubCommand::with_name("sh")
+                .about(
+                    "Prints the coefficients of the L2 spherical harmonics projection \
+                     of a cube map",
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .index(1)
+                        .value_name("INDIR")
+                        .help(
+                            "Specifies the path to a cube map. \
+                             A cube map is composed of six image files named posx.EXT, \
+                             negx.EXT (EXT can be anything), and so forth, and one of \
+                             such files must be specified as the parameter.",
+                        ),
+                ),
         )
-        .get_matches();
+        .get_matches_from(args);
+
+    match matches.subcommand() {
+        ("blur", Some(matches)) => blur(matches),
+        ("convert", Some(matches)) => convert(matches),
+        ("mips", Some(matches)) => mips(matches),
+        ("sh", Some(matches)) => sh(matches),
+        _ => unreachable!(),
+    }
+}
 
+/// Check whether `path` names a Radiance HDR (`.hdr`) image, which stores
+/// linear RGB values without clamping them to `[0, 1]`.
+fn is_hdr_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ext.eq_ignore_ascii_case("hdr"))
+        .unwrap_or(false)
+}
+
+/// Load an image as premultiplied, linear RGBA values.
+///
+/// Radiance HDR (`.hdr`) images are read as linear RGB values with an alpha
+/// of one. Other images are converted from gamma 2.0 RGBA.
+fn load_image(path: &Path) -> Image<Vector4<f32>> {
+    println!("Loading {}", path.display());
+    if is_hdr_path(path) {
+        let decoder =
+            image::hdr::HDRDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let metadata = decoder.metadata();
+        Image {
+            data: decoder
+                .read_image_hdr()
+                .unwrap()
+                .iter()
+                .map(|rgb| Vector4::new(rgb.data[0], rgb.data[1], rgb.data[2], 1.0))
+                .collect::<Vec<_>>(),
+            width: metadata.width as usize,
+            height: metadata.height as usize,
+        }
+    } else {
+        let img = image::open(&path).unwrap().to_rgba();
+
+        // Convert to premultiplied, linear RGBAF32
+        Image {
+            data: img.pixels()
+                .map(|rgba| pixel::srgb_u8_to_linear_f32(rgba.data))
+                .collect::<Vec<_>>(),
+            width: img.width() as usize,
+            height: img.height() as usize,
+        }
+    }
+}
+
+/// Save an image given as premultiplied, linear RGBA values.
+///
+/// Radiance HDR (`.hdr`) images receive the linear RGB values as they are
+/// (`encoding` is ignored), and the alpha channel is discarded.
+fn save_image(path: &Path, image: &Image<Vector4<f32>>, encoding: Encoding) {
+    println!("Saving {}", path.display());
+    if is_hdr_path(path) {
+        let data: Vec<_> = image
+            .data
+            .iter()
+            .map(|&rgba| {
+                let rgb = pixel::unpremultiply(rgba);
+                image::Rgb { data: [rgb.x, rgb.y, rgb.z] }
+            })
+            .collect();
+        image::hdr::HDREncoder::new(BufWriter::new(File::create(path).unwrap()))
+            .encode(&data, image.width, image.height)
+            .unwrap();
+        return;
+    }
+
+    let mut img = image::RgbaImage::new(image.width as u32, image.height as u32);
+    for (y, &rgba) in img.pixels_mut().zip(image.data.iter()) {
+        y.data = if encoding == Encoding::Gamma {
+            pixel::linear_f32_to_srgb_u8(rgba)
+        } else {
+            // Convert to straight alpha
+            let rgb = pixel::unpremultiply(rgba);
+            match encoding {
+                Encoding::Rgbm => color::encode_rgbm(rgb, RGBM_RANGE),
+                Encoding::Rgbd => color::encode_rgbd(rgb),
+                Encoding::Gamma => unreachable!(),
+            }
+        };
+    }
+    img.save(path).unwrap();
+}
+
+/// Load the six faces of a cube map as premultiplied, linear RGBA values
+/// (see `load_image`). Returns the faces and their size.
+fn load_cube_map(files: &CubeMapPathSet) -> (Vec<Image<Vector4<f32>>>, usize) {
+    let images: Vec<_> = files.paths().iter().map(|path| load_image(path)).collect();
+
+    // Validate the image size
+    let cube_map = match CubeMap::from_images(images) {
+        Ok(cube_map) => cube_map,
+        Err(e) => {
+            let face = match e {
+                CubeMapError::NonSquare { face, .. } | CubeMapError::SizeMismatch { face, .. } => {
+                    face
+                }
+                CubeMapError::FaceCount(_) => unreachable!(),
+            };
+            eprintln!(
+                "Error: Image size of '{}' is invalid ({}) — all images must be square \
+                 and have the same size",
+                files.path(face).display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let size = cube_map.size();
+    let images = cube_map
+        .into_faces()
+        .into_iter()
+        .map(|data| Image {
+            data,
+            width: size,
+            height: size,
+        })
+        .collect();
+
+    (images, size)
+}
+
+fn blur(matches: &clap::ArgMatches) {
     let input_files = CubeMapPathSet::from_one(Path::new(matches.value_of_os("input").unwrap()))
         .ok_or("Invalid input path — Try --help")
         .unwrap();
@@ -134,104 +564,76 @@ fn main() {
     use std::str::FromStr;
     let sigma = f32::from_str(matches.value_of("sigma").unwrap()).unwrap();
 
-    let mut images: Vec<_> = input_files
-        .paths()
-        .iter()
-        .map(|path| {
-            println!("Loading {}", path.display());
-            let img = image::open(&path).unwrap().to_rgba();
-
-            // Convert to RGBAF32
-            (
-                Image {
-                    data: img.pixels()
-                        .map(|rgba| {
-                            let rgba = Vector4::new(
-                                rgba.data[0],
-                                rgba.data[1],
-                                rgba.data[2],
-                                rgba.data[3],
-                            ).cast::<f32>();
-
-                            // Linearize
-                            Vector4::new(
-                                rgba.x * rgba.x * rgba.w,
-                                rgba.y * rgba.y * rgba.w,
-                                rgba.z * rgba.z * rgba.w,
-                                rgba.w,
-                            )
-                        })
-                        .collect::<Vec<_>>(),
-                    width: img.width() as usize,
-                    height: img.height() as usize,
-                },
-                path.clone(),
-            )
-        })
-        .collect();
+    let orientations: Vec<(CubeFace, FaceOrientationOverride)> = matches
+        .values_of("orient")
+        .map(|values| values.map(parse_orientation).collect())
+        .unwrap_or_default();
 
-    let size = images[0].0.width;
+    let (mut images, size) = load_cube_map(&input_files);
 
-    // Validate the image size
-    for &(ref image, ref name) in images.iter() {
-        if image.width != size || image.height != size {
-            panic!(
-                "Image size of '{}' is invalid — all images must be square and have the same size",
-                name.display()
+    for &(face, orientation) in orientations.iter() {
+        orientation.apply(&mut images[face.as_ordinal()].data, size);
+    }
+
+    // A single non-finite value would spread over its entire footprint
+    if !matches.is_present("no-repair") {
+        let report = repair_faces(
+            &mut images.iter_mut().map(|i| &mut i.data[..]).collect::<Vec<_>>(),
+            size,
+            RepairPolicy::NeighborAverage,
+        );
+        if report.total() > 0 {
+            println!(
+                "Repaired {} non-finite texels (per face: {:?})",
+                report.total(),
+                report.num_repaired
             );
         }
     }
 
-    // Strip path info
-    let mut images: Vec<_> = images.drain(..).map(|(img, _)| img).collect();
-
-    // Design the filter.
-    // Find the smallest `num_passes` such that
-    //  - `sigma1 * sigma1 * num_passes = sigma * sigma`
-    //  - `sigma1 < 1 / sqrt(3) / kernel_ratio`
     println!("Image size = {}", size);
 
-    let kernel_ratio = 2.0f32;
-    let kernel_upsample = 3.0f32;
-    let sigma1_limit = 1.0 / 2.0 / kernel_ratio;
-    let num_passes = (sigma * sigma / (sigma1_limit * sigma1_limit)).ceil() as usize;
-    let sigma1 = (sigma * sigma / num_passes as f32).sqrt();
-    let sigma1_pxs = sigma1 * size as f32;
-    let kernel_radius = (sigma1_pxs * kernel_ratio * kernel_upsample).ceil() as usize;
-    println!("(Input) σ = {}", sigma);
-    println!("σ₁ = {} = {}px / {}px", sigma1, sigma1_pxs, size);
-    println!("# of Passes = {}", num_passes);
-    println!(
-        "Kernel Radius = {} ≈ 2σ₁ * kernel_upsample",
-        kernel_radius
-    );
-
-    let kernel = ltasgblur::gaussian_kernel(kernel_radius, sigma1_pxs);
+    let quality = match matches.value_of("quality").unwrap() {
+        "draft" => ltasgblur::Quality::Draft,
+        "normal" => ltasgblur::Quality::Normal,
+        "high" => ltasgblur::Quality::High,
+        _ => unreachable!(),
+    };
+    let max_passes = usize::from_str(matches.value_of("max-passes").unwrap()).unwrap();
 
     // Apply the filter
-    let mut images2 = images.clone();
-    for i in 0..num_passes {
-        println!("Pass {}/{}", i + 1, num_passes);
-        for k in 0..3 {
-            println!("  Phase {}... ", k + 1);
-
-            {
-                let mut out_faces: Vec<_> = images2.iter_mut().map(|i| &mut i.data[..]).collect();
-                let in_faces: Vec<_> = images.iter().map(|i| &i.data[..]).collect();
-                ltasgblur::ltasg_single(
-                    &mut out_faces,
-                    &in_faces,
-                    size,
-                    &kernel,
-                    1.0 / kernel_upsample,
-                    k,
-                    ltasgblur::StandardCubeMapTrait,
-                );
-            }
-
-            swap(&mut images, &mut images2);
-        }
+    println!("Applying the blur with σ = {}...", sigma);
+    let mut cube_map =
+        CubeMap::from_faces(images.into_iter().map(|i| i.data).collect(), size).unwrap();
+    let report = ltasgblur::BlurBuilder::new()
+        .sigma(sigma)
+        .quality(quality)
+        .max_passes(max_passes)
+        .run(&mut cube_map);
+    println!(
+        "σ₁ = {} = {}px / {}px",
+        report.sigma1,
+        report.sigma1 * size as f32,
+        size
+    );
+    println!("# of Passes = {}", report.passes);
+    println!("Effective σ = {}", report.effective_sigma);
+    if let Some(warning) = report.warning {
+        println!(
+            "Warning: {}. The blur is limited to σ = {}.",
+            warning,
+            report.effective_sigma
+        );
     }
+    let mut images: Vec<_> = cube_map
+        .into_faces()
+        .into_iter()
+        .map(|data| Image {
+            data,
+            width: size,
+            height: size,
+        })
+        .collect();
 
     if matches.is_present("normalize") {
         let max_value = images
@@ -240,45 +642,214 @@ fn main() {
                 image
                     .data
                     .iter()
-                    .map(|pixel| {
-                        [
-                            pixel[0] / pixel[3],
-                            pixel[1] / pixel[3],
-                            pixel[2] / pixel[3],
-                        ].iter()
-                            .fold(0.0f32, |x, y| x.max(*y))
+                    .map(|&pixel| {
+                        let rgb = pixel::unpremultiply(pixel);
+                        rgb.x.max(rgb.y).max(rgb.z).max(0.0)
                     })
                     .fold(0.0f32, |x, y| x.max(y))
             })
             .fold(0.0f32, |x, y| x.max(y));
         println!("Maximum value = {}", max_value);
-        for image in images.iter_mut() {
-            for x in image.data.iter_mut() {
-                x[0] *= (255.0 * 255.0) / max_value;
-                x[1] *= (255.0 * 255.0) / max_value;
-                x[2] *= (255.0 * 255.0) / max_value;
+        // Leave a black (or fully transparent) image as it is
+        if max_value > 0.0 {
+            for image in images.iter_mut() {
+                for x in image.data.iter_mut() {
+                    x[0] *= 1.0 / max_value;
+                    x[1] *= 1.0 / max_value;
+                    x[2] *= 1.0 / max_value;
+                }
             }
         }
     }
 
-    // Output the processed images
+    let encoding = match matches.value_of("encode").unwrap() {
+        "gamma" => Encoding::Gamma,
+        "rgbm" => Encoding::Rgbm,
+        "rgbd" => Encoding::Rgbd,
+        _ => unreachable!(),
+    };
+
+    save_cube_map(&output_files, &images, encoding);
+
+    if let Some(path) = matches.value_of_os("preview") {
+        let preview_files = CubeMapPathSet::from_one(Path::new(path))
+            .ok_or("Invalid preview path — Try --help")
+            .unwrap();
+        let tone_map = match matches.value_of("tonemap").unwrap() {
+            "reinhard" => pixel::ToneMap::Reinhard,
+            "aces" => pixel::ToneMap::Aces,
+            _ => unreachable!(),
+        };
+        save_preview(&preview_files, &images, size, tone_map);
+    }
+
+    if matches.is_present("output-ktx2") || matches.is_present("output-dds") {
+        // Each level must be even-sized to be downsampled by 2×2 blocks
+        let max_levels = cmp::min(mip::mip_count(size), size.trailing_zeros() as usize + 1);
+        let num_levels = match matches.value_of("levels") {
+            Some(levels) => usize::from_str(levels).unwrap(),
+            None => max_levels,
+        };
+        if num_levels == 0 || num_levels > max_levels {
+            eprintln!(
+                "Error: The number of mip levels must be in range [1, {}] for the image \
+                 size {}",
+                max_levels,
+                size
+            );
+            std::process::exit(1);
+        }
+
+        let base = CubeMap::from_faces(images.iter().map(|i| i.data.clone()).collect(), size);
+        let mut levels = vec![base.unwrap()];
+        while levels.len() < num_levels {
+            let next = levels.last().unwrap().downsample_2x();
+            levels.push(next);
+        }
+        println!("# of Mip Levels = {}", num_levels);
+
+        if let Some(path) = matches.value_of_os("output-ktx2") {
+            println!("Saving {}", Path::new(path).display());
+            let mut writer = BufWriter::new(File::create(path).unwrap());
+            ktx2::write_ktx2(&mut writer, &levels).unwrap();
+        }
+        if let Some(path) = matches.value_of_os("output-dds") {
+            println!("Saving {}", Path::new(path).display());
+            let mut writer = BufWriter::new(File::create(path).unwrap());
+            dds::write_dds(&mut writer, &levels).unwrap();
+        }
+    }
+}
+
+fn convert(matches: &clap::ArgMatches) {
+    use std::str::FromStr;
+    let input = Path::new(matches.value_of_os("input").unwrap());
+    let output = Path::new(matches.value_of_os("output").unwrap());
+    let size = matches
+        .value_of("size")
+        .map(|size| usize::from_str(size).unwrap());
+
+    match CubeMapPathSet::from_one(input) {
+        Some(ref input_files) if input_files.exists() => {
+            let (images, size_in) = load_cube_map(input_files);
+            let cube_map =
+                CubeMap::from_faces(images.into_iter().map(|i| i.data).collect(), size_in)
+                    .unwrap();
+
+            let height = size.unwrap_or(size_in * 2);
+            let mut data = vec![Vector4::new(0.0, 0.0, 0.0, 0.0); height * 2 * height];
+            equirect::cubemap_to_equirect(&cube_map, &mut data, height * 2, height);
+            println!("Image size = {}×{}", height * 2, height);
+
+            save_image(output, &Image::new(height * 2, height, data), Encoding::Gamma);
+        }
+        _ => {
+            let output_files = CubeMapPathSet::from_one(output)
+                .ok_or("Invalid output path — Try --help")
+                .unwrap();
+
+            let image = load_image(input);
+            let size = size.unwrap_or(cmp::max(image.width / 4, 1));
+            let mut cube_map = CubeMap::zeros(size);
+            equirect::equirect_to_cubemap(&image.data, image.width, image.height, &mut cube_map);
+            println!("Image size = {}", size);
+
+            let images: Vec<_> = cube_map
+                .into_faces()
+                .into_iter()
+                .map(|data| Image::new(size, size, data))
+                .collect();
+            save_cube_map(&output_files, &images, Encoding::Gamma);
+        }
+    }
+}
+
+fn mips(matches: &clap::ArgMatches) {
+    use std::str::FromStr;
+    let input_files = CubeMapPathSet::from_one(Path::new(matches.value_of_os("input").unwrap()))
+        .ok_or("Invalid input path — Try --help")
+        .unwrap();
+    let output = Path::new(matches.value_of_os("output").unwrap());
+
+    let (images, size) = load_cube_map(&input_files);
+    println!("Image size = {}", size);
+
+    // `generate_mip_pyramid` downsamples each level by 2×2 blocks
+    let max_levels = cmp::min(mip::mip_count(size), size.trailing_zeros() as usize + 1);
+    let num_levels = match matches.value_of("levels") {
+        Some(levels) => usize::from_str(levels).unwrap(),
+        None => max_levels,
+    };
+    if num_levels == 0 || num_levels > max_levels {
+        eprintln!(
+            "Error: The number of mip levels must be in range [1, {}] for the image \
+             size {}",
+            max_levels,
+            size
+        );
+        std::process::exit(1);
+    }
+
+    let base = CubeMap::from_faces(images.into_iter().map(|i| i.data).collect(), size).unwrap();
+    println!("Generating {} mip levels...", num_levels);
+    let levels = pbr::generate_mip_pyramid(&base, num_levels, MIP_KERNEL_SIGMA, MIP_KERNEL_RATIO);
+    for (i, level) in levels.iter().enumerate() {
+        println!(
+            "Level {}: size = {}, roughness = {}",
+            i,
+            level.size(),
+            pbr::roughness_for_sigma(pbr::mip_level_sigma(i))
+        );
+    }
+
+    println!("Saving {}", output.display());
+    let is_dds = output
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ext.eq_ignore_ascii_case("dds"))
+        .unwrap_or(false);
+    let mut writer = BufWriter::new(File::create(output).unwrap());
+    if is_dds {
+        dds::write_dds(&mut writer, &levels).unwrap();
+    } else {
+        ktx2::write_ktx2(&mut writer, &levels).unwrap();
+    }
+}
+
+fn sh(matches: &clap::ArgMatches) {
+    let input_files = CubeMapPathSet::from_one(Path::new(matches.value_of_os("input").unwrap()))
+        .ok_or("Invalid input path — Try --help")
+        .unwrap();
+
+    let (images, size) = load_cube_map(&input_files);
+    let cube_map = CubeMap::from_faces(images.into_iter().map(|i| i.data).collect(), size).unwrap();
+
+    // Print the RGB parts of the coefficients in the order of `sh9_basis`
+    for (i, coef) in pbr::project_sh9(&cube_map).iter().enumerate() {
+        println!("{}: {} {} {}", i, coef.x, coef.y, coef.z);
+    }
+}
+
+/// Save the six faces of a cube map given as premultiplied, linear RGBA
+/// values (see `save_image`).
+fn save_cube_map(files: &CubeMapPathSet, images: &[Image<Vector4<f32>>], encoding: Encoding) {
+    for (image, path) in images.iter().zip(files.paths().iter()) {
+        save_image(path, image, encoding);
+    }
+}
+
+/// Save the six faces of a cube map given as premultiplied, linear RGBA
+/// values as tone-mapped 8-bit images.
+fn save_preview(
+    files: &CubeMapPathSet,
+    images: &[Image<Vector4<f32>>],
+    size: usize,
+    tone_map: pixel::ToneMap,
+) {
     let mut img = image::RgbaImage::new(size as u32, size as u32);
-    for (image, path) in images.iter().zip(output_files.paths().iter()) {
-        for (y, x) in img.pixels_mut().zip(image.data.iter()) {
-            let rgba = *x;
-
-            // De-linearize, convert to straight alpha, and round
-            let rgba = Vector4::new(
-                ((rgba.x / rgba.w).sqrt()).round().min(255.0),
-                ((rgba.y / rgba.w).sqrt()).round().min(255.0),
-                ((rgba.z / rgba.w).sqrt()).round().min(255.0),
-                (rgba.w).round().min(255.0),
-            ).cast::<u8>();
-
-            y.data[0] = rgba.x;
-            y.data[1] = rgba.y;
-            y.data[2] = rgba.z;
-            y.data[3] = rgba.w;
+    for (image, path) in images.iter().zip(files.paths().iter()) {
+        for (y, &x) in img.pixels_mut().zip(image.data.iter()) {
+            y.data = pixel::tonemap_to_u8(x, tone_map, 1.0);
         }
         println!("Saving {}", path.display());
         img.save(path).unwrap();
@@ -9,9 +9,6 @@
 
 use std::{ops, convert};
 
-#[cfg(not(debug_assertions))]
-use std::slice;
-
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct SliceAccessor<T> {
     slice: T,
@@ -42,66 +39,35 @@ impl<T> ops::DerefMut for SliceAccessor<T> {
     }
 }
 
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::Index<I> for SliceAccessor<&'a [T]>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    type Output = I::Output;
-    fn index(&self, index: I) -> &Self::Output {
-        unsafe { self.slice.get_unchecked(index) }
-    }
-}
-
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::Index<I> for SliceAccessor<&'a Vec<T>>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    type Output = I::Output;
-    fn index(&self, index: I) -> &Self::Output {
-        unsafe { self.slice.get_unchecked(index) }
-    }
-}
-
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::Index<I> for SliceAccessor<&'a mut [T]>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    type Output = I::Output;
-    fn index(&self, index: I) -> &Self::Output {
-        unsafe { self.slice.get_unchecked(index) }
-    }
-}
-
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::IndexMut<I> for SliceAccessor<&'a mut [T]>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    fn index_mut(&mut self, index: I) -> &mut Self::Output {
-        unsafe { self.slice.get_unchecked_mut(index) }
-    }
-}
-
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::Index<I> for SliceAccessor<&'a mut Vec<T>>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    type Output = I::Output;
-    fn index(&self, index: I) -> &Self::Output {
-        unsafe { self.slice.get_unchecked(index) }
-    }
+/// Implement `Index<$idx>` (and `IndexMut<$idx>` for mutable containers) for
+/// `SliceAccessor<$slice>` using the unchecked accessors of slices.
+macro_rules! impl_index {
+    ($idx:ty, $output:ty) => {
+        impl_index!(@index &'a [T], $idx, $output);
+        impl_index!(@index &'a Vec<T>, $idx, $output);
+        impl_index!(@index &'a mut [T], $idx, $output);
+        impl_index!(@index &'a mut Vec<T>, $idx, $output);
+        impl_index!(@index_mut &'a mut [T], $idx, $output);
+        impl_index!(@index_mut &'a mut Vec<T>, $idx, $output);
+    };
+    (@index $slice:ty, $idx:ty, $output:ty) => {
+        #[cfg(not(debug_assertions))]
+        impl<'a, T> ops::Index<$idx> for SliceAccessor<$slice> {
+            type Output = $output;
+            fn index(&self, index: $idx) -> &Self::Output {
+                unsafe { self.slice.get_unchecked(index) }
+            }
+        }
+    };
+    (@index_mut $slice:ty, $idx:ty, $output:ty) => {
+        #[cfg(not(debug_assertions))]
+        impl<'a, T> ops::IndexMut<$idx> for SliceAccessor<$slice> {
+            fn index_mut(&mut self, index: $idx) -> &mut Self::Output {
+                unsafe { self.slice.get_unchecked_mut(index) }
+            }
+        }
+    };
 }
 
-#[cfg(not(debug_assertions))]
-impl<'a, T, I> ops::IndexMut<I> for SliceAccessor<&'a mut Vec<T>>
-where
-    I: slice::SliceIndex<[T]>,
-{
-    fn index_mut(&mut self, index: I) -> &mut Self::Output {
-        unsafe { self.slice.get_unchecked_mut(index) }
-    }
-}
+impl_index!(usize, T);
+impl_index!(ops::Range<usize>, [T]);
@@ -0,0 +1,145 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides an edge-preserving (bilateral) blur for cleaning up captured
+//! environment maps.
+use std::ops;
+use cgmath::num_traits;
+
+use container::CubeMap;
+use cubemap::gather_offset;
+use pixel::Luminance;
+
+/// Apply a bilateral filter to a cube map.
+///
+/// Each output texel is the weighted average of the input texels within
+/// `3 * spatial_sigma` texels, where the weight of a neighbor is the product
+/// of a Gaussian of its distance (`spatial_sigma`, measured in texels on the
+/// face grid) and a Gaussian of its luminance difference from the center
+/// texel (`range_sigma`). Neighbors across a large brightness step, such as
+/// the edge of the sun disk, receive little weight, so the step survives
+/// while the noise on either side is smoothed out. The footprint continues
+/// across the face seams.
+///
+/// Unlike `ltasg_single`, this filter is not separable and its cost grows
+/// with `spatial_sigma²`. A very large `range_sigma` makes it a plain
+/// (non-spherical) Gaussian blur.
+pub fn bilateral_blur<T>(
+    faces: &[&[T]],
+    size: usize,
+    spatial_sigma: f32,
+    range_sigma: f32,
+) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero + Luminance,
+{
+    assert!(spatial_sigma >= 0.0);
+    assert!(range_sigma > 0.0);
+
+    let radius = (spatial_sigma * 3.0).ceil() as isize;
+    assert!(radius < size as isize);
+
+    let spatial_weights: Vec<f32> = (-radius..radius + 1)
+        .flat_map(|dy| (-radius..radius + 1).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| {
+            let d2 = (dx * dx + dy * dy) as f32;
+            if spatial_sigma > 0.0 {
+                (-0.5 * d2 / (spatial_sigma * spatial_sigma)).exp()
+            } else if d2 == 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let range_scale = -0.5 / (range_sigma * range_sigma);
+
+    CubeMap::from_fn(size, |face, x, y| {
+        let center = faces[face.as_ordinal()][x + y * size].luminance();
+        let mut sum = T::zero();
+        let mut weight_sum = 0.0f32;
+        let mut weights = spatial_weights.iter();
+
+        for dy in -radius..radius + 1 {
+            for dx in -radius..radius + 1 {
+                let spatial_weight = *weights.next().unwrap();
+                if spatial_weight == 0.0 {
+                    continue;
+                }
+                let texel = gather_offset(faces, size, face, x, y, dx, dy);
+                let diff = texel.luminance() - center;
+                let weight = spatial_weight * (diff * diff * range_scale).exp();
+                sum = sum + texel * weight;
+                weight_sum += weight;
+            }
+        }
+
+        // The center texel always contributes with weight 1
+        sum * (1.0 / weight_sum)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CubeFace;
+
+    /// A cube map with a vertical step edge at the center of every face.
+    fn step_edge(size: usize) -> Vec<Vec<f32>> {
+        (0..6)
+            .map(|_| {
+                (0..size * size)
+                    .map(|i| if i % size >= size / 2 { 1.0 } else { 0.0 })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn high_range_sigma_is_gaussian() {
+        let size = 16;
+        let spatial_sigma = 1.5;
+        let src = ::container::test_pattern(size).into_faces();
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+
+        let out = bilateral_blur(&src, size, spatial_sigma, 1.0e6);
+
+        let radius = 5isize;
+        for (face, x, y, &actual) in out.iter_texels() {
+            let (mut sum, mut weight_sum) = (0.0, 0.0);
+            for dy in -radius..radius + 1 {
+                for dx in -radius..radius + 1 {
+                    let w = (-0.5 * (dx * dx + dy * dy) as f32 /
+                        (spatial_sigma * spatial_sigma)).exp();
+                    sum += gather_offset(&src, size, face, x, y, dx, dy) * w;
+                    weight_sum += w;
+                }
+            }
+            let expected = sum / weight_sum;
+            assert!((actual - expected).abs() < 1.0e-4, "{} != {}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn low_range_sigma_preserves_edge() {
+        let size = 16;
+        let src = step_edge(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+
+        let sharp = bilateral_blur(&src, size, 2.0, 0.05);
+        let blurry = bilateral_blur(&src, size, 2.0, 1.0e6);
+
+        let y = size / 2;
+        let (dark, bright) = (size / 2 - 1, size / 2);
+        let face = sharp.face(CubeFace::PositiveZ);
+        assert!(face[dark + y * size] < 1.0e-3, "{}", face[dark + y * size]);
+        assert!(face[bright + y * size] > 1.0 - 1.0e-3, "{}", face[bright + y * size]);
+
+        let face = blurry.face(CubeFace::PositiveZ);
+        assert!(face[dark + y * size] > 0.2);
+        assert!(face[bright + y * size] < 0.8);
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides functions for blending environment maps.
+use std::ops;
+
+use container::CubeMap;
+use pixel::Luminance;
+
+/// Interpolate between two cube maps, producing `a * (1 - t) + b * t`.
+///
+/// Both inputs must contain linear radiance values (the working space of this
+/// library) and consist of six `size`×`size` images. Blending in linear space
+/// preserves energy, so the blend of two environments at `t = 0.5` receives
+/// exactly the average of their incident light. A lerp performed on
+/// gamma-encoded values, on the other hand, darkens the midpoint.
+pub fn blend_cubemaps<T>(a: &[&[T]], b: &[&[T]], t: f32, size: usize) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    CubeMap::from_fn(size, |face, x, y| {
+        let i = face.as_ordinal();
+        let k = x + y * size;
+        a[i][k] * (1.0 - t) + b[i][k] * t
+    })
+}
+
+/// The exponent of the lightness curve used by `blend_cubemaps_perceptual`.
+const PERCEPTUAL_GAMMA: f32 = 2.2;
+
+/// Interpolate between two cube maps so that the perceived brightness of each
+/// texel progresses uniformly with `t`.
+///
+/// With a linear blend, the midpoint between a bright and a dark environment
+/// looks almost as bright as the bright one because the perceived brightness
+/// is a concave function of the luminance. This function instead interpolates
+/// the lightness (`luminance^(1 / 2.2)`) of each texel and then chooses the
+/// linear blend weight that reproduces the interpolated luminance, so the
+/// output is still a convex combination of the inputs (no hue shift, no
+/// negative values). Texels with equal luminance on both sides use `t`
+/// as-is.
+///
+/// The same assumptions as `blend_cubemaps` apply to the inputs.
+pub fn blend_cubemaps_perceptual<T>(
+    a: &[&[T]],
+    b: &[&[T]],
+    t: f32,
+    size: usize,
+) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + Luminance,
+{
+    CubeMap::from_fn(size, |face, x, y| {
+        let i = face.as_ordinal();
+        let k = x + y * size;
+        let (a, b) = (a[i][k], b[i][k]);
+
+        let (lum_a, lum_b) = (a.luminance().max(0.0), b.luminance().max(0.0));
+        let lightness = lum_a.powf(1.0 / PERCEPTUAL_GAMMA) * (1.0 - t) +
+            lum_b.powf(1.0 / PERCEPTUAL_GAMMA) * t;
+        let lum = lightness.powf(PERCEPTUAL_GAMMA);
+
+        let weight = if (lum_b - lum_a).abs() > 1.0e-20 {
+            ((lum - lum_a) / (lum_b - lum_a)).max(0.0).min(1.0)
+        } else {
+            t
+        };
+
+        a * (1.0 - weight) + b * weight
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+
+    fn constant(size: usize, value: f32) -> Vec<Vec<f32>> {
+        vec![vec![value; size * size]; 6]
+    }
+
+    fn gradient(size: usize) -> Vec<Vec<f32>> {
+        (0..6)
+            .map(|face| (0..size * size).map(|i| (i + face) as f32).collect())
+            .collect()
+    }
+
+    #[test]
+    fn endpoints_reproduce_inputs() {
+        let size = 4;
+        let a = gradient(size);
+        let b = constant(size, 3.0);
+        let a: Vec<_> = a.iter().map(Vec::as_slice).collect();
+        let b: Vec<_> = b.iter().map(Vec::as_slice).collect();
+
+        for &(t, expected) in [(0.0, &a), (1.0, &b)].iter() {
+            let linear = blend_cubemaps(&a, &b, t, size);
+            let perceptual = blend_cubemaps_perceptual(&a, &b, t, size);
+            for &face in CUBE_FACES.iter() {
+                assert_eq!(linear.face(face), expected[face.as_ordinal()]);
+                for (x, y) in perceptual
+                    .face(face)
+                    .iter()
+                    .zip(expected[face.as_ordinal()].iter())
+                {
+                    assert!((x - y).abs() < 1.0e-4, "{} ≠ {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn midpoint_is_linear_average() {
+        let size = 4;
+        let a = constant(size, 1.0);
+        let b = constant(size, 0.25);
+        let a: Vec<_> = a.iter().map(Vec::as_slice).collect();
+        let b: Vec<_> = b.iter().map(Vec::as_slice).collect();
+
+        let blended = blend_cubemaps(&a, &b, 0.5, size);
+        for &face in CUBE_FACES.iter() {
+            assert!(blended.face(face).iter().all(|&x| x == 0.625));
+        }
+
+        // The perceptual blend puts the midpoint below the linear average
+        let blended = blend_cubemaps_perceptual(&a, &b, 0.5, size);
+        for &face in CUBE_FACES.iter() {
+            assert!(blended.face(face).iter().all(|&x| x > 0.25 && x < 0.625));
+        }
+    }
+}
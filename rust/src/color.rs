@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides color encodings for storing HDR values in 8-bit RGBA textures,
+//! and the white balance adjustment.
+//!
+//! All functions take and return linear RGB values. The channels are stored
+//! linearly as well; they must not be sampled through an sRGB texture format.
+use cgmath::Vector3;
+
+use container::CubeMap;
+use cubemap::{texel_solid_angle, CUBE_FACES};
+use pixel::{Luminance, Rgb};
+
+fn max3(rgb: Vector3<f32>) -> f32 {
+    rgb.x.max(rgb.y).max(rgb.z)
+}
+
+fn quantize(x: f32) -> u8 {
+    (x * 255.0).round().max(0.0).min(255.0) as u8
+}
+
+/// Encode a linear RGB value as RGBM.
+///
+/// RGBM stores `rgb / (m * range)` in the color channels and the multiplier
+/// `m` in the alpha channel, so the decoded value is `rgb * m * range`. The
+/// representable range is `[0, range]`; larger values are clamped. Since
+/// `m` is quantized to 8 bits, the precision degrades toward the bottom of
+/// the range: the values below `range / 255` are stored with the smallest
+/// multiplier and therefore an absolute step of `range / 255²`. `range` is
+/// typically between 6 and 8 for lighting.
+pub fn encode_rgbm(rgb: Vector3<f32>, range: f32) -> [u8; 4] {
+    assert!(range > 0.0);
+    let rgb = Vector3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0));
+    let m = (max3(rgb) / range).max(1.0 / 255.0).min(1.0);
+    // Round up so that the color channels stay within `[0, 1]`
+    let m = (m * 255.0).ceil() / 255.0;
+    let scale = 1.0 / (m * range);
+    [
+        quantize(rgb.x * scale),
+        quantize(rgb.y * scale),
+        quantize(rgb.z * scale),
+        quantize(m),
+    ]
+}
+
+/// Decode an RGBM value produced by `encode_rgbm`.
+pub fn decode_rgbm(rgbm: [u8; 4], range: f32) -> Vector3<f32> {
+    let scale = rgbm[3] as f32 * range / (255.0 * 255.0);
+    Vector3::new(rgbm[0] as f32, rgbm[1] as f32, rgbm[2] as f32) * scale
+}
+
+/// Encode a linear RGB value as RGBD.
+///
+/// RGBD stores `rgb * d` in the color channels and the divisor `d` in the
+/// alpha channel, so the decoded value is `rgb / d`. The values in `[0, 1]`
+/// are stored with `d = 1` (i.e., as plain 8-bit values); brighter values
+/// are scaled down to fit. With `d` quantized to 8 bits, the representable
+/// range is `[0, 255]`. Compared to RGBM, the precision is concentrated on
+/// the low end, and the relative precision of the high end degrades with the
+/// brightness.
+pub fn encode_rgbd(rgb: Vector3<f32>) -> [u8; 4] {
+    let rgb = Vector3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0));
+    let d = (1.0 / max3(rgb)).max(1.0 / 255.0).min(1.0);
+    // Round down so that the color channels stay within `[0, 1]`
+    let d = ((d * 255.0).floor() / 255.0).max(1.0 / 255.0);
+    [
+        quantize(rgb.x * d),
+        quantize(rgb.y * d),
+        quantize(rgb.z * d),
+        quantize(d),
+    ]
+}
+
+/// Decode an RGBD value produced by `encode_rgbd`.
+pub fn decode_rgbd(rgbd: [u8; 4]) -> Vector3<f32> {
+    let scale = 1.0 / rgbd[3].max(1) as f32;
+    Vector3::new(rgbd[0] as f32, rgbd[1] as f32, rgbd[2] as f32) * scale
+}
+
+/// Estimate the per-channel gains that neutralize the color cast of a cube
+/// map.
+///
+/// Uses the gray-world assumption: the average color of the environment,
+/// weighted by the solid angles of the texels, is assumed to be gray. The
+/// gains map the average color to the gray of the same luminance, so
+/// applying them does not change the overall brightness. A channel whose
+/// average is zero or not finite gets a gain of `1`. Note that a dominant
+/// colored object or sky biases the estimate; inspect the result before
+/// applying it to such environments.
+///
+/// `faces` is a slice of six `size`×`size` raster images. Apply the result
+/// with `apply_white_balance`.
+pub fn estimate_white_balance<T>(faces: &[&[T]], size: usize) -> Vector3<f32>
+where
+    T: Rgb,
+{
+    let mut sum = [0.0f64; 3];
+    for &face in CUBE_FACES.iter() {
+        let image = &faces[face.as_ordinal()][0..size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let solid_angle = texel_solid_angle(x, y, size) as f64;
+                let rgb = image[x + y * size].rgb();
+                for i in 0..3 {
+                    sum[i] += rgb[i] as f64 * solid_angle;
+                }
+            }
+        }
+    }
+
+    // The weighted sum is proportional to the average color
+    let sum = Vector3::new(sum[0] as f32, sum[1] as f32, sum[2] as f32);
+    let gray = sum.luminance();
+    let gain = |x: f32| {
+        let gain = gray / x;
+        if x > 0.0 && gain.is_finite() {
+            gain
+        } else {
+            1.0
+        }
+    };
+    Vector3::new(gain(sum.x), gain(sum.y), gain(sum.z))
+}
+
+/// Multiply the RGB channels of every texel of a cube map by `gains`.
+pub fn apply_white_balance<T>(cube_map: &mut CubeMap<T>, gains: Vector3<f32>)
+where
+    T: Rgb,
+{
+    for (_, _, _, texel) in cube_map.iter_texels_mut() {
+        let rgb = texel.rgb();
+        *texel = texel.with_rgb(Vector3::new(rgb.x * gains.x, rgb.y * gains.y, rgb.z * gains.z));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_values() -> Vec<Vector3<f32>> {
+        let mut values = vec![Vector3::new(0.0, 0.0, 0.0)];
+        for i in 0..200 {
+            let t = i as f32 / 199.0;
+            // Exponentially distributed magnitudes with varying hues
+            let magnitude = (t * 14.0 - 8.0).exp2();
+            values.push(Vector3::new(1.0, 0.5 + 0.5 * t, 0.2) * magnitude);
+            values.push(Vector3::new(0.1 * t, 1.0, 0.7) * magnitude);
+        }
+        values
+    }
+
+    fn clamp_rgb(rgb: Vector3<f32>, max: f32) -> Vector3<f32> {
+        Vector3::new(rgb.x.min(max), rgb.y.min(max), rgb.z.min(max))
+    }
+
+    #[test]
+    fn rgbm_round_trip() {
+        let range = 6.0;
+        for &value in test_values().iter() {
+            let value = clamp_rgb(value, range);
+            let encoded = encode_rgbm(value, range);
+            let decoded = decode_rgbm(encoded, range);
+            // Half a step of the color channels at the chosen multiplier
+            let tolerance = encoded[3] as f32 * range / (255.0 * 255.0) * 0.5 + 1.0e-6;
+            for i in 0..3 {
+                assert!(
+                    (decoded[i] - value[i]).abs() <= tolerance,
+                    "{:?} → {:?} → {:?}",
+                    value,
+                    encoded,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rgbd_round_trip() {
+        for &value in test_values().iter() {
+            let value = clamp_rgb(value, 255.0);
+            let encoded = encode_rgbd(value);
+            let decoded = decode_rgbd(encoded);
+            // Half a step of the color channels at the chosen divisor
+            let tolerance = 0.5 / encoded[3] as f32 + 1.0e-6;
+            for i in 0..3 {
+                assert!(
+                    (decoded[i] - value[i]).abs() <= tolerance,
+                    "{:?} → {:?} → {:?}",
+                    value,
+                    encoded,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_is_clamped() {
+        let decoded = decode_rgbm(encode_rgbm(Vector3::new(100.0, 3.0, -1.0), 6.0), 6.0);
+        assert_eq!(decoded.x, 6.0);
+        assert!((decoded.y - 6.0 * 128.0 / 255.0).abs() < 1.0e-6);
+        assert_eq!(decoded.z, 0.0);
+    }
+
+    fn white_balance_of(cube_map: &CubeMap<Vector3<f32>>) -> Vector3<f32> {
+        let faces: Vec<_> = (&cube_map).into_iter().map(|(_, image)| image).collect();
+        estimate_white_balance(&faces, cube_map.size())
+    }
+
+    fn gray_pattern(size: usize) -> CubeMap<f32> {
+        CubeMap::from_fn(size, |face, x, y| {
+            0.2 + ((face.as_ordinal() * 7 + x * 3 + y * 5) % 11) as f32 * 0.1
+        })
+    }
+
+    #[test]
+    fn white_balance_neutralizes_tint() {
+        let tint = Vector3::new(1.3, 1.0, 0.6);
+        let mut cube_map = gray_pattern(8).map(|&x| tint * x);
+        let gains = white_balance_of(&cube_map);
+        apply_white_balance(&mut cube_map, gains);
+        for (_, _, _, &rgb) in cube_map.iter_texels() {
+            assert!((rgb.x - rgb.y).abs() < 1.0e-5 && (rgb.z - rgb.y).abs() < 1.0e-5, "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn white_balance_of_neutral_is_unit() {
+        let cube_map = gray_pattern(8).map(|&x| Vector3::new(x, x, x));
+        let gains = white_balance_of(&cube_map);
+        for i in 0..3 {
+            assert!((gains[i] - 1.0).abs() < 1.0e-5, "{:?}", gains);
+        }
+
+        // Nothing to adjust
+        let black = CubeMap::filled(8, Vector3::new(0.0, 0.0, 0.0));
+        assert_eq!(white_balance_of(&black), Vector3::new(1.0, 1.0, 1.0));
+    }
+}
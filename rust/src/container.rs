@@ -0,0 +1,1106 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides a container type for cube map images.
+use std::{error, fmt, iter, ops, slice};
+use cgmath::{num_traits, Quaternion, Vector2, Vector3};
+use cgmath::prelude::*;
+use cubemap::{texel_direction, wrap_texel, CubeFace, FaceOrientationOverride, CUBE_FACES};
+use mip::{downsample, MipFilter};
+use pixel::Luminance;
+use raster::Image;
+
+/// An error returned when constructing a `CubeMap` from existing images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CubeMapError {
+    /// The number of faces was not six.
+    FaceCount(usize),
+    /// The number of texels of `face` was not `size * size`.
+    SizeMismatch {
+        face: CubeFace,
+        expected: usize,
+        found: usize,
+    },
+    /// The image of `face` was not square.
+    NonSquare {
+        face: CubeFace,
+        width: usize,
+        height: usize,
+    },
+}
+
+impl fmt::Display for CubeMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CubeMapError::FaceCount(count) => {
+                write!(f, "expected 6 faces, found {}", count)
+            }
+            &CubeMapError::SizeMismatch {
+                face,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {} texels in the face {:?}, found {}",
+                expected,
+                face,
+                found
+            ),
+            &CubeMapError::NonSquare {
+                face,
+                width,
+                height,
+            } => write!(
+                f,
+                "the face {:?} is not square ({}×{})",
+                face,
+                width,
+                height
+            ),
+        }
+    }
+}
+
+impl error::Error for CubeMapError {
+    fn description(&self) -> &str {
+        match self {
+            &CubeMapError::FaceCount(_) => "wrong number of faces",
+            &CubeMapError::SizeMismatch { .. } => "wrong face size",
+            &CubeMapError::NonSquare { .. } => "non-square face",
+        }
+    }
+}
+
+/// The reason a `CubeMap` cannot be uploaded to a GPU as it is. Returned by
+/// `CubeMap::validate_gpu_compatible`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuCompatError {
+    /// The face size was not a power of two.
+    NotPowerOfTwo { size: usize },
+}
+
+impl fmt::Display for GpuCompatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &GpuCompatError::NotPowerOfTwo { size } => {
+                write!(f, "the face size must be a power of two, but was {}", size)
+            }
+        }
+    }
+}
+
+impl error::Error for GpuCompatError {
+    fn description(&self) -> &str {
+        match self {
+            &GpuCompatError::NotPowerOfTwo { .. } => "face size not a power of two",
+        }
+    }
+}
+
+/// Six `size`×`size` raster images forming a cube map.
+///
+/// The faces are stored in the order defined by `CUBE_FACES`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeMap<T> {
+    size: usize,
+    faces: Vec<Vec<T>>,
+}
+
+impl<T> CubeMap<T> {
+    /// Construct a `CubeMap` by evaluating `f(face, x, y)` for every texel.
+    pub fn from_fn<F>(size: usize, mut f: F) -> Self
+    where
+        F: FnMut(CubeFace, usize, usize) -> T,
+    {
+        let faces = CUBE_FACES
+            .iter()
+            .map(|&face| {
+                let mut image = Vec::with_capacity(size * size);
+                for y in 0..size {
+                    for x in 0..size {
+                        image.push(f(face, x, y));
+                    }
+                }
+                image
+            })
+            .collect();
+        Self { size, faces }
+    }
+
+    /// Construct a `CubeMap` from six `size`×`size` raster images in the order
+    /// defined by `CUBE_FACES`.
+    pub fn from_faces(faces: Vec<Vec<T>>, size: usize) -> Result<Self, CubeMapError> {
+        if faces.len() != 6 {
+            return Err(CubeMapError::FaceCount(faces.len()));
+        }
+        for (&face, image) in CUBE_FACES.iter().zip(faces.iter()) {
+            if image.len() != size * size {
+                return Err(CubeMapError::SizeMismatch {
+                    face,
+                    expected: size * size,
+                    found: image.len(),
+                });
+            }
+        }
+        Ok(Self { size, faces })
+    }
+
+    /// Construct a `CubeMap` from six square raster images of the same size in
+    /// the order defined by `CUBE_FACES`.
+    ///
+    /// Returns `CubeMapError::NonSquare` if any of the images is not square,
+    /// and `CubeMapError::SizeMismatch` if the images differ in size from the
+    /// first one.
+    pub fn from_images(images: Vec<Image<T>>) -> Result<Self, CubeMapError> {
+        if images.len() != 6 {
+            return Err(CubeMapError::FaceCount(images.len()));
+        }
+        for (&face, image) in CUBE_FACES.iter().zip(images.iter()) {
+            if image.width != image.height {
+                return Err(CubeMapError::NonSquare {
+                    face,
+                    width: image.width,
+                    height: image.height,
+                });
+            }
+        }
+        let size = images[0].width;
+        Self::from_faces(images.into_iter().map(|image| image.data).collect(), size)
+    }
+
+    /// Construct a `CubeMap` with every texel set to `value`.
+    pub fn filled(size: usize, value: T) -> Self
+    where
+        T: Clone,
+    {
+        Self {
+            size,
+            faces: vec![vec![value; size * size]; 6],
+        }
+    }
+
+    /// Construct a `CubeMap` with every texel set to zero.
+    pub fn zeros(size: usize) -> Self
+    where
+        T: Clone + num_traits::Zero,
+    {
+        Self::filled(size, T::zero())
+    }
+
+    /// Return the size of each face in pixels.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Check if the cube map can be uploaded to a GPU with a full mip chain
+    /// (see `mip::mip_count`) without any resizing.
+    ///
+    /// The faces are always square, so this only checks that the face size
+    /// is a power of two, which is required by many graphics APIs and texture
+    /// compression formats.
+    pub fn validate_gpu_compatible(&self) -> Result<(), GpuCompatError> {
+        if !self.size.is_power_of_two() {
+            return Err(GpuCompatError::NotPowerOfTwo { size: self.size });
+        }
+        Ok(())
+    }
+
+    pub fn face(&self, face: CubeFace) -> &[T] {
+        &self.faces[face.as_ordinal()]
+    }
+
+    pub fn face_mut(&mut self, face: CubeFace) -> &mut [T] {
+        &mut self.faces[face.as_ordinal()]
+    }
+
+    /// Overwrite every texel with `f(dir)`, where `dir` is the normalized
+    /// direction toward the center of the texel.
+    pub fn fill_from_direction<F>(&mut self, mut f: F)
+    where
+        F: FnMut(Vector3<f32>) -> T,
+    {
+        let size = self.size;
+        for (face, x, y, texel) in self.iter_texels_mut() {
+            *texel = f(texel_direction(face, x, y, size));
+        }
+    }
+
+    /// The parallel version of `fill_from_direction`. The rows of every face
+    /// are processed concurrently on the rayon thread pool.
+    ///
+    /// The result is identical to that of `fill_from_direction` as long as `f`
+    /// is deterministic.
+    #[cfg(feature = "rayon")]
+    pub fn fill_from_direction_par<F>(&mut self, f: F)
+    where
+        T: Send,
+        F: Fn(Vector3<f32>) -> T + Sync,
+    {
+        use rayon::prelude::*;
+        let size = self.size;
+        let f = &f;
+        self.faces
+            .par_iter_mut()
+            .zip(CUBE_FACES.par_iter())
+            .for_each(|(image, &face)| {
+                image.par_chunks_mut(size).enumerate().for_each(|(y, row)| {
+                    for (x, texel) in row.iter_mut().enumerate() {
+                        *texel = f(texel_direction(face, x, y, size));
+                    }
+                })
+            });
+    }
+
+    /// Sample the cube map in the direction `dir` with bilinear filtering.
+    /// `dir` does not have to be normalized but must not be the zero vector.
+    ///
+    /// The filter footprint continues onto the adjacent faces across the
+    /// seams, so the result is continuous over the whole sphere. Near a
+    /// corner, where three faces meet, one of the four texels of the footprint
+    /// falls outside every face; the average of the three available texels is
+    /// used in its place.
+    pub fn sample_dir(&self, dir: Vector3<f32>) -> T
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+    {
+        let (face, uv) = CubeFace::from_direction_uv(dir);
+        self.sample_face_uv(face, uv)
+    }
+
+    /// Sample the point `uv` (in `[-1, 1]²`) on `face` with bilinear
+    /// filtering. See `sample_dir` for details.
+    fn sample_face_uv(&self, face: CubeFace, uv: Vector2<f32>) -> T
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+    {
+        let size = self.size;
+        let s = size as isize;
+
+        // Convert to texel coordinates where the texel `(i, j)` is centered at
+        // `(i, j)`
+        let x = (uv.x + 1.0) * 0.5 * size as f32 - 0.5;
+        let y = (uv.y + 1.0) * 0.5 * size as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let fetch = |dx: isize, dy: isize| {
+            let (x, y) = (x0 + dx, y0 + dy);
+            if (x < 0 || x >= s) && (y < 0 || y >= s) {
+                // Diagonally adjacent to a corner
+                None
+            } else {
+                let (face, i) = wrap_texel(face, x, y, size);
+                Some(self.faces[face.as_ordinal()][i])
+            }
+        };
+        let mut texels = [fetch(0, 0), fetch(1, 0), fetch(0, 1), fetch(1, 1)];
+
+        // At most one of them can be missing
+        if let Some(missing) = texels.iter().position(Option::is_none) {
+            let mut others = texels.iter().filter_map(|&t| t);
+            let first = others.next().unwrap();
+            let sum = others.fold(first, |sum, t| sum + t);
+            texels[missing] = Some(sum * (1.0 / 3.0));
+        }
+
+        let t = |i: usize| texels[i].unwrap();
+        let top = t(0) * (1.0 - fx) + t(1) * fx;
+        let bottom = t(2) * (1.0 - fx) + t(3) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Construct a `CubeMap` of the same size whose contents are rotated by
+    /// `rotation`, i.e., the output in the direction `rotation * dir` is the
+    /// input in the direction `dir`.
+    ///
+    /// Each output texel is resampled from the input with `sample_dir`. The
+    /// rotations by multiples of 90° about the coordinate axes map the texel
+    /// centers onto each other, so they are lossless up to rounding errors.
+    /// Other rotations blur the image slightly because of the bilinear
+    /// filtering.
+    pub fn rotate(&self, rotation: Quaternion<f32>) -> Self
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+    {
+        let size = self.size;
+        let inverse = rotation.invert();
+        CubeMap::from_fn(size, |face, x, y| {
+            let dir = face.info().pixel_direction(x, y, size);
+            self.sample_dir(inverse.rotate_vector(dir))
+        })
+    }
+
+    /// Compute the integral of the cube map over the sphere, i.e., the sum of
+    /// the texels weighted by their solid angles (see
+    /// `CubeFace::texel_solid_angle`). Dividing the result by `4π` yields
+    /// the average value over the sphere.
+    pub fn weighted_sum(&self) -> T
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+    {
+        let size = self.size;
+        self.iter_texels().fold(T::zero(), |sum, (face, x, y, &texel)| {
+            sum + texel * face.texel_solid_angle(x, y, size)
+        })
+    }
+
+    /// Replace the texels at each of the eight corners of the cube with the
+    /// average of the three texels (one on each of the three faces meeting
+    /// there).
+    ///
+    /// These texels represent nearly the same direction, so any difference
+    /// between them (e.g., caused by processing the faces independently)
+    /// shows up as a twinkling artifact when the cube map is sampled.
+    pub fn fixup_corners(&mut self)
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+    {
+        let size = self.size;
+        for i in 0..8 {
+            let sign = |bit: usize| if i & bit != 0 { 1.0 } else { -1.0 };
+            let corner = Vector3::new(sign(1), sign(2), sign(4));
+
+            // The corner texel of each face adjoining the corner
+            let coord = |axis: Vector3<f32>| if axis.dot(corner) > 0.0 { size - 1 } else { 0 };
+            let texels: Vec<(usize, usize)> = CUBE_FACES
+                .iter()
+                .filter(|face| face.normal::<f32>().dot(corner) > 0.0)
+                .map(|face| (face.as_ordinal(), coord(face.u_vec()) + coord(face.v_vec()) * size))
+                .collect();
+
+            let sum = texels
+                .iter()
+                .fold(T::zero(), |sum, &(face, i)| sum + self.faces[face][i]);
+            let average = sum * (1.0 / texels.len() as f32);
+            for &(face, i) in texels.iter() {
+                self.faces[face][i] = average;
+            }
+        }
+    }
+
+    /// Replace each pair of texels facing each other across a seam with their
+    /// average, removing the hairline seams caused by mismatching edges
+    /// (e.g., in cube maps authored or processed per face). The corners are
+    /// then fixed up by `fixup_corners`.
+    ///
+    /// Unlike the blur, only the outermost texels of each face are modified.
+    pub fn fixup_edges(&mut self)
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+    {
+        let size = self.size;
+        let s = size as isize;
+        let edge_coord = |d: isize| if d < 0 { 0 } else { s - 1 };
+        for &face in CUBE_FACES.iter() {
+            // Visit the texels along each edge, and their neighbors across it
+            for &(dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter() {
+                for t in 0..s {
+                    let (x, y) = if dx != 0 { (edge_coord(dx), t) } else { (t, edge_coord(dy)) };
+                    let (adj, j) = wrap_texel(face, x + dx, y + dy, size);
+
+                    // Process each pair only once
+                    if adj.as_ordinal() < face.as_ordinal() {
+                        continue;
+                    }
+                    let i = (x + y * s) as usize;
+                    let average = (self.faces[face.as_ordinal()][i] +
+                        self.faces[adj.as_ordinal()][j]) * 0.5;
+                    self.faces[face.as_ordinal()][i] = average;
+                    self.faces[adj.as_ordinal()][j] = average;
+                }
+            }
+        }
+        self.fixup_corners();
+    }
+
+    /// Mirror the face `face` horizontally (i.e., reverse the order of the
+    /// texels in each row) in place.
+    pub fn flip_face_h(&mut self, face: CubeFace)
+    where
+        T: Clone,
+    {
+        let size = self.size;
+        FaceOrientationOverride::FlipHorizontal.apply(self.face_mut(face), size);
+    }
+
+    /// Mirror the face `face` vertically (i.e., reverse the order of the rows)
+    /// in place.
+    pub fn flip_face_v(&mut self, face: CubeFace)
+    where
+        T: Clone,
+    {
+        let size = self.size;
+        FaceOrientationOverride::FlipVertical.apply(self.face_mut(face), size);
+    }
+
+    /// Rotate the face `face` clockwise by `90 * times` degrees in place,
+    /// assuming `y` increases downward. See `FaceOrientationOverride` for
+    /// other transformations.
+    pub fn rotate_face_90(&mut self, face: CubeFace, times: usize)
+    where
+        T: Clone,
+    {
+        let size = self.size;
+        let transform = match times % 4 {
+            0 => return,
+            1 => FaceOrientationOverride::Rotate90,
+            2 => FaceOrientationOverride::Rotate180,
+            _ => FaceOrientationOverride::Rotate270,
+        };
+        transform.apply(self.face_mut(face), size);
+    }
+
+    /// Produce the next mip level by averaging each 2×2 block of texels.
+    /// The size must be even.
+    ///
+    /// Since the size is even, every 2×2 block lies within a single face, and
+    /// the output texels on both sides of a seam cover exactly the input
+    /// texels adjacent to it, so the result is as seamless as the input
+    /// without reading across the seams. (Filters with a larger footprint do
+    /// have to, see `mip::downsample`.)
+    pub fn downsample_2x(&self) -> Self
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+    {
+        downsample(&self.faces(), self.size, MipFilter::Box)
+    }
+
+    /// Return the slices of all faces in the order defined by `CUBE_FACES`.
+    /// The result can be passed to the functions taking `&[&[T]]`.
+    pub fn faces(&self) -> [&[T]; 6] {
+        let f = &self.faces;
+        [&f[0], &f[1], &f[2], &f[3], &f[4], &f[5]]
+    }
+
+    /// The mutable version of `faces`.
+    pub fn faces_mut(&mut self) -> [&mut [T]; 6] {
+        let mut it = self.faces.iter_mut().map(|image| &mut image[..]);
+        let mut next = || it.next().unwrap();
+        [next(), next(), next(), next(), next(), next()]
+    }
+
+    /// Destruct the `CubeMap` into the face images in the order defined by
+    /// `CUBE_FACES`.
+    pub fn into_faces(self) -> Vec<Vec<T>> {
+        self.faces
+    }
+
+    /// Construct a new `CubeMap` by applying `f` to every texel.
+    pub fn map<U, F>(&self, mut f: F) -> CubeMap<U>
+    where
+        F: FnMut(&T) -> U,
+    {
+        CubeMap {
+            size: self.size,
+            faces: self.faces
+                .iter()
+                .map(|image| image.iter().map(&mut f).collect())
+                .collect(),
+        }
+    }
+
+    /// Construct a new `CubeMap` containing the Rec. 709 luminance of every
+    /// texel (see `pixel::Luminance`).
+    pub fn luminance(&self) -> CubeMap<f32>
+    where
+        T: Luminance,
+    {
+        self.map(T::luminance)
+    }
+
+    /// Iterate over all texels with their face and coordinates.
+    ///
+    /// The texels are visited in the order of `CUBE_FACES`, and in the
+    /// row-major order within each face.
+    pub fn iter_texels<'a>(&'a self) -> impl Iterator<Item = (CubeFace, usize, usize, &'a T)> + 'a {
+        let size = self.size;
+        CUBE_FACES
+            .iter()
+            .zip(self.faces.iter())
+            .flat_map(move |(&face, image)| {
+                image
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, texel)| (face, i % size, i / size, texel))
+            })
+    }
+
+    /// Iterate over all texels with their face, index within the face, and
+    /// normalized direction toward the texel center (computed by
+    /// `CubeFaceInfo::pixel_direction`).
+    ///
+    /// The texels are visited in the same order as `iter_texels`.
+    pub fn iter_faces_with_dir<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (CubeFace, usize, Vector3<f32>, &'a T)> + 'a {
+        let size = self.size;
+        CUBE_FACES
+            .iter()
+            .zip(self.faces.iter())
+            .flat_map(move |(&face, image)| {
+                let info = face.info();
+                image.iter().enumerate().map(move |(i, texel)| {
+                    (face, i, info.pixel_direction(i % size, i / size, size), texel)
+                })
+            })
+    }
+
+    /// The mutable version of `iter_texels`.
+    pub fn iter_texels_mut<'a>(
+        &'a mut self,
+    ) -> impl Iterator<Item = (CubeFace, usize, usize, &'a mut T)> + 'a {
+        let size = self.size;
+        CUBE_FACES
+            .iter()
+            .zip(self.faces.iter_mut())
+            .flat_map(move |(&face, image)| {
+                image
+                    .iter_mut()
+                    .enumerate()
+                    .map(move |(i, texel)| (face, i % size, i / size, texel))
+            })
+    }
+}
+
+/// An iterator over the faces of a `CubeMap`, in the order of `CUBE_FACES`.
+#[derive(Debug, Clone)]
+pub struct Faces<'a, T: 'a> {
+    inner: iter::Zip<slice::Iter<'static, CubeFace>, slice::Iter<'a, Vec<T>>>,
+}
+
+impl<'a, T: 'a> Iterator for Faces<'a, T> {
+    type Item = (CubeFace, &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(&face, image)| (face, &image[..]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, T: 'a> ExactSizeIterator for Faces<'a, T> {}
+
+/// A mutable iterator over the faces of a `CubeMap`, in the order of
+/// `CUBE_FACES`.
+#[derive(Debug)]
+pub struct FacesMut<'a, T: 'a> {
+    inner: iter::Zip<slice::Iter<'static, CubeFace>, slice::IterMut<'a, Vec<T>>>,
+}
+
+impl<'a, T: 'a> Iterator for FacesMut<'a, T> {
+    type Item = (CubeFace, &'a mut [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(&face, image)| (face, &mut image[..]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, T: 'a> ExactSizeIterator for FacesMut<'a, T> {}
+
+impl<'a, T: 'a> IntoIterator for &'a CubeMap<T> {
+    type Item = (CubeFace, &'a [T]);
+    type IntoIter = Faces<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Faces { inner: CUBE_FACES.iter().zip(self.faces.iter()) }
+    }
+}
+
+impl<'a, T: 'a> IntoIterator for &'a mut CubeMap<T> {
+    type Item = (CubeFace, &'a mut [T]);
+    type IntoIter = FacesMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        FacesMut { inner: CUBE_FACES.iter().zip(self.faces.iter_mut()) }
+    }
+}
+
+/// Computes the texel `(x, y)` of `face` of `test_pattern(size)`.
+#[cfg(test)]
+pub(crate) fn test_pattern_texel(face: CubeFace, x: usize, y: usize, size: usize) -> f32 {
+    (((x + y * size) * 7919 + face.as_ordinal() * 104729) % 1021) as f32 / 1021.0
+}
+
+/// Generates a deterministic, non-uniform test cube map with values in
+/// `[0, 1)`.
+#[cfg(test)]
+pub(crate) fn test_pattern(size: usize) -> CubeMap<f32> {
+    CubeMap::from_fn(size, |face, x, y| test_pattern_texel(face, x, y, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::gather_offset;
+
+    #[test]
+    fn luminance() {
+        use cgmath::Vector4;
+        let cube_map = CubeMap::from_fn(2, |face, x, _| {
+            Vector4::new(face.as_ordinal() as f32, x as f32, 0.0, 1.0)
+        });
+        let luminance = cube_map.luminance();
+        assert_eq!(luminance.size(), 2);
+        for ((_, _, _, &l), (_, _, _, &texel)) in
+            luminance.iter_texels().zip(cube_map.iter_texels())
+        {
+            assert_eq!(l, texel.luminance());
+        }
+        assert!((luminance.face(CubeFace::NegativeZ)[1] - (0.2126 * 5.0 + 0.7152)).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn fixup_corners() {
+        let size = 4;
+        let mut cube_map = CubeMap::from_fn(size, |face, x, y| (face.as_ordinal() + x + y) as f32);
+
+        // The corner (+X, +Y, +Z)
+        let corner = Vector3::new(1.0, 1.0, 1.0);
+        let corner_texels: Vec<(CubeFace, usize)> = [
+            CubeFace::PositiveX,
+            CubeFace::PositiveY,
+            CubeFace::PositiveZ,
+        ].iter()
+            .map(|&face| {
+                let i = (0..size * size)
+                    .max_by(|&i, &k| {
+                        let dir = |i: usize| texel_direction(face, i % size, i / size, size);
+                        dir(i).dot(corner).partial_cmp(&dir(k).dot(corner)).unwrap()
+                    })
+                    .unwrap();
+                (face, i)
+            })
+            .collect();
+        for (&(face, i), &value) in corner_texels.iter().zip([3.0, 6.0, 12.0].iter()) {
+            cube_map.face_mut(face)[i] = value;
+        }
+        let original = cube_map.clone();
+
+        cube_map.fixup_corners();
+        for &(face, i) in corner_texels.iter() {
+            assert_eq!(cube_map.face(face)[i], 7.0);
+        }
+
+        // Only the corner texels are modified
+        let is_corner = |i: usize| {
+            let (x, y) = (i % size, i / size);
+            (x == 0 || x == size - 1) && (y == 0 || y == size - 1)
+        };
+        for &face in CUBE_FACES.iter() {
+            for (i, (&x, &y)) in cube_map.face(face).iter().zip(original.face(face)).enumerate() {
+                if !is_corner(i) {
+                    assert_eq!(x, y);
+                }
+            }
+        }
+
+        // Idempotent
+        let fixed = cube_map.clone();
+        cube_map.fixup_corners();
+        assert_eq!(cube_map, fixed);
+    }
+
+    #[test]
+    fn fixup_edges() {
+        // A discontinuity across the edges of +X
+        let size = 6;
+        let mut cube_map = CubeMap::from_fn(size, |face, _, _| {
+            if face == CubeFace::PositiveX { 1.0f32 } else { 0.0 }
+        });
+        cube_map.fixup_edges();
+
+        let faces = cube_map.faces();
+        for (face, x, y, &texel) in cube_map.iter_texels() {
+            let on_edge = |c: usize| c == 0 || c == size - 1;
+            if on_edge(x) && on_edge(y) {
+                // The corners shared with +X are blended
+                if texel_direction(face, x, y, size).x > 0.0 {
+                    assert!(texel > 0.0 && texel < 1.0, "{:?}: {}", (face, x, y), texel);
+                } else {
+                    assert_eq!(texel, 0.0, "{:?}", (face, x, y));
+                }
+            } else if on_edge(x) || on_edge(y) {
+                // Each edge texel and its neighbor across the seam match
+                let dx = if x == 0 { -1 } else if x == size - 1 { 1 } else { 0 };
+                let dy = if dx != 0 { 0 } else if y == 0 { -1 } else { 1 };
+                let neighbor = gather_offset(&faces, size, face, x, y, dx, dy);
+                assert_eq!(texel, neighbor, "{:?}", (face, x, y));
+                if face == CubeFace::PositiveX {
+                    assert_eq!(texel, 0.5);
+                }
+            } else {
+                let expected = if face == CubeFace::PositiveX { 1.0 } else { 0.0 };
+                assert_eq!(texel, expected, "{:?}", (face, x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn flip_and_rotate_face() {
+        let size = 3;
+        let original =
+            CubeMap::from_fn(size, |face, x, y| (face.as_ordinal() * 9 + x + y * 3) as u32);
+        let mut cube_map = original.clone();
+
+        cube_map.flip_face_h(CubeFace::PositiveY);
+        assert_eq!(cube_map.face(CubeFace::PositiveY), &[20, 19, 18, 23, 22, 21, 26, 25, 24]);
+        cube_map.flip_face_h(CubeFace::PositiveY);
+        assert_eq!(cube_map, original);
+
+        cube_map.flip_face_v(CubeFace::PositiveY);
+        assert_eq!(cube_map.face(CubeFace::PositiveY), &[24, 25, 26, 21, 22, 23, 18, 19, 20]);
+        cube_map.flip_face_v(CubeFace::PositiveY);
+        assert_eq!(cube_map, original);
+
+        // Clockwise with `y` pointing downward
+        cube_map.rotate_face_90(CubeFace::PositiveY, 1);
+        assert_eq!(cube_map.face(CubeFace::PositiveY), &[24, 21, 18, 25, 22, 19, 26, 23, 20]);
+        cube_map.rotate_face_90(CubeFace::PositiveY, 3);
+        assert_eq!(cube_map, original);
+        for &face in CUBE_FACES.iter() {
+            cube_map.rotate_face_90(face, 4);
+            assert_eq!(cube_map, original);
+        }
+
+        // A horizontal and a vertical flip make a 180° rotation
+        cube_map.flip_face_h(CubeFace::NegativeZ);
+        cube_map.flip_face_v(CubeFace::NegativeZ);
+        cube_map.rotate_face_90(CubeFace::NegativeZ, 2);
+        assert_eq!(cube_map, original);
+    }
+
+    #[test]
+    fn gpu_compatible() {
+        assert_eq!(CubeMap::<f32>::zeros(256).validate_gpu_compatible(), Ok(()));
+        assert_eq!(CubeMap::<f32>::zeros(1).validate_gpu_compatible(), Ok(()));
+        assert_eq!(
+            CubeMap::<f32>::zeros(100).validate_gpu_compatible(),
+            Err(GpuCompatError::NotPowerOfTwo { size: 100 })
+        );
+    }
+
+    #[test]
+    fn iter_texels_order() {
+        let size = 3;
+        let mut cube_map = CubeMap::from_fn(size, |face, x, y| (face, x, y));
+
+        let visited: Vec<_> = cube_map.iter_texels().map(|(f, x, y, &t)| (f, x, y, t)).collect();
+        assert_eq!(visited.len(), 6 * size * size);
+
+        let mut expected = Vec::new();
+        for &face in CUBE_FACES.iter() {
+            for y in 0..size {
+                for x in 0..size {
+                    expected.push((face, x, y, (face, x, y)));
+                }
+            }
+        }
+        assert_eq!(visited, expected);
+
+        for (face, x, y, texel) in cube_map.iter_texels_mut() {
+            *texel = (-face, y, x);
+        }
+        assert_eq!(cube_map.face(CubeFace::NegativeY)[1], (CubeFace::PositiveY, 0, 1));
+    }
+
+    #[test]
+    fn iter_faces_with_dir() {
+        let size = 3;
+        let cube_map = CubeMap::from_fn(size, |face, x, y| (face, x, y));
+        let mut count = 0;
+        for (face, i, dir, &(face2, x, y)) in cube_map.iter_faces_with_dir() {
+            assert_eq!((face, i), (face2, x + y * size));
+            let expected = texel_direction(face, x, y, size);
+            assert!((dir - expected).magnitude() < 1.0e-5, "{:?} vs {:?}", dir, expected);
+            count += 1;
+        }
+        assert_eq!(count, size * size * 6);
+    }
+
+    #[test]
+    fn into_iter_faces() {
+        let size = 4;
+        let mut cube_map = CubeMap::from_fn(size, |face, _, _| face);
+
+        let faces: Vec<_> = (&cube_map).into_iter().collect();
+        assert_eq!(faces.len(), 6);
+        for (&(face, image), &expected) in faces.iter().zip(CUBE_FACES.iter()) {
+            assert_eq!(face, expected);
+            assert_eq!(image.len(), size * size);
+            assert!(image.iter().all(|&t| t == expected));
+        }
+
+        for (face, image) in &mut cube_map {
+            image[0] = -face;
+        }
+        assert_eq!(cube_map.face(CubeFace::PositiveZ)[0], CubeFace::NegativeZ);
+    }
+
+    #[test]
+    fn filled_and_zeros() {
+        let size = 5;
+        let cube_map = CubeMap::filled(size, 0.5f32);
+        assert_eq!(cube_map.iter_texels().count(), 6 * size * size);
+        assert!(cube_map.iter_texels().all(|(_, _, _, &t)| t == 0.5));
+
+        let cube_map = CubeMap::<f32>::zeros(size);
+        assert_eq!(cube_map.iter_texels().count(), 6 * size * size);
+        assert!(cube_map.iter_texels().all(|(_, _, _, &t)| t == 0.0));
+    }
+
+    #[cfg(feature = "rayon")]
+    fn direction_fn(dir: Vector3<f32>) -> f32 {
+        (dir.x * 3.0).sin() * dir.y + dir.z.powi(3)
+    }
+
+    #[test]
+    fn fill_from_direction() {
+        let size = 8;
+        let mut cube_map = CubeMap::zeros(size);
+        cube_map.fill_from_direction(|dir| dir);
+        for (face, _, _, &dir) in cube_map.iter_texels() {
+            assert!((dir.magnitude() - 1.0).abs() < 1.0e-5);
+            assert_eq!(CubeFace::from_direction(dir), face);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn fill_from_direction_par_matches_sequential() {
+        let size = 37;
+        let mut expected = CubeMap::zeros(size);
+        expected.fill_from_direction(direction_fn);
+        let mut actual = CubeMap::zeros(size);
+        actual.fill_from_direction_par(direction_fn);
+        for ((_, _, _, x), (_, _, _, y)) in expected.iter_texels().zip(actual.iter_texels()) {
+            assert_eq!(x.to_bits(), y.to_bits());
+        }
+    }
+
+    #[test]
+    fn from_faces() {
+        let size = 4;
+        let cube_map = CubeMap::from_fn(size, |face, x, y| (face.as_ordinal(), x, y));
+        let faces = cube_map.clone().into_faces();
+        assert_eq!(CubeMap::from_faces(faces.clone(), size), Ok(cube_map.clone()));
+
+        let mut faces2 = faces.clone();
+        faces2[3].pop();
+        assert_eq!(
+            CubeMap::from_faces(faces2, size),
+            Err(CubeMapError::SizeMismatch {
+                face: CUBE_FACES[3],
+                expected: 16,
+                found: 15,
+            })
+        );
+
+        assert_eq!(
+            CubeMap::from_faces(faces[0..5].to_vec(), size),
+            Err(CubeMapError::FaceCount(5))
+        );
+    }
+
+    #[test]
+    fn from_images() {
+        let size = 4;
+        let image = |width: usize, height: usize| {
+            Image::new(width, height, vec![0u8; width * height])
+        };
+        let images = || (0..6).map(|_| image(size, size)).collect::<Vec<_>>();
+        assert_eq!(CubeMap::from_images(images()), Ok(CubeMap::zeros(size)));
+
+        let mut images2 = images();
+        images2[2] = image(size, size + 1);
+        assert_eq!(
+            CubeMap::from_images(images2),
+            Err(CubeMapError::NonSquare {
+                face: CUBE_FACES[2],
+                width: 4,
+                height: 5,
+            })
+        );
+
+        let mut images2 = images();
+        images2[5] = image(size * 2, size * 2);
+        assert_eq!(
+            CubeMap::from_images(images2),
+            Err(CubeMapError::SizeMismatch {
+                face: CUBE_FACES[5],
+                expected: 16,
+                found: 64,
+            })
+        );
+
+        assert_eq!(
+            CubeMap::from_images(images()[0..5].to_vec()),
+            Err(CubeMapError::FaceCount(5))
+        );
+    }
+
+    #[test]
+    fn faces_and_faces_mut() {
+        let size = 3;
+        let mut cube_map = CubeMap::from_fn(size, |face, _, _| face.as_ordinal());
+        for (i, image) in cube_map.faces_mut().iter_mut().enumerate() {
+            assert!(image.iter().all(|&x| x == i));
+            image[0] = 42;
+        }
+        for (&face, image) in CUBE_FACES.iter().zip(cube_map.faces().iter()) {
+            assert_eq!(*image, cube_map.face(face));
+            assert_eq!(image[0], 42);
+        }
+    }
+
+    #[test]
+    fn sample_dir_texel_centers() {
+        let size = 8;
+        let cube_map = test_pattern(size);
+        for (face, x, y, &texel) in cube_map.iter_texels() {
+            let value = cube_map.sample_dir(texel_direction(face, x, y, size));
+            assert!((value - texel).abs() < 1.0e-5, "{} vs {}", value, texel);
+        }
+    }
+
+    #[test]
+    fn sample_dir_edges_match() {
+        let size = 8;
+        let cube_map = test_pattern(size);
+        for &face in CUBE_FACES.iter() {
+            for &edge in [face.u_face(), -face.u_face(), face.v_face(), -face.v_face()].iter() {
+                for i in 0..17 {
+                    let t = i as f32 / 8.0 - 1.0;
+                    let tangent = if edge.abs() == face.u_face().abs() {
+                        face.v_vec::<f32>()
+                    } else {
+                        face.u_vec::<f32>()
+                    };
+                    let dir = face.normal::<f32>() + edge.normal::<f32>() + tangent * t;
+                    let a = cube_map.sample_face_uv(face, face.project(dir));
+                    let b = cube_map.sample_face_uv(edge, edge.project(dir));
+                    assert!(
+                        (a - b).abs() < 1.0e-5,
+                        "{:?}/{:?} {:?}: {} vs {}",
+                        face,
+                        edge,
+                        dir,
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sample_dir_corner_is_average() {
+        let size = 8;
+        let cube_map = test_pattern(size);
+        let dir = Vector3::new(1.0, 1.0, 1.0);
+        let faces = [CubeFace::PositiveX, CubeFace::PositiveY, CubeFace::PositiveZ];
+        let values: Vec<f32> = faces
+            .iter()
+            .map(|&face| cube_map.sample_face_uv(face, face.project(dir)))
+            .collect();
+        for &value in values.iter() {
+            assert!((value - values[0]).abs() < 1.0e-5, "{:?}", values);
+        }
+    }
+
+    #[test]
+    fn rotate_identity() {
+        let size = 8;
+        let cube_map = test_pattern(size);
+        let result = cube_map.rotate(Quaternion::one());
+        for (face, x, y, &texel) in result.iter_texels() {
+            let expected = cube_map.face(face)[x + y * size];
+            assert!((texel - expected).abs() < 1.0e-5, "{} vs {}", texel, expected);
+        }
+    }
+
+    #[test]
+    fn rotate_90_about_z() {
+        use cgmath::Rad;
+        use std::f32::consts::FRAC_PI_2;
+
+        let size = 8;
+        let cube_map = test_pattern(size);
+        let rotation = Quaternion::from_angle_z(Rad(FRAC_PI_2));
+        let result = cube_map.rotate(rotation);
+
+        // Every output face is a rearrangement of the input face it came from
+        let sorted = |image: &[f32]| {
+            let mut image = image.to_vec();
+            image.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            image
+        };
+        for &(from, to) in [
+            (CubeFace::PositiveX, CubeFace::PositiveY),
+            (CubeFace::PositiveY, CubeFace::NegativeX),
+            (CubeFace::NegativeX, CubeFace::NegativeY),
+            (CubeFace::NegativeY, CubeFace::PositiveX),
+            (CubeFace::PositiveZ, CubeFace::PositiveZ),
+            (CubeFace::NegativeZ, CubeFace::NegativeZ),
+        ].iter()
+        {
+            let expected = sorted(cube_map.face(from));
+            let actual = sorted(result.face(to));
+            for (a, b) in actual.iter().zip(expected.iter()) {
+                assert!((a - b).abs() < 1.0e-5, "{:?} → {:?}: {} vs {}", from, to, a, b);
+            }
+        }
+
+        // ...in the orientation given by the rotation
+        for (face, x, y, &texel) in cube_map.iter_texels() {
+            let dir = rotation.rotate_vector(texel_direction(face, x, y, size));
+            let value = result.sample_dir(dir);
+            assert!((value - texel).abs() < 1.0e-5, "{} vs {}", value, texel);
+        }
+    }
+
+    #[test]
+    fn weighted_sum() {
+        use std::f32::consts::PI;
+        let sum = CubeMap::filled(16, 2.0f32).weighted_sum();
+        assert!((sum - 8.0 * PI).abs() < 1.0e-3, "{}", sum);
+
+        // A cube map linear in the direction integrates to zero
+        let mut cube_map = CubeMap::zeros(16);
+        cube_map.fill_from_direction(|dir| dir.x + dir.y * 2.0 - dir.z);
+        let sum = cube_map.weighted_sum();
+        assert!(sum.abs() < 1.0e-3, "{}", sum);
+    }
+
+    #[test]
+    fn downsample_2x_checkerboard() {
+        // A checkerboard of 2×2 blocks offset by one texel, so every output
+        // texel sees both colors
+        let size = 8;
+        let cube_map = CubeMap::from_fn(size, |face, x, y| {
+            ((x + 1) / 2 + (y + 1) / 2 + face.as_ordinal()) as f32 % 2.0
+        });
+        let result = cube_map.downsample_2x();
+        assert_eq!(result.size(), size / 2);
+        for (face, x, y, &texel) in result.iter_texels() {
+            let input = cube_map.face(face);
+            let expected = (input[x * 2 + y * 2 * size] + input[x * 2 + 1 + y * 2 * size] +
+                input[x * 2 + (y * 2 + 1) * size] +
+                input[x * 2 + 1 + (y * 2 + 1) * size]) * 0.25;
+            assert_eq!(texel, expected, "{:?} ({}, {})", face, x, y);
+        }
+
+        // The border texels average their own block
+        let border = result.face(CubeFace::PositiveX)[0];
+        assert_eq!(border, 0.25 * (0.0 + 1.0 + 1.0 + 0.0));
+    }
+}
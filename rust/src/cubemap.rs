@@ -5,11 +5,32 @@
  * comes with the source code for use conditions.
  */
 //! Provides cube map face definitions.
-use std::ops;
-use cgmath::{Vector3, Matrix4};
+use std::{cmp, error, fmt, ops};
+use std::convert::TryFrom;
+use cgmath::{Vector2, Vector3, Matrix4};
 use cgmath::num_traits::NumCast;
 use cgmath::prelude::*;
 
+/// Identifies a face of a cube map.
+///
+/// The texel `(x, y)` of a `size`×`size` face image is mapped to the point
+/// `normal() + u_vec() * u + v_vec() * v` on the cube, where `u` and `v`
+/// increase from `-1` to `1` as `x` and `y` increase from `0` to `size - 1`.
+/// Assuming `y` increases downward in image files, this is the convention
+/// used by OpenGL and Direct3D:
+///
+/// | Face        | Right (+X in image) | Down (+Y in image) |
+/// | ----------- | ------------------- | ------------------ |
+/// | `PositiveX` | -Z                  | -Y                 |
+/// | `NegativeX` | +Z                  | -Y                 |
+/// | `PositiveY` | +X                  | +Z                 |
+/// | `NegativeY` | +X                  | -Z                 |
+/// | `PositiveZ` | +X                  | -Y                 |
+/// | `NegativeZ` | -X                  | -Y                 |
+///
+/// The top (`PositiveY`) and bottom (`NegativeY`) faces are the most common
+/// sources of mismatches. Use `FaceOrientationOverride` to correct faces
+/// authored with a different convention.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum CubeFace {
@@ -89,6 +110,85 @@ impl CubeFace {
         }.cast()
     }
 
+    /// Returns the face onto which the direction `dir` is projected.
+    ///
+    /// Only the major axis of `dir` is examined, so this is cheaper than
+    /// computing the UV coordinates as well.
+    ///
+    /// Ties between components with the same absolute value (i.e., directions
+    /// lying exactly on an edge or a corner of the cube) are broken in favor
+    /// of the X axis, then the Y axis. The sign of the major component selects
+    /// between the positive and negative faces; zero (including `-0.0`)
+    /// counts as positive. Consequently, the zero vector maps to `PositiveX`.
+    ///
+    /// In other words, the boundaries of the faces are owned as follows:
+    ///
+    /// | Boundary                   | Owner    |
+    /// | -------------------------- | -------- |
+    /// | Edges between ±X and ±Y/±Z | ±X       |
+    /// | Edges between ±Y and ±Z    | ±Y       |
+    /// | Corners                    | ±X       |
+    ///
+    /// Since only the ratios between the components matter, a direction on a
+    /// boundary maps to the same face regardless of its length.
+    pub fn from_direction(dir: Vector3<f32>) -> CubeFace {
+        let (ax, ay, az) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+        if ax >= ay && ax >= az {
+            if dir.x >= 0.0 {
+                CubeFace::PositiveX
+            } else {
+                CubeFace::NegativeX
+            }
+        } else if ay >= az {
+            if dir.y >= 0.0 {
+                CubeFace::PositiveY
+            } else {
+                CubeFace::NegativeY
+            }
+        } else {
+            if dir.z >= 0.0 {
+                CubeFace::PositiveZ
+            } else {
+                CubeFace::NegativeZ
+            }
+        }
+    }
+
+    /// Returns the face onto which the direction `dir` is projected along with
+    /// the UV coordinates of the projected point on that face, which lie in
+    /// `[-1, 1]²`.
+    ///
+    /// The face is selected in the same way as `from_direction`; the UV
+    /// coordinates are computed by `project`. `dir` must not be the zero
+    /// vector.
+    pub fn from_direction_uv(dir: Vector3<f32>) -> (CubeFace, Vector2<f32>) {
+        let face = CubeFace::from_direction(dir);
+        (face, face.project(dir))
+    }
+
+    /// Project the direction `dir` onto the plane of this face and return the
+    /// point's UV coordinates, which lie in `[-1, 1]²` if `dir` maps to this
+    /// face (see `from_direction`). `u` and `v` increase along `u_vec` and
+    /// `v_vec`, respectively.
+    ///
+    /// The result is meaningless if `dir` points away from this face.
+    pub fn project(&self, dir: Vector3<f32>) -> Vector2<f32> {
+        let depth = dir.dot(self.normal());
+        Vector2::new(dir.dot(self.u_vec()) / depth, dir.dot(self.v_vec()) / depth)
+    }
+
+    /// Compute the solid angle subtended by the texel `(x, y)` of this face,
+    /// whose size is `size`. The result is exact (up to rounding errors), and
+    /// the solid angles of all texels of all faces sum to `4π`.
+    ///
+    /// The texels near the edges of a face cover less of the sphere than the
+    /// ones near the center because of the `(1 + u² + v²)^(-3/2)` stretch of
+    /// the projection, so any integration over a cube map must weight the
+    /// texels by this. The result is the same for all faces.
+    pub fn texel_solid_angle(&self, x: usize, y: usize, size: usize) -> f32 {
+        texel_solid_angle(x, y, size)
+    }
+
     pub fn info(&self) -> &'static CubeFaceInfo {
         &CUBE_FACE_INFOS[*self as usize]
     }
@@ -98,6 +198,26 @@ impl CubeFace {
             Self::from_ordinal_unchecked(self.as_ordinal() & !1)
         }
     }
+
+    /// Get the name commonly used for the file of the face (`posx`, `negx`,
+    /// `posy`, `negy`, `posz`, or `negz`).
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CubeFace::PositiveX => "posx",
+            CubeFace::NegativeX => "negx",
+            CubeFace::PositiveY => "posy",
+            CubeFace::NegativeY => "negy",
+            CubeFace::PositiveZ => "posz",
+            CubeFace::NegativeZ => "negz",
+        }
+    }
+}
+
+/// Formats the face as `CubeFace::name`.
+impl fmt::Display for CubeFace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 impl ops::Neg for CubeFace {
@@ -109,11 +229,94 @@ impl ops::Neg for CubeFace {
     }
 }
 
+/// An error returned when converting a value to `CubeFace` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CubeFaceParseError {
+    /// The ordinal was not in `0..6`.
+    InvalidOrdinal(u8),
+    /// The name was not one of `posx`, `negx`, `posy`, `negy`, `posz`, and
+    /// `negz`.
+    InvalidName(String),
+}
+
+impl fmt::Display for CubeFaceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CubeFaceParseError::InvalidOrdinal(ordinal) => {
+                write!(f, "the face ordinal must be less than 6, but was {}", ordinal)
+            }
+            &CubeFaceParseError::InvalidName(ref name) => write!(
+                f,
+                "the face name must be one of posx, negx, posy, negy, posz, and negz, but was \
+                 '{}'",
+                name
+            ),
+        }
+    }
+}
+
+impl error::Error for CubeFaceParseError {
+    fn description(&self) -> &str {
+        match self {
+            &CubeFaceParseError::InvalidOrdinal(_) => "invalid face ordinal",
+            &CubeFaceParseError::InvalidName(_) => "invalid face name",
+        }
+    }
+}
+
+impl From<CubeFace> for u8 {
+    fn from(face: CubeFace) -> u8 {
+        face as u8
+    }
+}
+
+/// The inverse of `u8::from(CubeFace)`, i.e., `CubeFace::from_ordinal`.
+impl TryFrom<u8> for CubeFace {
+    type Error = CubeFaceParseError;
+
+    fn try_from(ordinal: u8) -> Result<Self, Self::Error> {
+        CubeFace::from_ordinal(ordinal as usize).ok_or(CubeFaceParseError::InvalidOrdinal(ordinal))
+    }
+}
+
+/// The inverse of `CubeFace::name`.
+impl<'a> TryFrom<&'a str> for CubeFace {
+    type Error = CubeFaceParseError;
+
+    fn try_from(name: &'a str) -> Result<Self, Self::Error> {
+        CUBE_FACES
+            .iter()
+            .cloned()
+            .find(|face| face.name() == name)
+            .ok_or_else(|| CubeFaceParseError::InvalidName(name.to_owned()))
+    }
+}
+
 pub struct CubeFaceInfo {
     pub view_proj_mat: Matrix4<f32>,
     pub inv_view_proj_mat: Matrix4<f32>,
 }
 
+impl CubeFaceInfo {
+    /// Get the face described by `self`, i.e., the one whose normal is the
+    /// last row of `view_proj_mat`.
+    pub fn face(&self) -> CubeFace {
+        let m = &self.view_proj_mat;
+        CubeFace::from_direction(Vector3::new(m.x.w, m.y.w, m.z.w))
+    }
+
+    /// Compute the normalized direction toward the center of the texel
+    /// `(x, y)` of a face of the size `size`.
+    ///
+    /// The texel centers are located at the UV coordinates
+    /// `(i + 0.5) / size * 2 - 1`, which is the convention of
+    /// `StandardCubeMapTrait`. Equivalent to transforming the point from the
+    /// clip space by `inv_view_proj_mat`.
+    pub fn pixel_direction(&self, x: usize, y: usize, size: usize) -> Vector3<f32> {
+        texel_direction(self.face(), x, y, size)
+    }
+}
+
 lazy_static! {
     pub static ref CUBE_FACE_INFOS: Vec<CubeFaceInfo> = CUBE_FACES.iter()
         .map(|face| {
@@ -134,3 +337,561 @@ lazy_static! {
         })
         .collect();
 }
+
+/// A cone of directions around `center_dir`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SphericalCap {
+    /// The axis of the cone. Does not have to be normalized.
+    pub center_dir: Vector3<f32>,
+    /// The angle between the axis and the boundary of the cone, measured in
+    /// radians. Values equal to or greater than `π` cover the whole sphere.
+    pub half_angle: f32,
+}
+
+impl SphericalCap {
+    /// Check whether the direction `dir` lies in the cap. `dir` does not have
+    /// to be normalized.
+    pub fn contains(&self, dir: Vector3<f32>) -> bool {
+        if self.half_angle >= ::std::f32::consts::PI {
+            return true;
+        }
+        let cos = dir.dot(self.center_dir) / (dir.magnitude() * self.center_dir.magnitude());
+        cos >= self.half_angle.cos()
+    }
+}
+
+/// A transformation applied to a face image to correct its orientation.
+///
+/// This covers all eight symmetries of a square. Rotations are clockwise
+/// assuming `y` increases downward.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FaceOrientationOverride {
+    Identity,
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Mirror along the main diagonal (swap `x` and `y`).
+    Transpose,
+    /// Mirror along the anti-diagonal.
+    AntiTranspose,
+}
+
+impl FaceOrientationOverride {
+    /// Return the coordinates of the source texel that is moved to `(x, y)`
+    /// by this transformation.
+    pub fn source_coords(&self, x: usize, y: usize, size: usize) -> (usize, usize) {
+        let n = size - 1;
+        match self {
+            &FaceOrientationOverride::Identity => (x, y),
+            &FaceOrientationOverride::FlipHorizontal => (n - x, y),
+            &FaceOrientationOverride::FlipVertical => (x, n - y),
+            &FaceOrientationOverride::Rotate90 => (y, n - x),
+            &FaceOrientationOverride::Rotate180 => (n - x, n - y),
+            &FaceOrientationOverride::Rotate270 => (n - y, x),
+            &FaceOrientationOverride::Transpose => (y, x),
+            &FaceOrientationOverride::AntiTranspose => (n - y, n - x),
+        }
+    }
+
+    /// Return the transformation that undoes this one.
+    pub fn inverse(&self) -> Self {
+        match self {
+            &FaceOrientationOverride::Rotate90 => FaceOrientationOverride::Rotate270,
+            &FaceOrientationOverride::Rotate270 => FaceOrientationOverride::Rotate90,
+            x => *x,
+        }
+    }
+
+    /// Transform a `size`×`size` raster image in place.
+    pub fn apply<T: Clone>(&self, image: &mut [T], size: usize) {
+        if *self == FaceOrientationOverride::Identity {
+            return;
+        }
+        let image = &mut image[0..size * size];
+        let src = image.to_vec();
+        for y in 0..size {
+            for x in 0..size {
+                let (sx, sy) = self.source_coords(x, y, size);
+                image[x + y * size] = src[sx + sy * size].clone();
+            }
+        }
+    }
+}
+
+/// Compute the linear indices of the texels of `face` along its edge shared
+/// with `edge`.
+///
+/// Returns `(base_idx, main_offs, cross_offs)`. The texel `t` texels along the
+/// shared edge (measured in the coordinate of `edge` running along the shared
+/// edge) and `d` texels away from it is located at
+/// `base_idx + t * main_offs + d * cross_offs` in the image of `face`.
+pub(crate) fn map_edge_index(face: CubeFace, edge: CubeFace, size: usize) -> (isize, isize, isize) {
+    let size = size as isize;
+    if face.u_face().abs() == edge.abs() {
+        // Either `face`'s +U or -U edge is adjacent to `edge`
+        let (x, cross_offs) = if face.u_face() == edge {
+            (size - 1, -1)
+        } else {
+            (0, 1)
+        };
+        if face.v_face() == edge.u_face() || face.v_face() == edge.v_face() {
+            (x, size, cross_offs)
+        } else {
+            debug_assert!(face.v_face() == -edge.u_face() || face.v_face() == -edge.v_face());
+            (x + (size - 1) * size, -size, cross_offs)
+        }
+    } else {
+        debug_assert!(face.v_face().abs() == edge.abs());
+        // Either `face`'s +V or -V edge is adjacent to `edge`
+        let (y, cross_offs) = if face.v_face() == edge {
+            (size - 1, -size)
+        } else {
+            (0, size)
+        };
+        if face.u_face() == edge.u_face() || face.u_face() == edge.v_face() {
+            (y * size, 1, cross_offs)
+        } else {
+            debug_assert!(face.u_face() == -edge.u_face() || face.u_face() == -edge.v_face());
+            (size - 1 + y * size, -1, cross_offs)
+        }
+    }
+}
+
+/// The two-dimensional counterpart of `map_edge_index`. Returns the
+/// coordinates of the first texel on the shared edge, and the steps along and
+/// across (toward the center of `face`) the edge.
+pub(crate) fn map_edge_coords(
+    face: CubeFace,
+    edge: CubeFace,
+    size: usize,
+) -> (Vector2<isize>, Vector2<isize>, Vector2<isize>) {
+    let s = size as isize;
+    let (base_idx, main_offs, cross_offs) = map_edge_index(face, edge, size);
+    let step = |offs: isize| if offs.abs() == 1 {
+        Vector2::new(offs, 0)
+    } else {
+        Vector2::new(0, offs / s)
+    };
+    (
+        Vector2::new(base_idx % s, base_idx / s),
+        step(main_offs),
+        step(cross_offs),
+    )
+}
+
+/// Compute the normalized direction toward the center of the texel `(x, y)`
+/// on `face`.
+pub(crate) fn texel_direction(face: CubeFace, x: usize, y: usize, size: usize) -> Vector3<f32> {
+    let uv = |i: usize| (i as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+    (face.normal::<f32>() + face.u_vec::<f32>() * uv(x) + face.v_vec::<f32>() * uv(y)).normalize()
+}
+
+/// Compute the solid angle subtended by the texel `(x, y)` of a face of the
+/// size `size`. See `CubeFace::texel_solid_angle`.
+pub(crate) fn texel_solid_angle(x: usize, y: usize, size: usize) -> f32 {
+    // The solid angle of the rectangle `[0, u] × [0, v]` on a face
+    let area = |u: f64, v: f64| (u * v).atan2((u * u + v * v + 1.0).sqrt());
+    let uv = |i: usize| i as f64 * 2.0 / size as f64 - 1.0;
+    let (u0, u1, v0, v1) = (uv(x), uv(x + 1), uv(y), uv(y + 1));
+    (area(u0, v0) - area(u0, v1) - area(u1, v0) + area(u1, v1)) as f32
+}
+
+/// Locate the texel at the possibly out-of-bounds coordinates `(x, y)` on
+/// `face`, continuing onto the adjacent face if they are outside `face`.
+///
+/// Returns the face containing the texel and its linear index. Only one
+/// coordinate is allowed to be out of bounds; if both are (i.e., in the
+/// regions diagonally adjacent to the corners), `y` is clamped first. The
+/// distance into the adjacent face is clamped to the face size.
+pub(crate) fn wrap_texel(face: CubeFace, x: isize, y: isize, size: usize) -> (CubeFace, usize) {
+    let s = size as isize;
+    let clamp = |v: isize| cmp::max(0, cmp::min(s - 1, v));
+    if x >= 0 && x < s && y >= 0 && y < s {
+        (face, (x + y * s) as usize)
+    } else if x < 0 || x >= s {
+        let (adj, dist) = if x >= s {
+            (face.u_face(), x - s)
+        } else {
+            (-face.u_face(), -1 - x)
+        };
+        let (base_idx, main_offs, cross_offs) = map_edge_index(adj, face, size);
+        let idx = base_idx + clamp(y) * main_offs + clamp(dist) * cross_offs;
+        (adj, idx as usize)
+    } else {
+        let (adj, dist) = if y >= s {
+            (face.v_face(), y - s)
+        } else {
+            (-face.v_face(), -1 - y)
+        };
+        let (base_idx, main_offs, cross_offs) = map_edge_index(adj, face, size);
+        let idx = base_idx + x * main_offs + clamp(dist) * cross_offs;
+        (adj, idx as usize)
+    }
+}
+
+/// Fetch the texel at the offset `(dx, dy)` from the texel `(x, y)` on
+/// `face`, continuing onto the adjacent faces across the seams.
+///
+/// `faces` is a slice of six `size`×`size` raster images. The offsets leaving
+/// the face are resolved in the same way as the blur does at the face
+/// boundaries; in the regions diagonally adjacent to the corners, where no
+/// texel exists, one of the coordinates is clamped.
+pub fn gather_offset<T: Copy>(
+    faces: &[&[T]],
+    size: usize,
+    face: CubeFace,
+    x: usize,
+    y: usize,
+    dx: isize,
+    dy: isize,
+) -> T {
+    let (face, i) = wrap_texel(face, x as isize + dx, y as isize + dy, size);
+    faces[face.as_ordinal()][i]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Vector4;
+
+    #[test]
+    fn try_from_u8() {
+        for &face in CUBE_FACES.iter() {
+            let ordinal: u8 = face.into();
+            assert_eq!(CubeFace::try_from(ordinal), Ok(face));
+        }
+        assert_eq!(CubeFace::try_from(6u8), Err(CubeFaceParseError::InvalidOrdinal(6)));
+        assert_eq!(CubeFace::try_from(255u8), Err(CubeFaceParseError::InvalidOrdinal(255)));
+    }
+
+    #[test]
+    fn try_from_str() {
+        let names = ["posx", "negx", "posy", "negy", "posz", "negz"];
+        for (&face, &name) in CUBE_FACES.iter().zip(names.iter()) {
+            assert_eq!(CubeFace::try_from(name), Ok(face));
+        }
+        for &name in ["", "PosX", "pos_x", "posw", "posx "].iter() {
+            assert_eq!(
+                CubeFace::try_from(name),
+                Err(CubeFaceParseError::InvalidName(name.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for &face in CUBE_FACES.iter() {
+            assert_eq!(CubeFace::try_from(face.name()), Ok(face));
+            assert_eq!(format!("{}", face), face.name());
+        }
+    }
+
+    #[test]
+    fn texel_solid_angles_sum_to_sphere() {
+        for &size in [1, 2, 7, 64].iter() {
+            let mut total = 0.0f64;
+            for &face in CUBE_FACES.iter() {
+                for y in 0..size {
+                    for x in 0..size {
+                        total += face.texel_solid_angle(x, y, size) as f64;
+                    }
+                }
+            }
+            let expected = 4.0 * ::std::f64::consts::PI;
+            assert!((total - expected).abs() < 1.0e-4, "{}: {}", size, total);
+        }
+
+        // A single texel covers a whole face
+        let face = CubeFace::PositiveX.texel_solid_angle(0, 0, 1);
+        assert!((face - 4.0 * ::std::f32::consts::PI / 6.0).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn from_direction_normals() {
+        for &face in CUBE_FACES.iter() {
+            assert_eq!(CubeFace::from_direction(face.normal()), face);
+            assert_eq!(CubeFace::from_direction(face.normal() * 0.001), face);
+        }
+    }
+
+    #[test]
+    fn from_direction_projects_inside_face() {
+        let steps = 8;
+        for ix in -steps..steps + 1 {
+            for iy in -steps..steps + 1 {
+                for iz in -steps..steps + 1 {
+                    if ix == 0 && iy == 0 && iz == 0 {
+                        continue;
+                    }
+                    let dir = Vector3::new(ix, iy, iz).cast::<f32>();
+                    let face = CubeFace::from_direction(dir);
+
+                    // Project `dir` onto the selected face. The result must
+                    // lie within the face's [-1, 1]² square.
+                    let p = face.info().view_proj_mat * Vector4::new(dir.x, dir.y, dir.z, 1.0);
+                    assert!(p.w > 0.0, "{:?} → {:?}", dir, face);
+                    assert!(p.x.abs() <= p.w && p.y.abs() <= p.w, "{:?} → {:?}", dir, face);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_direction_uv_normals() {
+        for &face in CUBE_FACES.iter() {
+            let (face2, uv) = CubeFace::from_direction_uv(face.normal() * 2.0);
+            assert_eq!(face2, face);
+            assert!(uv.x.abs() < 1.0e-6 && uv.y.abs() < 1.0e-6, "{:?}", uv);
+        }
+    }
+
+    #[test]
+    fn from_direction_uv_follows_u_and_v_faces() {
+        for &face in CUBE_FACES.iter() {
+            let dir = face.normal::<f32>() + face.u_vec::<f32>() * 0.5 - face.v_vec::<f32>() * 0.25;
+            let (face2, uv) = CubeFace::from_direction_uv(dir);
+            assert_eq!(face2, face);
+            assert!((uv.x - 0.5).abs() < 1.0e-6 && (uv.y + 0.25).abs() < 1.0e-6, "{:?}", uv);
+        }
+    }
+
+    #[test]
+    fn project_texel_centers() {
+        let size = 8;
+        for &face in CUBE_FACES.iter() {
+            for y in 0..size {
+                for x in 0..size {
+                    let dir = texel_direction(face, x, y, size) * 3.0;
+                    let uv = face.project(dir);
+                    let expected = |i: usize| (i as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+                    assert!((uv.x - expected(x)).abs() < 1.0e-5, "{:?}", uv);
+                    assert!((uv.y - expected(y)).abs() < 1.0e-5, "{:?}", uv);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn info_face() {
+        for &face in CUBE_FACES.iter() {
+            assert_eq!(face.info().face(), face);
+        }
+    }
+
+    #[test]
+    fn pixel_direction_center_is_normal() {
+        let size = 5;
+        for &face in CUBE_FACES.iter() {
+            let dir = face.info().pixel_direction(2, 2, size);
+            assert!((dir - face.normal()).magnitude() < 1.0e-6, "{:?}", dir);
+        }
+    }
+
+    #[test]
+    fn pixel_direction_matches_inv_view_proj_mat() {
+        let size = 8;
+        let uv = |i: usize| (i as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+        for &face in CUBE_FACES.iter() {
+            let info = face.info();
+            for y in 0..size {
+                for x in 0..size {
+                    let dir = info.pixel_direction(x, y, size);
+                    let p = info.inv_view_proj_mat * Vector4::new(uv(x), uv(y), 1.0, 1.0);
+                    let expected = p.truncate().normalize();
+                    assert!((dir - expected).magnitude() < 1.0e-5, "{:?}", dir);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_direction_tie_break() {
+        let cases = [
+            ((1.0, 1.0, 0.0), CubeFace::PositiveX),
+            ((-1.0, 1.0, 0.0), CubeFace::NegativeX),
+            ((0.0, -1.0, 1.0), CubeFace::NegativeY),
+            ((0.0, 1.0, -1.0), CubeFace::PositiveY),
+            ((1.0, 0.0, -1.0), CubeFace::PositiveX),
+            ((-1.0, -1.0, -1.0), CubeFace::NegativeX),
+            ((-0.0, 0.0, 0.0), CubeFace::PositiveX),
+        ];
+        for &((x, y, z), face) in cases.iter() {
+            assert_eq!(CubeFace::from_direction(Vector3::new(x, y, z)), face);
+        }
+    }
+
+    #[test]
+    fn from_direction_edges_and_corners() {
+        let cases = [
+            // Edges between ±X and ±Y
+            ((1, 1, 0), CubeFace::PositiveX),
+            ((1, -1, 0), CubeFace::PositiveX),
+            ((-1, 1, 0), CubeFace::NegativeX),
+            ((-1, -1, 0), CubeFace::NegativeX),
+            // Edges between ±X and ±Z
+            ((1, 0, 1), CubeFace::PositiveX),
+            ((1, 0, -1), CubeFace::PositiveX),
+            ((-1, 0, 1), CubeFace::NegativeX),
+            ((-1, 0, -1), CubeFace::NegativeX),
+            // Edges between ±Y and ±Z
+            ((0, 1, 1), CubeFace::PositiveY),
+            ((0, 1, -1), CubeFace::PositiveY),
+            ((0, -1, 1), CubeFace::NegativeY),
+            ((0, -1, -1), CubeFace::NegativeY),
+            // Corners
+            ((1, 1, 1), CubeFace::PositiveX),
+            ((1, 1, -1), CubeFace::PositiveX),
+            ((1, -1, 1), CubeFace::PositiveX),
+            ((1, -1, -1), CubeFace::PositiveX),
+            ((-1, 1, 1), CubeFace::NegativeX),
+            ((-1, 1, -1), CubeFace::NegativeX),
+            ((-1, -1, 1), CubeFace::NegativeX),
+            ((-1, -1, -1), CubeFace::NegativeX),
+        ];
+        for &((x, y, z), face) in cases.iter() {
+            let dir = Vector3::new(x as f32, y as f32, z as f32);
+            for &scale in [1.0e-30f32, 0.5f32.sqrt(), 1.0, 3.0f32.sqrt().recip(), 1.0e30].iter() {
+                assert_eq!(CubeFace::from_direction(dir * scale), face, "{:?}", dir * scale);
+            }
+        }
+    }
+
+    #[test]
+    fn wrap_texel_adjacency() {
+        let size = 5;
+        // The center of a texel on the cube's surface
+        let texel_pos = |face: CubeFace, i: usize| -> Vector3<f32> {
+            let uv = |c: usize| (c as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+            face.normal::<f32>() + face.u_vec::<f32>() * uv(i % size) +
+                face.v_vec::<f32>() * uv(i / size)
+        };
+        for &face in CUBE_FACES.iter() {
+            for t in 0..size as isize {
+                let edge = size as isize - 1;
+                for &(x, y, dx, dy) in [
+                    (-1, t, 1, 0),
+                    (edge + 1, t, -1, 0),
+                    (t, -1, 0, 1),
+                    (t, edge + 1, 0, -1),
+                ].iter()
+                {
+                    let (adj, i) = wrap_texel(face, x, y, size);
+                    assert!(adj != face && adj != -face);
+                    let inner = ((x + dx) + (y + dy) * size as isize) as usize;
+                    let dist = (texel_pos(adj, i) - texel_pos(face, inner)).magnitude();
+                    assert!(dist < 1.5 / size as f32, "{:?} ({}, {}) → {:?}", face, x, y, adj);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edge_coords_match_edge_index() {
+        for &size in [1, 2, 5].iter() {
+            let s = size as isize;
+            for &face in CUBE_FACES.iter() {
+                for &edge in [face.u_face(), -face.u_face(), face.v_face(), -face.v_face()]
+                    .iter()
+                {
+                    let (base_idx, main_offs, cross_offs) = map_edge_index(face, edge, size);
+                    let (base, main, cross) = map_edge_coords(face, edge, size);
+                    for t in 0..s {
+                        for d in 0..s {
+                            let p = base + main * t + cross * d;
+                            assert!(p.x >= 0 && p.x < s && p.y >= 0 && p.y < s);
+                            assert_eq!(
+                                p.x + p.y * s,
+                                base_idx + main_offs * t + cross_offs * d
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orientation_override_restores_seams() {
+        let size = 8;
+        let uv = |c: usize| (c as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+        // A smooth function of the direction
+        let mut faces: Vec<Vec<f32>> = CUBE_FACES
+            .iter()
+            .map(|face| {
+                (0..size * size)
+                    .map(|i| {
+                        let p = face.normal::<f32>() + face.u_vec::<f32>() * uv(i % size) +
+                            face.v_vec::<f32>() * uv(i / size);
+                        p.normalize().dot(Vector3::new(0.3, 0.5, -0.8))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let max_seam_gap = |faces: &Vec<Vec<f32>>| {
+            let mut gap = 0.0f32;
+            for &face in CUBE_FACES.iter() {
+                for t in 0..size as isize {
+                    let edge = size as isize - 1;
+                    for &(x, y, ix, iy) in [
+                        (-1, t, 0, t),
+                        (edge + 1, t, edge, t),
+                        (t, -1, t, 0),
+                        (t, edge + 1, t, edge),
+                    ].iter()
+                    {
+                        let (adj, i) = wrap_texel(face, x, y, size);
+                        let inner = faces[face.as_ordinal()][(ix + iy * size as isize) as usize];
+                        gap = gap.max((faces[adj.as_ordinal()][i] - inner).abs());
+                    }
+                }
+            }
+            gap
+        };
+        let original_gap = max_seam_gap(&faces);
+
+        // Simulate a bottom face stored with a different convention
+        let neg_y = CubeFace::NegativeY.as_ordinal();
+        FaceOrientationOverride::Rotate90.apply(&mut faces[neg_y], size);
+        assert!(max_seam_gap(&faces) > original_gap * 4.0);
+
+        // Correct it on load
+        FaceOrientationOverride::Rotate90
+            .inverse()
+            .apply(&mut faces[neg_y], size);
+        assert_eq!(max_seam_gap(&faces), original_gap);
+    }
+
+    #[test]
+    fn orientation_override_inverse() {
+        use self::FaceOrientationOverride::*;
+        let size = 3;
+        let image: Vec<usize> = (0..size * size).collect();
+        for &o in [
+            Identity,
+            FlipHorizontal,
+            FlipVertical,
+            Rotate90,
+            Rotate180,
+            Rotate270,
+            Transpose,
+            AntiTranspose,
+        ].iter()
+        {
+            let mut transformed = image.clone();
+            o.apply(&mut transformed, size);
+            if o != Identity {
+                assert!(transformed != image, "{:?}", o);
+            }
+            o.inverse().apply(&mut transformed, size);
+            assert_eq!(transformed, image, "{:?}", o);
+        }
+
+        // Clockwise rotation moves the top-left corner to the top-right corner
+        let mut rotated = image.clone();
+        Rotate90.apply(&mut rotated, size);
+        assert_eq!(rotated[size - 1], 0);
+    }
+}
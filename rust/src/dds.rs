@@ -0,0 +1,181 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Writes cube maps in the DirectDraw Surface (DDS) format.
+//!
+//! The texels are stored uncompressed as `DXGI_FORMAT_R32G32B32A32_FLOAT`
+//! using the `DDS_HEADER_DXT10` extension, with the alpha mode set to
+//! premultiplied. DDS stores the faces in the order `+X`, `-X`, `+Y`, `-Y`,
+//! `+Z`, `-Z`, which matches `CUBE_FACES`, and each face is followed by its
+//! whole mip chain (unlike KTX 2.0, where each level contains all faces).
+use std::io::{self, Write};
+use cgmath::Vector4;
+use container::CubeMap;
+use ktx2::write_u32;
+
+const DDS_MAGIC: u32 = 0x2053_4444; // "DDS "
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+
+const DDPF_FOURCC: u32 = 0x4;
+const FOURCC_DX10: u32 = 0x3031_5844; // "DX10"
+
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x40_0000;
+
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+/// `DDSCAPS2_CUBEMAP_POSITIVEX` through `DDSCAPS2_CUBEMAP_NEGATIVEZ`
+const DDSCAPS2_CUBEMAP_ALLFACES: u32 = 0xfc00;
+
+const DXGI_FORMAT_R32G32B32A32_FLOAT: u32 = 2;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+const DDS_ALPHA_MODE_PREMULTIPLIED: u32 = 2;
+
+/// The size of a texel in bytes.
+const TEXEL_SIZE: usize = 16;
+
+/// Write the mip pyramid `levels` of a cube map as a DDS file.
+///
+/// `levels[0]` is the base level, and the size of each subsequent level must
+/// be half (rounded down) of the previous one.
+pub fn write_dds<W: Write>(writer: &mut W, levels: &[CubeMap<Vector4<f32>>]) -> io::Result<()> {
+    assert!(!levels.is_empty(), "no levels");
+    let size = levels[0].size();
+    assert!(size > 0 && size * TEXEL_SIZE <= u32::max_value() as usize);
+    for (i, level) in levels.iter().enumerate() {
+        assert_eq!(level.size(), size >> i, "invalid size of level {}", i);
+    }
+
+    write_u32(writer, DDS_MAGIC)?;
+
+    // DDS_HEADER
+    write_u32(writer, 124)?; // dwSize
+    write_u32(
+        writer,
+        DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PITCH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT,
+    )?;
+    write_u32(writer, size as u32)?; // dwHeight
+    write_u32(writer, size as u32)?; // dwWidth
+    write_u32(writer, (size * TEXEL_SIZE) as u32)?; // dwPitchOrLinearSize
+    write_u32(writer, 0)?; // dwDepth
+    write_u32(writer, levels.len() as u32)?; // dwMipMapCount
+    for _ in 0..11 {
+        write_u32(writer, 0)?; // dwReserved1
+    }
+
+    // DDS_PIXELFORMAT
+    write_u32(writer, 32)?; // dwSize
+    write_u32(writer, DDPF_FOURCC)?;
+    write_u32(writer, FOURCC_DX10)?;
+    for _ in 0..5 {
+        write_u32(writer, 0)?; // dwRGBBitCount and the bit masks
+    }
+
+    let mipmap = if levels.len() > 1 { DDSCAPS_MIPMAP } else { 0 };
+    write_u32(writer, DDSCAPS_COMPLEX | DDSCAPS_TEXTURE | mipmap)?;
+    write_u32(writer, DDSCAPS2_CUBEMAP | DDSCAPS2_CUBEMAP_ALLFACES)?;
+    write_u32(writer, 0)?; // dwCaps3
+    write_u32(writer, 0)?; // dwCaps4
+    write_u32(writer, 0)?; // dwReserved2
+
+    // DDS_HEADER_DXT10
+    write_u32(writer, DXGI_FORMAT_R32G32B32A32_FLOAT)?;
+    write_u32(writer, D3D10_RESOURCE_DIMENSION_TEXTURE2D)?;
+    write_u32(writer, DDS_RESOURCE_MISC_TEXTURECUBE)?;
+    write_u32(writer, 1)?; // arraySize (the number of cube maps)
+    write_u32(writer, DDS_ALPHA_MODE_PREMULTIPLIED)?;
+
+    for face in 0..6 {
+        for level in levels.iter() {
+            for &texel in level.faces()[face].iter() {
+                for i in 0..4 {
+                    write_u32(writer, texel[i].to_bits())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        data[offset..offset + 4]
+            .iter()
+            .rev()
+            .fold(0, |x, &b| (x << 8) | b as u32)
+    }
+
+    #[test]
+    fn header() {
+        let levels: Vec<_> = [4, 2, 1].iter().map(|&size| CubeMap::zeros(size)).collect();
+        let mut data = Vec::new();
+        write_dds(&mut data, &levels).unwrap();
+
+        assert_eq!(&data[0..4], b"DDS ");
+        assert_eq!(read_u32(&data, 4), 124);
+        assert_eq!(read_u32(&data, 12), 4);
+        assert_eq!(read_u32(&data, 16), 4);
+        assert_eq!(read_u32(&data, 28), 3);
+        assert_eq!(&data[84..88], b"DX10");
+
+        let caps = read_u32(&data, 108);
+        assert_eq!(caps & DDSCAPS_MIPMAP, DDSCAPS_MIPMAP);
+        let caps2 = read_u32(&data, 112);
+        assert_eq!(caps2 & DDSCAPS2_CUBEMAP, DDSCAPS2_CUBEMAP);
+        for i in 0..6 {
+            // DDSCAPS2_CUBEMAP_POSITIVEX << i
+            assert_ne!(caps2 & (0x400 << i), 0, "face {}", i);
+        }
+
+        assert_eq!(read_u32(&data, 128), DXGI_FORMAT_R32G32B32A32_FLOAT);
+        assert_eq!(read_u32(&data, 136), DDS_RESOURCE_MISC_TEXTURECUBE);
+        assert_eq!(read_u32(&data, 140), 1);
+
+        let texels_per_face = 16 + 4 + 1;
+        assert_eq!(data.len(), 148 + texels_per_face * 6 * TEXEL_SIZE);
+    }
+
+    #[test]
+    fn face_major_order() {
+        let levels: Vec<_> = [2, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                CubeMap::from_fn(size, |face, x, y| {
+                    Vector4::new(i as f32, face.as_ordinal() as f32, x as f32, y as f32)
+                })
+            })
+            .collect();
+        let mut data = Vec::new();
+        write_dds(&mut data, &levels).unwrap();
+
+        let mut offset = 148;
+        for &face in CUBE_FACES.iter() {
+            for level in levels.iter() {
+                for texel in level.face(face).iter() {
+                    for k in 0..4 {
+                        let value = f32::from_bits(read_u32(&data, offset + k * 4));
+                        assert_eq!(value, texel[k]);
+                    }
+                    offset += TEXEL_SIZE;
+                }
+            }
+        }
+        assert_eq!(offset, data.len());
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides tools for inspecting the accuracy of the blur.
+use std::mem::swap;
+use cgmath::Vector3;
+use cgmath::prelude::*;
+
+use container::CubeMap;
+use cubemap::{texel_direction, CubeFace};
+use ltasgblur::{ltasg_single, StandardCubeMapTrait};
+use reference::spherical_gaussian_reference;
+
+/// Compute the per-texel approximation error of `ltasg_single` compared to a
+/// true spherical Gaussian blur.
+///
+/// For every texel, the spread of the footprint from which the three phases of
+/// `ltasg_single` (with the given `kernel` and `kernel_scale`) gather the
+/// texel's value is compared against that of
+/// `reference::spherical_gaussian_reference` centered at the texel. The spread
+/// is measured as the root mean square of the chord length between the texel
+/// and the footprint, which approximates the angular distance for small
+/// blurs. The standard deviation of the reference is derived from the second
+/// moment of `kernel`, scaled by `kernel_scale` and measured at the face
+/// center. The stored value is the relative error of the spread; e.g., `0.1`
+/// means the blur is 10% wider or narrower than it should be.
+///
+/// Since the blur is run once per texel, this takes `O(size⁴)` time and is
+/// intended for small sizes (e.g., `size ≤ 32`). The returned map can be
+/// saved as an image; bright regions (typically around the corners) indicate
+/// where the approximation is the weakest.
+pub fn ltasg_error_map(size: usize, kernel: &[f32], kernel_scale: f32) -> CubeMap<f32> {
+    let radius = (kernel.len() / 2) as f32;
+    let (weight_sum, variance) = kernel.iter().enumerate().fold(
+        (0.0f32, 0.0f32),
+        |(sum, var), (i, &w)| (sum + w, var + w * (i as f32 - radius).powi(2)),
+    );
+    // Measured in the face size like `spherical_gaussian_reference` expects
+    // (the texel pitch at the face center is `2 / size` radians)
+    let sigma = (variance / weight_sum).sqrt() * kernel_scale / size as f32;
+
+    // The mean square chord length from the unit vector `dir` to a
+    // distribution of directions is `2 - 2 * dot(dir, mean)`, so blurring the
+    // directions themselves gives the spread of both blurs
+    let directions = CubeMap::from_fn(size, |face, x, y| texel_direction(face, x, y, size));
+    let rms_chord = |dir: Vector3<f32>, mean: Vector3<f64>| {
+        (2.0 - 2.0 * mean.dot(dir.cast::<f64>())).max(0.0).sqrt()
+    };
+
+    // Accumulate the footprint of every output texel, one input texel (the
+    // impulse response of which is a column of the blur matrix) at a time
+    let num_texels = size * size * 6;
+    let mut sums = vec![0f64; num_texels];
+    let mut means = vec![Vector3::new(0f64, 0.0, 0.0); num_texels];
+    let mut faces = vec![vec![0f32; size * size]; 6];
+    let mut temp = faces.clone();
+    for (input, (_, _, _, &input_dir)) in directions.iter_texels().enumerate() {
+        for image in faces.iter_mut() {
+            for x in image.iter_mut() {
+                *x = 0.0;
+            }
+        }
+        faces[input / (size * size)][input % (size * size)] = 1.0;
+        for phase in 0..3 {
+            ltasg_single(
+                &mut temp.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &faces.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                size,
+                kernel,
+                kernel_scale,
+                phase,
+                StandardCubeMapTrait,
+            );
+            swap(&mut faces, &mut temp);
+        }
+        let response = faces.iter().flat_map(|image| image.iter());
+        for (output, &weight) in response.enumerate() {
+            if weight != 0.0 {
+                sums[output] += weight as f64;
+                means[output] += input_dir.cast::<f64>() * weight as f64;
+            }
+        }
+    }
+
+    let reference = spherical_gaussian_reference(&directions, sigma, 1);
+
+    CubeMap::from_fn(size, |face: CubeFace, x, y| {
+        let output = face.as_ordinal() * size * size + x + y * size;
+        let dir = texel_direction(face, x, y, size);
+        let spread = rms_chord(dir, means[output] / sums[output]);
+        let ref_spread = rms_chord(dir, reference.face(face)[x + y * size].cast::<f64>());
+        (spread / ref_spread - 1.0).abs() as f32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+    use ltasgblur::gaussian_kernel;
+
+    #[test]
+    fn error_peaks_near_corners() {
+        let size = 16;
+        let kernel = gaussian_kernel(6, 2.0);
+        let map = ltasg_error_map(size, &kernel, 1.0);
+
+        for &face in CUBE_FACES.iter() {
+            let image = map.face(face);
+            let center = image[size / 2 + size / 2 * size];
+            for &corner in [0, size - 1, size * (size - 1), size * size - 1].iter() {
+                let corner = image[corner];
+                assert!(center < 0.1, "{:?}: {}", face, center);
+                assert!(corner > 0.2 && corner > center * 3.0, "{:?}: {}", face, corner);
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Conversion between equirectangular (latitude-longitude) images and cube
+//! maps.
+//!
+//! Converting a cube map to an equirectangular image is handy for inspecting
+//! a blur result as a single image rather than six.
+//!
+//! An equirectangular image maps the azimuth `phi = atan2(x, -z)` linearly to
+//! the horizontal axis, with `phi = -π` at the left edge and `phi = π` at the
+//! right edge (thus the center column faces `-Z`), and the polar angle
+//! `theta = acos(y)` linearly to the vertical axis, with `theta = 0` (`+Y`)
+//! at the top edge and `theta = π` (`-Y`) at the bottom edge.
+use std::{f32, ops};
+use cgmath::Vector3;
+use container::CubeMap;
+use cubemap::texel_direction;
+use raster::{sample_bilinear, WrapMode};
+
+/// Convert a normalized direction to a point on an equirectangular image of
+/// the size `1`×`1`.
+pub fn direction_to_equirect(dir: Vector3<f32>) -> (f32, f32) {
+    let phi = dir.x.atan2(-dir.z);
+    let theta = dir.y.max(-1.0).min(1.0).acos();
+    (phi / (f32::consts::PI * 2.0) + 0.5, theta / f32::consts::PI)
+}
+
+/// Convert a point on an equirectangular image of the size `1`×`1` to a
+/// normalized direction. The inverse of `direction_to_equirect`.
+pub fn equirect_to_direction(u: f32, v: f32) -> Vector3<f32> {
+    let phi = (u - 0.5) * (f32::consts::PI * 2.0);
+    let theta = v * f32::consts::PI;
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    Vector3::new(sin_phi * sin_theta, cos_theta, -cos_phi * sin_theta)
+}
+
+/// Resample the `eq_width`×`eq_height` equirectangular image `equirect`
+/// (stored in the row-major order) into `out`.
+///
+/// Each output texel is computed by bilinearly sampling `equirect` at the
+/// direction toward the texel's center. The source image wraps around
+/// horizontally (at `phi = ±π`) and is clamped vertically (at the poles).
+pub fn equirect_to_cubemap<T>(
+    equirect: &[T],
+    eq_width: usize,
+    eq_height: usize,
+    out: &mut CubeMap<T>,
+) where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert!(eq_width > 0 && eq_height > 0);
+    assert_eq!(equirect.len(), eq_width * eq_height);
+
+    let size = out.size();
+    for (face, x, y, texel) in out.iter_texels_mut() {
+        let (u, v) = direction_to_equirect(texel_direction(face, x, y, size));
+        *texel = sample_bilinear(
+            equirect,
+            eq_width,
+            eq_height,
+            u * eq_width as f32,
+            v * eq_height as f32,
+            WrapMode::Repeat,
+            WrapMode::Clamp,
+        );
+    }
+}
+
+/// Resample `cube_map` into the `width`×`height` equirectangular image `out`
+/// (stored in the row-major order).
+///
+/// Each output texel is computed by bilinearly sampling `cube_map` at the
+/// direction toward the texel's center using `CubeMap::sample_dir`.
+pub fn cubemap_to_equirect<T>(cube_map: &CubeMap<T>, out: &mut [T], width: usize, height: usize)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert_eq!(out.len(), width * height);
+
+    for (i, texel) in out.iter_mut().enumerate() {
+        let u = ((i % width) as f32 + 0.5) / width as f32;
+        let v = ((i / width) as f32 + 0.5) / height as f32;
+        *texel = cube_map.sample_dir(equirect_to_direction(u, v));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::{wrap_texel, CUBE_FACES};
+
+    fn gradient(dir: Vector3<f32>) -> f32 {
+        dir.x + dir.y * 2.0 + dir.z * 0.5
+    }
+
+    fn gradient_equirect(eq_width: usize, eq_height: usize) -> Vec<f32> {
+        (0..eq_width * eq_height)
+            .map(|i| {
+                let u = ((i % eq_width) as f32 + 0.5) / eq_width as f32;
+                let v = ((i / eq_width) as f32 + 0.5) / eq_height as f32;
+                gradient(equirect_to_direction(u, v))
+            })
+            .collect()
+    }
+
+    fn gradient_cubemap(size: usize) -> CubeMap<f32> {
+        let (eq_width, eq_height) = (256, 128);
+        let equirect = gradient_equirect(eq_width, eq_height);
+
+        let mut cube_map = CubeMap::zeros(size);
+        equirect_to_cubemap(&equirect, eq_width, eq_height, &mut cube_map);
+        cube_map
+    }
+
+    #[test]
+    fn direction_round_trip() {
+        for &(u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7), (0.3, 0.95)].iter() {
+            let (u2, v2) = direction_to_equirect(equirect_to_direction(u, v));
+            assert!((u - u2).abs() < 1.0e-4 && (v - v2).abs() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn gradient_round_trip() {
+        let size = 32;
+        let cube_map = gradient_cubemap(size);
+        for &face in CUBE_FACES.iter() {
+            for y in 0..size {
+                for x in 0..size {
+                    let expected = gradient(texel_direction(face, x, y, size));
+                    let actual = cube_map.face(face)[x + y * size];
+                    assert!(
+                        (expected - actual).abs() < 0.02,
+                        "{:?} ({}, {}): {} vs {}",
+                        face,
+                        x,
+                        y,
+                        expected,
+                        actual
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn seams_are_continuous() {
+        let size = 32;
+        let cube_map = gradient_cubemap(size);
+        let last = size as isize - 1;
+        for &face in CUBE_FACES.iter() {
+            for i in 0..size as isize {
+                for &(x, y, nx, ny) in [
+                    (0, i, -1, i),
+                    (last, i, last + 1, i),
+                    (i, 0, i, -1),
+                    (i, last, i, last + 1),
+                ].iter()
+                {
+                    let inner = cube_map.face(face)[(x + y * size as isize) as usize];
+                    let (face2, index) = wrap_texel(face, nx, ny, size);
+                    let outer = cube_map.face(face2)[index];
+                    assert!(
+                        (inner - outer).abs() < 0.2,
+                        "{:?} ({}, {}) -> {:?}: {} vs {}",
+                        face,
+                        x,
+                        y,
+                        face2,
+                        inner,
+                        outer
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equirect_round_trip() {
+        let (eq_width, eq_height) = (128, 64);
+        let equirect = gradient_equirect(eq_width, eq_height);
+
+        let mut cube_map = CubeMap::zeros(32);
+        equirect_to_cubemap(&equirect, eq_width, eq_height, &mut cube_map);
+
+        let mut result = vec![0.0; eq_width * eq_height];
+        cubemap_to_equirect(&cube_map, &mut result, eq_width, eq_height);
+
+        for (i, (&expected, &actual)) in equirect.iter().zip(result.iter()).enumerate() {
+            assert!(
+                (expected - actual).abs() < 0.03,
+                "({}, {}): {} vs {}",
+                i % eq_width,
+                i / eq_width,
+                expected,
+                actual
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides the extraction of peak highlights (e.g., the sun) as analytic
+//! lights, so they can be kept sharp while the rest of the environment is
+//! blurred.
+use std::{mem, ops};
+use cgmath::{num_traits, Vector3};
+
+use container::CubeMap;
+use cubemap::{texel_direction, texel_solid_angle};
+use ltasgblur::{ltasg, StandardCubeMapTrait};
+use pixel::Luminance;
+
+/// A light source extracted from a single texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalyticLight<T> {
+    /// The normalized direction toward the center of the texel.
+    pub direction: Vector3<f32>,
+    /// The value of the texel.
+    pub radiance: T,
+    /// The solid angle subtended by the texel, measured in steradians. The
+    /// irradiance contributed by the light is `radiance * solid_angle`.
+    pub solid_angle: f32,
+}
+
+/// Extract the texels whose luminance is greater than `threshold` as analytic
+/// lights, replacing them with zero.
+///
+/// `threshold` is measured in the same unit as the texels. It should be well
+/// above the brightest part of the environment that must remain in the map
+/// (e.g., the sky around the sun); every texel above it is removed, so a low
+/// value tears holes into bright but extended regions. Adjacent texels are
+/// not merged; a light source covering several texels produces as many
+/// lights.
+pub fn extract_highlights<T>(cube_map: &mut CubeMap<T>, threshold: f32) -> Vec<AnalyticLight<T>>
+where
+    T: Luminance + num_traits::Zero,
+{
+    let size = cube_map.size();
+    let mut lights = Vec::new();
+    for (face, x, y, texel) in cube_map.iter_texels_mut() {
+        if !(texel.luminance() > threshold) {
+            continue;
+        }
+        lights.push(AnalyticLight {
+            direction: texel_direction(face, x, y, size),
+            radiance: mem::replace(texel, T::zero()),
+            solid_angle: texel_solid_angle(x, y, size),
+        });
+    }
+    lights
+}
+
+/// Blur a cube map by a single pass of `ltasg` after extracting the
+/// peak highlights by `extract_highlights`.
+///
+/// Returns the blurred cube map (without the highlights) and the extracted
+/// lights. See `ltasg_single` for `kernel` and `kernel_scale`, and
+/// `extract_highlights` for `threshold`. `faces` is a slice of six
+/// `size`×`size` raster images.
+pub fn ltasg_blur_with_highlights<T>(
+    faces: &[&[T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    threshold: f32,
+) -> (CubeMap<T>, Vec<AnalyticLight<T>>)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero + Luminance,
+{
+    let mut cube_map = CubeMap::from_fn(size, |face, x, y| faces[face.as_ordinal()][x + y * size]);
+    let lights = extract_highlights(&mut cube_map, threshold);
+
+    let mut output = CubeMap::zeros(size);
+    let mut scratch = CubeMap::zeros(size);
+    ltasg(
+        &mut output.faces_mut(),
+        &cube_map.faces(),
+        &mut scratch.faces_mut(),
+        size,
+        kernel,
+        kernel_scale,
+        1,
+        StandardCubeMapTrait,
+    );
+
+    (output, lights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::prelude::*;
+    use cubemap::CubeFace;
+    use ltasgblur::gaussian_kernel;
+
+    #[test]
+    fn single_texel_is_extracted() {
+        let size = 16;
+        let (sun_face, sun_x, sun_y) = (CubeFace::NegativeY, 11, 4);
+        let input = CubeMap::from_fn(size, |face, x, y| {
+            if (face, x, y) == (sun_face, sun_x, sun_y) {
+                1000.0f32
+            } else {
+                0.5
+            }
+        });
+        let faces: Vec<_> = (&input).into_iter().map(|(_, image)| image).collect();
+
+        let (blurred, lights) =
+            ltasg_blur_with_highlights(&faces, size, &gaussian_kernel(4, 2.0), 0.5, 100.0);
+
+        assert_eq!(lights.len(), 1);
+        let light = lights[0];
+        assert_eq!(light.radiance, 1000.0);
+        assert_eq!(CubeFace::from_direction(light.direction), sun_face);
+        let expected_dir = (sun_face.normal::<f32>() + sun_face.u_vec::<f32>() * (7.0 / 16.0) +
+                                sun_face.v_vec::<f32>() * (-7.0 / 16.0))
+            .normalize();
+        assert!(light.direction.dot(expected_dir) > 0.99999, "{:?}", light.direction);
+        assert!(light.solid_angle > 0.0 && light.solid_angle < (2.0f32 / 16.0).powi(2));
+
+        // The hole left by the light slightly darkens its surroundings
+        for (_, _, _, &x) in blurred.iter_texels() {
+            assert!(x < 0.5 + 1.0e-4 && x > 0.4, "{}", x);
+        }
+    }
+
+    #[test]
+    fn solid_angles_sum_to_sphere() {
+        let size = 16;
+        let mut cube_map = CubeMap::filled(size, 1.0f32);
+        let lights = extract_highlights(&mut cube_map, 0.5);
+        assert_eq!(lights.len(), 6 * size * size);
+        let total: f32 = lights.iter().map(|l| l.solid_angle).sum();
+        assert!((total - 4.0 * ::std::f32::consts::PI).abs() < 1.0e-3, "{}", total);
+        assert!(cube_map.iter_texels().all(|(_, _, _, &x)| x == 0.0));
+    }
+}
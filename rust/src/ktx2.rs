@@ -0,0 +1,241 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Writes cube maps in the [KTX 2.0] container format.
+//!
+//! The texels are stored uncompressed as `VK_FORMAT_R32G32B32A32_SFLOAT` with
+//! premultiplied alpha and the linear transfer function, which is what the
+//! rest of this crate works with. The faces are stored in the order defined
+//! by `CUBE_FACES`, which matches the one KTX 2.0 (and Vulkan) expects.
+//!
+//! [KTX 2.0]: https://github.khronos.org/KTX-Specification/
+use std::io::{self, Write};
+use cgmath::Vector4;
+use container::CubeMap;
+
+/// The file identifier `«KTX 20»\r\n\x1A\n`.
+const IDENTIFIER: [u8; 12] = [
+    0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+];
+
+/// `VK_FORMAT_R32G32B32A32_SFLOAT`
+const VK_FORMAT_R32G32B32A32_SFLOAT: u32 = 109;
+
+/// The size of a texel in bytes.
+const TEXEL_SIZE: usize = 16;
+
+/// The size of the identifier, the header, and the index, which precede the
+/// level index.
+const LEVEL_INDEX_OFFSET: usize = 80;
+
+/// The size of a level index entry.
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+
+/// The size of the data format descriptor including `dfdTotalSize`.
+const DFD_SIZE: usize = 92;
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    writer.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+fn write_u64<W: Write>(writer: &mut W, x: u64) -> io::Result<()> {
+    write_u32(writer, x as u32)?;
+    write_u32(writer, (x >> 32) as u32)
+}
+
+/// Write the data format descriptor (a single basic descriptor block) for
+/// four 32-bit float channels with premultiplied alpha.
+fn write_dfd<W: Write>(writer: &mut W) -> io::Result<()> {
+    const KHR_DF_MODEL_RGBSDA: u32 = 1;
+    const KHR_DF_PRIMARIES_BT709: u32 = 1;
+    const KHR_DF_TRANSFER_LINEAR: u32 = 1;
+    const KHR_DF_FLAG_ALPHA_PREMULTIPLIED: u32 = 1;
+    const KHR_DF_SAMPLE_DATATYPE_SIGNED: u32 = 0x40;
+    const KHR_DF_SAMPLE_DATATYPE_FLOAT: u32 = 0x80;
+
+    write_u32(writer, DFD_SIZE as u32)?;
+    // vendorId = KHR_DF_VENDORID_KHRONOS,
+    // descriptorType = KHR_DF_KHR_DESCRIPTORTYPE_BASICFORMAT
+    write_u32(writer, 0)?;
+    // versionNumber = KHR_DF_VERSIONNUMBER_1_3, descriptorBlockSize
+    write_u32(writer, 2 | ((DFD_SIZE as u32 - 4) << 16))?;
+    write_u32(
+        writer,
+        KHR_DF_MODEL_RGBSDA | (KHR_DF_PRIMARIES_BT709 << 8) | (KHR_DF_TRANSFER_LINEAR << 16) |
+            (KHR_DF_FLAG_ALPHA_PREMULTIPLIED << 24),
+    )?;
+    // texelBlockDimension[0..4] (minus one)
+    write_u32(writer, 0)?;
+    // bytesPlane[0..8]
+    write_u32(writer, TEXEL_SIZE as u32)?;
+    write_u32(writer, 0)?;
+
+    // R, G, B, and A
+    for (i, &channel) in [0u32, 1, 2, 15].iter().enumerate() {
+        let channel_type = channel | KHR_DF_SAMPLE_DATATYPE_SIGNED | KHR_DF_SAMPLE_DATATYPE_FLOAT;
+        // bitOffset, bitLength (minus one), channelType
+        write_u32(writer, (i as u32 * 32) | (31 << 16) | (channel_type << 24))?;
+        // samplePosition[0..4]
+        write_u32(writer, 0)?;
+        // sampleLower, sampleUpper
+        write_u32(writer, (-1.0f32).to_bits())?;
+        write_u32(writer, 1.0f32.to_bits())?;
+    }
+    Ok(())
+}
+
+/// Write the mip pyramid `levels` of a cube map as a KTX 2.0 file.
+///
+/// `levels[0]` is the base level, and the size of each subsequent level must
+/// be half (rounded down) of the previous one.
+pub fn write_ktx2<W: Write>(writer: &mut W, levels: &[CubeMap<Vector4<f32>>]) -> io::Result<()> {
+    assert!(!levels.is_empty(), "no levels");
+    let size = levels[0].size();
+    assert!(size > 0 && size <= u32::max_value() as usize);
+    for (i, level) in levels.iter().enumerate() {
+        assert_eq!(level.size(), size >> i, "invalid size of level {}", i);
+    }
+
+    let level_len = |level: &CubeMap<Vector4<f32>>| level.size() * level.size() * 6 * TEXEL_SIZE;
+    let align = |x: usize| (x + TEXEL_SIZE - 1) / TEXEL_SIZE * TEXEL_SIZE;
+
+    // The levels are stored from the smallest one to the largest one, each
+    // aligned to the texel size
+    let dfd_offset = LEVEL_INDEX_OFFSET + LEVEL_INDEX_ENTRY_SIZE * levels.len();
+    let mut offsets = vec![0; levels.len()];
+    let mut offset = dfd_offset + DFD_SIZE;
+    for (i, level) in levels.iter().enumerate().rev() {
+        offset = align(offset);
+        offsets[i] = offset;
+        offset += level_len(level);
+    }
+
+    writer.write_all(&IDENTIFIER)?;
+
+    // Header
+    write_u32(writer, VK_FORMAT_R32G32B32A32_SFLOAT)?;
+    write_u32(writer, 4)?; // typeSize
+    write_u32(writer, size as u32)?; // pixelWidth
+    write_u32(writer, size as u32)?; // pixelHeight
+    write_u32(writer, 0)?; // pixelDepth
+    write_u32(writer, 0)?; // layerCount
+    write_u32(writer, 6)?; // faceCount
+    write_u32(writer, levels.len() as u32)?; // levelCount
+    write_u32(writer, 0)?; // supercompressionScheme
+
+    // Index
+    write_u32(writer, dfd_offset as u32)?;
+    write_u32(writer, DFD_SIZE as u32)?;
+    write_u32(writer, 0)?; // kvdByteOffset
+    write_u32(writer, 0)?; // kvdByteLength
+    write_u64(writer, 0)?; // sgdByteOffset
+    write_u64(writer, 0)?; // sgdByteLength
+
+    // Level index
+    for (level, &offset) in levels.iter().zip(offsets.iter()) {
+        write_u64(writer, offset as u64)?;
+        write_u64(writer, level_len(level) as u64)?; // byteLength
+        write_u64(writer, level_len(level) as u64)?; // uncompressedByteLength
+    }
+
+    write_dfd(writer)?;
+
+    // Level data
+    let mut offset = dfd_offset + DFD_SIZE;
+    for (level, &level_offset) in levels.iter().zip(offsets.iter()).rev() {
+        writer.write_all(&[0u8; TEXEL_SIZE][..level_offset - offset])?;
+        for &texel in level.faces().iter().flat_map(|image| image.iter()) {
+            for i in 0..4 {
+                write_u32(writer, texel[i].to_bits())?;
+            }
+        }
+        offset = level_offset + level_len(level);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        data[offset..offset + 4]
+            .iter()
+            .rev()
+            .fold(0, |x, &b| (x << 8) | b as u32)
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        read_u32(data, offset) as u64 | ((read_u32(data, offset + 4) as u64) << 32)
+    }
+
+    #[test]
+    fn layout() {
+        let levels: Vec<_> = [4, 2, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                CubeMap::from_fn(size, |face, x, y| {
+                    Vector4::new(i as f32, face.as_ordinal() as f32, x as f32, y as f32)
+                })
+            })
+            .collect();
+        let mut data = Vec::new();
+        write_ktx2(&mut data, &levels).unwrap();
+
+        assert_eq!(&data[0..12], &IDENTIFIER[..]);
+        assert_eq!(read_u32(&data, 12), VK_FORMAT_R32G32B32A32_SFLOAT);
+        assert_eq!(read_u32(&data, 16), 4);
+        assert_eq!(read_u32(&data, 20), 4);
+        assert_eq!(read_u32(&data, 24), 4);
+        assert_eq!(read_u32(&data, 28), 0);
+        assert_eq!(read_u32(&data, 32), 0);
+        assert_eq!(read_u32(&data, 36), 6);
+        assert_eq!(read_u32(&data, 40), 3);
+        assert_eq!(read_u32(&data, 44), 0);
+
+        // The data format descriptor
+        let dfd_offset = read_u32(&data, 48) as usize;
+        assert_eq!(dfd_offset, LEVEL_INDEX_OFFSET + LEVEL_INDEX_ENTRY_SIZE * 3);
+        assert_eq!(read_u32(&data, 52) as usize, DFD_SIZE);
+        assert_eq!(read_u32(&data, dfd_offset) as usize, DFD_SIZE);
+        assert_eq!(read_u32(&data, dfd_offset + 8) >> 16, DFD_SIZE as u32 - 4);
+
+        // The levels are stored from the smallest one, without overlapping
+        let mut end = dfd_offset + DFD_SIZE;
+        for (i, level) in levels.iter().enumerate().rev() {
+            let entry = LEVEL_INDEX_OFFSET + LEVEL_INDEX_ENTRY_SIZE * i;
+            let offset = read_u64(&data, entry) as usize;
+            let len = read_u64(&data, entry + 8) as usize;
+            let size = level.size();
+            assert_eq!(offset % TEXEL_SIZE, 0);
+            assert!(offset >= end);
+            assert_eq!(len, size * size * 6 * TEXEL_SIZE);
+            assert_eq!(read_u64(&data, entry + 16) as usize, len);
+            end = offset + len;
+
+            for (face_index, &face) in CUBE_FACES.iter().enumerate() {
+                for (j, texel) in level.face(face).iter().enumerate() {
+                    let texel_offset = offset + (face_index * size * size + j) * TEXEL_SIZE;
+                    for k in 0..4 {
+                        let value = f32::from_bits(read_u32(&data, texel_offset + k * 4));
+                        assert_eq!(value, texel[k]);
+                    }
+                }
+            }
+        }
+        assert_eq!(end, data.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_level_size() {
+        let levels = [CubeMap::zeros(4), CubeMap::zeros(1)];
+        write_ktx2(&mut Vec::new(), &levels).unwrap();
+    }
+}
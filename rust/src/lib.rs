@@ -4,11 +4,76 @@
  * This file is a part of hyper3d-envmapgen. Please read the license text that
  * comes with the source code for use conditions.
  */
-#![cfg_attr(not(debug_assertions), feature(slice_get_slice))]
 extern crate cgmath;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "half")]
+extern crate half;
 
 mod accessor;
 pub mod ltasgblur;
 pub mod cubemap;
+pub mod container;
+pub mod pixel;
+pub mod blend;
+pub mod stats;
+pub mod bilateral;
+pub mod repair;
+pub mod pbr;
+pub mod raster;
+pub mod color;
+pub mod mip;
+pub mod separable;
+pub mod highlight;
+pub mod diagnostics;
+pub mod equirect;
+pub mod octahedral;
+pub mod paraboloid;
+pub mod ktx2;
+pub mod dds;
+pub mod reference;
+
+#[cfg(test)]
+mod tests {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    /// The public types must be usable from parallel asset pipelines.
+    #[test]
+    fn public_types_are_send_sync() {
+        use cgmath::Vector4;
+        type Pixel = Vector4<f32>;
+        assert_send_sync::<::container::CubeMap<Pixel>>();
+        assert_send_sync::<::container::CubeMapError>();
+        assert_send_sync::<::container::Faces<Pixel>>();
+        assert_send_sync::<::container::FacesMut<Pixel>>();
+        assert_send_sync::<::container::GpuCompatError>();
+        assert_send_sync::<::cubemap::CubeFace>();
+        assert_send_sync::<::cubemap::CubeFaceInfo>();
+        assert_send_sync::<::cubemap::CubeFaceParseError>();
+        assert_send_sync::<::cubemap::SphericalCap>();
+        assert_send_sync::<::ltasgblur::BlurBuilder>();
+        assert_send_sync::<::ltasgblur::BlurProgress>();
+        assert_send_sync::<::ltasgblur::BlurReport>();
+        #[cfg(feature = "diagnostics")]
+        assert_send_sync::<::ltasgblur::BlurStats>();
+        assert_send_sync::<::ltasgblur::Cancelled>();
+        assert_send_sync::<::ltasgblur::LtasgError>();
+        assert_send_sync::<::ltasgblur::LtasgOptions>();
+        assert_send_sync::<::ltasgblur::PlanWarning>();
+        assert_send_sync::<::ltasgblur::Quality>();
+        assert_send_sync::<::ltasgblur::SliceFaceSampler<Pixel>>();
+        assert_send_sync::<::ltasgblur::StandardCubeMapTrait>();
+        assert_send_sync::<::ltasgblur::StretchedCubeMapTrait>();
+        assert_send_sync::<::ltasgblur::TiledBlur>();
+        assert_send_sync::<::pixel::ColorSpace>();
+        assert_send_sync::<::pixel::ToneMap>();
+        assert_send_sync::<::raster::Image<Pixel>>();
+        assert_send_sync::<::repair::RepairPolicy>();
+        assert_send_sync::<::repair::RepairReport>();
+        assert_send_sync::<::mip::MipFilter>();
+        assert_send_sync::<::stats::ExposureTarget>();
+        assert_send_sync::<::highlight::AnalyticLight<Pixel>>();
+    }
+}
@@ -7,12 +7,15 @@
 //! Provides a linear-time approximate spherical Gaussian blur implementation.
 //! Please see `Pre-filtering Environment Maps.lyx` for the principle behind
 //! this algorithm.
-use std::ops;
+use std::{cmp, error, f32, fmt};
 use std::mem::swap;
-use cgmath::{num_traits, Vector2};
+use std::sync::atomic::{AtomicBool, Ordering};
+use cgmath::{num_traits, InnerSpace, Vector2, Vector3, Vector4};
 use accessor::SliceAccessor;
 
-use cubemap::CubeFace;
+use container::CubeMap;
+use pixel::{linear_f32_to_srgb_u8, srgb_u8_to_linear_f32, unpremultiply, BlurPixel};
+use cubemap::{map_edge_coords, CubeFace, SphericalCap, CUBE_FACES};
 
 /// Faster alternative for the standard `f32::round` function.
 #[inline(always)]
@@ -20,9 +23,69 @@ fn roundf32(x: f32) -> f32 {
     (x + 0.5).floor()
 }
 
+/// Approximation of `x.exp()` with the relative error of about `1e-4`.
+/// Returns zero if the result would not be a normal number.
+#[inline]
+fn fast_exp(x: f32) -> f32 {
+    let t = x * f32::consts::LOG2_E;
+    let n = t.floor();
+    if n < -126.0 {
+        return 0.0;
+    } else if n > 127.0 {
+        return f32::INFINITY;
+    }
+
+    // Minimax polynomial of `2^f` on `[0, 1]`
+    let f = t - n;
+    let p = 0.9999252 + f * (0.69583354 + f * (0.22606716 + f * 0.07802452));
+    p * f32::from_bits(((n as i32 + 127) as u32) << 23)
+}
+
 pub fn gaussian_kernel(radius: usize, sigma: f32) -> Vec<f32> {
+    gaussian_kernel_with_exp(radius, sigma, f32::exp)
+}
+
+/// `gaussian_kernel` using a fast approximation of `exp`, for callers that
+/// rebuild kernels frequently (e.g., per mip level per frame).
+///
+/// Each element is within the relative error of `1e-3` from the
+/// corresponding one of `gaussian_kernel`'s output, except for those that
+/// are smaller than `f32::MIN_POSITIVE` (before normalization), which become
+/// zero.
+pub fn gaussian_kernel_fast(radius: usize, sigma: f32) -> Vec<f32> {
+    gaussian_kernel_with_exp(radius, sigma, fast_exp)
+}
+
+fn gaussian_kernel_with_exp<F>(radius: usize, sigma: f32, exp: F) -> Vec<f32>
+where
+    F: Fn(f32) -> f32,
+{
+    let mut v: Vec<f32> = (-(radius as isize)..(radius as isize) + 1)
+        .map(|i| exp(-0.5 * (i as f32 * (1.0 / sigma)).powi(2)))
+        .collect();
+
+    // normalize
+    let sum: f32 = v.iter().sum();
+    for x in v.iter_mut() {
+        *x *= 1.0 / sum;
+    }
+
+    v
+}
+
+/// Construct a box kernel of the length `2 * radius + 1` whose weights are
+/// all equal and sum to one.
+pub fn box_kernel(radius: usize) -> Vec<f32> {
+    let len = radius * 2 + 1;
+    vec![1.0 / len as f32; len]
+}
+
+/// Construct a tent (triangular) kernel of the length `2 * radius + 1` whose
+/// weights decrease linearly from the center and sum to one. The weights
+/// would reach zero at the distance `radius + 1` from the center.
+pub fn tent_kernel(radius: usize) -> Vec<f32> {
     let mut v: Vec<f32> = (-(radius as isize)..(radius as isize) + 1)
-        .map(|i| (-0.5 * (i as f32 * (1.0 / sigma)).powi(2)).exp())
+        .map(|i| (radius as isize + 1 - i.abs()) as f32)
         .collect();
 
     // normalize
@@ -34,10 +97,154 @@ pub fn gaussian_kernel(radius: usize, sigma: f32) -> Vec<f32> {
     v
 }
 
+/// The parameters of `ltasg` achieving a given blur amount, computed by
+/// `KernelDesign::for_sigma`.
+///
+/// The blur is split into `num_passes` passes of the standard deviation
+/// `sigma1`, whose variances add up to that of the target blur. Each pass uses
+/// a Gaussian kernel truncated at `kernel_ratio * sigma1` and sampled at
+/// `kernel_upsample` taps per texel (i.e., `kernel_scale = 1 /
+/// kernel_upsample`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelDesign {
+    /// The kernel to be passed to `ltasg`.
+    pub kernel: Vec<f32>,
+    /// The kernel scale to be passed to `ltasg`.
+    pub kernel_scale: f32,
+    /// The number of passes to be passed to `ltasg`.
+    pub num_passes: usize,
+    /// The standard deviation of each pass, measured in the face size.
+    pub sigma1: f32,
+}
+
+impl KernelDesign {
+    /// Compute the largest `sigma1` accepted by `ltasg_single` (with
+    /// `StandardCubeMapTrait`; specify `size - 1` for
+    /// `StretchedCubeMapTrait`) for the given parameters.
+    ///
+    /// The kernel footprint is measured along the axis of a face, but in the
+    /// worst case (near the corners) it is traversed diagonally, stretching
+    /// it by up to `√3`. `ltasg_single` therefore requires the footprint
+    /// `kernel_ratio * sigma1 * size` multiplied by `√3` to fit in a face.
+    pub fn max_sigma1(size: usize, kernel_ratio: f32, kernel_upsample: f32) -> f32 {
+        // The kernel radius is rounded up to a whole tap, which enlarges the
+        // footprint by up to `1 / kernel_upsample` texels
+        let max_footprint = size as f32 / 3.0f32.sqrt() - 1.0 / kernel_upsample;
+        (max_footprint / (kernel_ratio * size as f32)).max(0.0) * 0.999
+    }
+
+    /// Design a blur of `num_passes` passes of the standard deviation
+    /// `sigma1` (measured in the face size) for faces of the size `size`.
+    /// `sigma1` must not exceed `max_sigma1(size, kernel_ratio,
+    /// kernel_upsample)`.
+    pub fn new(
+        sigma1: f32,
+        num_passes: usize,
+        size: usize,
+        kernel_ratio: f32,
+        kernel_upsample: f32,
+    ) -> Self {
+        let sigma1_taps = sigma1 * size as f32 * kernel_upsample;
+        let kernel_radius = (sigma1_taps * kernel_ratio).ceil() as usize;
+        let kernel = if kernel_radius == 0 {
+            vec![1.0]
+        } else {
+            gaussian_kernel(kernel_radius, sigma1_taps)
+        };
+        Self {
+            kernel,
+            kernel_scale: 1.0 / kernel_upsample,
+            num_passes,
+            sigma1,
+        }
+    }
+
+    /// Design a blur with the standard deviation `target_sigma` (measured in
+    /// the face size) for faces of the size `size`, using the fewest passes
+    /// with `sigma1` not exceeding `0.5 / kernel_ratio`.
+    ///
+    /// This is the limit used by the `blurcubemap` example by default. See
+    /// `for_sigma_with_limit` for the details.
+    pub fn for_sigma(
+        target_sigma: f32,
+        size: usize,
+        kernel_ratio: f32,
+        kernel_upsample: f32,
+    ) -> Self {
+        Self::for_sigma_with_limit(
+            target_sigma,
+            size,
+            kernel_ratio,
+            kernel_upsample,
+            0.5 / kernel_ratio,
+        )
+    }
+
+    /// Design a blur with the standard deviation `target_sigma` (measured in
+    /// the face size) for faces of the size `size`, using the fewest passes
+    /// with `sigma1` not exceeding `sigma1_limit`.
+    ///
+    /// `sigma1_limit` is clamped to `max_sigma1`. Smaller values improve the
+    /// accuracy at the cost of more passes (see
+    /// `diagnostics::ltasg_error_map`).
+    pub fn for_sigma_with_limit(
+        target_sigma: f32,
+        size: usize,
+        kernel_ratio: f32,
+        kernel_upsample: f32,
+        sigma1_limit: f32,
+    ) -> Self {
+        let sigma1_limit =
+            sigma1_limit.min(Self::max_sigma1(size, kernel_ratio, kernel_upsample));
+        assert!(sigma1_limit > 0.0, "size is too small for the kernel");
+
+        let variance = target_sigma * target_sigma;
+        let num_passes = (variance / (sigma1_limit * sigma1_limit)).ceil() as usize;
+        let sigma1 = if num_passes == 0 {
+            0.0
+        } else {
+            // Avoid exceeding the limit due to the rounding error
+            (variance / num_passes as f32).sqrt().min(sigma1_limit)
+        };
+        Self::new(sigma1, num_passes, size, kernel_ratio, kernel_upsample)
+    }
+
+    /// Return the standard deviation of the designed blur (measured in the
+    /// face size), which is `target_sigma` unless it was made by `new`.
+    pub fn sigma(&self) -> f32 {
+        self.sigma1 * (self.num_passes as f32).sqrt()
+    }
+}
+
 pub trait CubeMapTrait {
     fn edge_stretch_fixup(&self) -> bool;
+
+    /// Specifies how the kernel taps falling outside a face are handled.
+    /// Defaults to `EdgeMode::Wrap`.
+    fn edge_mode(&self) -> EdgeMode {
+        EdgeMode::Wrap
+    }
+}
+
+/// Specifies how `ltasg_single` handles the kernel taps falling outside the
+/// face being processed. Returned by `CubeMapTrait::edge_mode`.
+///
+/// The modes other than `Wrap` do not read the adjacent faces, so they can be
+/// used to blur faces independently, e.g., to apply the blur to plain 2D
+/// images or to cube maps with intentional hard borders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeMode {
+    /// Continue onto the adjacent face.
+    Wrap,
+    /// Use the texel on the edge of the face.
+    Clamp,
+    /// Treat the texels outside the face as zero. See
+    /// `LtasgOptions::renormalize` for compensating the lost weight.
+    Zero,
 }
 
+/// The standard mapping where the texel centers are located at
+/// `(i + 0.5) / size * 2 - 1`.
 #[derive(Debug, Clone, Copy)]
 pub struct StandardCubeMapTrait;
 
@@ -47,18 +254,82 @@ impl CubeMapTrait for StandardCubeMapTrait {
     }
 }
 
+/// The stretched mapping where the texel centers are located at
+/// `i / (size - 1) * 2 - 1`, i.e., the outermost texels lie exactly on the
+/// edges of the cube.
 #[derive(Debug, Clone, Copy)]
 pub struct StretchedCubeMapTrait;
 
 impl CubeMapTrait for StretchedCubeMapTrait {
     fn edge_stretch_fixup(&self) -> bool {
-        false
+        true
+    }
+}
+
+/// Provides the input texels to `ltasg_single_with_sampler`.
+///
+/// Implement this trait to blur cube maps that are not stored as in-memory
+/// slices, e.g., memory-mapped files, tile caches, or procedurally generated
+/// images. `sample` is called once for every kernel tap, so it should be
+/// cheap.
+pub trait FaceSampler<T> {
+    /// Fetch the texel at `(x, y)` on `face`. `x` and `y` are always in the
+    /// range `[0, size)`.
+    fn sample(&self, face: CubeFace, x: usize, y: usize) -> T;
+}
+
+/// Closures `Fn(face, x, y) -> T` are samplers as well, which is convenient
+/// for procedural environments (e.g., analytic sky models).
+impl<T, F> FaceSampler<T> for F
+where
+    F: Fn(CubeFace, usize, usize) -> T,
+{
+    #[inline(always)]
+    fn sample(&self, face: CubeFace, x: usize, y: usize) -> T {
+        self(face, x, y)
+    }
+}
+
+/// A `FaceSampler` reading from six in-memory `size`×`size` raster images.
+/// This is what `ltasg_single` uses.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceFaceSampler<'a, T: 'a> {
+    faces: [SliceAccessor<&'a [T]>; 6],
+    size: usize,
+}
+
+impl<'a, T: 'a> SliceFaceSampler<'a, T> {
+    /// Construct a `SliceFaceSampler`. Panics if `faces` has less than six
+    /// elements or any of them has less than `size * size` elements.
+    pub fn new(faces: &[&'a [T]], size: usize) -> Self {
+        let face = |i: usize| unsafe { SliceAccessor::new(&faces[i][0..size * size]) };
+        Self {
+            faces: [face(0), face(1), face(2), face(3), face(4), face(5)],
+            size,
+        }
     }
 }
 
+impl<'a, T: Copy + 'a> FaceSampler<T> for SliceFaceSampler<'a, T> {
+    /// Panics if `x` or `y` is out of bounds.
+    #[inline(always)]
+    fn sample(&self, face: CubeFace, x: usize, y: usize) -> T {
+        // `new` checked the face lengths, so this keeps the unchecked access
+        // in bounds
+        assert!(x < self.size && y < self.size);
+        self.faces[face.as_ordinal()][x + y * self.size]
+    }
+}
+
+/// The pixel value representing zero, i.e., `T::Accumulator::zero()`.
+#[inline(always)]
+fn zero_texel<T: BlurPixel>() -> T {
+    T::from_accumulator(num_traits::Zero::zero())
+}
+
 // Convert from a given fixed point value to integer with the nearest rounding mode.
 #[inline(always)]
-fn round_xp2i(v: i32) -> i32 {
+fn round_xp2i(v: i64) -> i64 {
     (v + 32768) >> 16
 }
 
@@ -69,6 +340,8 @@ fn round_xp2i(v: i32) -> i32 {
 ///  - `in_faces` is a slice of six `size`×`size` raster images to be processed.
 ///  - `size` specifies the size of each cube map face in pixels. Note that
 ///    all input/output cube faces must have the same size and must be square.
+///    `size` does not have to be a power of two, but uploading the result to
+///    a GPU might require one (see `CubeMap::validate_gpu_compatible`).
 ///  - `kernel` is a slice containing the kernel used for the convolution.
 ///    The size of the kernel is scaled by `kernel_scale`. Furthermore, it is
 ///    scaled by up to √3 due to the cube map projection onto a unit sphere.
@@ -82,6 +355,9 @@ fn round_xp2i(v: i32) -> i32 {
 ///    cube faces. Usually you specifiy `StandardCubeMapTrait` or
 ///    `StretchedCubeMapTrait`.
 ///
+/// Panics if the parameters are invalid. Use `try_ltasg_single` to handle
+/// them as an error instead.
+///
 /// See the example `blurcubemap` for the usage.
 pub fn ltasg_single<T, Trait>(
     out_faces: &mut [&mut [T]],
@@ -92,378 +368,3854 @@ pub fn ltasg_single<T, Trait>(
     phase: usize,
     cube_map_trait: Trait,
 ) where
-    T: Copy + Clone + ops::Mul<f32, Output = T> + ops::Add + num_traits::Zero,
+    T: BlurPixel,
     Trait: CubeMapTrait,
 {
-    assert!(phase < 3);
-    assert!(kernel.len() % 2 == 1);
-
-    let kernel_radius = kernel.len() / 2;
-    assert!(kernel_scale >= 0.0);
-    assert!(size as f32 > kernel_radius as f32 * kernel_scale * 3.0f32.sqrt());
-
-    // Limitation due to the fixed point arithmetics
-    assert!(size <= 32768);
-
-    let out_faces = &mut out_faces[0..6];
-    let in_faces = &in_faces[0..6];
+    try_ltasg_single(
+        out_faces,
+        in_faces,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+    ).unwrap();
+}
 
-    let (corner_uv, duv_dxy, brd_min, brd_max) = if cube_map_trait.edge_stretch_fixup() {
-        (-1.0, 2.0 / (size - 1) as f32, 0.0, size as f32 - 1.0)
-    } else {
-        (
-            1.0 / size as f32 - 1.0,
-            2.0 / size as f32,
-            -0.5,
-            size as f32 - 0.5,
-        )
-    };
+/// The maximum face size supported by `ltasg_single`.
+///
+/// The kernel taps are stepped in 48.16 fixed point, which is not the limiting
+/// factor. The texel coordinates in the radial blur are computed in `f32`,
+/// whose precision drops to `1/8` texels at this size.
+pub const MAX_SIZE: usize = 1 << 20;
 
-    let axis = match phase {
-        0 => CubeFace::PositiveX,
-        1 => CubeFace::PositiveY,
-        2 => CubeFace::PositiveZ,
-        _ => unreachable!(),
-    };
+/// An error returned by `try_ltasg_single` for invalid parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LtasgError {
+    /// `phase` was not one of `0`, `1`, and `2`.
+    PhaseOutOfRange { phase: usize },
+    /// `kernel.len()` (`len`) was not odd.
+    EvenKernel { len: usize },
+    /// `kernel_scale` was negative or NaN.
+    NegativeScale { kernel_scale: f32 },
+    /// The kernel footprint, `kernel.len() / 2 * kernel_scale * √3`
+    /// (`footprint`), was not smaller than the distance between the face
+    /// borders (`span`), which is `size` for `StandardCubeMapTrait` and
+    /// `size - 1` for `StretchedCubeMapTrait`.
+    SizeTooSmallForKernel { span: f32, footprint: f32 },
+    /// `size` was greater than `MAX_SIZE`.
+    SizeTooLarge { size: usize },
+    /// `in_faces` or `out_faces` had fewer than six elements (`count`).
+    TooFewFaces { count: usize },
+    /// The face `face` (indexed by `CubeFace::as_ordinal`) of `in_faces` or
+    /// `out_faces` had fewer than `size * size` (`min_len`) elements (`len`).
+    FaceTooShort { face: usize, len: usize, min_len: usize },
+}
 
-    fn map_edge_index(face: CubeFace, edge: CubeFace, size: usize) -> (isize, isize, isize) {
-        let size = size as isize;
-        if face.u_face().abs() == edge.abs() {
-            // Either `face`'s +U or -U edge is adjacent to `edge`
-            let (x, cross_offs) = if face.u_face() == edge {
-                (size - 1, -1)
-            } else {
-                (0, 1)
-            };
-            if face.v_face() == edge.u_face() || face.v_face() == edge.v_face() {
-                (x, size, cross_offs)
-            } else {
-                debug_assert!(face.v_face() == -edge.u_face() || face.v_face() == -edge.v_face());
-                (x + (size - 1) * size, -size, cross_offs)
+impl fmt::Display for LtasgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &LtasgError::PhaseOutOfRange { phase } => {
+                write!(f, "phase must be 0, 1, or 2, but was {}", phase)
             }
-        } else {
-            debug_assert!(face.v_face().abs() == edge.abs());
-            // Either `face`'s +V or -V edge is adjacent to `edge`
-            let (y, cross_offs) = if face.v_face() == edge {
-                (size - 1, -size)
-            } else {
-                (0, size)
-            };
-            if face.u_face() == edge.u_face() || face.u_face() == edge.v_face() {
-                (y * size, 1, cross_offs)
-            } else {
-                debug_assert!(face.u_face() == -edge.u_face() || face.u_face() == -edge.v_face());
-                (size - 1 + y * size, -1, cross_offs)
+            &LtasgError::EvenKernel { len } => {
+                write!(f, "the kernel length must be odd, but was {}", len)
+            }
+            &LtasgError::NegativeScale { kernel_scale } => write!(
+                f,
+                "the kernel scale must be non-negative, but was {}",
+                kernel_scale
+            ),
+            &LtasgError::SizeTooSmallForKernel { span, footprint } => write!(
+                f,
+                "the kernel footprint ({}) must be smaller than the face ({})",
+                footprint,
+                span
+            ),
+            &LtasgError::SizeTooLarge { size } => write!(
+                f,
+                "the face size must be at most {}, but was {}",
+                MAX_SIZE,
+                size
+            ),
+            &LtasgError::TooFewFaces { count } => {
+                write!(f, "six faces are required, but {} were given", count)
             }
+            &LtasgError::FaceTooShort { face, len, min_len } => write!(
+                f,
+                "face {} must have at least {} elements, but had {}",
+                face,
+                min_len,
+                len
+            ),
         }
     }
+}
 
-    for (out_face_i, out_face_img) in out_faces.iter_mut().enumerate() {
-        let out_face_img = &mut out_face_img[0..size * size];
-        let out_face = CubeFace::from_ordinal(out_face_i).unwrap();
-        let in_face_img = unsafe { SliceAccessor::new(&in_faces[out_face_i][0..size * size]) };
+impl error::Error for LtasgError {
+    fn description(&self) -> &str {
+        match self {
+            &LtasgError::PhaseOutOfRange { .. } => "phase out of range",
+            &LtasgError::EvenKernel { .. } => "kernel length is even",
+            &LtasgError::NegativeScale { .. } => "kernel scale is negative",
+            &LtasgError::SizeTooSmallForKernel { .. } => "face too small for the kernel",
+            &LtasgError::SizeTooLarge { .. } => "face too large",
+            &LtasgError::TooFewFaces { .. } => "too few faces",
+            &LtasgError::FaceTooShort { .. } => "face too short",
+        }
+    }
+}
 
-        if out_face.abs() == axis {
-            // Radial blur
-            // Compute the indices, etc. for the adjacent faces
-            let pos_u_face = out_face.u_face();
-            let neg_u_face = -pos_u_face;
-            let pos_v_face = out_face.v_face();
-            let neg_v_face = -pos_v_face;
+/// Validate the parameters of `ltasg_single` and its variants.
+fn check_parameters<Trait: CubeMapTrait>(
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: &Trait,
+) -> Result<(), LtasgError> {
+    if phase >= 3 {
+        return Err(LtasgError::PhaseOutOfRange { phase });
+    }
+    if kernel.len() % 2 != 1 {
+        return Err(LtasgError::EvenKernel { len: kernel.len() });
+    }
+    if !(kernel_scale >= 0.0) {
+        return Err(LtasgError::NegativeScale { kernel_scale });
+    }
+    if size > MAX_SIZE {
+        return Err(LtasgError::SizeTooLarge { size });
+    }
 
-            let pos_u_img =
-                unsafe { SliceAccessor::new(&in_faces[pos_u_face.as_ordinal()][0..size * size]) };
-            let neg_u_img =
-                unsafe { SliceAccessor::new(&in_faces[neg_u_face.as_ordinal()][0..size * size]) };
-            let pos_v_img =
-                unsafe { SliceAccessor::new(&in_faces[pos_v_face.as_ordinal()][0..size * size]) };
-            let neg_v_img =
-                unsafe { SliceAccessor::new(&in_faces[neg_v_face.as_ordinal()][0..size * size]) };
-
-            let pos_u_idx = map_edge_index(pos_u_face, out_face, size);
-            let neg_u_idx = map_edge_index(neg_u_face, out_face, size);
-            let pos_v_idx = map_edge_index(pos_v_face, out_face, size);
-            let neg_v_idx = map_edge_index(neg_v_face, out_face, size);
-
-            let mut i = 0;
-            let mut cur_v = corner_uv;
-            for y in 0..size {
-                let mut cur_u = corner_uv;
-                for x in 0..size {
-                    let local_scale = kernel_scale * (1.0 + cur_u * cur_u + cur_v * cur_v).sqrt();
-                    let mut sum = T::zero();
+    // The footprint must not cross both borders
+    let span = if cube_map_trait.edge_stretch_fixup() {
+        size as f32 - 1.0
+    } else {
+        size as f32
+    };
+    let footprint = (kernel.len() / 2) as f32 * kernel_scale * 3.0f32.sqrt();
+    if !(span > footprint) {
+        return Err(LtasgError::SizeTooSmallForKernel { span, footprint });
+    }
 
-                    let mut in_coord_f = Vector2::new(x, y).cast::<f32>();
-                    let mut in_coord_df = Vector2::new(cur_u, cur_v) * local_scale;
-                    in_coord_f -= in_coord_df * kernel_radius as f32;
+    Ok(())
+}
 
-                    let mut offs = Vector2::new(1, size);
+/// Validate the number and the lengths of the faces given to `ltasg_single`.
+fn check_faces<I>(face_lens: I, size: usize) -> Result<(), LtasgError>
+where
+    I: ExactSizeIterator<Item = usize>,
+{
+    if face_lens.len() < 6 {
+        return Err(LtasgError::TooFewFaces { count: face_lens.len() });
+    }
+    for (face, len) in face_lens.take(6).enumerate() {
+        if len < size * size {
+            return Err(LtasgError::FaceTooShort { face, len, min_len: size * size });
+        }
+    }
+    Ok(())
+}
 
-                    // Swap the coordinates if Y is the major axis
-                    let major_y = if cur_v.abs() > cur_u.abs() {
-                        swap(&mut in_coord_f.x, &mut in_coord_f.y);
-                        swap(&mut in_coord_df.x, &mut in_coord_df.y);
-                        swap(&mut offs.x, &mut offs.y);
-                        true
-                    } else {
-                        false
-                    };
+/// `ltasg_single` returning an error instead of panicking if the parameters
+/// are invalid. See `LtasgError` for the checked conditions.
+pub fn try_ltasg_single<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+) -> Result<(), LtasgError>
+where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+{
+    check_parameters(size, kernel, kernel_scale, phase, &cube_map_trait)?;
+    check_faces(in_faces.iter().map(|f| f.len()), size)?;
+    check_faces(out_faces.iter().map(|f| f.len()), size)?;
+    ltasg_single_with_options(
+        out_faces,
+        in_faces,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        &LtasgOptions::default(),
+    );
+    Ok(())
+}
 
-                    // The footprint possibly crosses the boundary?
-                    // (We must be conservative due to FP rounding)
-                    let in_coord_end_f = in_coord_f + in_coord_df * kernel.len() as f32;
+/// `ltasg_single` operating on `CubeMap`s. `out_cube_map` and `in_cube_map`
+/// must have the same size.
+pub fn ltasg_single_cubemap<T, Trait>(
+    out_cube_map: &mut CubeMap<T>,
+    in_cube_map: &CubeMap<T>,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+{
+    let size = in_cube_map.size();
+    assert_eq!(out_cube_map.size(), size);
+    ltasg_single(
+        &mut out_cube_map.faces_mut(),
+        &in_cube_map.faces(),
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+    );
+}
 
-                    if in_coord_end_f.x <= brd_min || in_coord_end_f.x >= brd_max {
-                        let ((base_idx, main_offs, cross_offs), overflow_img) = if major_y {
-                            if in_coord_df.x >= 0.0 {
-                                (pos_v_idx, pos_v_img)
-                            } else {
-                                (neg_v_idx, neg_v_img)
-                            }
-                        } else {
-                            if in_coord_df.x >= 0.0 {
-                                (pos_u_idx, pos_u_img)
-                            } else {
-                                (neg_u_idx, neg_u_img)
-                            }
-                        };
+/// Perform `num_passes` iterations of the LTASG blur, each consisting of the
+/// three phases of `ltasg_single`.
+///
+/// The result is always stored in `out_faces`. The phases ping-pong between
+/// `out_faces` and `scratch_faces`, starting with the one chosen so that the
+/// last phase writes `out_faces`: when the total number of phases
+/// (`3 * num_passes`) is odd (i.e., `num_passes` is odd), the first phase
+/// writes `out_faces`; otherwise, it writes `scratch_faces`. `in_faces` is
+/// only read, and the contents of `scratch_faces` are unspecified after the
+/// call. If `num_passes` is zero, `in_faces` is copied to `out_faces`.
+///
+/// See `ltasg_single` for the other parameters.
+pub fn ltasg<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+    cube_map_trait: Trait,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    ltasg_with_progress(
+        out_faces,
+        in_faces,
+        scratch_faces,
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        cube_map_trait,
+        &mut |_| {},
+    );
+}
 
-                        // Where does it possibly cross the boundary?
-                        let mut minor_pos = if in_coord_df.x >= 0.0 {
-                            (brd_max - in_coord_f.x) / in_coord_df.x
-                        } else {
-                            (brd_min - in_coord_f.x) / in_coord_df.x
-                        } * in_coord_df.y +
-                            in_coord_f.y;
-
-                        // Corner case (causes a crash)
-                        if minor_pos <= brd_min {
-                            minor_pos = brd_min + 0.00001;
-                        } else if minor_pos >= brd_max {
-                            minor_pos = brd_max - 0.00001;
-                        }
+/// Reports the progress of a multi-pass blur to the callback of
+/// `ltasg_with_progress` and `pbr::generate_mip_pyramid_with_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlurProgress {
+    /// The mip level being processed. Always `0` for `ltasg_with_progress`.
+    pub level: usize,
+    /// The index of the pass the completed phase belongs to.
+    pub pass: usize,
+    /// The index of the completed phase within the pass (`0..3`).
+    pub phase: usize,
+    /// The total number of passes (of the current level).
+    pub total_passes: usize,
+}
 
-                        let (overflow_start, overflow_offs) =
-                            (
-                                base_idx + main_offs * roundf32(minor_pos) as isize,
-                                cross_offs,
-                            );
-
-                        // Convert to 16.16 fixed point
-                        let mut in_coord_f = (in_coord_f * 65536.0).cast::<i32>();
-                        let mut in_coord_df = (in_coord_df * 65536.0).cast::<i32>();
-                        let brd_min = (brd_min * 65536.0) as i32;
-                        let brd_max = (brd_max * 65536.0) as i32;
-
-                        macro_rules! step_overflow {
-                            ($dist:expr) => ({
-                                let distance_i = round_xp2i($dist - brd_min) as isize;
-                                overflow_img[(overflow_start + distance_i * overflow_offs) as usize]
-                            })
-                        }
-                        macro_rules! step_normal {
-                            () => ({
-                                let in_coord_x = round_xp2i(in_coord_f.x) as usize;
-                                let in_coord_y = round_xp2i(in_coord_f.y) as usize;
-                                in_face_img[in_coord_x * offs.x + in_coord_y * offs.y]
-                            })
-                        }
+/// `ltasg` calling `progress` after each phase, i.e., `3 * num_passes` times
+/// in total. Nothing is reported if `num_passes` is zero.
+pub fn ltasg_with_progress<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+    cube_map_trait: Trait,
+    progress: &mut dyn FnMut(BlurProgress),
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    ltasg_cancellable(
+        out_faces,
+        in_faces,
+        scratch_faces,
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        cube_map_trait,
+        progress,
+        &AtomicBool::new(false),
+    ).unwrap();
+}
 
-                        // `in_coord_df.x` is monotonically increasing/decreasing, so...
-                        let mut it = kernel.iter();
+/// Indicates that `ltasg_cancellable` was cancelled by `should_cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
 
-                        loop {
-                            if in_coord_df.x >= 0 {
-                                if in_coord_f.x >= brd_max {
-                                    break;
-                                }
-                            } else {
-                                if in_coord_f.x <= brd_min {
-                                    break;
-                                }
-                            }
-                            if let Some(weight) = it.next() {
-                                sum = sum + step_normal!() * *weight;
-                                in_coord_f += in_coord_df;
-                            } else {
-                                break;
-                            }
-                        }
-                        while let Some(weight) = it.next() {
-                            let distance = if in_coord_df.x >= 0 {
-                                in_coord_f.x - brd_max
-                            } else {
-                                brd_min - in_coord_f.x
-                            };
-                            debug_assert!(distance >= 0);
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the blur was cancelled")
+    }
+}
 
-                            sum = sum + step_overflow!(distance) * *weight;
-                            in_coord_f += in_coord_df;
-                        }
-                    } else {
-                        // Convert to 16.16 fixed point
-                        let mut in_coord_f = (in_coord_f * 65536.0).cast::<i32>();
-                        let mut in_coord_df = (in_coord_df * 65536.0).cast::<i32>();
+impl error::Error for Cancelled {
+    fn description(&self) -> &str {
+        "cancelled"
+    }
+}
 
-                        for weight in kernel.iter() {
-                            let in_coord_x = round_xp2i(in_coord_f.x) as usize;
-                            let in_coord_y = round_xp2i(in_coord_f.y) as usize;
+/// `ltasg_with_progress` that stops as soon as `should_cancel` is set.
+///
+/// `should_cancel` is checked before each phase, so a phase that has already
+/// started always runs to completion. When cancelled, `Err(Cancelled)` is
+/// returned and the contents of `out_faces` and `scratch_faces` are
+/// unspecified (they hold the intermediate results of the completed phases,
+/// or whatever they held before the call). `progress` is not called for the
+/// phases that were not run.
+pub fn ltasg_cancellable<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+    cube_map_trait: Trait,
+    progress: &mut dyn FnMut(BlurProgress),
+    should_cancel: &AtomicBool,
+) -> Result<(), Cancelled>
+where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    ltasg_core(
+        out_faces,
+        in_faces,
+        scratch_faces,
+        size,
+        [(kernel, kernel_scale); 3],
+        num_passes,
+        cube_map_trait,
+        progress,
+        should_cancel,
+    )
+}
 
-                            sum = sum +
-                                in_face_img[in_coord_x * offs.x + in_coord_y * offs.y] * *weight;
+/// `ltasg` using a different kernel and scale for each phase, performing an
+/// anisotropic blur.
+///
+/// `phases[i]` specifies `(kernel, kernel_scale)` for the phase `i`, which
+/// blurs along the X, Y, and Z axis for `i = 0, 1, 2`, respectively. Passing
+/// `(&[1.0], 0.0)` disables the blur along the corresponding axis.
+pub fn ltasg_aniso<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    phases: [(&[f32], f32); 3],
+    num_passes: usize,
+    cube_map_trait: Trait,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    ltasg_core(
+        out_faces,
+        in_faces,
+        scratch_faces,
+        size,
+        phases,
+        num_passes,
+        cube_map_trait,
+        &mut |_| {},
+        &AtomicBool::new(false),
+    ).unwrap();
+}
 
-                            in_coord_f += in_coord_df;
-                        }
-                    }
+/// The common part of `ltasg_cancellable` and `ltasg_aniso`.
+fn ltasg_core<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    phases: [(&[f32], f32); 3],
+    num_passes: usize,
+    cube_map_trait: Trait,
+    progress: &mut dyn FnMut(BlurProgress),
+    should_cancel: &AtomicBool,
+) -> Result<(), Cancelled>
+where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    if num_passes == 0 {
+        for (out_face, in_face) in out_faces[0..6].iter_mut().zip(in_faces[0..6].iter()) {
+            out_face[0..size * size].copy_from_slice(&in_face[0..size * size]);
+        }
+        return Ok(());
+    }
 
-                    out_face_img[i] = sum;
+    let num_phases = num_passes * 3;
+    for i in 0..num_phases {
+        if should_cancel.load(Ordering::Relaxed) {
+            return Err(Cancelled);
+        }
 
-                    i += 1;
-                    cur_u += duv_dxy;
-                }
-                cur_v += duv_dxy;
-            }
-        } else {
-            // Directional blur
-            let (pos_axis_face, neg_axis_face) = if out_face.u_face().abs() == axis {
-                (out_face.u_face(), -out_face.u_face())
+        let (kernel, kernel_scale) = phases[i % 3];
+        let run = |dst: &mut [&mut [T]], src: &[&mut [T]]| {
+            let src: Vec<&[T]> = if i == 0 {
+                in_faces.to_vec()
             } else {
-                (out_face.v_face(), -out_face.v_face())
+                src.iter().map(|face| &face[..]).collect()
             };
+            ltasg_single(
+                dst,
+                &src,
+                size,
+                kernel,
+                kernel_scale,
+                i % 3,
+                cube_map_trait.clone(),
+            );
+        };
 
-            let pos_axis_img = unsafe {
-                SliceAccessor::new(&in_faces[pos_axis_face.as_ordinal()][0..size * size])
-            };
-            let neg_axis_img = unsafe {
-                SliceAccessor::new(&in_faces[neg_axis_face.as_ordinal()][0..size * size])
-            };
+        // Make sure the last phase writes `out_faces`
+        if (num_phases - 1 - i) % 2 == 0 {
+            run(out_faces, scratch_faces);
+        } else {
+            run(scratch_faces, out_faces);
+        }
 
-            let pos_axis_idx = map_edge_index(pos_axis_face, out_face, size);
-            let neg_axis_idx = map_edge_index(neg_axis_face, out_face, size);
+        progress(BlurProgress {
+            level: 0,
+            pass: i / 3,
+            phase: i % 3,
+            total_passes: num_passes,
+        });
+    }
+    Ok(())
+}
 
-            let mut offs = Vector2::new(1, size);
+/// `ltasg` returning an error instead of panicking if the parameters are
+/// invalid. See `LtasgError` for the checked conditions.
+pub fn try_ltasg<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    scratch_faces: &mut [&mut [T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+    cube_map_trait: Trait,
+) -> Result<(), LtasgError>
+where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    check_parameters(size, kernel, kernel_scale, 0, &cube_map_trait)?;
+    check_faces(in_faces.iter().map(|f| f.len()), size)?;
+    check_faces(out_faces.iter().map(|f| f.len()), size)?;
+    check_faces(scratch_faces.iter().map(|f| f.len()), size)?;
+    ltasg(
+        out_faces,
+        in_faces,
+        scratch_faces,
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        cube_map_trait,
+    );
+    Ok(())
+}
 
-            // Swap the coordinates if `out_face.v_face().abs() == axis`
-            // TODO: transpose the input image for an `improved cache utilization
-            if out_face.v_face().abs() == axis {
-                swap(&mut offs.x, &mut offs.y);
-            }
+/// Perform `num_passes` iterations of the LTASG blur (see `ltasg`) on a cube
+/// map with 8-bit RGBA pixels with straight alpha.
+///
+/// The pixels are converted to premultiplied, linear values with
+/// `srgb_u8_to_linear_f32` before blurring and back with
+/// `linear_f32_to_srgb_u8` after that. `StandardCubeMapTrait` is used.
+pub fn blur_srgb_cubemap(
+    out_faces: &mut [&mut [[u8; 4]]],
+    in_faces: &[&[[u8; 4]]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+) {
+    let linear_faces: Vec<Vec<Vector4<f32>>> = in_faces[0..6]
+        .iter()
+        .map(|face| {
+            face[0..size * size]
+                .iter()
+                .map(|&rgba| srgb_u8_to_linear_f32(rgba))
+                .collect()
+        })
+        .collect();
+    let mut out_linear = linear_faces.clone();
+    let mut scratch = linear_faces.clone();
 
-            let mut cur_v = corner_uv;
-            for y in 0..size {
-                let mut cur_u = corner_uv;
-                for x in 0..size {
-                    let local_scale = kernel_scale * (1.0 + cur_u * cur_u + cur_v * cur_v).sqrt();
-                    let mut sum = T::zero();
-
-                    // 48.16 fixed point values
-                    let mut in_coord_x_f = (x as i32) << 16;
-                    let in_coord_x_df = (local_scale * 65536.0) as i32;
-                    let brd_min = (brd_min * 65536.0) as i32;
-                    let brd_max = (brd_max * 65536.0) as i32;
-                    let cur_v = (cur_v * 65536.0) as i32;
-
-                    in_coord_x_f -= in_coord_x_df * kernel_radius as i32;
-
-                    macro_rules! step_overflow {
-                        ($idx:expr, $img:expr, $dist:expr) => ({
-                            // Toward the center
-                            let (base_idx, main_offs, cross_offs) = $idx;
-                            let overflow_main_df = $dist as i64 * (-cur_v as i64); // 32.32
-                            let overflow_main = y as isize + ((overflow_main_df + (32768 << 16)) >> 32) as isize;
-                            let overflow_cross = round_xp2i($dist) as isize;
-                            $img[(base_idx + overflow_main * main_offs +
-                                overflow_cross * cross_offs) as usize]
-                        })
-                    }
-                    macro_rules! step_normal {
-                        () => ({
-                            let in_coord_x = round_xp2i(in_coord_x_f) as usize;
-                            let in_coord_y = y;
+    ltasg(
+        &mut out_linear.iter_mut().map(|f| &mut f[..]).collect::<Vec<_>>(),
+        &linear_faces.iter().map(|f| &f[..]).collect::<Vec<_>>(),
+        &mut scratch.iter_mut().map(|f| &mut f[..]).collect::<Vec<_>>(),
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        StandardCubeMapTrait,
+    );
 
-                            in_face_img[in_coord_x * offs.x + in_coord_y * offs.y]
-                        })
+    for (out_face, linear) in out_faces[0..6].iter_mut().zip(out_linear.iter()) {
+        for (out_pixel, &pixel) in out_face[0..size * size].iter_mut().zip(linear.iter()) {
+            *out_pixel = linear_f32_to_srgb_u8(pixel);
+        }
+    }
+}
+
+/// Perform `num_passes` iterations of the LTASG blur (see `ltasg`) on a cube
+/// map with linear RGB pixels without an alpha channel.
+///
+/// This is suitable for opaque (e.g., HDR) environment maps, where storing
+/// the alpha channel would only waste memory bandwidth. The scratch buffer
+/// required by `ltasg` is allocated internally. `StandardCubeMapTrait` is
+/// used.
+pub fn blur_rgb_cubemap(
+    out_faces: &mut [&mut [Vector3<f32>]],
+    in_faces: &[&[Vector3<f32>]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+) {
+    let mut scratch = vec![vec![Vector3::new(0.0, 0.0, 0.0); size * size]; 6];
+    ltasg(
+        out_faces,
+        in_faces,
+        &mut scratch.iter_mut().map(|f| &mut f[..]).collect::<Vec<_>>(),
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        StandardCubeMapTrait,
+    );
+}
+
+/// Perform `num_passes` iterations of the LTASG blur (see `ltasg`) on a cube
+/// map with linear RGBA pixels with straight alpha, producing straight alpha.
+///
+/// Blurring straight-alpha values directly would mix the color of the
+/// transparent texels (which is meaningless, and often black) into the
+/// neighboring opaque ones, producing dark fringes around them. Therefore,
+/// the color channels are multiplied by the alpha before blurring, so each
+/// texel contributes in proportion to its coverage, and divided by the
+/// blurred alpha after that (see `pixel::unpremultiply`). Texels whose
+/// blurred alpha is zero become `(0, 0, 0, 0)`. `StandardCubeMapTrait` is
+/// used.
+pub fn blur_premultiplied(
+    out_faces: &mut [&mut [Vector4<f32>]],
+    in_faces: &[&[Vector4<f32>]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    num_passes: usize,
+) {
+    let premultiplied: Vec<Vec<Vector4<f32>>> = in_faces[0..6]
+        .iter()
+        .map(|face| {
+            face[0..size * size]
+                .iter()
+                .map(|&rgba| (rgba.truncate() * rgba.w).extend(rgba.w))
+                .collect()
+        })
+        .collect();
+    let mut scratch = premultiplied.clone();
+
+    ltasg(
+        out_faces,
+        &premultiplied.iter().map(|f| &f[..]).collect::<Vec<_>>(),
+        &mut scratch.iter_mut().map(|f| &mut f[..]).collect::<Vec<_>>(),
+        size,
+        kernel,
+        kernel_scale,
+        num_passes,
+        StandardCubeMapTrait,
+    );
+
+    for out_face in out_faces[0..6].iter_mut() {
+        for pixel in out_face[0..size * size].iter_mut() {
+            *pixel = if pixel.w > 0.0 {
+                unpremultiply(*pixel).extend(pixel.w)
+            } else {
+                Vector4::new(0.0, 0.0, 0.0, 0.0)
+            };
+        }
+    }
+}
+
+/// The trade-off between the accuracy and the number of passes, used by
+/// `BlurBuilder::quality`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Quality {
+    /// Use the largest `sigma1` accepted by `ltasg_single`, i.e.,
+    /// `KernelDesign::max_sigma1`, and therefore the fewest passes, at the
+    /// cost of a larger approximation error.
+    Draft,
+    /// Limit `sigma1` to `0.5 / kernel_ratio` (see `KernelDesign::for_sigma`).
+    Normal,
+    /// Limit `sigma1` to `0.25 / kernel_ratio`, which quadruples the number
+    /// of passes compared to `Normal`.
+    High,
+}
+
+impl Quality {
+    /// Get the `sigma1_limit` passed to `KernelDesign::for_sigma_with_limit`.
+    /// `Draft` returns infinity, which is clamped to `max_sigma1` there.
+    pub fn sigma1_limit(&self, kernel_ratio: f32) -> f32 {
+        match *self {
+            Quality::Draft => f32::INFINITY,
+            Quality::Normal => 0.5 / kernel_ratio,
+            Quality::High => 0.25 / kernel_ratio,
+        }
+    }
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Quality::Normal
+    }
+}
+
+/// Blurs a `CubeMap` by a given standard deviation, designing the kernel
+/// with `KernelDesign` and running the passes with `ltasg`.
+///
+/// ```
+/// use hyperenvmap::container::CubeMap;
+/// use hyperenvmap::cubemap::CubeFace;
+/// use hyperenvmap::ltasgblur::BlurBuilder;
+///
+/// // A single bright texel at the center of +X
+/// let size = 32;
+/// let mut cube_map = CubeMap::zeros(size);
+/// cube_map.face_mut(CubeFace::PositiveX)[size / 2 * (size + 1)] = 1.0f32;
+///
+/// // Blur it with σ = 0.05 (relative to the face size)
+/// BlurBuilder::new().sigma(0.05).run(&mut cube_map);
+/// assert!(cube_map.face(CubeFace::PositiveX)[size / 2 * (size + 1)] < 0.1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BlurBuilder<Trait = StandardCubeMapTrait> {
+    sigma: f32,
+    kernel_ratio: f32,
+    kernel_upsample: f32,
+    cube_map_trait: Trait,
+    quality: Quality,
+    passes_override: Option<usize>,
+    max_passes: usize,
+}
+
+impl BlurBuilder<StandardCubeMapTrait> {
+    /// Construct a `BlurBuilder` with `sigma = 0` (no blur), `kernel_ratio =
+    /// 2`, `kernel_upsample = 3` (the values used by the `blurcubemap`
+    /// example), `StandardCubeMapTrait`, `Quality::Normal`, and `max_passes
+    /// = 256`.
+    pub fn new() -> Self {
+        Self {
+            sigma: 0.0,
+            kernel_ratio: 2.0,
+            kernel_upsample: 3.0,
+            cube_map_trait: StandardCubeMapTrait,
+            quality: Quality::Normal,
+            passes_override: None,
+            max_passes: 256,
+        }
+    }
+}
+
+impl Default for BlurBuilder<StandardCubeMapTrait> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Trait: CubeMapTrait + Clone> BlurBuilder<Trait> {
+    /// Set the standard deviation of the blur, measured in the face size.
+    pub fn sigma(self, sigma: f32) -> Self {
+        Self { sigma, ..self }
+    }
+
+    /// Set the ratio of the kernel radius to the standard deviation of each
+    /// pass. See `KernelDesign`.
+    pub fn kernel_ratio(self, kernel_ratio: f32) -> Self {
+        Self { kernel_ratio, ..self }
+    }
+
+    /// Set the number of kernel taps per texel. See `KernelDesign`.
+    pub fn kernel_upsample(self, kernel_upsample: f32) -> Self {
+        Self {
+            kernel_upsample,
+            ..self
+        }
+    }
+
+    /// Set the `CubeMapTrait` describing the cube map.
+    pub fn cube_map_trait<U: CubeMapTrait + Clone>(self, cube_map_trait: U) -> BlurBuilder<U> {
+        BlurBuilder {
+            sigma: self.sigma,
+            kernel_ratio: self.kernel_ratio,
+            kernel_upsample: self.kernel_upsample,
+            cube_map_trait,
+            quality: self.quality,
+            passes_override: self.passes_override,
+            max_passes: self.max_passes,
+        }
+    }
+
+    /// Set the trade-off between the accuracy and the number of passes.
+    /// Defaults to `Quality::Normal`.
+    pub fn quality(self, quality: Quality) -> Self {
+        Self { quality, ..self }
+    }
+
+    /// Use the specified number of passes instead of the fewest ones
+    /// achieving `sigma` (`None`, default). If `sigma` cannot be achieved in
+    /// that many passes, the largest possible blur is applied instead.
+    pub fn passes_override(self, passes_override: Option<usize>) -> Self {
+        Self {
+            passes_override,
+            ..self
+        }
+    }
+
+    /// Set the maximum number of passes. If `sigma` requires more passes
+    /// (which usually means it was specified in pixels instead of relative to
+    /// the face size), the blur is limited to what `max_passes` passes can
+    /// achieve, and `BlurReport::warning` is set to
+    /// `PlanWarning::PassesClamped`. Does not affect `passes_override`.
+    /// Defaults to `256`.
+    pub fn max_passes(self, max_passes: usize) -> Self {
+        Self { max_passes, ..self }
+    }
+
+    /// Design the kernel for faces of the size `size`, which must not be
+    /// zero.
+    pub fn design(&self, size: usize) -> KernelDesign {
+        self.design_with_warning(size).0
+    }
+
+    fn design_with_warning(&self, size: usize) -> (KernelDesign, Option<PlanWarning>) {
+        assert!(size > 0, "size must not be zero");
+
+        // `KernelDesign` measures the texel spacing in the face size
+        let size = if self.cube_map_trait.edge_stretch_fixup() {
+            size - 1
+        } else {
+            size
+        };
+        match self.passes_override {
+            Some(num_passes) => {
+                let max_sigma1 =
+                    KernelDesign::max_sigma1(size, self.kernel_ratio, self.kernel_upsample);
+                let sigma1 = if num_passes == 0 {
+                    0.0
+                } else {
+                    (self.sigma / (num_passes as f32).sqrt()).min(max_sigma1)
+                };
+                let design = KernelDesign::new(
+                    sigma1,
+                    num_passes,
+                    size,
+                    self.kernel_ratio,
+                    self.kernel_upsample,
+                );
+                (design, None)
+            }
+            None => {
+                let design = KernelDesign::for_sigma_with_limit(
+                    self.sigma,
+                    size,
+                    self.kernel_ratio,
+                    self.kernel_upsample,
+                    self.quality.sigma1_limit(self.kernel_ratio),
+                );
+                if design.num_passes <= self.max_passes {
+                    return (design, None);
+                }
+
+                // Running the required number of passes could take forever.
+                // Keep `sigma1` within the limit and apply a weaker blur
+                // instead.
+                let warning = PlanWarning::PassesClamped {
+                    required_passes: design.num_passes,
+                };
+                let design = KernelDesign::new(
+                    design.sigma1,
+                    self.max_passes,
+                    size,
+                    self.kernel_ratio,
+                    self.kernel_upsample,
+                );
+                (design, Some(warning))
+            }
+        }
+    }
+
+    /// Blur `cube_map` in place. Temporarily allocates two copies of it.
+    pub fn run<T>(&self, cube_map: &mut CubeMap<T>) -> BlurReport
+    where
+        T: BlurPixel,
+    {
+        let input = cube_map.clone();
+        let mut scratch = cube_map.clone();
+        self.run_into(&input, cube_map, &mut scratch)
+    }
+
+    /// Blur `input` into `out` without allocating the cube maps, using
+    /// `scratch` as the intermediate storage. This allows reusing the buffers
+    /// when blurring many cube maps. All three must have the same size.
+    pub fn run_into<T>(
+        &self,
+        input: &CubeMap<T>,
+        out: &mut CubeMap<T>,
+        scratch: &mut CubeMap<T>,
+    ) -> BlurReport
+    where
+        T: BlurPixel,
+    {
+        let size = input.size();
+        assert_eq!(out.size(), size);
+        assert_eq!(scratch.size(), size);
+        let (design, warning) = self.design_with_warning(size);
+        ltasg(
+            &mut out.faces_mut(),
+            &input.faces(),
+            &mut scratch.faces_mut(),
+            size,
+            &design.kernel,
+            design.kernel_scale,
+            design.num_passes,
+            self.cube_map_trait.clone(),
+        );
+        BlurReport {
+            passes: design.num_passes,
+            sigma1: design.sigma1,
+            effective_sigma: design.sigma(),
+            warning,
+        }
+    }
+
+    /// Update `prev_result` to the blur of `new_input`, only recomputing the
+    /// texels affected by the changes to the input. Temporarily allocates
+    /// two copies of the cube map.
+    ///
+    /// `prev_result` must be the output of `run` with the same parameters for
+    /// an input that differs from `new_input` only at the texels set in
+    /// `change_mask`. All three must have the same size. The output is
+    /// identical to that of `run` for `new_input`.
+    ///
+    /// The changed texels are enclosed in a spherical cap, and the phases are
+    /// run with `LtasgOptions::region`. Each phase reads up to `m =
+    /// kernel.len() / 2 * kernel_scale * √3` texels (rounded up) away along
+    /// each axis of the face (see `TiledBlur`). A texel spacing subtends at
+    /// most `2 / size` radians, so the halo conservatively widens the cap by
+    /// `2 * m + 2` texel spacings per phase. The last phase is recomputed in
+    /// the cap widened by the halos of all phases, and each earlier phase
+    /// further by the halos of the phases following it. A change spread over
+    /// the whole sphere thus falls back to the full blur.
+    pub fn run_warmstart<T>(
+        &self,
+        prev_result: &mut CubeMap<T>,
+        new_input: &CubeMap<T>,
+        change_mask: &CubeMap<bool>,
+    ) -> BlurReport
+    where
+        T: BlurPixel,
+    {
+        let size = new_input.size();
+        assert_eq!(prev_result.size(), size);
+        assert_eq!(change_mask.size(), size);
+        let (design, warning) = self.design_with_warning(size);
+        let report = BlurReport {
+            passes: design.num_passes,
+            sigma1: design.sigma1,
+            effective_sigma: design.sigma(),
+            warning,
+        };
+
+        // The texel centers used by `LtasgOptions::region`
+        let duv = if self.cube_map_trait.edge_stretch_fixup() {
+            2.0 / cmp::max(size - 1, 1) as f32
+        } else {
+            2.0 / size as f32
+        };
+        let uv = |i: usize| if self.cube_map_trait.edge_stretch_fixup() {
+            i as f32 * duv - 1.0
+        } else {
+            (i as f32 + 0.5) * duv - 1.0
+        };
+        let changed: Vec<Vector3<f32>> = change_mask
+            .iter_texels()
+            .filter(|&(_, _, _, &is_changed)| is_changed)
+            .map(|(face, x, y, _)| {
+                (face.normal::<f32>() + face.u_vec() * uv(x) + face.v_vec() * uv(y)).normalize()
+            })
+            .collect();
+        if changed.is_empty() {
+            return report;
+        }
+
+        let num_phases = design.num_passes * 3;
+        if num_phases == 0 {
+            prev_result.clone_from(new_input);
+            return report;
+        }
+
+        // The smallest cap around the changed texels is hard to find. Center
+        // it at their mean direction instead, which is good enough for a
+        // localized change.
+        let sum = changed.iter().fold(Vector3::new(0.0, 0.0, 0.0), |a, &b| a + b);
+        let (center_dir, cap_angle) = if sum.magnitude() < 1.0e-3 * changed.len() as f32 {
+            (Vector3::new(0.0, 0.0, 1.0), f32::consts::PI)
+        } else {
+            let center_dir = sum.normalize();
+            let min_cos = changed
+                .iter()
+                .fold(1.0f32, |a, dir| a.min(dir.dot(center_dir)));
+            // Absorb the rounding errors of `SphericalCap::contains`
+            (center_dir, min_cos.max(-1.0).acos() + 1.0e-3)
+        };
+
+        let reach = (design.kernel.len() / 2) as f32 * design.kernel_scale * 3.0f32.sqrt();
+        let halo = (2.0 * reach.ceil() + 2.0) * duv;
+
+        let mut src = new_input.clone();
+        let mut dst = new_input.clone();
+        for i in 0..num_phases {
+            let options = LtasgOptions {
+                region: Some(SphericalCap {
+                    center_dir,
+                    half_angle: cap_angle + (2 * num_phases - 1 - i) as f32 * halo,
+                }),
+                ..Default::default()
+            };
+            // The last phase leaves the texels outside the region of
+            // `prev_result` untouched
+            let out = if i + 1 == num_phases {
+                &mut *prev_result
+            } else {
+                &mut dst
+            };
+            let input = if i == 0 { new_input } else { &src };
+            ltasg_single_with_options(
+                &mut out.faces_mut(),
+                &input.faces(),
+                size,
+                &design.kernel,
+                design.kernel_scale,
+                i % 3,
+                self.cube_map_trait.clone(),
+                &options,
+            );
+            swap(&mut src, &mut dst);
+        }
+
+        report
+    }
+}
+
+/// Describes the blur performed by `BlurBuilder::run`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlurReport {
+    /// The number of passes (each consisting of three phases).
+    pub passes: usize,
+    /// The standard deviation of each pass, measured in the face size.
+    pub sigma1: f32,
+    /// The standard deviation of the blur actually applied, measured in the
+    /// face size. This is the requested one unless it was limited by
+    /// `BlurBuilder::passes_override` or `BlurBuilder::max_passes`.
+    pub effective_sigma: f32,
+    /// Indicates that the blur differs from the requested one.
+    pub warning: Option<PlanWarning>,
+}
+
+/// A problem found while designing the blur, reported by `BlurReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanWarning {
+    /// `sigma` required `required_passes` passes, which exceeds
+    /// `BlurBuilder::max_passes`. The blur was limited to `max_passes`
+    /// passes.
+    PassesClamped { required_passes: usize },
+}
+
+impl fmt::Display for PlanWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PlanWarning::PassesClamped { required_passes } => write!(
+                f,
+                "the blur requires {} passes, which exceeds the maximum (note that σ is \
+                 relative to the face size)",
+                required_passes
+            ),
+        }
+    }
+}
+
+/// Optional parameters for `ltasg_single_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct LtasgOptions {
+    /// Process the directional blur in `tile_size`×`tile_size` blocks instead
+    /// of whole rows.
+    ///
+    /// On faces whose blur axis runs along the V direction, accessing the
+    /// texels along the axis is strided by `size` elements, which thrashes
+    /// the cache for large faces. The row-major traversal avoids this by
+    /// transposing such faces into a temporary buffer (which costs an
+    /// additional `size * size` texels of memory), whereas visiting the face
+    /// in blocks keeps the working set (a block plus a halo of
+    /// `kernel_radius` texels) in the L2 cache without the buffer. The output
+    /// is identical to that of the row-major traversal. `None` (default)
+    /// selects the row-major traversal.
+    pub tile_size: Option<usize>,
+
+    /// Divide each output texel by the total weight applied to it.
+    ///
+    /// With `EdgeMode::Wrap` and `EdgeMode::Clamp`, every tap of the scaled
+    /// kernel fetches exactly one texel, so the applied weights add up to the
+    /// sum of `kernel`, and this option only corrects a `kernel` that is not
+    /// normalized or was normalized with a rounding error. With
+    /// `EdgeMode::Zero`, the taps falling outside the face are not counted,
+    /// so the kernel truncated at the face edge is renormalized and the
+    /// texels near the edge do not darken. Either way a constant environment
+    /// map stays exactly constant. The cost is an extra accumulation per tap
+    /// and a division per texel. Defaults to `false`.
+    pub renormalize: bool,
+
+    /// Only compute the output texels whose directions lie in the given
+    /// spherical cap, leaving the other output texels untouched.
+    ///
+    /// This is useful when only a part of the environment is visible (e.g.,
+    /// the upper hemisphere for a reflection probe on the floor). Note that
+    /// the texels inside the cap still read input texels outside it (and in
+    /// other faces) as needed. Consequently, when performing multiple passes,
+    /// the cap must be widened by the blur radius of the remaining passes so
+    /// that the texels near the boundary do not read stale values. `None`
+    /// (default) processes the whole sphere.
+    pub region: Option<SphericalCap>,
+}
+
+/// `ltasg_single` with additional options. See `LtasgOptions` for the
+/// available options.
+pub fn ltasg_single_with_options<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    options: &LtasgOptions,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+{
+    ltasg_single_with_sampler(
+        out_faces,
+        &SliceFaceSampler::new(in_faces, size),
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        options,
+    );
+}
+
+/// `ltasg_single_with_options` reading the input texels via a `FaceSampler`
+/// instead of in-memory slices.
+pub fn ltasg_single_with_sampler<T, Trait, S>(
+    out_faces: &mut [&mut [T]],
+    sampler: &S,
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    options: &LtasgOptions,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+    S: FaceSampler<T> + ?Sized,
+{
+    let tile_size = options.tile_size.unwrap_or(size).max(1);
+    let transpose = options.tile_size.is_none();
+    ltasg_single_core(
+        out_faces,
+        sampler,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        options,
+        [true; 6],
+        |face, out_face_img| for (y, out_row) in out_face_img.chunks_mut(size).enumerate() {
+            face.process_row(y, out_row);
+        },
+        |face, out_face_img| {
+            if !(face.transposed && transpose) {
+                for tile_y in (0..size).step_by(tile_size) {
+                    for tile_x in (0..size).step_by(tile_size) {
+                        for y in tile_y..cmp::min(tile_y + tile_size, size) {
+                            for x in tile_x..cmp::min(tile_x + tile_size, size) {
+                                let i = face.out_index(x, y);
+                                if !face.is_skipped(i) {
+                                    out_face_img[i] = face.texel(x, y);
+                                }
+                            }
+                        }
+                    }
+                }
+                return;
+            }
+
+            // The blur axis runs along the V direction. Reading the face
+            // along it directly would be strided by `size`, so read the
+            // kernel taps from a transposed copy of the input image instead
+            let input = face.input_image();
+            let input = unsafe { SliceAccessor::new(&input[..]) };
+            let fetch = |x: usize, y: usize| input[x + y * size];
+            for y in 0..size {
+                for x in 0..size {
+                    let i = face.out_index(x, y);
+                    if !face.is_skipped(i) {
+                        out_face_img[i] = face.texel_with_input(x, y, &fetch);
+                    }
+                }
+            }
+        },
+    );
+}
+
+/// `ltasg_single` evaluating `sampler(face, x, y)` for the input texels
+/// instead of reading them from in-memory slices, so procedurally generated
+/// environments can be blurred without materializing them first.
+///
+/// `sampler` is called once for every kernel tap, i.e., many times for each
+/// input texel, so it should be cheap.
+pub fn ltasg_single_from_fn<T, Trait, F>(
+    out_faces: &mut [&mut [T]],
+    sampler: F,
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+    F: Fn(CubeFace, usize, usize) -> T,
+{
+    ltasg_single_with_sampler(
+        out_faces,
+        &sampler,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        &LtasgOptions::default(),
+    );
+}
+
+/// `ltasg_single_with_options` processing the rows of each face in parallel
+/// using Rayon.
+///
+/// The output rows of both the directional blur (on four of the six faces in
+/// each phase) and the radial blur (on the other two) are independent of each
+/// other, so each row is processed as a separate task. The output is
+/// identical to that of `ltasg_single_with_options`. `options.tile_size` is
+/// ignored.
+#[cfg(feature = "rayon")]
+pub fn ltasg_single_par<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    options: &LtasgOptions,
+) where
+    T: BlurPixel + Send + Sync,
+    Trait: CubeMapTrait,
+{
+    use rayon::prelude::*;
+    ltasg_single_core(
+        out_faces,
+        &SliceFaceSampler::new(in_faces, size),
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        options,
+        [true; 6],
+        |face, out_face_img| {
+            out_face_img
+                .par_chunks_mut(size)
+                .enumerate()
+                .for_each(|(y, out_row)| face.process_row(y, out_row));
+        },
+        |face, out_face_img| {
+            if !face.transposed {
+                out_face_img.par_chunks_mut(size).enumerate().for_each(|(y, out_row)| {
+                    for (x, out_texel) in out_row.iter_mut().enumerate() {
+                        if !face.is_skipped(x + y * size) {
+                            *out_texel = face.texel(x, y);
+                        }
+                    }
+                });
+                return;
+            }
+
+            // The blur axis runs along the V direction. Read the input texels
+            // from a transposed copy so the kernel taps are contiguous (see
+            // `LtasgOptions::tile_size`)
+            let input = face.input_image();
+            let input = unsafe { SliceAccessor::new(&input[..]) };
+            let fetch = |x: usize, y: usize| input[x + y * size];
+            out_face_img
+                .par_chunks_mut(size)
+                .enumerate()
+                .for_each(|(row, out_row)| for (col, out_texel) in out_row.iter_mut().enumerate() {
+                    if !face.is_skipped(row * size + col) {
+                        *out_texel = face.texel_with_input(row, col, &fetch);
+                    }
+                })
+        },
+    );
+}
+
+/// `ltasg_single` storing the result in the input faces.
+///
+/// `ltasg_single` requires a separate set of six output faces. In a single
+/// phase, however, the four faces processed by the directional blur only read
+/// the texels on the same line (along the blur axis) of themselves and the two
+/// faces processed by the radial blur (whose normals are parallel to the blur
+/// axis). The radial blur reads all of the neighboring faces. This function
+/// takes advantage of this:
+///
+///  1. The radial blur is computed into temporary buffers.
+///  2. The directional blur is computed line by line, each line being copied
+///     to a line buffer before being overwritten.
+///  3. The temporary buffers are copied into the radial faces.
+///
+/// Thus, the additional memory required is `2 * size * size` texels for the
+/// radial faces (plus two lines) instead of `6 * size * size` texels for a
+/// whole set of output faces. The result is identical to that of
+/// `ltasg_single`.
+pub fn ltasg_single_in_place<T, Trait>(
+    faces: &mut [&mut [T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait + Clone,
+{
+    check_parameters(size, kernel, kernel_scale, phase, &cube_map_trait).unwrap();
+    let faces = &mut faces[0..6];
+    let axis = [CubeFace::PositiveX, CubeFace::PositiveY, CubeFace::PositiveZ][phase];
+    let is_radial = |i: usize| CubeFace::from_ordinal(i).unwrap().abs() == axis;
+    let mut is_radial_mask = [false; 6];
+    for (i, x) in is_radial_mask.iter_mut().enumerate() {
+        *x = is_radial(i);
+    }
+    let mut is_directional_mask = is_radial_mask;
+    for x in is_directional_mask.iter_mut() {
+        *x = !*x;
+    }
+    let options = LtasgOptions::default();
+
+    // Compute the radial blur
+    let mut radial_out: Vec<Vec<T>> = (0..6)
+        .map(|i| if is_radial(i) { vec![zero_texel(); size * size] } else { Vec::new() })
+        .collect();
+    ltasg_single_core(
+        &mut radial_out.iter_mut().map(|f| &mut f[..]).collect::<Vec<_>>(),
+        &SliceFaceSampler::new(&faces.iter().map(|f| &f[..]).collect::<Vec<_>>(), size),
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait.clone(),
+        &options,
+        is_radial_mask,
+        |face, out_face_img| for (y, out_row) in out_face_img.chunks_mut(size).enumerate() {
+            face.process_row(y, out_row);
+        },
+        |_, _| unreachable!(),
+    );
+
+    // Compute the directional blur. The radial faces are still intact here.
+    {
+        let (mut directional, mut radial): (Vec<_>, Vec<_>) = (Vec::new(), Vec::new());
+        for (i, face) in faces.iter_mut().enumerate() {
+            let face = &mut face[0..size * size];
+            if is_radial(i) {
+                directional.push(&mut [][..]);
+                radial.push(&face[..]);
+            } else {
+                directional.push(face);
+                radial.push(&[][..]);
+            }
+        }
+
+        let sampler = RadialFaceSampler { faces: radial, size };
+        let mut line = Vec::with_capacity(size);
+        let mut line_out = Vec::with_capacity(size);
+        ltasg_single_core(
+            &mut directional,
+            &sampler,
+            size,
+            kernel,
+            kernel_scale,
+            phase,
+            cube_map_trait,
+            &options,
+            is_directional_mask,
+            |_, _| unreachable!(),
+            |face, out_face_img| for y in 0..size {
+                line.clear();
+                line.extend((0..size).map(|x| out_face_img[face.out_index(x, y)]));
+                line_out.clear();
+                line_out.extend((0..size).map(|x| face.texel_with_input(x, y, |x, _| line[x])));
+                for (x, &texel) in line_out.iter().enumerate() {
+                    out_face_img[face.out_index(x, y)] = texel;
+                }
+            },
+        );
+    }
+
+    for (face, radial) in faces.iter_mut().zip(radial_out.iter()) {
+        if !radial.is_empty() {
+            face[0..size * size].copy_from_slice(radial);
+        }
+    }
+}
+
+/// A `FaceSampler` only providing the faces processed by the radial blur, used
+/// by `ltasg_single_in_place`. The other faces are empty.
+struct RadialFaceSampler<'a, T: 'a> {
+    faces: Vec<&'a [T]>,
+    size: usize,
+}
+
+impl<'a, T: Copy + 'a> FaceSampler<T> for RadialFaceSampler<'a, T> {
+    #[inline(always)]
+    fn sample(&self, face: CubeFace, x: usize, y: usize) -> T {
+        self.faces[face.as_ordinal()][x + y * self.size]
+    }
+}
+
+/// Statistics about the face boundary crossings of the blur, collected by
+/// `ltasg_single_with_stats` to help debugging seam artifacts.
+///
+/// The arrays are indexed by `CubeFace::as_ordinal`.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlurStats {
+    /// The number of output texels of each face whose kernel footprint
+    /// crossed the face boundary and read texels of the adjacent faces.
+    pub overflow_texels: [usize; 6],
+    /// The smallest and largest coordinates `((min_x, min_y), (max_x,
+    /// max_y))` of the texels of each face read while processing the other
+    /// faces, or `None` if no such texels were read.
+    pub overflow_sample_range: [Option<((usize, usize), (usize, usize))>; 6],
+    /// The largest distance from the face border (`min(x, y, size - 1 - x,
+    /// size - 1 - y)`) of the texels of each face read while processing the
+    /// other faces.
+    pub overflow_sample_depth: [usize; 6],
+}
+
+#[cfg(feature = "diagnostics")]
+impl BlurStats {
+    fn record_sample(&mut self, face: CubeFace, x: usize, y: usize, size: usize) {
+        let depth = cmp::min(cmp::min(x, y), cmp::min(size - 1 - x, size - 1 - y));
+        let max_depth = &mut self.overflow_sample_depth[face.as_ordinal()];
+        *max_depth = cmp::max(*max_depth, depth);
+
+        let range = &mut self.overflow_sample_range[face.as_ordinal()];
+        *range = Some(match *range {
+            Some(((min_x, min_y), (max_x, max_y))) => (
+                (cmp::min(min_x, x), cmp::min(min_y, y)),
+                (cmp::max(max_x, x), cmp::max(max_y, y)),
+            ),
+            None => ((x, y), (x, y)),
+        });
+    }
+}
+
+/// `ltasg_single` collecting the statistics about the face boundary
+/// crossings into `stats`. The statistics are added to the existing values,
+/// so calling this for every phase accumulates the statistics of a whole
+/// pass.
+///
+/// Only the reads of the adjacent faces are recorded, so nothing is recorded
+/// for `CubeMapTrait`s whose `edge_mode` is not `EdgeMode::Wrap`. This does
+/// not affect the performance of the other functions.
+#[cfg(feature = "diagnostics")]
+pub fn ltasg_single_with_stats<T, Trait>(
+    out_faces: &mut [&mut [T]],
+    in_faces: &[&[T]],
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    stats: &mut BlurStats,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+{
+    use std::cell::{Cell, RefCell};
+
+    /// A `FaceSampler` recording the reads of the faces other than the one
+    /// being processed.
+    struct RecordingSampler<'a, T: 'a> {
+        inner: SliceFaceSampler<'a, T>,
+        size: usize,
+        out_face: Cell<CubeFace>,
+        crossed: Cell<bool>,
+        stats: RefCell<&'a mut BlurStats>,
+    }
+
+    impl<'a, T: Copy + 'a> FaceSampler<T> for RecordingSampler<'a, T> {
+        fn sample(&self, face: CubeFace, x: usize, y: usize) -> T {
+            if face != self.out_face.get() {
+                self.crossed.set(true);
+                self.stats.borrow_mut().record_sample(face, x, y, self.size);
+            }
+            self.inner.sample(face, x, y)
+        }
+    }
+
+    impl<'a, T: 'a> RecordingSampler<'a, T> {
+        /// Compute an output texel of `out_face` by `f`, counting it if it
+        /// read the other faces.
+        fn texel<F: FnOnce() -> T>(&self, out_face: CubeFace, f: F) -> T {
+            self.out_face.set(out_face);
+            self.crossed.set(false);
+            let texel = f();
+            if self.crossed.get() {
+                self.stats.borrow_mut().overflow_texels[out_face.as_ordinal()] += 1;
+            }
+            texel
+        }
+    }
+
+    let sampler = RecordingSampler {
+        inner: SliceFaceSampler::new(in_faces, size),
+        size,
+        out_face: Cell::new(CubeFace::PositiveX),
+        crossed: Cell::new(false),
+        stats: RefCell::new(stats),
+    };
+    ltasg_single_core(
+        out_faces,
+        &sampler,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        &LtasgOptions::default(),
+        [true; 6],
+        |face, out_face_img| for (i, out_texel) in out_face_img.iter_mut().enumerate() {
+            *out_texel = sampler.texel(face.out_face, || face.texel(i % size, i / size));
+        },
+        |face, out_face_img| for y in 0..size {
+            for x in 0..size {
+                out_face_img[face.out_index(x, y)] =
+                    sampler.texel(face.out_face, || face.texel(x, y));
+            }
+        },
+    );
+}
+
+/// Performs a single phase of `ltasg_single` in horizontal bands of output
+/// rows, for cube maps too large to hold in memory.
+///
+/// The input texels are read via a `FaceSampler`, which may fetch them from
+/// a memory-mapped file or a tile cache. Let `m` be `kernel.len() / 2 *
+/// kernel_scale * √3` rounded up, the farthest a kernel tap reaches. The band
+/// of output rows `y0..y1` of a face reads the rows `y0..y1` of the face
+/// itself if the face is blurred along its rows, and the rows `y0 - m..y1 +
+/// m` (clamped to the face) otherwise, i.e., for the radial blur and the
+/// directional blur along the columns. Besides, it only reads the strips of
+/// `m` texels along the borders of the adjacent faces. A cache holding that
+/// window of rows and those strips suffices. The output texels are passed to
+/// a callback one band at a time, so only a single band of `band_height *
+/// size` texels is resident at once.
+///
+/// The output is identical to that of `ltasg_single_with_options` (with
+/// `renormalize` set as specified). To perform multiple phases, store the
+/// output of each phase where the sampler of the next one reads from.
+#[derive(Debug, Clone)]
+pub struct TiledBlur<'a, Trait = StandardCubeMapTrait> {
+    size: usize,
+    kernel: &'a [f32],
+    kernel_scale: f32,
+    cube_map_trait: Trait,
+    band_height: usize,
+    renormalize: bool,
+}
+
+impl<'a> TiledBlur<'a, StandardCubeMapTrait> {
+    /// Construct a `TiledBlur` with the parameters of `ltasg_single`, bands
+    /// of 64 rows, and `StandardCubeMapTrait`.
+    pub fn new(size: usize, kernel: &'a [f32], kernel_scale: f32) -> Self {
+        Self {
+            size,
+            kernel,
+            kernel_scale,
+            cube_map_trait: StandardCubeMapTrait,
+            band_height: 64,
+            renormalize: false,
+        }
+    }
+}
+
+impl<'a, Trait: CubeMapTrait + Clone> TiledBlur<'a, Trait> {
+    /// Set the `CubeMapTrait` describing the cube map.
+    pub fn cube_map_trait<U: CubeMapTrait + Clone>(self, cube_map_trait: U) -> TiledBlur<'a, U> {
+        TiledBlur {
+            size: self.size,
+            kernel: self.kernel,
+            kernel_scale: self.kernel_scale,
+            cube_map_trait,
+            band_height: self.band_height,
+            renormalize: self.renormalize,
+        }
+    }
+
+    /// Set the number of output rows passed to the callback at once. The
+    /// last band of each face may be shorter.
+    pub fn band_height(self, band_height: usize) -> Self {
+        assert!(band_height > 0);
+        Self {
+            band_height,
+            ..self
+        }
+    }
+
+    /// Set `LtasgOptions::renormalize`.
+    pub fn renormalize(self, renormalize: bool) -> Self {
+        Self {
+            renormalize,
+            ..self
+        }
+    }
+
+    /// Perform the phase `phase`, reading the input texels from `sampler`.
+    ///
+    /// `store(face, y, band)` is called for every band, where `band`
+    /// contains the output rows starting from `y` of `face`. The bands are
+    /// produced in order, from top to bottom of each face in the order
+    /// defined by `CUBE_FACES`.
+    pub fn run_phase<T, S, F>(&self, phase: usize, sampler: &S, mut store: F)
+    where
+        T: BlurPixel,
+        S: FaceSampler<T> + ?Sized,
+        F: FnMut(CubeFace, usize, &[T]),
+    {
+        let size = self.size;
+        let options = LtasgOptions {
+            renormalize: self.renormalize,
+            ..Default::default()
+        };
+        let mut band = Vec::with_capacity(cmp::min(self.band_height, size) * size);
+        for_each_phase_face(
+            sampler,
+            size,
+            self.kernel,
+            self.kernel_scale,
+            phase,
+            self.cube_map_trait.clone(),
+            &options,
+            |_| true,
+            |face| for band_y in (0..size).step_by(self.band_height) {
+                let band_end = cmp::min(band_y + self.band_height, size);
+                band.clear();
+                band.resize((band_end - band_y) * size, zero_texel());
+                let out_face = match face {
+                    PhaseFace::Radial(ref face) => {
+                        for (y, out_row) in (band_y..band_end).zip(band.chunks_mut(size)) {
+                            face.process_row(y, out_row);
+                        }
+                        face.out_face
+                    }
+                    PhaseFace::Directional(ref face) => {
+                        for (row, out_row) in (band_y..band_end).zip(band.chunks_mut(size)) {
+                            for (col, out_texel) in out_row.iter_mut().enumerate() {
+                                let (x, y) = if face.transposed { (row, col) } else { (col, row) };
+                                *out_texel = face.texel(x, y);
+                            }
+                        }
+                        face.out_face
+                    }
+                };
+                store(out_face, band_y, &band);
+            },
+        );
+    }
+}
+
+/// The state for computing the directional blur of a single face.
+///
+/// `x` and `y` are the texel coordinates in the frame where the blur axis
+/// runs along the X axis.
+struct DirectionalFace<'a, S: ?Sized + 'a> {
+    sampler: &'a S,
+    size: usize,
+    kernel: &'a [f32],
+    kernel_scale: f32,
+    uv_table: &'a [f32],
+    brd_min: f32,
+    brd_max: f32,
+    renormalize: bool,
+    edge_mode: EdgeMode,
+    out_face: CubeFace,
+    pos_axis_face: CubeFace,
+    neg_axis_face: CubeFace,
+    pos_axis_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    neg_axis_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    /// The blur axis runs along the V direction of the face.
+    transposed: bool,
+    skip_mask: Option<Vec<bool>>,
+}
+
+impl<'a, S: ?Sized + 'a> DirectionalFace<'a, S> {
+    /// Compute the index of the output texel.
+    #[inline]
+    fn out_index(&self, x: usize, y: usize) -> usize {
+        if self.transposed {
+            x * self.size + y
+        } else {
+            x + y * self.size
+        }
+    }
+
+    /// Check whether the output texel at the index `i` is excluded by
+    /// `LtasgOptions::region`.
+    #[inline]
+    fn is_skipped(&self, i: usize) -> bool {
+        self.skip_mask.as_ref().map(|mask| mask[i]).unwrap_or(false)
+    }
+
+    /// Compute the output texel, reading the input texels of `out_face` via
+    /// the sampler.
+    #[inline]
+    fn texel<T>(&self, x: usize, y: usize) -> T
+    where
+        T: BlurPixel,
+        S: FaceSampler<T>,
+    {
+        let (sampler, out_face) = (self.sampler, self.out_face);
+        if self.transposed {
+            self.texel_with_input(x, y, |x, y| sampler.sample(out_face, y, x))
+        } else {
+            self.texel_with_input(x, y, |x, y| sampler.sample(out_face, x, y))
+        }
+    }
+
+    /// Copy the input texels of `out_face` in the frame where the blur axis
+    /// runs along the X axis (i.e., transposed if `transposed` is set) so
+    /// they can be read sequentially by `texel_with_input`.
+    fn input_image<T>(&self) -> Vec<T>
+    where
+        S: FaceSampler<T>,
+    {
+        let size = self.size;
+        (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size, i / size);
+                if self.transposed {
+                    self.sampler.sample(self.out_face, y, x)
+                } else {
+                    self.sampler.sample(self.out_face, x, y)
+                }
+            })
+            .collect()
+    }
+
+    /// Compute the output texel. The input texels of `out_face` are fetched
+    /// by `fetch(x, y)` in the frame where the blur axis runs along the X
+    /// axis.
+    #[inline]
+    fn texel_with_input<T, F>(&self, x: usize, y: usize, fetch: F) -> T
+    where
+        T: BlurPixel,
+        S: FaceSampler<T>,
+        F: Fn(usize, usize) -> T,
+    {
+        let DirectionalFace {
+            sampler,
+            kernel,
+            kernel_scale,
+            uv_table,
+            brd_min,
+            brd_max,
+            pos_axis_face,
+            neg_axis_face,
+            pos_axis_idx,
+            neg_axis_idx,
+            ..
+        } = *self;
+        let kernel_radius = kernel.len() / 2;
+
+        let cur_u = uv_table[x];
+        let cur_v = uv_table[y];
+        let local_scale = kernel_scale * (1.0 + cur_u * cur_u + cur_v * cur_v).sqrt();
+        let mut sum: T::Accumulator = num_traits::Zero::zero();
+        let mut weight_sum = 0.0f32;
+
+        // 48.16 fixed point values
+        let mut in_coord_x_f = (x as i64) << 16;
+        let in_coord_x_df = (local_scale * 65536.0) as i64;
+        let brd_min = (brd_min * 65536.0) as i64;
+        let brd_max = (brd_max * 65536.0) as i64;
+        let cur_v = (cur_v * 65536.0) as i64;
+
+        in_coord_x_f -= in_coord_x_df * kernel_radius as i64;
+
+        macro_rules! step_overflow {
+            ($face:expr, $idx:expr, $dist:expr, $edge_x:expr) => (match self.edge_mode {
+                EdgeMode::Wrap => {
+                    // Toward the center
+                    let (base_idx, main_offs, cross_offs) = $idx;
+                    let overflow_main_df = $dist * -cur_v; // 32.32
+                    let overflow_main =
+                        y as isize + ((overflow_main_df + (32768 << 16)) >> 32) as isize;
+                    let overflow_cross = round_xp2i($dist) as isize;
+                    let p = base_idx + main_offs * overflow_main + cross_offs * overflow_cross;
+                    sampler.sample($face, p.x as usize, p.y as usize)
+                }
+                EdgeMode::Clamp => fetch($edge_x, y),
+                EdgeMode::Zero => zero_texel(),
+            })
+        }
+        macro_rules! step_normal {
+            () => ({
+                let in_coord_x = round_xp2i(in_coord_x_f) as usize;
+                fetch(in_coord_x, y)
+            })
+        }
+
+        // `in_coord_x_f` monotonically increases, so...
+        let mut it = kernel.iter();
+        loop {
+            if in_coord_x_f > brd_min {
+                break;
+            }
+            if let Some(weight) = it.next() {
+                sum = sum +
+                    step_overflow!(
+                        neg_axis_face,
+                        neg_axis_idx,
+                        brd_min - in_coord_x_f,
+                        0
+                    ).to_accumulator() * *weight;
+                if self.edge_mode != EdgeMode::Zero {
+                    weight_sum += *weight;
+                }
+            } else {
+                break;
+            }
+            in_coord_x_f += in_coord_x_df;
+        }
+        loop {
+            if in_coord_x_f >= brd_max {
+                break;
+            }
+            if let Some(weight) = it.next() {
+                sum = sum + step_normal!().to_accumulator() * *weight;
+                weight_sum += *weight;
+            } else {
+                break;
+            }
+            in_coord_x_f += in_coord_x_df;
+        }
+        while let Some(weight) = it.next() {
+            sum = sum +
+                step_overflow!(
+                    pos_axis_face,
+                    pos_axis_idx,
+                    in_coord_x_f - brd_max,
+                    self.size - 1
+                ).to_accumulator() * *weight;
+            if self.edge_mode != EdgeMode::Zero {
+                weight_sum += *weight;
+            }
+            in_coord_x_f += in_coord_x_df;
+        }
+
+        T::from_accumulator(if self.renormalize {
+            sum * (1.0 / weight_sum)
+        } else {
+            sum
+        })
+    }
+}
+
+/// The state for computing the radial blur of a single face.
+struct RadialFace<'a, S: ?Sized + 'a> {
+    sampler: &'a S,
+    size: usize,
+    kernel: &'a [f32],
+    kernel_scale: f32,
+    uv_table: &'a [f32],
+    brd_min: f32,
+    brd_max: f32,
+    renormalize: bool,
+    edge_mode: EdgeMode,
+    out_face: CubeFace,
+    pos_u_face: CubeFace,
+    neg_u_face: CubeFace,
+    pos_v_face: CubeFace,
+    neg_v_face: CubeFace,
+    pos_u_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    neg_u_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    pos_v_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    neg_v_idx: (Vector2<isize>, Vector2<isize>, Vector2<isize>),
+    skip_mask: Option<Vec<bool>>,
+}
+
+impl<'a, S: ?Sized + 'a> RadialFace<'a, S> {
+    /// Check whether the output texel at the index `i` is excluded by
+    /// `LtasgOptions::region`.
+    #[inline]
+    fn is_skipped(&self, i: usize) -> bool {
+        self.skip_mask.as_ref().map(|mask| mask[i]).unwrap_or(false)
+    }
+
+    /// Compute the (non-skipped) output texels in the row `y`.
+    #[inline]
+    fn process_row<T>(&self, y: usize, out_row: &mut [T])
+    where
+        T: BlurPixel,
+        S: FaceSampler<T>,
+    {
+        for (x, out_texel) in out_row.iter_mut().enumerate() {
+            if !self.is_skipped(x + y * self.size) {
+                *out_texel = self.texel(x, y);
+            }
+        }
+    }
+
+    /// Compute the output texel at `(x, y)`.
+    fn texel<T>(&self, x: usize, y: usize) -> T
+    where
+        T: BlurPixel,
+        S: FaceSampler<T>,
+    {
+        let RadialFace {
+            sampler,
+            size,
+            kernel,
+            kernel_scale,
+            brd_min,
+            brd_max,
+            edge_mode,
+            out_face,
+            pos_u_face,
+            neg_u_face,
+            pos_v_face,
+            neg_v_face,
+            pos_u_idx,
+            neg_u_idx,
+            pos_v_idx,
+            neg_v_idx,
+            ..
+        } = *self;
+        let kernel_radius = kernel.len() / 2;
+        let cur_u = self.uv_table[x];
+        let cur_v = self.uv_table[y];
+
+        let local_scale = kernel_scale * (1.0 + cur_u * cur_u + cur_v * cur_v).sqrt();
+        let mut sum: T::Accumulator = num_traits::Zero::zero();
+        let mut weight_sum = 0.0f32;
+
+        let mut in_coord_f = Vector2::new(x, y).cast::<f32>();
+        let mut in_coord_df = Vector2::new(cur_u, cur_v) * local_scale;
+        in_coord_f -= in_coord_df * kernel_radius as f32;
+
+        // Swap the coordinates if Y is the major axis
+        let major_y = if cur_v.abs() > cur_u.abs() {
+            swap(&mut in_coord_f.x, &mut in_coord_f.y);
+            swap(&mut in_coord_df.x, &mut in_coord_df.y);
+            true
+        } else {
+            false
+        };
+
+        // The footprint possibly crosses the boundary?
+        // (We must be conservative due to FP rounding)
+        let in_coord_end_f = in_coord_f + in_coord_df * kernel.len() as f32;
+
+        if in_coord_end_f.x <= brd_min || in_coord_end_f.x >= brd_max {
+            let (overflow_face, (base_idx, main_offs, cross_offs)) = if major_y {
+                if in_coord_df.x >= 0.0 {
+                    (pos_v_face, pos_v_idx)
+                } else {
+                    (neg_v_face, neg_v_idx)
+                }
+            } else {
+                if in_coord_df.x >= 0.0 {
+                    (pos_u_face, pos_u_idx)
+                } else {
+                    (neg_u_face, neg_u_idx)
+                }
+            };
+
+            // Where does it possibly cross the boundary?
+            let minor_pos = if in_coord_df.x >= 0.0 {
+                (brd_max - in_coord_f.x) / in_coord_df.x
+            } else {
+                (brd_min - in_coord_f.x) / in_coord_df.x
+            } * in_coord_df.y +
+                in_coord_f.y;
+
+            // Near the corners, the crossing point may lie on (or
+            // beyond) the perpendicular edge. Clamping the texel
+            // index (rather than nudging `minor_pos` by a fixed
+            // epsilon, which vanishes in the FP rounding of large
+            // coordinates) keeps the index in bounds for every
+            // `size`.
+            let minor_idx = cmp::max(
+                0,
+                cmp::min(size as isize - 1, roundf32(minor_pos) as isize),
+            );
+
+            let (overflow_start, overflow_offs) =
+                (base_idx + main_offs * minor_idx, cross_offs);
+
+            // Convert to 48.16 fixed point
+            let mut in_coord_f = (in_coord_f * 65536.0).cast::<i64>();
+            let mut in_coord_df = (in_coord_df * 65536.0).cast::<i64>();
+            let brd_min = (brd_min * 65536.0) as i64;
+            let brd_max = (brd_max * 65536.0) as i64;
+
+            macro_rules! step_overflow {
+                ($dist:expr) => (match edge_mode {
+                    EdgeMode::Wrap => {
+                        let distance_i = round_xp2i($dist - brd_min) as isize;
+                        let p = overflow_start + overflow_offs * distance_i;
+                        sampler.sample(overflow_face, p.x as usize, p.y as usize)
                     }
+                    EdgeMode::Clamp => {
+                        let clamp = |x: i64| {
+                            cmp::max(0, cmp::min(size as i64 - 1, round_xp2i(x)))
+                                as usize
+                        };
+                        let (in_coord_x, in_coord_y) =
+                            (clamp(in_coord_f.x), clamp(in_coord_f.y));
+                        if major_y {
+                            sampler.sample(out_face, in_coord_y, in_coord_x)
+                        } else {
+                            sampler.sample(out_face, in_coord_x, in_coord_y)
+                        }
+                    }
+                    EdgeMode::Zero => zero_texel(),
+                })
+            }
+            macro_rules! step_normal {
+                () => ({
+                    let in_coord_x = round_xp2i(in_coord_f.x) as usize;
+                    let in_coord_y = round_xp2i(in_coord_f.y) as usize;
+                    if major_y {
+                        sampler.sample(out_face, in_coord_y, in_coord_x)
+                    } else {
+                        sampler.sample(out_face, in_coord_x, in_coord_y)
+                    }
+                })
+            }
+
+            // `in_coord_df.x` is monotonically increasing/decreasing, so...
+            let mut it = kernel.iter();
+
+            loop {
+                if in_coord_df.x >= 0 {
+                    if in_coord_f.x >= brd_max {
+                        break;
+                    }
+                } else {
+                    if in_coord_f.x <= brd_min {
+                        break;
+                    }
+                }
+                if let Some(weight) = it.next() {
+                    sum = sum + step_normal!().to_accumulator() * *weight;
+                    weight_sum += *weight;
+                    in_coord_f += in_coord_df;
+                } else {
+                    break;
+                }
+            }
+            while let Some(weight) = it.next() {
+                let distance = if in_coord_df.x >= 0 {
+                    in_coord_f.x - brd_max
+                } else {
+                    brd_min - in_coord_f.x
+                };
+                debug_assert!(distance >= 0);
+
+                sum = sum + step_overflow!(distance).to_accumulator() * *weight;
+                if edge_mode != EdgeMode::Zero {
+                    weight_sum += *weight;
+                }
+                in_coord_f += in_coord_df;
+            }
+        } else {
+            // Convert to 48.16 fixed point
+            let mut in_coord_f = (in_coord_f * 65536.0).cast::<i64>();
+            let mut in_coord_df = (in_coord_df * 65536.0).cast::<i64>();
+
+            for weight in kernel.iter() {
+                let in_coord_x = round_xp2i(in_coord_f.x) as usize;
+                let in_coord_y = round_xp2i(in_coord_f.y) as usize;
+                let texel = if major_y {
+                    sampler.sample(out_face, in_coord_y, in_coord_x)
+                } else {
+                    sampler.sample(out_face, in_coord_x, in_coord_y)
+                };
+
+                sum = sum + texel.to_accumulator() * *weight;
+                weight_sum += *weight;
+
+                in_coord_f += in_coord_df;
+            }
+        }
+
+        T::from_accumulator(if self.renormalize {
+            sum * (1.0 / weight_sum)
+        } else {
+            sum
+        })
+    }
+}
+
+/// The common part of `ltasg_single_with_sampler`, `ltasg_single_par`, and
+/// `ltasg_single_in_place`. `process_radial` and `process_directional`
+/// compute every (non-skipped) output texel of a face processed by the radial
+/// and directional blur, respectively. The faces for which `is_needed` is
+/// `false` are not processed at all, and their output slices may be empty.
+fn ltasg_single_core<T, Trait, S, R, D>(
+    out_faces: &mut [&mut [T]],
+    sampler: &S,
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    options: &LtasgOptions,
+    is_needed: [bool; 6],
+    mut process_radial: R,
+    mut process_directional: D,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+    S: FaceSampler<T> + ?Sized,
+    R: FnMut(&RadialFace<S>, &mut [T]),
+    D: FnMut(&DirectionalFace<S>, &mut [T]),
+{
+    let out_faces = &mut out_faces[0..6];
+    for_each_phase_face(
+        sampler,
+        size,
+        kernel,
+        kernel_scale,
+        phase,
+        cube_map_trait,
+        options,
+        |out_face| is_needed[out_face.as_ordinal()],
+        |face| match face {
+            PhaseFace::Radial(face) => {
+                let out_face_img = &mut out_faces[face.out_face.as_ordinal()];
+                process_radial(&face, &mut out_face_img[0..size * size]);
+            }
+            PhaseFace::Directional(face) => {
+                let out_face_img = &mut out_faces[face.out_face.as_ordinal()];
+                process_directional(&face, &mut out_face_img[0..size * size]);
+            }
+        },
+    );
+}
+
+/// A face processed in a single phase.
+enum PhaseFace<'a, S: ?Sized + 'a> {
+    Radial(RadialFace<'a, S>),
+    Directional(DirectionalFace<'a, S>),
+}
+
+/// Construct the state for computing each face in a single phase, and pass
+/// it to `process`. The faces for which `is_needed` returns `false` are
+/// skipped.
+fn for_each_phase_face<T, Trait, S, N, P>(
+    sampler: &S,
+    size: usize,
+    kernel: &[f32],
+    kernel_scale: f32,
+    phase: usize,
+    cube_map_trait: Trait,
+    options: &LtasgOptions,
+    mut is_needed: N,
+    mut process: P,
+) where
+    T: BlurPixel,
+    Trait: CubeMapTrait,
+    S: FaceSampler<T> + ?Sized,
+    N: FnMut(CubeFace) -> bool,
+    P: FnMut(PhaseFace<S>),
+{
+    check_parameters(size, kernel, kernel_scale, phase, &cube_map_trait).unwrap();
+    let edge_mode = cube_map_trait.edge_mode();
+
+    let (corner_uv, duv_dxy, brd_min, brd_max) = if cube_map_trait.edge_stretch_fixup() {
+        (-1.0, 2.0 / (size - 1) as f32, 0.0, size as f32 - 1.0)
+    } else {
+        (
+            1.0 / size as f32 - 1.0,
+            2.0 / size as f32,
+            -0.5,
+            size as f32 - 0.5,
+        )
+    };
+
+    // The UV coordinates of the texel centers. They are accumulated the same
+    // way regardless of the traversal order so the tiled and row-major
+    // traversals produce bit-identical results.
+    let uv_table: Vec<f32> = (0..size)
+        .scan(corner_uv, |uv, _| {
+            let cur = *uv;
+            *uv += duv_dxy;
+            Some(cur)
+        })
+        .collect();
+
+    let axis = match phase {
+        0 => CubeFace::PositiveX,
+        1 => CubeFace::PositiveY,
+        2 => CubeFace::PositiveZ,
+        _ => unreachable!(),
+    };
+
+    for &out_face in CUBE_FACES.iter() {
+        if !is_needed(out_face) {
+            continue;
+        }
+
+        // Which output texels should be skipped?
+        let skip_mask: Option<Vec<bool>> = options.region.map(|region| {
+            let (n, u, v) = (out_face.normal(), out_face.u_vec(), out_face.v_vec());
+            (0..size * size)
+                .map(|i| !region.contains(n + u * uv_table[i % size] + v * uv_table[i / size]))
+                .collect()
+        });
+
+        if out_face.abs() == axis {
+            // Radial blur
+            // Compute the indices, etc. for the adjacent faces
+            let pos_u_face = out_face.u_face();
+            let neg_u_face = -pos_u_face;
+            let pos_v_face = out_face.v_face();
+            let neg_v_face = -pos_v_face;
+
+            let face = RadialFace {
+                sampler,
+                size,
+                kernel,
+                kernel_scale,
+                uv_table: &uv_table,
+                brd_min,
+                brd_max,
+                renormalize: options.renormalize,
+                edge_mode,
+                out_face,
+                pos_u_face,
+                neg_u_face,
+                pos_v_face,
+                neg_v_face,
+                pos_u_idx: map_edge_coords(pos_u_face, out_face, size),
+                neg_u_idx: map_edge_coords(neg_u_face, out_face, size),
+                pos_v_idx: map_edge_coords(pos_v_face, out_face, size),
+                neg_v_idx: map_edge_coords(neg_v_face, out_face, size),
+                skip_mask,
+            };
+            process(PhaseFace::Radial(face));
+        } else {
+            // Directional blur
+            let (pos_axis_face, neg_axis_face) = if out_face.u_face().abs() == axis {
+                (out_face.u_face(), -out_face.u_face())
+            } else {
+                (out_face.v_face(), -out_face.v_face())
+            };
+
+            let face = DirectionalFace {
+                sampler,
+                size,
+                kernel,
+                kernel_scale,
+                uv_table: &uv_table,
+                brd_min,
+                brd_max,
+                renormalize: options.renormalize,
+                edge_mode,
+                out_face,
+                pos_axis_face,
+                neg_axis_face,
+                pos_axis_idx: map_edge_coords(pos_axis_face, out_face, size),
+                neg_axis_idx: map_edge_coords(neg_axis_face, out_face, size),
+                transposed: out_face.v_face().abs() == axis,
+                skip_mask,
+            };
+            process(PhaseFace::Directional(face));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernels_are_normalized() {
+        for radius in 0..10 {
+            for kernel in [box_kernel(radius), tent_kernel(radius), gaussian_kernel(radius, 2.0)]
+                .iter()
+            {
+                assert_eq!(kernel.len(), radius * 2 + 1);
+                let sum: f32 = kernel.iter().sum();
+                assert!((sum - 1.0).abs() < 1.0e-5, "{:?}", kernel);
+            }
+
+            let tent = tent_kernel(radius);
+            for i in 0..radius {
+                assert!(tent[i] < tent[i + 1], "{:?}", tent);
+                assert_eq!(tent[i], tent[radius * 2 - i]);
+            }
+        }
+    }
+
+    #[test]
+    fn gaussian_kernel_fast_matches_exact() {
+        for &(radius, sigma) in [(0, 1.0), (3, 0.5), (8, 4.0), (16, 2.0), (40, 20.0)].iter() {
+            let exact = gaussian_kernel(radius, sigma);
+            let fast = gaussian_kernel_fast(radius, sigma);
+            assert_eq!(fast.len(), exact.len());
+            for (&x, &y) in fast.iter().zip(exact.iter()) {
+                assert!((x / y - 1.0).abs() < 1.0e-3, "{:?} != {:?}", fast, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn kernel_design() {
+        let (kernel_ratio, kernel_upsample) = (2.0, 3.0);
+        for &size in [8, 16, 32, 64, 128, 256].iter() {
+            for &target_sigma in [0.0, 0.001, 0.01, 0.05, 0.1, 0.25, 0.3, 1.0].iter() {
+                let design =
+                    KernelDesign::for_sigma(target_sigma, size, kernel_ratio, kernel_upsample);
+                let desc = format!("size = {}, σ = {}, {:?}", size, target_sigma, design);
+
+                assert!(design.sigma1 <= 0.5 / kernel_ratio, "{}", desc);
+                assert!(
+                    design.sigma1 <= KernelDesign::max_sigma1(size, kernel_ratio, kernel_upsample),
+                    "{}",
+                    desc
+                );
+                assert!((design.sigma() - target_sigma).abs() < 1.0e-4, "{}", desc);
+                for phase in 0..3 {
+                    check_parameters(
+                        size,
+                        &design.kernel,
+                        design.kernel_scale,
+                        phase,
+                        &StandardCubeMapTrait,
+                    ).expect(&desc);
+                }
+
+                // The kernel's standard deviation measured in texels. The
+                // truncation at `2σ` reduces it to about `0.88σ`.
+                let radius = (design.kernel.len() / 2) as isize;
+                let variance: f32 = design
+                    .kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &w)| {
+                        w * ((i as isize - radius) as f32 * design.kernel_scale).powi(2)
+                    })
+                    .sum();
+                let expected = design.sigma1 * size as f32;
+                if expected > 2.0 {
+                    let ratio = variance.sqrt() / expected;
+                    assert!(ratio > 0.85 && ratio < 1.01, "{}: {}", desc, ratio);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edge_modes() {
+        #[derive(Clone)]
+        struct Mode(EdgeMode);
+        impl CubeMapTrait for Mode {
+            fn edge_stretch_fixup(&self) -> bool {
+                false
+            }
+            fn edge_mode(&self) -> EdgeMode {
+                self.0
+            }
+        }
+
+        // A single bright texel on the edge of `PositiveX` adjoining
+        // `PositiveZ`. In phase 0, `PositiveX` is blurred radially and
+        // `PositiveZ` directionally toward `PositiveX`.
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let bright = 8 * size;
+        let mut src = vec![vec![0f32; size * size]; 6];
+        src[CubeFace::PositiveX as usize][bright] = 1.0;
+
+        let run = |mode| {
+            let mut out = vec![vec![0f32; size * size]; 6];
+            ltasg_single(
+                &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &src.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                size,
+                &kernel,
+                0.5,
+                0,
+                Mode(mode),
+            );
+            out
+        };
+        let wrap = run(EdgeMode::Wrap);
+        let clamp = run(EdgeMode::Clamp);
+        let zero = run(EdgeMode::Zero);
+
+        let leaked = |out: &Vec<Vec<f32>>| {
+            out[CubeFace::PositiveZ as usize].iter().any(|&x| x != 0.0)
+        };
+        assert!(leaked(&wrap));
+        assert!(!leaked(&clamp));
+        assert!(!leaked(&zero));
+
+        let edge = |out: &Vec<Vec<f32>>| out[CubeFace::PositiveX as usize][bright];
+        assert!(edge(&zero) > 0.0);
+        assert_eq!(edge(&zero), edge(&wrap));
+        assert!(edge(&clamp) > edge(&zero) * 1.5, "{} {}", edge(&clamp), edge(&zero));
+
+        // `Clamp` keeps a constant image constant
+        let src = vec![vec![1f32; size * size]; 6];
+        let mut out = vec![vec![0f32; size * size]; 6];
+        for phase in 0..3 {
+            ltasg_single(
+                &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &src.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                size,
+                &kernel,
+                0.5,
+                phase,
+                Mode(EdgeMode::Clamp),
+            );
+            for &x in out.iter().flat_map(|face| face.iter()) {
+                assert!((x - 1.0).abs() < 1.0e-4, "{}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn in_place_matches_two_buffer() {
+        let kernel = gaussian_kernel(4, 2.0);
+        for &size in [15, 16].iter() {
+            let src = test_pattern(size);
+            for phase in 0..3 {
+                let mut expected = vec![vec![0f32; size * size]; 6];
+                ltasg_single(
+                    &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+
+                let mut actual = src.clone();
+                ltasg_single_in_place(
+                    &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                assert_eq!(actual, expected, "size = {}, phase = {}", size, phase);
+            }
+        }
+    }
+
+    #[test]
+    fn large_coordinates() {
+        // Allocating a cube map of this size is impractical, so evaluate a
+        // few texels of a directional face reading from a procedural ramp
+        struct Ramp;
+        impl FaceSampler<f32> for Ramp {
+            fn sample(&self, _: CubeFace, x: usize, _: usize) -> f32 {
+                x as f32
+            }
+        }
+
+        let size = 40000;
+        let kernel = [0.25, 0.5, 0.25];
+        assert_eq!(
+            check_parameters(size, &kernel, 1.0, 0, &StandardCubeMapTrait),
+            Ok(())
+        );
+
+        let uv_table: Vec<f32> = (0..size)
+            .map(|i| (i as f32 * 2.0 + 1.0) / size as f32 - 1.0)
+            .collect();
+        let (out_face, pos_axis_face) = (CubeFace::PositiveZ, CubeFace::PositiveX);
+        let face = DirectionalFace {
+            sampler: &Ramp,
+            size,
+            kernel: &kernel,
+            kernel_scale: 1.0,
+            uv_table: &uv_table,
+            brd_min: -0.5,
+            brd_max: size as f32 - 0.5,
+            renormalize: false,
+            edge_mode: EdgeMode::Wrap,
+            out_face,
+            pos_axis_face,
+            neg_axis_face: -pos_axis_face,
+            pos_axis_idx: map_edge_coords(pos_axis_face, out_face, size),
+            neg_axis_idx: map_edge_coords(-pos_axis_face, out_face, size),
+            transposed: false,
+            skip_mask: None,
+        };
+        for &x in [100, 20000, 32767, 32768, 39000].iter() {
+            let texel: f32 = face.texel(x, size / 2);
+            assert_eq!(texel, x as f32);
+        }
+    }
+
+    #[test]
+    fn doesnt_panic() {
+        let kernel = gaussian_kernel(8, 4.0);
+        for &size in [15, 16, 32, 64, 100, 127, 128, 256].iter() {
+            println!("Trying size {}", size);
+            let count = size * size;
+            ltasg_single(
+                &mut [
+                    &mut vec![0f32; count][..],
+                    &mut vec![0f32; count][..],
+                    &mut vec![0f32; count][..],
+                    &mut vec![0f32; count][..],
+                    &mut vec![0f32; count][..],
+                    &mut vec![0f32; count][..],
+                ],
+                &[
+                    &vec![0f32; count][..],
+                    &vec![0f32; count][..],
+                    &vec![0f32; count][..],
+                    &vec![0f32; count][..],
+                    &vec![0f32; count][..],
+                    &vec![0f32; count][..],
+                ],
+                size,
+                &kernel,
+                0.5,
+                0,
+                StandardCubeMapTrait,
+            );
+        }
+    }
+
+    /// `container::test_pattern` as a `Vec` of faces.
+    fn test_pattern(size: usize) -> Vec<Vec<f32>> {
+        ::container::test_pattern(size).into_faces()
+    }
+
+    #[test]
+    fn transposed_matches_strided() {
+        let kernel = gaussian_kernel(8, 4.0);
+        for &size in [15, 64].iter() {
+            let src = test_pattern(size);
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            for phase in 0..3 {
+                let mut expected = vec![vec![0f32; size * size]; 6];
+                ltasg_single_core(
+                    &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &SliceFaceSampler::new(&src, size),
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                    &Default::default(),
+                    [true; 6],
+                    |face, out_face_img| for (y, out_row) in
+                        out_face_img.chunks_mut(size).enumerate()
+                    {
+                        face.process_row(y, out_row);
+                    },
+                    |face, out_face_img| for y in 0..size {
+                        for x in 0..size {
+                            out_face_img[face.out_index(x, y)] = face.texel(x, y);
+                        }
+                    },
+                );
+
+                let mut actual = vec![vec![0f32; size * size]; 6];
+                ltasg_single(
+                    &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                assert_eq!(actual, expected, "size = {}, phase = {}", size, phase);
+            }
+        }
+    }
+
+    #[test]
+    fn tiled_matches_row_major() {
+        let kernel = gaussian_kernel(8, 4.0);
+        for &size in [15, 64, 100].iter() {
+            let src = test_pattern(size);
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            for phase in 0..3 {
+                let mut expected = vec![vec![0f32; size * size]; 6];
+                ltasg_single(
+                    &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                for &tile_size in [1, 7, 16, 64].iter() {
+                    let mut actual = vec![vec![0f32; size * size]; 6];
+                    ltasg_single_with_options(
+                        &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                        &src,
+                        size,
+                        &kernel,
+                        0.5,
+                        phase,
+                        StandardCubeMapTrait,
+                        &LtasgOptions {
+                            tile_size: Some(tile_size),
+                            ..Default::default()
+                        },
+                    );
+                    assert_eq!(actual, expected, "size = {}, tile_size = {}", size, tile_size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn slice_sampler_out_of_bounds() {
+        let src = test_pattern(4);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        SliceFaceSampler::new(&src, 4).sample(CubeFace::NegativeZ, 4, 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn slice_sampler_short_face() {
+        let mut src = test_pattern(4);
+        src[5].pop();
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        SliceFaceSampler::new(&src, 4);
+    }
+
+    #[test]
+    fn custom_sampler_matches_slices() {
+        /// Generates `test_pattern` on the fly.
+        struct PatternSampler {
+            size: usize,
+        }
+        impl FaceSampler<f32> for PatternSampler {
+            fn sample(&self, face: CubeFace, x: usize, y: usize) -> f32 {
+                ::container::test_pattern_texel(face, x, y, self.size)
+            }
+        }
+
+        let kernel = gaussian_kernel(8, 4.0);
+        for &size in [15, 64].iter() {
+            let src = test_pattern(size);
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            for phase in 0..3 {
+                let mut expected = vec![vec![0f32; size * size]; 6];
+                ltasg_single(
+                    &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                let mut actual = vec![vec![0f32; size * size]; 6];
+                ltasg_single_with_sampler(
+                    &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &PatternSampler { size },
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                    &LtasgOptions::default(),
+                );
+                assert_eq!(actual, expected, "size = {}, phase = {}", size, phase);
+            }
+        }
+    }
+
+    #[test]
+    fn closure_sampler_matches_slices() {
+        let size = 32;
+        let kernel = gaussian_kernel(8, 4.0);
+        let gradient = |face: CubeFace, x: usize, y: usize| {
+            face.as_ordinal() as f32 + x as f32 * 0.1 - y as f32 * 0.05
+        };
+        let src = CubeMap::from_fn(size, gradient);
+        for phase in 0..3 {
+            let mut expected = CubeMap::zeros(size);
+            ltasg_single(
+                &mut expected.faces_mut(),
+                &src.faces(),
+                size,
+                &kernel,
+                0.5,
+                phase,
+                StandardCubeMapTrait,
+            );
+            let mut actual = CubeMap::zeros(size);
+            ltasg_single_from_fn(
+                &mut actual.faces_mut(),
+                gradient,
+                size,
+                &kernel,
+                0.5,
+                phase,
+                StandardCubeMapTrait,
+            );
+            assert_eq!(actual, expected, "phase = {}", phase);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_matches_sequential() {
+        use cgmath::Vector3;
+        let kernel = gaussian_kernel(8, 4.0);
+        let region = SphericalCap {
+            center_dir: Vector3::new(0.3, -0.2, 1.0),
+            half_angle: 0.9,
+        };
+        for &size in [15, 64, 100].iter() {
+            let src = test_pattern(size);
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            for options in [
+                LtasgOptions::default(),
+                LtasgOptions {
+                    region: Some(region),
+                    ..Default::default()
+                },
+            ].iter()
+            {
+                for phase in 0..3 {
+                    let mut expected = vec![vec![0f32; size * size]; 6];
+                    ltasg_single_with_options(
+                        &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                        &src,
+                        size,
+                        &kernel,
+                        0.5,
+                        phase,
+                        StandardCubeMapTrait,
+                        options,
+                    );
+                    let mut actual = vec![vec![0f32; size * size]; 6];
+                    ltasg_single_par(
+                        &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                        &src,
+                        size,
+                        &kernel,
+                        0.5,
+                        phase,
+                        StandardCubeMapTrait,
+                        options,
+                    );
+                    assert_eq!(actual, expected, "size = {}, phase = {}", size, phase);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spread_is_uniform_on_sphere() {
+        use cgmath::prelude::*;
+        use cgmath::Vector3;
+        use cubemap::CUBE_FACES;
+
+        let size = 64;
+        let kernel_upsample = 3.0;
+        let sigma = 0.05; // relative to the face size
+        let sigma_pxs = sigma * size as f32;
+        let kernel = gaussian_kernel(
+            (sigma_pxs * 2.0 * kernel_upsample).ceil() as usize,
+            sigma_pxs * kernel_upsample,
+        );
+
+        let uv = |c: usize| (c as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+        let direction = |face: CubeFace, x: usize, y: usize| -> Vector3<f32> {
+            (face.normal::<f32>() + face.u_vec::<f32>() * uv(x) + face.v_vec::<f32>() * uv(y))
+                .normalize()
+        };
+
+        let measure = |face: CubeFace, x: usize, y: usize| -> f32 {
+            let mut faces = vec![vec![0f32; size * size]; 6];
+            faces[face.as_ordinal()][x + y * size] = 1.0;
+            let mut temp = faces.clone();
+            for phase in 0..3 {
+                ltasg_single(
+                    &mut temp.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &faces.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                    size,
+                    &kernel,
+                    1.0 / kernel_upsample,
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                swap(&mut faces, &mut temp);
+            }
 
-                    // `in_coord_x_f` monotonically increases, so...
-                    let mut it = kernel.iter();
-                    loop {
-                        if in_coord_x_f > brd_min {
-                            break;
-                        }
-                        if let Some(weight) = it.next() {
-                            sum = sum +
-                                step_overflow!(
-                                    neg_axis_idx,
-                                    neg_axis_img,
-                                    brd_min - in_coord_x_f
-                                ) * *weight;
+            // The second moment of the angular distance, weighted by the solid
+            // angle of each texel
+            let center = direction(face, x, y);
+            let (mut sum, mut moment) = (0.0f64, 0.0f64);
+            for (&f, image) in CUBE_FACES.iter().zip(faces.iter()) {
+                for (i, &value) in image.iter().enumerate() {
+                    let (u, v) = (uv(i % size), uv(i / size));
+                    let solid_angle = (1.0 + u * u + v * v).powf(-1.5);
+                    let angle = direction(f, i % size, i / size).dot(center).min(1.0).acos();
+                    let w = (value * solid_angle) as f64;
+                    sum += w;
+                    moment += w * (angle * angle) as f64;
+                }
+            }
+            // A 2D Gaussian of standard deviation σ has `E[θ²] = 2σ²`
+            (moment / sum / 2.0).sqrt() as f32
+        };
+
+        let reference = measure(CubeFace::PositiveZ, size / 2, size / 2);
+        assert!((reference - sigma * 2.0).abs() < sigma * 2.0 * 0.1, "{}", reference);
+
+        // Without the `(1 + u² + v²).sqrt()` correction, the spread shrinks by
+        // about 30% at the edges and 50% at the corners. The approximation is
+        // the least accurate at the corners, where it is still within 25%.
+        for &(face, x, y, tolerance) in [
+            (CubeFace::PositiveX, size / 4, size / 2, 0.1),
+            (CubeFace::NegativeY, size - 1, size / 2, 0.1),
+            (CubeFace::PositiveY, size / 2, 0, 0.1),
+            (CubeFace::NegativeZ, size - 1, size - 1, 0.25),
+            (CubeFace::PositiveX, 0, 0, 0.25),
+        ].iter()
+        {
+            let spread = measure(face, x, y);
+            assert!(
+                (spread - reference).abs() < reference * tolerance,
+                "{:?} ({}, {}): {} (reference: {})",
+                face,
+                x,
+                y,
+                spread,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn overflow_reads_correct_neighbor() {
+        use cubemap::CUBE_FACES;
+
+        let size = 16;
+        let mid = size / 2;
+        let kernel = gaussian_kernel(4, 2.0);
+        // Reaches up to 4 × 1.0 × √2 texels at the middle of the edges
+        let kernel_scale = 1.0;
+
+        for phase in 0..3 {
+            let axis = CUBE_FACES[phase * 2];
+
+            // `contrib[k][f][i]`: the weight applied to the face `k` to
+            // compute the texel `i` of the face `f`
+            let contrib: Vec<Vec<Vec<f32>>> = CUBE_FACES
+                .iter()
+                .map(|&k| {
+                    let src: Vec<Vec<f32>> = CUBE_FACES
+                        .iter()
+                        .map(|&f| vec![if f == k { 1.0 } else { 0.0 }; size * size])
+                        .collect();
+                    let mut out = vec![vec![0f32; size * size]; 6];
+                    ltasg_single(
+                        &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                        &src.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                        size,
+                        &kernel,
+                        kernel_scale,
+                        phase,
+                        StandardCubeMapTrait,
+                    );
+                    out
+                })
+                .collect();
+
+            for &face in CUBE_FACES.iter() {
+                let radial = face.abs() == axis;
+                let along_u = face.u_face().abs() == axis;
+                for &(x, y, edge_face, crosses) in [
+                    (size - 1, mid, face.u_face(), radial || along_u),
+                    (0, mid, -face.u_face(), radial || along_u),
+                    (mid, size - 1, face.v_face(), radial || !along_u),
+                    (mid, 0, -face.v_face(), radial || !along_u),
+                ].iter()
+                {
+                    let i = x + y * size;
+                    let weights: Vec<f32> = CUBE_FACES
+                        .iter()
+                        .map(|k| contrib[k.as_ordinal()][face.as_ordinal()][i])
+                        .collect();
+                    let message = format!(
+                        "phase {}, {:?} ({}, {}): weights = {:?}",
+                        phase,
+                        face,
+                        x,
+                        y,
+                        weights
+                    );
+
+                    assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1.0e-5, "{}", message);
+                    for &k in CUBE_FACES.iter() {
+                        let w = weights[k.as_ordinal()];
+                        if k == face {
+                            assert!(w > 0.5, "{}", message);
+                        } else if k == edge_face && crosses {
+                            assert!(w > 0.05, "{}", message);
                         } else {
-                            break;
+                            assert_eq!(w, 0.0, "{}", message);
                         }
-                        in_coord_x_f += in_coord_x_df;
                     }
-                    loop {
-                        if in_coord_x_f >= brd_max {
-                            break;
-                        }
-                        if let Some(weight) = it.next() {
-                            sum = sum + step_normal!() * *weight;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multi_pass_matches_single() {
+        use std::mem::swap;
+
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let input: Vec<Vec<f32>> = (0..6)
+            .map(|face| (0..size * size).map(|i| ((i * 31 + face * 7) % 13) as f32).collect())
+            .collect();
+
+        for num_passes in 0..4 {
+            let mut expected = input.clone();
+            let mut temp = input.clone();
+            for _ in 0..num_passes {
+                for phase in 0..3 {
+                    ltasg_single(
+                        &mut temp.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                        &expected.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                        size,
+                        &kernel,
+                        0.5,
+                        phase,
+                        StandardCubeMapTrait,
+                    );
+                    swap(&mut expected, &mut temp);
+                }
+            }
+
+            let mut actual = vec![vec![-1.0f32; size * size]; 6];
+            let mut scratch = vec![vec![-1.0f32; size * size]; 6];
+            ltasg(
+                &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &input.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                &mut scratch.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                size,
+                &kernel,
+                0.5,
+                num_passes,
+                StandardCubeMapTrait,
+            );
+            assert_eq!(actual, expected, "num_passes = {}", num_passes);
+        }
+    }
+
+    #[test]
+    fn blur_srgb_cubemap_constant() {
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let input = vec![vec![[200u8, 100, 30, 255]; size * size]; 6];
+        let mut output = vec![vec![[0u8; 4]; size * size]; 6];
+        blur_srgb_cubemap(
+            &mut output.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &input.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+            0.5,
+            2,
+        );
+        for pixel in output.iter().flat_map(|face| face.iter()) {
+            for (&x, &y) in pixel.iter().zip(input[0][0].iter()) {
+                assert!((x as i32 - y as i32).abs() <= 1, "{:?}", pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn blur_builder_matches_ltasg() {
+        let size = 32;
+        let pattern = test_pattern(size);
+        let input = CubeMap::from_faces(pattern, size).unwrap();
+
+        let design = KernelDesign::for_sigma(0.4, size, 2.0, 3.0);
+        assert!(design.num_passes > 1);
+        let mut expected = input.clone();
+        let mut scratch = input.clone();
+        ltasg(
+            &mut expected.faces_mut(),
+            &input.faces(),
+            &mut scratch.faces_mut(),
+            size,
+            &design.kernel,
+            design.kernel_scale,
+            design.num_passes,
+            StandardCubeMapTrait,
+        );
+
+        let mut actual = input.clone();
+        let report = BlurBuilder::new().sigma(0.4).run(&mut actual);
+        assert_eq!(actual, expected);
+        assert_eq!(report.passes, design.num_passes);
+        assert_eq!(report.sigma1, design.sigma1);
+
+        // Forcing fewer passes than needed limits the blur
+        let builder = BlurBuilder::new().sigma(0.4).passes_override(Some(1));
+        let design = builder.design(size);
+        assert_eq!(design.num_passes, 1);
+        assert_eq!(design.sigma1, KernelDesign::max_sigma1(size, 2.0, 3.0));
+
+        // `StretchedCubeMapTrait` measures the texel spacing in `size - 1`
+        let builder = BlurBuilder::new()
+            .sigma(0.1)
+            .cube_map_trait(StretchedCubeMapTrait);
+        assert_eq!(builder.design(size), KernelDesign::for_sigma(0.1, size - 1, 2.0, 3.0));
+        builder.run(&mut actual);
+    }
+
+    #[test]
+    fn blur_report_effective_sigma() {
+        let size = 16;
+        for i in 0..20 {
+            let sigma = i as f32 * 0.05;
+            let report = BlurBuilder::new().sigma(sigma).run(&mut CubeMap::<f32>::zeros(size));
+            // `num_passes` is rounded up, so the per-pass σ is reduced to
+            // compensate
+            assert!(
+                (report.effective_sigma - sigma).abs() <= sigma * 1.0e-5,
+                "{}: {:?}",
+                sigma,
+                report
+            );
+            assert!(report.passes as f32 >= (sigma / 0.25).powi(2) - 1.0e-4);
+        }
+
+        // A forced pass count may not reach the requested σ
+        let report = BlurBuilder::new()
+            .sigma(0.5)
+            .passes_override(Some(1))
+            .run(&mut CubeMap::<f32>::zeros(size));
+        assert_eq!(report.passes, 1);
+        assert!(report.effective_sigma < 0.5);
+    }
+
+    #[test]
+    fn blur_builder_run_into() {
+        let size = 24;
+        let builder = BlurBuilder::new().sigma(0.3);
+        let mut out = CubeMap::zeros(size);
+        let mut scratch = CubeMap::zeros(size);
+
+        // The buffers are reused for the second input
+        for &seed in [0, 1].iter() {
+            let input = CubeMap::from_fn(size, |face, x, y| {
+                ((face.as_ordinal() * 7 + x * 3 + y * 5 + seed) % 11) as f32
+            });
+            let mut expected = input.clone();
+            let expected_report = builder.run(&mut expected);
+
+            let report = builder.run_into(&input, &mut out, &mut scratch);
+            assert_eq!(out, expected);
+            assert_eq!(report, expected_report);
+        }
+    }
+
+    #[test]
+    fn blur_builder_warmstart() {
+        fn check<Trait: CubeMapTrait + Clone>(builder: BlurBuilder<Trait>) {
+            let size = 32;
+            let old_input = CubeMap::from_fn(size, |face, x, y| {
+                ((face.as_ordinal() * 7 + x * 3 + y * 5) % 11) as f32
+            });
+
+            let mut prev_result = old_input.clone();
+            builder.run(&mut prev_result);
+
+            // An empty mask keeps the previous result
+            let mut actual = prev_result.clone();
+            builder.run_warmstart(&mut actual, &old_input, &CubeMap::filled(size, false));
+            assert_eq!(actual, prev_result);
+
+            // A full mask performs a fresh blur
+            let new_input = old_input.map(|x| x * 0.5 + 1.0);
+            let mut expected = new_input.clone();
+            builder.run(&mut expected);
+            let mut actual = prev_result.clone();
+            builder.run_warmstart(&mut actual, &new_input, &CubeMap::filled(size, true));
+            assert_eq!(actual, expected);
+
+            // A local change near a corner of +Z
+            let mut new_input = old_input.clone();
+            let mut change_mask = CubeMap::filled(size, false);
+            for &(x, y) in [(size - 1, size - 1), (size - 3, size - 2)].iter() {
+                new_input.face_mut(CubeFace::PositiveZ)[x + y * size] = 100.0;
+                change_mask.face_mut(CubeFace::PositiveZ)[x + y * size] = true;
+            }
+            let mut expected = new_input.clone();
+            builder.run(&mut expected);
+            let mut actual = prev_result.clone();
+            builder.run_warmstart(&mut actual, &new_input, &change_mask);
+            assert_eq!(actual, expected);
+
+            // Most of the result was not affected
+            let num_unaffected = actual
+                .iter_texels()
+                .zip(prev_result.iter_texels())
+                .filter(|&((_, _, _, a), (_, _, _, b))| a == b)
+                .count();
+            assert!(num_unaffected > size * size * 4, "{}", num_unaffected);
+        }
+        check(BlurBuilder::new().sigma(0.1));
+        check(BlurBuilder::new().sigma(0.1).cube_map_trait(StretchedCubeMapTrait));
+    }
+
+    #[test]
+    fn blur_builder_quality() {
+        use cubemap::texel_direction;
+        use reference::spherical_gaussian_reference;
+
+        // The hemisphere around +X, whose boundary crosses four faces
+        let size = 16;
+        let sigma = 0.2;
+        let cube_map = CubeMap::from_fn(size, |face, x, y| {
+            if texel_direction(face, x, y, size).x > 0.0 {
+                1.0f32
+            } else {
+                0.0
+            }
+        });
+        let expected = spherical_gaussian_reference(&cube_map, sigma, 2);
+
+        let run = |quality: Quality| {
+            let mut actual = cube_map.clone();
+            let report = BlurBuilder::new()
+                .sigma(sigma)
+                .quality(quality)
+                .run(&mut actual);
+            let sum_sq: f32 = actual
+                .iter_texels()
+                .zip(expected.iter_texels())
+                .map(|((_, _, _, &a), (_, _, _, &e))| (a - e) * (a - e))
+                .sum();
+            (report.passes, (sum_sq / (6 * size * size) as f32).sqrt())
+        };
+        let (draft_passes, draft_rmse) = run(Quality::Draft);
+        let (normal_passes, _) = run(Quality::Normal);
+        let (high_passes, high_rmse) = run(Quality::High);
+
+        // Each pass consists of three calls to `ltasg_single`
+        assert!(draft_passes < high_passes, "{} {}", draft_passes, high_passes);
+        assert!(draft_passes <= normal_passes && normal_passes <= high_passes);
+        assert!(draft_rmse > high_rmse, "{} {}", draft_rmse, high_rmse);
+
+        // `Draft` stays within the limit of `ltasg_single` even for small
+        // faces
+        let design = BlurBuilder::new()
+            .sigma(0.4)
+            .quality(Quality::Draft)
+            .design(size);
+        assert!(design.sigma1 <= KernelDesign::max_sigma1(size, 2.0, 3.0));
+        BlurBuilder::new()
+            .sigma(0.4)
+            .quality(Quality::Draft)
+            .run(&mut cube_map.clone());
+    }
+
+    #[test]
+    #[should_panic(expected = "size must not be zero")]
+    fn blur_builder_zero_size() {
+        BlurBuilder::new()
+            .sigma(0.1)
+            .cube_map_trait(StretchedCubeMapTrait)
+            .design(0);
+    }
+
+    #[test]
+    fn blur_builder_max_passes() {
+        let size = 16;
+
+        // σ given in pixels instead of relative to the face size
+        let builder = BlurBuilder::new().sigma(size as f32);
+        let design = builder.design(size);
+        assert_eq!(design.num_passes, 256);
+        assert!(design.sigma1 <= 0.25);
+
+        let report = builder.max_passes(2).run(&mut CubeMap::<f32>::zeros(size));
+        assert_eq!(report.passes, 2);
+        assert!(report.effective_sigma < 1.0);
+        match report.warning {
+            Some(PlanWarning::PassesClamped { required_passes }) => {
+                assert!(required_passes > 256, "{}", required_passes)
+            }
+            x => panic!("{:?}", x),
+        }
+
+        // A reasonable σ is not affected
+        let report = BlurBuilder::new().sigma(0.3).run(&mut CubeMap::<f32>::zeros(size));
+        assert_eq!(report.warning, None);
+    }
+
+    #[test]
+    fn ltasg_progress() {
+        let size = 8;
+        let kernel = gaussian_kernel(2, 1.0);
+        let input = CubeMap::<f32>::zeros(size);
+        for num_passes in 0..4 {
+            let mut output = input.clone();
+            let mut scratch = input.clone();
+            let mut reports = Vec::new();
+            ltasg_with_progress(
+                &mut output.faces_mut(),
+                &input.faces(),
+                &mut scratch.faces_mut(),
+                size,
+                &kernel,
+                0.5,
+                num_passes,
+                StandardCubeMapTrait,
+                &mut |progress| reports.push(progress),
+            );
+            assert_eq!(reports.len(), num_passes * 3);
+            for (i, progress) in reports.iter().enumerate() {
+                assert_eq!(
+                    *progress,
+                    BlurProgress {
+                        level: 0,
+                        pass: i / 3,
+                        phase: i % 3,
+                        total_passes: num_passes,
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ltasg_cancel_after_first_pass() {
+        let size = 8;
+        let kernel = gaussian_kernel(2, 1.0);
+        let input = CubeMap::<f32>::zeros(size);
+        let mut output = input.clone();
+        let mut scratch = input.clone();
+        let should_cancel = AtomicBool::new(false);
+        let mut reports = Vec::new();
+        let result = ltasg_cancellable(
+            &mut output.faces_mut(),
+            &input.faces(),
+            &mut scratch.faces_mut(),
+            size,
+            &kernel,
+            0.5,
+            4,
+            StandardCubeMapTrait,
+            &mut |progress| {
+                if progress.pass == 0 && progress.phase == 2 {
+                    should_cancel.store(true, Ordering::Relaxed);
+                }
+                reports.push(progress);
+            },
+            &should_cancel,
+        );
+        assert_eq!(result, Err(Cancelled));
+        // No phase was run after the first pass
+        assert_eq!(reports.len(), 3);
+    }
+
+    #[test]
+    fn aniso_blur_along_x() {
+        // A single bright texel at the center of +Z, whose rows run along
+        // the X axis
+        let size = 33;
+        let center = size / 2;
+        let mut input = CubeMap::zeros(size);
+        input.face_mut(CubeFace::PositiveZ)[center * (size + 1)] = 1.0f32;
+        assert_eq!(CubeFace::PositiveZ.u_face().abs(), CubeFace::PositiveX);
+
+        let kernel = gaussian_kernel(4, 2.0);
+        let identity = [1.0];
+        let mut output = input.clone();
+        let mut scratch = input.clone();
+        ltasg_aniso(
+            &mut output.faces_mut(),
+            &input.faces(),
+            &mut scratch.faces_mut(),
+            size,
+            [(&kernel, 0.5), (&identity, 0.0), (&identity, 0.0)],
+            2,
+            StandardCubeMapTrait,
+        );
+
+        // Spread along X...
+        let face = output.face(CubeFace::PositiveZ);
+        for dx in 1..4 {
+            assert!(face[center * size + center - dx] > 0.0);
+            assert!(face[center * size + center + dx] > 0.0);
+        }
+        assert!(face[center * (size + 1)] < 0.5);
+
+        // ...but not along Y or Z
+        for (face, x, y, &texel) in output.iter_texels() {
+            if face != CubeFace::PositiveZ || y != center {
+                assert_eq!(texel, 0.0, "{:?} ({}, {})", face, x, y);
+            }
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn stats_count_border_texels() {
+        // With `kernel_scale = 0.9` and a radius of one tap, the local scale
+        // (`0.9 * √(1 + u² + v²)`) is between `0.9` and `1.5` everywhere on an
+        // 8×8 face. Hence, only the outermost texels read the adjacent faces:
+        // the whole border (28 texels) of a face processed by the radial blur
+        // and the two columns at both ends of the blur axis (16 texels) of one
+        // processed by the directional blur.
+        let size = 8;
+        let kernel = [0.25, 0.5, 0.25];
+        let input = CubeMap::<f32>::zeros(size);
+        let mut output = input.clone();
+        for phase in 0..3 {
+            let mut stats = BlurStats::default();
+            ltasg_single_with_stats(
+                &mut output.faces_mut(),
+                &input.faces(),
+                size,
+                &kernel,
+                0.9,
+                phase,
+                StandardCubeMapTrait,
+                &mut stats,
+            );
+            for &face in CUBE_FACES.iter() {
+                let expected = if face.as_ordinal() / 2 == phase { 28 } else { 16 };
+                assert_eq!(
+                    stats.overflow_texels[face.as_ordinal()],
+                    expected,
+                    "phase {}: {:?}",
+                    phase,
+                    stats
+                );
+
+                // Every face is read by some of its neighbors, but only
+                // within one texel from its borders
+                assert!(stats.overflow_sample_range[face.as_ordinal()].is_some());
+                assert!(
+                    stats.overflow_sample_depth[face.as_ordinal()] <= 1,
+                    "phase {}: {:?}",
+                    phase,
+                    stats
+                );
+            }
+        }
+
+        // Nothing is recorded when the adjacent faces are not read
+        struct Clamp;
+        impl CubeMapTrait for Clamp {
+            fn edge_stretch_fixup(&self) -> bool {
+                false
+            }
+            fn edge_mode(&self) -> EdgeMode {
+                EdgeMode::Clamp
+            }
+        }
+        let mut stats = BlurStats::default();
+        ltasg_single_with_stats(
+            &mut output.faces_mut(),
+            &input.faces(),
+            size,
+            &kernel,
+            0.9,
+            0,
+            Clamp,
+            &mut stats,
+        );
+        assert_eq!(stats, BlurStats::default());
+    }
+
+    #[test]
+    fn tiled_blur_matches_in_memory() {
+        let size = 48;
+        let kernel = gaussian_kernel(4, 2.0);
+        let input = CubeMap::from_fn(size, |face, x, y| {
+            ((face.as_ordinal() * 7 + x * 3 + y * 5) % 11) as f32
+        });
+        for phase in 0..3 {
+            let mut expected = CubeMap::zeros(size);
+            ltasg_single(
+                &mut expected.faces_mut(),
+                &input.faces(),
+                size,
+                &kernel,
+                0.5,
+                phase,
+                StandardCubeMapTrait,
+            );
+
+            // A band height not dividing the size
+            let mut tiled = CubeMap::zeros(size);
+            TiledBlur::new(size, &kernel, 0.5).band_height(5).run_phase(
+                phase,
+                &SliceFaceSampler::new(&input.faces(), size),
+                |face, y, band: &[f32]| {
+                    assert!(band.len() <= 5 * size);
+                    let out = &mut tiled.face_mut(face)[y * size..][..band.len()];
+                    out.copy_from_slice(band);
+                },
+            );
+            assert_eq!(tiled, expected, "phase {}", phase);
+        }
+    }
+
+    #[test]
+    fn tiled_blur_footprint() {
+        use std::cell::RefCell;
+
+        let size = 40;
+        let kernel = gaussian_kernel(4, 2.0);
+        for &kernel_scale in [0.5f32, 1.3].iter() {
+            let reach = (kernel.len() / 2) as f32 * kernel_scale * 3.0f32.sqrt();
+            let m = reach.ceil() as usize;
+            for phase in 0..3 {
+                let axis = CUBE_FACES[phase * 2];
+                let accesses = RefCell::new(Vec::new());
+                let mut read_outside_band = false;
+                let sampler = |face: CubeFace, x: usize, y: usize| {
+                    accesses.borrow_mut().push((face, x, y));
+                    (x + y) as f32
+                };
+                TiledBlur::new(size, &kernel, kernel_scale).band_height(7).run_phase(
+                    phase,
+                    &sampler,
+                    |out_face, y0, band: &[f32]| {
+                        let y1 = y0 + band.len() / size;
+                        let along_rows = out_face.abs() != axis && out_face.v_face().abs() != axis;
+                        let (row_min, row_max) = if along_rows {
+                            (y0, y1)
                         } else {
-                            break;
+                            (y0.saturating_sub(m), cmp::min(y1 + m, size))
+                        };
+                        for (face, x, y) in accesses.borrow_mut().drain(..) {
+                            if face == out_face {
+                                read_outside_band |= y < y0 || y >= y1;
+                                assert!(
+                                    y >= row_min && y < row_max,
+                                    "phase {}, {:?} rows {}..{}: read row {}",
+                                    phase,
+                                    face,
+                                    y0,
+                                    y1,
+                                    y
+                                );
+                            } else {
+                                let border = cmp::min(
+                                    cmp::min(x, size - 1 - x),
+                                    cmp::min(y, size - 1 - y),
+                                );
+                                assert!(
+                                    border < m,
+                                    "phase {}, {:?} rows {}..{}: read {:?} ({}, {})",
+                                    phase,
+                                    out_face,
+                                    y0,
+                                    y1,
+                                    face,
+                                    x,
+                                    y
+                                );
+                            }
                         }
-                        in_coord_x_f += in_coord_x_df;
-                    }
-                    while let Some(weight) = it.next() {
-                        sum = sum +
-                            step_overflow!(pos_axis_idx, pos_axis_img, in_coord_x_f - brd_max) *
-                                *weight;
-                        in_coord_x_f += in_coord_x_df;
-                    }
+                    },
+                );
+
+                // The radial blur and the directional blur along the columns
+                // do read across the band boundaries
+                assert!(read_outside_band);
+            }
+        }
+    }
+
+    #[test]
+    fn blur_premultiplied_keeps_color() {
+        // A half-transparent white texel surrounded by fully transparent red
+        // ones, whose color must not bleed into it
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let center = size / 2 * (size + 1);
+        let mut input = CubeMap::filled(size, Vector4::new(1.0, 0.0, 0.0, 0.0));
+        input.face_mut(CubeFace::PositiveZ)[center] = Vector4::new(1.0, 1.0, 1.0, 0.5);
 
-                    out_face_img[x * offs.x + y * offs.y] = sum;
+        let mut output = input.clone();
+        blur_premultiplied(&mut output.faces_mut(), &input.faces(), size, &kernel, 0.5, 1);
 
-                    cur_u += duv_dxy;
+        let mut num_covered = 0;
+        for (_, _, _, &rgba) in output.iter_texels() {
+            if rgba.w > 0.0 {
+                num_covered += 1;
+                assert!(rgba.w < 0.5);
+                for i in 0..3 {
+                    assert!((rgba[i] - 1.0).abs() < 1.0e-4, "{:?}", rgba);
                 }
-                cur_v += duv_dxy;
+            } else {
+                assert_eq!(rgba, Vector4::new(0.0, 0.0, 0.0, 0.0));
             }
         }
+        assert!(num_covered > 1);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn blur_rgb_cubemap_matches_rgba() {
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let pattern = test_pattern(size);
+        let rgb: Vec<Vec<Vector3<f32>>> = pattern
+            .iter()
+            .map(|face| {
+                face.iter()
+                    .map(|&x| Vector3::new(x, 1.0 - x, x * x))
+                    .collect()
+            })
+            .collect();
+        let rgba: Vec<Vec<Vector4<f32>>> = rgb.iter()
+            .map(|face| face.iter().map(|x| x.extend(1.0)).collect())
+            .collect();
+
+        let mut rgb_out = rgb.clone();
+        blur_rgb_cubemap(
+            &mut rgb_out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &rgb.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+            0.5,
+            2,
+        );
+
+        let mut rgba_out = rgba.clone();
+        let mut scratch = rgba.clone();
+        ltasg(
+            &mut rgba_out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &rgba.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            &mut scratch.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+            0.5,
+            2,
+            StandardCubeMapTrait,
+        );
+
+        let rgb_out = rgb_out.iter().flat_map(|f| f.iter());
+        let rgba_out = rgba_out.iter().flat_map(|f| f.iter());
+        for (x, y) in rgb_out.zip(rgba_out) {
+            assert_eq!(*x, y.truncate());
+            assert!((y.w - 1.0).abs() < 1.0e-4, "{:?}", y);
+        }
+    }
 
     #[test]
-    fn doesnt_panic() {
-        let kernel = gaussian_kernel(8, 4.0);
-        for &size in [15, 16, 32, 64, 100, 127, 128, 256].iter() {
-            println!("Trying size {}", size);
-            let count = size * size;
+    fn cubemap_matches_slices() {
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let src = test_pattern(size);
+        let src_cube_map = CubeMap::from_faces(src.clone(), size).unwrap();
+        for phase in 0..3 {
+            let mut expected = vec![vec![0f32; size * size]; 6];
             ltasg_single(
-                &mut [
-                    &mut vec![0f32; count][..],
-                    &mut vec![0f32; count][..],
-                    &mut vec![0f32; count][..],
-                    &mut vec![0f32; count][..],
-                    &mut vec![0f32; count][..],
-                    &mut vec![0f32; count][..],
-                ],
-                &[
-                    &vec![0f32; count][..],
-                    &vec![0f32; count][..],
-                    &vec![0f32; count][..],
-                    &vec![0f32; count][..],
-                    &vec![0f32; count][..],
-                    &vec![0f32; count][..],
-                ],
+                &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &src.iter().map(Vec::as_slice).collect::<Vec<_>>(),
                 size,
                 &kernel,
                 0.5,
-                0,
+                phase,
+                StandardCubeMapTrait,
+            );
+
+            let mut actual = CubeMap::zeros(size);
+            ltasg_single_cubemap(
+                &mut actual,
+                &src_cube_map,
+                &kernel,
+                0.5,
+                phase,
+                StandardCubeMapTrait,
+            );
+            assert_eq!(actual.into_faces(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_faces() {
+        let size = 8;
+        let kernel = gaussian_kernel(2, 1.0);
+        let src = test_pattern(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        let mut out = vec![vec![0f32; size * size]; 6];
+        out[3].clear();
+        let mut out: Vec<_> = out.iter_mut().map(Vec::as_mut_slice).collect();
+        let run = |out: &mut [&mut [f32]], src: &[&[f32]]| {
+            try_ltasg_single(out, src, size, &kernel, 1.0, 0, StandardCubeMapTrait)
+        };
+        assert_eq!(
+            run(&mut out, &src),
+            Err(LtasgError::FaceTooShort { face: 3, len: 0, min_len: 64 })
+        );
+        assert_eq!(
+            run(&mut out, &src[0..5]),
+            Err(LtasgError::TooFewFaces { count: 5 })
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_output_face_panics() {
+        let size = 8;
+        let src = test_pattern(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        let mut out = vec![vec![0f32; size * size]; 6];
+        out[3].clear();
+        ltasg_single(
+            &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &src,
+            size,
+            &gaussian_kernel(2, 1.0),
+            1.0,
+            0,
+            StandardCubeMapTrait,
+        );
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let size = 8;
+        let src = test_pattern(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        let mut out = vec![vec![0f32; size * size]; 6];
+        let mut run = |size, kernel: &[f32], kernel_scale, phase| {
+            try_ltasg_single(
+                &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &src,
+                size,
+                kernel,
+                kernel_scale,
+                phase,
+                StandardCubeMapTrait,
+            )
+        };
+        let kernel = gaussian_kernel(2, 1.0);
+        assert_eq!(run(size, &kernel, 1.0, 0), Ok(()));
+        assert_eq!(
+            run(size, &kernel, 1.0, 3),
+            Err(LtasgError::PhaseOutOfRange { phase: 3 })
+        );
+        assert_eq!(
+            run(size, &kernel[0..4], 1.0, 0),
+            Err(LtasgError::EvenKernel { len: 4 })
+        );
+        assert_eq!(
+            run(size, &kernel, -1.0, 0),
+            Err(LtasgError::NegativeScale { kernel_scale: -1.0 })
+        );
+        match run(size, &kernel, 4.0, 0) {
+            Err(LtasgError::SizeTooSmallForKernel { span, footprint }) => {
+                assert_eq!(span, 8.0);
+                assert!((footprint - 8.0 * 3.0f32.sqrt()).abs() < 1.0e-4);
+            }
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(
+            run(MAX_SIZE + 1, &kernel, 1.0, 0),
+            Err(LtasgError::SizeTooLarge { size: MAX_SIZE + 1 })
+        );
+    }
+
+    #[test]
+    fn renormalize_keeps_constant() {
+        // An intentionally unnormalized kernel
+        let kernel: Vec<f32> = gaussian_kernel(6, 3.0).iter().map(|x| x * 1.3).collect();
+        for &size in [16, 33].iter() {
+            let src = vec![vec![0.7f32; size * size]; 6];
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            for phase in 0..3 {
+                let mut out = vec![vec![0f32; size * size]; 6];
+                ltasg_single_with_options(
+                    &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    StandardCubeMapTrait,
+                    &LtasgOptions {
+                        renormalize: true,
+                        ..Default::default()
+                    },
+                );
+                let first = out[0][0];
+                assert!((first - 0.7).abs() < 1.0e-6, "{}", first);
+                for face in out.iter() {
+                    assert!(face.iter().all(|&x| x == first));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn renormalize_zero_edges() {
+        #[derive(Clone)]
+        struct ZeroEdges;
+        impl CubeMapTrait for ZeroEdges {
+            fn edge_stretch_fixup(&self) -> bool {
+                false
+            }
+            fn edge_mode(&self) -> EdgeMode {
+                EdgeMode::Zero
+            }
+        }
+
+        // The kernel is normalized, but the taps outside the face are
+        // dropped, so the weight sum falls below one near the edges
+        let kernel = gaussian_kernel(6, 3.0);
+        let size = 16;
+        let src = vec![vec![0.7f32; size * size]; 6];
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        for phase in 0..3 {
+            let run = |renormalize| {
+                let mut out = vec![vec![0f32; size * size]; 6];
+                ltasg_single_with_options(
+                    &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    0.5,
+                    phase,
+                    ZeroEdges,
+                    &LtasgOptions {
+                        renormalize,
+                        ..Default::default()
+                    },
+                );
+                out
+            };
+            let plain = run(false);
+            let renormalized = run(true);
+
+            // Every face has an edge texel that loses weight in some phase.
+            // Only the plain output darkens there
+            let min = |out: &Vec<Vec<f32>>| {
+                out.iter()
+                    .flat_map(|face| face.iter())
+                    .fold(1.0f32, |a, &b| a.min(b))
+            };
+            assert!(min(&plain) < 0.7 * 0.9, "{}", min(&plain));
+            for face in renormalized.iter() {
+                for &x in face.iter() {
+                    assert!((x - 0.7).abs() < 1.0e-5, "{}", x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corner_texels_in_bounds() {
+        // Scale the kernel up to the limit so that the footprints of the
+        // texels near the corners cross the face boundaries as far as possible
+        let kernel = gaussian_kernel(4, 2.0);
+        for &size in [2, 3, 5, 16, 17, 128, 255, 256, 512].iter() {
+            let kernel_scale = |span: usize| span as f32 / (4.0 * 3.0f32.sqrt()) * 0.999;
+            let src = test_pattern(size);
+            let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+            let mut out = vec![vec![0f32; size * size]; 6];
+            for phase in 0..3 {
+                ltasg_single(
+                    &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    kernel_scale(size),
+                    phase,
+                    StandardCubeMapTrait,
+                );
+                ltasg_single(
+                    &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                    &src,
+                    size,
+                    &kernel,
+                    kernel_scale(size - 1),
+                    phase,
+                    StretchedCubeMapTrait,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stretched_differs_from_standard() {
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let src = test_pattern(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        fn run<Trait: CubeMapTrait>(
+            src: &[&[f32]],
+            kernel: &[f32],
+            phase: usize,
+            cube_map_trait: Trait,
+        ) -> Vec<Vec<f32>> {
+            let size = 16;
+            let mut out = vec![vec![0f32; size * size]; 6];
+            ltasg_single(
+                &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                src,
+                size,
+                kernel,
+                0.5,
+                phase,
+                cube_map_trait,
+            );
+            out
+        }
+        for phase in 0..3 {
+            let standard = run(&src, &kernel, phase, StandardCubeMapTrait);
+            let stretched = run(&src, &kernel, phase, StretchedCubeMapTrait);
+            // Compare the first rows
+            let num_edge_differences = (0..6)
+                .flat_map(|face| (0..size).map(move |i| (face, i)))
+                .filter(|&(face, i)| standard[face][i] != stretched[face][i])
+                .count();
+            assert!(num_edge_differences > 0, "phase = {}", phase);
+        }
+    }
+
+    #[test]
+    fn region_limits_output() {
+        use cgmath::Vector3;
+        use std::f32::consts::PI;
+
+        let kernel = gaussian_kernel(8, 4.0);
+        let size = 32;
+        let src = test_pattern(size);
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        let run = |phase, region| {
+            let mut out = vec![vec![-1f32; size * size]; 6];
+            ltasg_single_with_options(
+                &mut out.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &src,
+                size,
+                &kernel,
+                0.5,
+                phase,
                 StandardCubeMapTrait,
+                &LtasgOptions {
+                    region,
+                    ..Default::default()
+                },
             );
+            out
+        };
+
+        let center_dir = Vector3::new(0.2, 0.3, 1.0);
+        for phase in 0..3 {
+            // A cap covering the whole sphere
+            let full = SphericalCap {
+                center_dir,
+                half_angle: PI,
+            };
+            assert_eq!(run(phase, Some(full)), run(phase, None));
+
+            let narrow = SphericalCap {
+                center_dir,
+                half_angle: PI / 6.0,
+            };
+            let out = run(phase, Some(narrow));
+            let mut num_inside = 0;
+            for (face_i, image) in out.iter().enumerate() {
+                let face = CubeFace::from_ordinal(face_i).unwrap();
+                for (i, &x) in image.iter().enumerate() {
+                    let uv = |c: usize| (c as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+                    let dir = face.normal::<f32>() + face.u_vec::<f32>() * uv(i % size) +
+                        face.v_vec::<f32>() * uv(i / size);
+                    if narrow.contains(dir) {
+                        assert!(x != -1.0);
+                        num_inside += 1;
+                    } else {
+                        assert_eq!(x, -1.0);
+                    }
+                }
+            }
+            assert!(num_inside > 0);
         }
     }
 }
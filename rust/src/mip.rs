@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides the downsampling filters for generating mip levels.
+use std::ops;
+use std::f32::consts::PI;
+use cgmath::num_traits;
+
+use container::CubeMap;
+use cubemap::gather_offset;
+
+/// The support radius (measured in the output texels) of `MipFilter::Kaiser`.
+const KAISER_RADIUS: f32 = 3.0;
+
+/// Specifies the filter used by `downsample`.
+///
+/// The windowed-sinc filters (`Lanczos` and `Kaiser`) approximate the ideal
+/// low-pass filter and keep the details below the new Nyquist frequency
+/// that `Box` blurs away, resulting in crisper mip levels. The price is a
+/// larger footprint and ringing (over- and undershoot of up to about 10%
+/// next to a hard edge) because their kernels have negative lobes. Ringing
+/// around very bright features such as the sun may produce negative values,
+/// which should be clamped if they are a problem.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MipFilter {
+    /// Average 2×2 texels. Never overshoots.
+    Box,
+
+    /// Lanczos filter with the support radius of `a` output texels. `a = 3`
+    /// (Lanczos-3) is the common choice; smaller values ring less but are
+    /// blurrier.
+    Lanczos { a: usize },
+
+    /// Kaiser-windowed sinc filter with the support radius of three output
+    /// texels. `beta` controls the window shape; larger values suppress
+    /// ringing at the cost of sharpness. Values around 4 are typical.
+    Kaiser { beta: f32 },
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1.0e-6 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x2 = x * x * 0.25;
+    for k in 1..32 {
+        term *= half_x2 / (k * k) as f32;
+        sum += term;
+        if term < sum * 1.0e-8 {
+            break;
+        }
+    }
+    sum
+}
+
+impl MipFilter {
+    /// Return the support radius measured in the output texels.
+    fn radius(&self) -> f32 {
+        match self {
+            &MipFilter::Box => 0.5,
+            &MipFilter::Lanczos { a } => a as f32,
+            &MipFilter::Kaiser { .. } => KAISER_RADIUS,
+        }
+    }
+
+    /// Evaluate the one-dimensional kernel at the distance `x` (measured in
+    /// the output texels) from the center.
+    fn weight(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if x >= self.radius() {
+            return 0.0;
+        }
+        match self {
+            &MipFilter::Box => 1.0,
+            &MipFilter::Lanczos { a } => sinc(x) * sinc(x / a as f32),
+            &MipFilter::Kaiser { beta } => {
+                let t = x / KAISER_RADIUS;
+                sinc(x) * bessel_i0(beta * (1.0 - t * t).sqrt()) / bessel_i0(beta)
+            }
+        }
+    }
+}
+
+/// Return the number of levels in the full mip chain of a cube map whose
+/// faces are `size` in size, i.e., `log2(size) + 1` (rounded down).
+///
+/// Returns `0` if `size` is zero.
+pub fn mip_count(size: usize) -> usize {
+    (0usize.leading_zeros() - size.leading_zeros()) as usize
+}
+
+/// Produce the next mip level of a cube map, whose faces are `size / 2` in
+/// size.
+///
+/// `faces` is a slice of six `size`×`size` raster images. `size` must be
+/// even. The footprint of the filter continues across the face seams (see
+/// `gather_offset`). The weights are normalized for each output texel, so a
+/// constant input produces a constant output with every filter.
+pub fn downsample<T>(faces: &[&[T]], size: usize, filter: MipFilter) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(size >= 2 && size % 2 == 0);
+    if let MipFilter::Lanczos { a } = filter {
+        assert!(a > 0);
+    }
+
+    // The output texel `X` is centered at `2X + 1` in the input texel
+    // coordinates, and the input texel `2X + i` is centered at `2X + i + 0.5`
+    let num_taps = (filter.radius() * 2.0).ceil() as isize;
+    let offsets: Vec<isize> = (1 - num_taps..num_taps + 1).collect();
+    let weights: Vec<f32> = offsets
+        .iter()
+        .map(|&i| filter.weight((i as f32 - 0.5) * 0.5))
+        .collect();
+
+    let new_size = size / 2;
+    CubeMap::from_fn(new_size, |face, x, y| {
+        let mut sum = T::zero();
+        let mut weight_sum = 0.0f32;
+        for (&oy, &wy) in offsets.iter().zip(weights.iter()) {
+            if wy == 0.0 {
+                continue;
+            }
+            for (&ox, &wx) in offsets.iter().zip(weights.iter()) {
+                if wx == 0.0 {
+                    continue;
+                }
+                let texel = gather_offset(faces, size, face, x * 2, y * 2, ox, oy);
+                sum = sum + texel * (wx * wy);
+                weight_sum += wx * wy;
+            }
+        }
+        sum * (1.0 / weight_sum)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CubeFace;
+
+    const FILTERS: &[MipFilter] = &[
+        MipFilter::Box,
+        MipFilter::Lanczos { a: 2 },
+        MipFilter::Lanczos { a: 3 },
+        MipFilter::Kaiser { beta: 4.0 },
+    ];
+
+    #[test]
+    fn mip_counts() {
+        assert_eq!(mip_count(0), 0);
+        assert_eq!(mip_count(1), 1);
+        assert_eq!(mip_count(2), 2);
+        assert_eq!(mip_count(100), 7);
+        assert_eq!(mip_count(256), 9);
+    }
+
+    /// Downsample a cube map whose every row is given by `profile`, and
+    /// return the middle row of +Z.
+    fn downsample_profile<F>(size: usize, filter: MipFilter, profile: F) -> Vec<f32>
+    where
+        F: Fn(usize) -> f32,
+    {
+        let faces: Vec<Vec<f32>> = (0..6)
+            .map(|_| (0..size * size).map(|i| profile(i % size)).collect())
+            .collect();
+        let faces: Vec<_> = faces.iter().map(Vec::as_slice).collect();
+        let out = downsample(&faces, size, filter);
+        let new_size = size / 2;
+        out.face(CubeFace::PositiveZ)[new_size * new_size / 2..][..new_size].to_vec()
+    }
+
+    #[test]
+    fn constant_stays_constant() {
+        for &filter in FILTERS.iter() {
+            for &size in [2, 4, 16].iter() {
+                let faces = vec![vec![0.25f32; size * size]; 6];
+                let faces: Vec<_> = faces.iter().map(Vec::as_slice).collect();
+                let out = downsample(&faces, size, filter);
+                assert_eq!(out.size(), size / 2);
+                for (_, _, _, &texel) in out.iter_texels() {
+                    assert!((texel - 0.25).abs() < 1.0e-5, "{:?}: {}", filter, texel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lanczos_is_sharper_than_box() {
+        let size = 64;
+        // A soft edge, which is (almost) band-limited to the output
+        // resolution, and therefore should be preserved by downsampling
+        let edge = |x: f32| 1.0 / (1.0 + (-(x - 32.0) / 1.5).exp());
+        // The sum of the squared differences from the edge sampled at the
+        // output texel centers. The texels near the face boundary are
+        // excluded because the adjacent faces do not continue the edge.
+        let error = |filter| -> f32 {
+            downsample_profile(size, filter, |x| edge(x as f32 + 0.5))
+                .iter()
+                .enumerate()
+                .skip(4)
+                .take(size / 2 - 8)
+                .map(|(x, &value)| (value - edge(x as f32 * 2.0 + 1.0)).powi(2))
+                .sum()
+        };
+
+        let box_error = error(MipFilter::Box);
+        for &filter in [MipFilter::Lanczos { a: 3 }, MipFilter::Kaiser { beta: 4.0 }].iter() {
+            let filter_error = error(filter);
+            assert!(
+                filter_error < box_error * 0.25,
+                "{:?}: {} vs. {}",
+                filter,
+                filter_error,
+                box_error
+            );
+        }
+    }
+
+    #[test]
+    fn step_overshoot_is_bounded() {
+        let size = 64;
+        for &filter in FILTERS.iter() {
+            for &edge in [31, 32, 33].iter() {
+                let row = downsample_profile(size, filter, |x| if x < edge { 0.0 } else { 1.0 });
+                for &value in row.iter() {
+                    assert!(value > -0.12 && value < 1.12, "{:?}: {:?}", filter, row);
+                }
+                if filter == MipFilter::Box {
+                    assert!(row.iter().all(|&x| x >= 0.0 && x <= 1.0));
+                }
+            }
+        }
+    }
+}
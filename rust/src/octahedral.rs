@@ -0,0 +1,276 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Conversion between octahedral maps and cube maps.
+//!
+//! An octahedral map stores the whole sphere in a single square image. A
+//! direction is projected onto the octahedron `|x| + |y| + |z| = 1`, whose
+//! upper half (`z ≥ 0`) is mapped to the diamond inscribed in the square
+//! `[-1, 1]²` by dropping `z`. The lower half is folded outward over the
+//! diamond's edges to fill the four corners. The point `(p, q)` in the square
+//! maps to the image coordinates `((p + 1) / 2, (q + 1) / 2)` (both measured
+//! in the image size), so `+Z` is at the center and `-Z` at the four corners.
+//!
+//! Because of the folding, the outer edges of the image are mirrored onto
+//! themselves: the point `(0, v)` on the left edge coincides with `(0, 1 -
+//! v)`, and likewise for the other edges. Bilinear sampling of an octahedral
+//! map follows this rule, so the result is continuous everywhere.
+use std::ops;
+use cgmath::{Vector2, Vector3};
+use cgmath::prelude::*;
+use container::CubeMap;
+use cubemap::texel_direction;
+
+/// Compute `(1 - |p.y|, 1 - |p.x|)` with the signs of `p`, which maps the
+/// points in the upper half to the lower half of the octahedron and vice
+/// versa.
+fn fold(p: Vector2<f32>) -> Vector2<f32> {
+    let sign = |x: f32| if x >= 0.0 { 1.0 } else { -1.0 };
+    Vector2::new((1.0 - p.y.abs()) * sign(p.x), (1.0 - p.x.abs()) * sign(p.y))
+}
+
+/// Convert a direction to a point on an octahedral map of the size `1`×`1`.
+/// `dir` does not have to be normalized but must not be the zero vector.
+pub fn direction_to_octahedral(dir: Vector3<f32>) -> (f32, f32) {
+    let p = dir.truncate() * (1.0 / (dir.x.abs() + dir.y.abs() + dir.z.abs()));
+    let p = if dir.z < 0.0 { fold(p) } else { p };
+    (p.x * 0.5 + 0.5, p.y * 0.5 + 0.5)
+}
+
+/// Convert a point on an octahedral map of the size `1`×`1` to a normalized
+/// direction. The inverse of `direction_to_octahedral`.
+pub fn octahedral_to_direction(u: f32, v: f32) -> Vector3<f32> {
+    let p = Vector2::new(u * 2.0 - 1.0, v * 2.0 - 1.0);
+    let z = 1.0 - p.x.abs() - p.y.abs();
+    let p = if z < 0.0 { fold(p) } else { p };
+    p.extend(z).normalize()
+}
+
+/// Bilinearly sample the `size`×`size` octahedral map `image` at `(x, y)`
+/// (measured in pixels), following the mirroring at the outer edges.
+fn sample_octahedral<T>(image: &[T], size: usize, x: f32, y: f32) -> T
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    let s = size as isize;
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+
+    let fetch = |dx: isize, dy: isize| {
+        let (mut x, mut y) = (x0 + dx, y0 + dy);
+        // Crossing an edge mirrors the coordinate along it
+        if x < 0 || x >= s {
+            x = if x < 0 { -1 - x } else { s * 2 - 1 - x };
+            y = s - 1 - y;
+        }
+        if y < 0 || y >= s {
+            y = if y < 0 { -1 - y } else { s * 2 - 1 - y };
+            x = s - 1 - x;
+        }
+        let clamp = |i: isize| i.max(0).min(s - 1) as usize;
+        image[clamp(x) + clamp(y) * size]
+    };
+    let top = fetch(0, 0) * (1.0 - fx) + fetch(1, 0) * fx;
+    let bottom = fetch(0, 1) * (1.0 - fx) + fetch(1, 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Resample `cube` into the `size`×`size` octahedral map `out` (stored in
+/// the row-major order) using `CubeMap::sample_dir`.
+pub fn cubemap_to_octahedral<T>(cube: &CubeMap<T>, out: &mut [T], size: usize)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert_eq!(out.len(), size * size);
+    for (i, texel) in out.iter_mut().enumerate() {
+        let u = ((i % size) as f32 + 0.5) / size as f32;
+        let v = ((i / size) as f32 + 0.5) / size as f32;
+        *texel = cube.sample_dir(octahedral_to_direction(u, v));
+    }
+}
+
+/// Resample the `size`×`size` octahedral map `image` (stored in the
+/// row-major order) into `out` with bilinear filtering.
+pub fn octahedral_to_cubemap<T>(image: &[T], size: usize, out: &mut CubeMap<T>)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert!(size > 0);
+    assert_eq!(image.len(), size * size);
+    let cube_size = out.size();
+    for (face, x, y, texel) in out.iter_texels_mut() {
+        let (u, v) = direction_to_octahedral(texel_direction(face, x, y, cube_size));
+        *texel = sample_octahedral(image, size, u * size as f32, v * size as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+
+    fn gradient(dir: Vector3<f32>) -> f32 {
+        dir.x + dir.y * 2.0 - dir.z * 0.5
+    }
+
+    #[test]
+    fn direction_round_trip() {
+        let steps = 6;
+        for ix in -steps..steps + 1 {
+            for iy in -steps..steps + 1 {
+                for iz in -steps..steps + 1 {
+                    if ix == 0 && iy == 0 && iz == 0 {
+                        continue;
+                    }
+                    let dir = Vector3::new(ix, iy, iz).cast::<f32>().normalize();
+                    let (u, v) = direction_to_octahedral(dir);
+                    assert!(u >= 0.0 && u <= 1.0 && v >= 0.0 && v <= 1.0);
+                    let dir2 = octahedral_to_direction(u, v);
+                    assert!((dir - dir2).magnitude() < 1.0e-5, "{:?} → {:?}", dir, dir2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn known_directions() {
+        for &(dir, (u, v)) in [
+            ((0.0, 0.0, 1.0), (0.5, 0.5)),
+            ((1.0, 0.0, 0.0), (1.0, 0.5)),
+            ((-1.0, 0.0, 0.0), (0.0, 0.5)),
+            ((0.0, 1.0, 0.0), (0.5, 1.0)),
+            ((0.0, -1.0, 0.0), (0.5, 0.0)),
+            ((0.0, 0.0, -1.0), (1.0, 1.0)),
+            ((1.0, 1.0, 1.0), (2.0 / 3.0, 2.0 / 3.0)),
+            // Folded over the diamond's edges
+            ((1.0, -1.0, -1.0), (5.0 / 6.0, 1.0 / 6.0)),
+            ((-2.0, 1.0, -1.0), (0.125, 0.75)),
+        ].iter()
+        {
+            let dir = Vector3::new(dir.0, dir.1, dir.2);
+            let (u2, v2) = direction_to_octahedral(dir);
+            assert!(
+                (u2 - u).abs() < 1.0e-6 && (v2 - v).abs() < 1.0e-6,
+                "{:?} → {:?}",
+                dir,
+                (u2, v2)
+            );
+        }
+    }
+
+    #[test]
+    fn known_texels() {
+        // A narrow lobe around each direction lights exactly the texel
+        // containing it
+        let size = 8;
+        for &(dir, texel) in [
+            (Vector3::new(1.0, 1.0, 1.0), (5, 5)),
+            (Vector3::new(1.0, -1.0, -1.0), (6, 1)),
+            (Vector3::new(-1.0, 1.0, -1.0), (1, 6)),
+        ].iter()
+        {
+            let dir: Vector3<f32> = dir.normalize();
+            let mut cube = CubeMap::zeros(64);
+            cube.fill_from_direction(|d| if d.normalize().dot(dir) > 0.98 { 1.0 } else { 0.0 });
+            let mut oct = vec![0.0f32; size * size];
+            cubemap_to_octahedral(&cube, &mut oct, size);
+            for (i, &value) in oct.iter().enumerate() {
+                if (i % size, i / size) == texel {
+                    assert!(value > 0.5, "{:?} {:?}: {}", dir, texel, value);
+                } else {
+                    assert!(value < 0.5, "{:?} {:?}: {}", dir, (i % size, i / size), value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fold_is_continuous() {
+        // The adjacent texels, including those across the diamond's edges
+        // (the equator) and those mirrored across the outer edges, must
+        // represent nearby directions. A wrong fold would produce a jump to
+        // another part of the sphere.
+        let size = 32;
+        let dir = |x: usize, y: usize| {
+            octahedral_to_direction((x as f32 + 0.5) / size as f32, (y as f32 + 0.5) / size as f32)
+        };
+        let max_angle = 2.0 * ::std::f32::consts::PI / size as f32;
+        let last = size - 1;
+        for y in 0..size {
+            for x in 0..size {
+                let mut neighbors = Vec::new();
+                if x < last {
+                    neighbors.push((x + 1, y));
+                }
+                if y < last {
+                    neighbors.push((x, y + 1));
+                }
+                if x == 0 || x == last {
+                    neighbors.push((x, last - y));
+                }
+                if y == 0 || y == last {
+                    neighbors.push((last - x, y));
+                }
+                for &(x2, y2) in neighbors.iter() {
+                    let angle = dir(x, y).dot(dir(x2, y2)).min(1.0).acos();
+                    assert!(angle < max_angle, "{:?} {:?}: {}", (x, y), (x2, y2), angle);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edges_are_mirrored() {
+        for i in 0..9 {
+            let t = i as f32 / 8.0;
+            for &((u1, v1), (u2, v2)) in [
+                ((0.0, t), (0.0, 1.0 - t)),
+                ((1.0, t), (1.0, 1.0 - t)),
+                ((t, 0.0), (1.0 - t, 0.0)),
+                ((t, 1.0), (1.0 - t, 1.0)),
+            ].iter()
+            {
+                let dir1 = octahedral_to_direction(u1, v1);
+                let dir2 = octahedral_to_direction(u2, v2);
+                assert!((dir1 - dir2).magnitude() < 1.0e-5, "{:?} vs {:?}", dir1, dir2);
+            }
+        }
+    }
+
+    #[test]
+    fn cubemap_round_trip() {
+        let mut cube = CubeMap::zeros(32);
+        cube.fill_from_direction(gradient);
+
+        let oct_size = 64;
+        let mut oct = vec![0.0f32; oct_size * oct_size];
+        cubemap_to_octahedral(&cube, &mut oct, oct_size);
+
+        let size = 16;
+        let mut result = CubeMap::zeros(size);
+        octahedral_to_cubemap(&oct, oct_size, &mut result);
+
+        for &face in CUBE_FACES.iter() {
+            for y in 0..size {
+                for x in 0..size {
+                    let expected = gradient(texel_direction(face, x, y, size));
+                    let actual = result.face(face)[x + y * size];
+                    assert!(
+                        (expected - actual).abs() < 0.05,
+                        "{:?} ({}, {}): {} vs {}",
+                        face,
+                        x,
+                        y,
+                        expected,
+                        actual
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Conversion between dual-paraboloid maps and cube maps.
+//!
+//! A dual-paraboloid map consists of two `size`×`size` images, each of which
+//! stores a hemisphere in the disc inscribed in it. The *front* image covers
+//! `z ≥ 0` and the *back* image covers `z < 0`. The functions in this module
+//! store them in a single slice of the length `size * size * 2` — the front
+//! image comes first and is immediately followed by the back image, both in
+//! the row-major order.
+//!
+//! A normalized direction `(x, y, z)` maps to the point `(x, y) / (1 + |z|)`
+//! in the square `[-1, 1]²`, which in turn maps to the image coordinates
+//! `((p + 1) / 2, (q + 1) / 2)` (both measured in the image size). Both images
+//! use the same orientation, so a point on the rim of the front image
+//! represents the same direction as the corresponding point of the back
+//! image.
+//!
+//! The texels outside the disc hold the value of the nearest point on the
+//! rim. Samples near the rim are clamped to the disc rather than being
+//! wrapped to the other hemisphere.
+use std::ops;
+use cgmath::{Vector2, Vector3};
+use cgmath::prelude::*;
+use container::CubeMap;
+use cubemap::texel_direction;
+use raster::{sample_bilinear, WrapMode};
+
+/// Identifies one of the two images of a dual-paraboloid map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hemisphere {
+    /// The hemisphere `z ≥ 0`, stored in the first image.
+    Front,
+    /// The hemisphere `z < 0`, stored in the second image.
+    Back,
+}
+
+/// Convert a direction to a hemisphere and a point on its image of the size
+/// `1`×`1`. `dir` does not have to be normalized but must not be the zero
+/// vector.
+pub fn direction_to_paraboloid(dir: Vector3<f32>) -> (Hemisphere, f32, f32) {
+    let dir = dir.normalize();
+    let hemisphere = if dir.z >= 0.0 {
+        Hemisphere::Front
+    } else {
+        Hemisphere::Back
+    };
+    let p = dir.truncate() * (1.0 / (1.0 + dir.z.abs()));
+    (hemisphere, p.x * 0.5 + 0.5, p.y * 0.5 + 0.5)
+}
+
+/// Convert a point on an image of the size `1`×`1` to a normalized direction.
+/// Points outside the disc are moved to the nearest point on the rim. The
+/// inverse of `direction_to_paraboloid`.
+pub fn paraboloid_to_direction(hemisphere: Hemisphere, u: f32, v: f32) -> Vector3<f32> {
+    let mut p = Vector2::new(u * 2.0 - 1.0, v * 2.0 - 1.0);
+    let mut r2 = p.magnitude2();
+    if r2 > 1.0 {
+        p = p * (1.0 / r2.sqrt());
+        r2 = 1.0;
+    }
+    let z = match hemisphere {
+        Hemisphere::Front => 1.0 - r2,
+        Hemisphere::Back => r2 - 1.0,
+    };
+    (p * 2.0).extend(z) * (1.0 / (1.0 + r2))
+}
+
+/// Resample `cube` into the dual-paraboloid map `out` consisting of two
+/// `size`×`size` images (see the module documentation for the layout) using
+/// `CubeMap::sample_dir`.
+pub fn cubemap_to_dual_paraboloid<T>(cube: &CubeMap<T>, out: &mut [T], size: usize)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert_eq!(out.len(), size * size * 2);
+    let (front, back) = out.split_at_mut(size * size);
+    let fill = |hemisphere: Hemisphere, image: &mut [T]| {
+        for (i, texel) in image.iter_mut().enumerate() {
+            let u = ((i % size) as f32 + 0.5) / size as f32;
+            let v = ((i / size) as f32 + 0.5) / size as f32;
+            *texel = cube.sample_dir(paraboloid_to_direction(hemisphere, u, v));
+        }
+    };
+    fill(Hemisphere::Front, front);
+    fill(Hemisphere::Back, back);
+}
+
+/// Resample the dual-paraboloid map `image` consisting of two `size`×`size`
+/// images (see the module documentation for the layout) into `out` with
+/// bilinear filtering.
+pub fn dual_paraboloid_to_cubemap<T>(image: &[T], size: usize, out: &mut CubeMap<T>)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    assert!(size > 0);
+    assert_eq!(image.len(), size * size * 2);
+    let (front, back) = image.split_at(size * size);
+    let cube_size = out.size();
+    for (face, x, y, texel) in out.iter_texels_mut() {
+        let (hemisphere, u, v) = direction_to_paraboloid(texel_direction(face, x, y, cube_size));
+        let image = match hemisphere {
+            Hemisphere::Front => front,
+            Hemisphere::Back => back,
+        };
+        *texel = sample_bilinear(
+            image,
+            size,
+            size,
+            u * size as f32,
+            v * size as f32,
+            WrapMode::Clamp,
+            WrapMode::Clamp,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CUBE_FACES;
+
+    fn gradient(dir: Vector3<f32>) -> f32 {
+        dir.x + dir.y * 2.0 - dir.z * 0.5
+    }
+
+    #[test]
+    fn direction_round_trip() {
+        for &dir in [
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.3, -0.4, 0.5),
+            Vector3::new(-0.7, 0.2, -0.1),
+            Vector3::new(0.1, 0.9, -0.8),
+        ].iter()
+        {
+            let dir = dir.normalize();
+            let (hemisphere, u, v) = direction_to_paraboloid(dir);
+            let dir2 = paraboloid_to_direction(hemisphere, u, v);
+            assert!((dir - dir2).magnitude() < 1.0e-5, "{:?} → {:?}", dir, dir2);
+        }
+    }
+
+    #[test]
+    fn known_directions() {
+        let s = 0.5f32.sqrt();
+        let t = 0.5 * s / (1.0 + s);
+        for &(dir, (hemisphere, u, v)) in [
+            ((0.0, 0.0, 1.0), (Hemisphere::Front, 0.5, 0.5)),
+            ((0.0, 0.0, -1.0), (Hemisphere::Back, 0.5, 0.5)),
+            ((1.0, 0.0, 0.0), (Hemisphere::Front, 1.0, 0.5)),
+            ((0.0, -1.0, 0.0), (Hemisphere::Front, 0.5, 0.0)),
+            ((s, 0.0, s), (Hemisphere::Front, 0.5 + t, 0.5)),
+            ((0.0, s, -s), (Hemisphere::Back, 0.5, 0.5 + t)),
+            ((-s, 0.0, -s), (Hemisphere::Back, 0.5 - t, 0.5)),
+        ].iter()
+        {
+            let dir = Vector3::new(dir.0, dir.1, dir.2);
+            let (hemisphere2, u2, v2) = direction_to_paraboloid(dir);
+            assert_eq!(hemisphere2, hemisphere, "{:?}", dir);
+            assert!(
+                (u2 - u).abs() < 1.0e-6 && (v2 - v).abs() < 1.0e-6,
+                "{:?} → {:?}",
+                dir,
+                (u2, v2)
+            );
+        }
+    }
+
+    #[test]
+    fn known_texels() {
+        // A narrow lobe around each direction lights exactly the texel
+        // centered at it in the image of its hemisphere. `(24, 24, 23) / 41`
+        // maps to `p = (0.375, 0.375)`, the center of the texel `(5, 5)`.
+        let size = 8;
+        for &(dir, image, texel) in [
+            (Vector3::new(24.0, 24.0, 23.0), 0, (5, 5)),
+            (Vector3::new(-24.0, 24.0, 23.0), 0, (2, 5)),
+            (Vector3::new(24.0, 24.0, -23.0), 1, (5, 5)),
+            (Vector3::new(24.0, -24.0, -23.0), 1, (5, 2)),
+        ].iter()
+        {
+            let dir: Vector3<f32> = dir.normalize();
+            let mut cube = CubeMap::zeros(64);
+            cube.fill_from_direction(|d| if d.normalize().dot(dir) > 0.99 { 1.0 } else { 0.0 });
+            let mut pb = vec![0.0f32; size * size * 2];
+            cubemap_to_dual_paraboloid(&cube, &mut pb, size);
+            for (i, &value) in pb.iter().enumerate() {
+                let position = (i / (size * size), (i % size, i / size % size));
+                if position == (image, texel) {
+                    assert!(value > 0.5, "{:?} {:?}: {}", dir, position, value);
+                } else {
+                    assert!(value < 0.5, "{:?} {:?}: {}", dir, position, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rim_texels() {
+        // Every texel of the front image represents `z ≥ 0` and every texel
+        // of the back image `z ≤ 0`. The texels on or outside the rim
+        // represent the equator `z = 0`, identically in both images.
+        let size = 32;
+        for y in 0..size {
+            for x in 0..size {
+                let u = (x as f32 + 0.5) / size as f32;
+                let v = (y as f32 + 0.5) / size as f32;
+                let front = paraboloid_to_direction(Hemisphere::Front, u, v);
+                let back = paraboloid_to_direction(Hemisphere::Back, u, v);
+                assert!(front.z >= 0.0 && back.z <= 0.0, "{:?} {:?}", front, back);
+                assert!((front.magnitude() - 1.0).abs() < 1.0e-5, "{:?}", front);
+
+                let r = Vector2::new(u * 2.0 - 1.0, v * 2.0 - 1.0).magnitude();
+                if r >= 1.0 {
+                    assert_eq!(front.z, 0.0);
+                    assert_eq!(front, back);
+                } else {
+                    // Mirror images of each other across the equator
+                    assert_eq!(front, Vector3::new(back.x, back.y, -back.z));
+                }
+            }
+        }
+
+        // The directions just above and below the equator map to the same
+        // point on the rims of both images
+        for i in 0..16 {
+            let angle = i as f32 * ::std::f32::consts::PI / 8.0;
+            let (x, y) = (angle.cos(), angle.sin());
+            let (h1, u1, v1) = direction_to_paraboloid(Vector3::new(x, y, 1.0e-4));
+            let (h2, u2, v2) = direction_to_paraboloid(Vector3::new(x, y, -1.0e-4));
+            assert_eq!((h1, h2), (Hemisphere::Front, Hemisphere::Back));
+            assert!((u1 - u2).abs() < 1.0e-6 && (v1 - v2).abs() < 1.0e-6);
+            let r = Vector2::new(u1 * 2.0 - 1.0, v1 * 2.0 - 1.0).magnitude();
+            assert!((r - 1.0).abs() < 1.0e-3, "{}", r);
+        }
+    }
+
+    #[test]
+    fn outside_is_clamped_to_rim() {
+        for &hemisphere in [Hemisphere::Front, Hemisphere::Back].iter() {
+            let dir = paraboloid_to_direction(hemisphere, 1.0, 1.0);
+            let expected = Vector3::new(1.0, 1.0, 0.0).normalize();
+            assert!((dir - expected).magnitude() < 1.0e-5, "{:?}", dir);
+        }
+    }
+
+    #[test]
+    fn cubemap_round_trip() {
+        let mut cube = CubeMap::zeros(32);
+        cube.fill_from_direction(gradient);
+
+        let pb_size = 64;
+        let mut pb = vec![0.0f32; pb_size * pb_size * 2];
+        cubemap_to_dual_paraboloid(&cube, &mut pb, pb_size);
+
+        let size = 16;
+        let mut result = CubeMap::zeros(size);
+        dual_paraboloid_to_cubemap(&pb, pb_size, &mut result);
+
+        for &face in CUBE_FACES.iter() {
+            for y in 0..size {
+                for x in 0..size {
+                    let expected = gradient(texel_direction(face, x, y, size));
+                    let actual = result.face(face)[x + y * size];
+                    assert!(
+                        (expected - actual).abs() < 0.05,
+                        "{:?} ({}, {}): {} vs {}",
+                        face,
+                        x,
+                        y,
+                        expected,
+                        actual
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,551 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides the mapping between the mip levels of a prefiltered environment
+//! map and the material roughness.
+//!
+//! The mip level `i` is blurred with `σ = 2^(i - 5)` (measured in the face
+//! size, see `mip_level_sigma`), which is the scheme used by
+//! `generate_mip_pyramid`. A shader samples the prefiltered map at the LOD
+//! returned by `lod_for_roughness`.
+//!
+//! The roughness is the perceptual roughness `r` of the GGX model used by
+//! most PBR pipelines. It is converted to a Blinn-Phong exponent via the
+//! approximation `n = 2 / α² - 2` (where `α = r²`), and the Blinn-Phong lobe
+//! is in turn approximated by a Gaussian of `σ = 1 / (2√n)`. This is the
+//! same relationship as the one used in the shader snippet in `README.md`.
+//!
+//! This module also provides `prefilter_ggx`, which prefilters an environment
+//! map by importance-sampling the GGX distribution instead of applying the
+//! Gaussian blur. Its mip levels follow the common (linear) convention
+//! `roughness = level / (num_levels - 1)` rather than the mapping above.
+//! `irradiance_convolve` computes the irradiance map for the diffuse
+//! lighting, and `project_sh9` computes its compact representation based on
+//! spherical harmonics.
+use std::{f32, ops};
+use cgmath::{num_traits, Vector3};
+use cgmath::prelude::*;
+use container::CubeMap;
+use cubemap::{texel_direction, texel_solid_angle};
+use ltasgblur;
+
+/// Return the blur amount (the standard deviation measured in the face size)
+/// of the mip level `level`.
+pub fn mip_level_sigma(level: usize) -> f32 {
+    (level as f32 - 5.0).exp2()
+}
+
+/// Compute the blur amount (see `mip_level_sigma`) corresponding to the
+/// perceptual roughness `roughness`.
+pub fn sigma_for_roughness(roughness: f32) -> f32 {
+    let alpha = roughness * roughness;
+    let alpha2 = alpha * alpha;
+    let power = 2.0 / alpha2 - 2.0;
+    0.5 / power.sqrt()
+}
+
+/// The inverse of `sigma_for_roughness`.
+pub fn roughness_for_sigma(sigma: f32) -> f32 {
+    let power = 0.25 / (sigma * sigma);
+    let alpha = (2.0 / (power + 2.0)).sqrt();
+    alpha.sqrt()
+}
+
+/// Return the roughness each of the first `num_levels` mip levels
+/// corresponds to. The returned values are strictly increasing.
+pub fn mip_roughness_table(num_levels: usize) -> Vec<f32> {
+    (0..num_levels)
+        .map(|level| roughness_for_sigma(mip_level_sigma(level)))
+        .collect()
+}
+
+/// Compute the LOD at which a prefiltered environment map with `num_levels`
+/// mip levels should be sampled for the given roughness. The result is
+/// clamped to `[0, num_levels - 1]`.
+///
+/// This is the inverse of `mip_roughness_table`.
+pub fn lod_for_roughness(roughness: f32, num_levels: usize) -> f32 {
+    assert!(num_levels > 0);
+    let lod = sigma_for_roughness(roughness).log2() + 5.0;
+    if lod.is_nan() {
+        // `roughness > 1` has no corresponding blur amount
+        return (num_levels - 1) as f32;
+    }
+    lod.max(0.0).min((num_levels - 1) as f32)
+}
+
+/// The inverse of `lod_for_roughness`, i.e., compute the roughness the
+/// (possibly fractional) LOD `lod` corresponds to.
+pub fn roughness_for_lod(lod: f32) -> f32 {
+    roughness_for_sigma((lod - 5.0).exp2())
+}
+
+/// Design the blur applied to the mip level `level` (whose faces are `size`
+/// in size) by `generate_mip_pyramid`, i.e., the blur adding up to
+/// `mip_level_sigma(level)` in total when applied after the previous levels.
+///
+/// The number of passes and `sigma1` are chosen by
+/// `ltasgblur::KernelDesign::for_sigma`. Unlike `KernelDesign`, the kernel
+/// is a Gaussian of the standard deviation `kernel_sigma` (measured in kernel
+/// taps) truncated at `kernel_ratio * kernel_sigma` regardless of the level,
+/// and `kernel_scale` fits it to `sigma1`.
+pub fn mip_level_design(
+    level: usize,
+    size: usize,
+    kernel_sigma: f32,
+    kernel_ratio: f32,
+) -> ltasgblur::KernelDesign {
+    let sigma = mip_level_sigma(level);
+    let last_sigma = if level > 0 {
+        mip_level_sigma(level - 1)
+    } else {
+        0.0
+    };
+    let res_sigma = (sigma * sigma - last_sigma * last_sigma).max(0.0).sqrt();
+
+    // The kernel is the densest (relative to the texels) when `sigma1` is at
+    // the limit used by `for_sigma`
+    let kernel_upsample = kernel_sigma * kernel_ratio / (0.5 * size as f32);
+    let design = ltasgblur::KernelDesign::for_sigma(res_sigma, size, kernel_ratio, kernel_upsample);
+    let kernel_scale = if design.num_passes > 0 {
+        design.sigma1 * size as f32 / kernel_sigma
+    } else {
+        1.0
+    };
+    ltasgblur::KernelDesign {
+        kernel: ltasgblur::gaussian_kernel((kernel_sigma * kernel_ratio) as usize, kernel_sigma),
+        kernel_scale,
+        ..design
+    }
+}
+
+/// Generate the prefiltered mip pyramid of the environment map `base`.
+///
+/// Returns `num_levels` cube maps. The level `i` is `base.size() >> i` in
+/// size and blurred by `mip_level_sigma(i)` in total. Each level is produced
+/// by downsampling the previous one with a 2×2 box filter and applying the
+/// remaining blur with `ltasgblur::ltasg`, designed by `mip_level_design`.
+///
+/// `base.size()` must be divisible by `2^(num_levels - 1)`.
+pub fn generate_mip_pyramid<T>(
+    base: &CubeMap<T>,
+    num_levels: usize,
+    kernel_sigma: f32,
+    kernel_ratio: f32,
+) -> Vec<CubeMap<T>>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    generate_mip_pyramid_with_progress(base, num_levels, kernel_sigma, kernel_ratio, &mut |_| {})
+}
+
+/// `generate_mip_pyramid` calling `progress` after each phase of the blur
+/// (see `ltasgblur::ltasg_with_progress`), with `BlurProgress::level` set to
+/// the mip level being generated.
+pub fn generate_mip_pyramid_with_progress<T>(
+    base: &CubeMap<T>,
+    num_levels: usize,
+    kernel_sigma: f32,
+    kernel_ratio: f32,
+    progress: &mut dyn FnMut(ltasgblur::BlurProgress),
+) -> Vec<CubeMap<T>>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(num_levels > 0);
+    assert!(base.size() >> (num_levels - 1) << (num_levels - 1) == base.size());
+    assert!(base.size() >> (num_levels - 1) > 0);
+
+    let mut levels: Vec<CubeMap<T>> = Vec::with_capacity(num_levels);
+    for i in 0..num_levels {
+        let input = match levels.last() {
+            Some(last) => last.downsample_2x(),
+            None => base.clone(),
+        };
+        let size = input.size();
+        let design = mip_level_design(i, size, kernel_sigma, kernel_ratio);
+
+        let mut output = input.clone();
+        let mut scratch = input.clone();
+        ltasgblur::ltasg_with_progress(
+            &mut output.faces_mut(),
+            &input.faces(),
+            &mut scratch.faces_mut(),
+            size,
+            &design.kernel,
+            design.kernel_scale,
+            design.num_passes,
+            ltasgblur::StandardCubeMapTrait,
+            &mut |p| progress(ltasgblur::BlurProgress { level: i, ..p }),
+        );
+        levels.push(output);
+    }
+    levels
+}
+
+/// Compute the `i`-th point of the `n`-point Hammersley point set.
+fn hammersley(i: u32, n: u32) -> (f32, f32) {
+    let mut bits = i;
+    bits = (bits << 16) | (bits >> 16);
+    bits = ((bits & 0x55555555) << 1) | ((bits & 0xaaaaaaaa) >> 1);
+    bits = ((bits & 0x33333333) << 2) | ((bits & 0xcccccccc) >> 2);
+    bits = ((bits & 0x0f0f0f0f) << 4) | ((bits & 0xf0f0f0f0) >> 4);
+    bits = ((bits & 0x00ff00ff) << 8) | ((bits & 0xff00ff00) >> 8);
+    (i as f32 / n as f32, bits as f32 * (1.0 / 4294967296.0))
+}
+
+/// Compute the GGX-distributed half vector around `normal` for the sample
+/// point `xi` and `alpha = roughness²`.
+fn importance_sample_ggx(xi: (f32, f32), alpha: f32, normal: Vector3<f32>) -> Vector3<f32> {
+    let phi = f32::consts::PI * 2.0 * xi.0;
+    let cos_theta = ((1.0 - xi.1) / (1.0 + (alpha * alpha - 1.0) * xi.1)).sqrt();
+
+    from_tangent_space(phi, cos_theta, normal)
+}
+
+/// Compute the cosine-distributed direction around `normal` for the sample
+/// point `xi`.
+fn sample_cosine_hemisphere(xi: (f32, f32), normal: Vector3<f32>) -> Vector3<f32> {
+    let phi = f32::consts::PI * 2.0 * xi.0;
+    let cos_theta = (1.0 - xi.1).sqrt();
+    from_tangent_space(phi, cos_theta, normal)
+}
+
+/// Convert the spherical coordinates `(phi, acos(cos_theta))` around
+/// `normal` to a vector.
+fn from_tangent_space(phi: f32, cos_theta: f32, normal: Vector3<f32>) -> Vector3<f32> {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+    let up = if normal.z.abs() < 0.999 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_x()
+    };
+    let tangent_x = up.cross(normal).normalize();
+    let tangent_y = normal.cross(tangent_x);
+
+    tangent_x * (sin_theta * phi.cos()) + tangent_y * (sin_theta * phi.sin()) + normal * cos_theta
+}
+
+/// Prefilter the environment map `base` with the GGX distribution for the
+/// split-sum approximation and write the result to `levels`.
+///
+/// `levels` is the set of output mip levels, whose sizes are usually
+/// progressively halved. The level `i` is prefiltered for the roughness
+/// `i / (levels.len() - 1)` by importance-sampling the GGX NDF with
+/// `sample_count` samples per texel (with the common assumption `N = V = R`),
+/// fetching `base` with `CubeMap::sample_dir`. A roughness of zero simply
+/// resamples `base`.
+pub fn prefilter_ggx<T>(base: &CubeMap<T>, levels: &mut [CubeMap<T>], sample_count: usize)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(sample_count > 0);
+    let num_levels = levels.len();
+
+    for (level, image) in levels.iter_mut().enumerate() {
+        let roughness = if num_levels > 1 {
+            level as f32 / (num_levels - 1) as f32
+        } else {
+            0.0
+        };
+
+        if roughness == 0.0 {
+            image.fill_from_direction(|dir| base.sample_dir(dir));
+            continue;
+        }
+
+        let alpha = roughness * roughness;
+        image.fill_from_direction(|normal| {
+            let mut sum = T::zero();
+            let mut weight_sum = 0.0;
+            for i in 0..sample_count {
+                let xi = hammersley(i as u32, sample_count as u32);
+                let half = importance_sample_ggx(xi, alpha, normal);
+                let light = half * (2.0 * normal.dot(half)) - normal;
+                let n_dot_l = normal.dot(light);
+                if n_dot_l > 0.0 {
+                    sum = sum + base.sample_dir(light) * n_dot_l;
+                    weight_sum += n_dot_l;
+                }
+            }
+            sum * (1.0 / weight_sum)
+        });
+    }
+}
+
+/// Compute the irradiance map of the environment map `src` and write it to
+/// `dst`.
+///
+/// Each texel of `dst` receives the cosine-weighted average of `src` over
+/// the hemisphere around the texel's direction, estimated with `sample_count`
+/// samples fetched by `CubeMap::sample_dir`. This is the irradiance divided
+/// by `π`, so it can be multiplied by the albedo of a Lambertian surface to
+/// obtain the outgoing radiance. A constant environment map thus yields the
+/// same constant.
+///
+/// Since the irradiance is low-frequency, `dst` can be much smaller than
+/// `src` (e.g., 16×16).
+pub fn irradiance_convolve<T>(src: &CubeMap<T>, dst: &mut CubeMap<T>, sample_count: usize)
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(sample_count > 0);
+    let scale = 1.0 / sample_count as f32;
+    dst.fill_from_direction(|normal| {
+        let mut sum = T::zero();
+        for i in 0..sample_count {
+            let xi = hammersley(i as u32, sample_count as u32);
+            sum = sum + src.sample_dir(sample_cosine_hemisphere(xi, normal));
+        }
+        sum * scale
+    });
+}
+
+/// Evaluate the first nine real spherical harmonics basis functions (the
+/// bands `l = 0, 1, 2`) at the normalized direction `dir`.
+///
+/// The functions are ordered by `l` and then `m` (`Y00, Y1-1, Y10, Y11,
+/// Y2-2, Y2-1, Y20, Y21, Y22`).
+pub fn sh9_basis(dir: Vector3<f32>) -> [f32; 9] {
+    let (x, y, z) = (dir.x, dir.y, dir.z);
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3.0 * z * z - 1.0),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
+
+/// Project the environment map `cube` onto the first nine real spherical
+/// harmonics basis functions (see `sh9_basis`).
+///
+/// Each texel is weighted by the solid angle it subtends, so the texels near
+/// the corners of the faces, which cover less of the sphere, contribute less.
+pub fn project_sh9<T>(cube: &CubeMap<T>) -> [T; 9]
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    let size = cube.size();
+    let mut coefs = [T::zero(); 9];
+    for (face, x, y, &texel) in cube.iter_texels() {
+        let basis = sh9_basis(texel_direction(face, x, y, size));
+        let weighted = texel * texel_solid_angle(x, y, size);
+        for (coef, &b) in coefs.iter_mut().zip(basis.iter()) {
+            *coef = *coef + weighted * b;
+        }
+    }
+    coefs
+}
+
+/// Reconstruct the irradiance in the direction `dir` from the coefficients
+/// computed by `project_sh9`, applying the band scaling factors of the
+/// clamped cosine lobe (`π`, `2π/3`, and `π/4`).
+///
+/// Unlike `irradiance_convolve`, the result is not divided by `π`; a constant
+/// environment map with the radiance `L` yields the irradiance `πL`.
+pub fn eval_sh9_irradiance<T>(coefs: &[T; 9], dir: Vector3<f32>) -> T
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    let (a0, a1, a2) = (f32::consts::PI, f32::consts::PI * 2.0 / 3.0, f32::consts::PI * 0.25);
+    let band_scale = [a0, a1, a1, a1, a2, a2, a2, a2, a2];
+    let basis = sh9_basis(dir.normalize());
+    (0..9).fold(T::zero(), |sum, i| sum + coefs[i] * (basis[i] * band_scale[i]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_is_monotonic() {
+        let table = mip_roughness_table(8);
+        assert_eq!(table.len(), 8);
+        for pair in table.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", table);
+        }
+        assert!(table.iter().all(|&r| r > 0.0 && r < 1.0), "{:?}", table);
+    }
+
+    #[test]
+    fn lod_round_trip() {
+        let num_levels = 8;
+        for (level, &roughness) in mip_roughness_table(num_levels).iter().enumerate() {
+            let lod = lod_for_roughness(roughness, num_levels);
+            assert!((lod - level as f32).abs() < 1.0e-3, "{} != {}", lod, level);
+        }
+    }
+
+    #[test]
+    fn roughness_lod_round_trip() {
+        let num_levels = 8;
+        for i in 0..71 {
+            let lod = i as f32 * 0.1;
+            let roughness = roughness_for_lod(lod);
+            let lod2 = lod_for_roughness(roughness, num_levels);
+            assert!((lod - lod2).abs() < 1.0e-3, "{} != {}", lod, lod2);
+        }
+        for i in 1..20 {
+            let roughness = i as f32 * 0.05;
+            let lod = lod_for_roughness(roughness, num_levels);
+            if lod > 0.0 && lod < (num_levels - 1) as f32 {
+                let roughness2 = roughness_for_lod(lod);
+                assert!((roughness - roughness2).abs() < 1.0e-4, "{} != {}", roughness, roughness2);
+            }
+        }
+    }
+
+    #[test]
+    fn lod_is_clamped() {
+        assert_eq!(lod_for_roughness(0.0, 8), 0.0);
+        assert_eq!(lod_for_roughness(0.001, 8), 0.0);
+        assert_eq!(lod_for_roughness(1.0, 8), 7.0);
+        assert_eq!(lod_for_roughness(0.9999, 8), 7.0);
+    }
+
+    #[test]
+    fn prefilter_ggx_constant() {
+        let base = CubeMap::filled(16, 0.75f32);
+        let mut levels: Vec<_> = (0..5).map(|i| CubeMap::zeros(16 >> i)).collect();
+        prefilter_ggx(&base, &mut levels, 64);
+        for (level, image) in levels.iter().enumerate() {
+            for (face, x, y, &texel) in image.iter_texels() {
+                assert!(
+                    (texel - 0.75).abs() < 1.0e-4,
+                    "level {}, {:?} ({}, {}): {}",
+                    level,
+                    face,
+                    x,
+                    y,
+                    texel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn irradiance_convolve_constant() {
+        let src = CubeMap::filled(8, 0.5f32);
+        let mut dst = CubeMap::zeros(4);
+        irradiance_convolve(&src, &mut dst, 64);
+        assert!(dst.iter_texels().all(|(_, _, _, &t)| (t - 0.5).abs() < 1.0e-4));
+    }
+
+    #[test]
+    fn irradiance_convolve_bright_face() {
+        use cubemap::{texel_direction, CubeFace};
+
+        let size = 8;
+        let src = CubeMap::from_fn(16, |face, _, _| {
+            if face == CubeFace::PositiveX {
+                1.0f32
+            } else {
+                0.0
+            }
+        });
+        let mut dst = CubeMap::zeros(size);
+        irradiance_convolve(&src, &mut dst, 1024);
+
+        // Peaks on the texels facing +X
+        let (max_face, _, _, &max) = dst.iter_texels()
+            .max_by(|a, b| a.3.partial_cmp(b.3).unwrap())
+            .unwrap();
+        assert_eq!(max_face, CubeFace::PositiveX);
+        assert!(max > 0.5, "{}", max);
+
+        let center = (size / 2) * (size + 1);
+        assert!(dst.face(CubeFace::NegativeX)[center] < 0.01);
+
+        // Falls off smoothly as the direction turns away from +X
+        for &face in [CubeFace::PositiveY, CubeFace::PositiveZ].iter() {
+            let mut texels: Vec<_> = (0..size * size)
+                .map(|i| {
+                    let dir = texel_direction(face, i % size, i / size, size);
+                    (dir.x, dst.face(face)[i])
+                })
+                .collect();
+            texels.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            for pair in texels.windows(2) {
+                if pair[1].0 - pair[0].0 > 0.05 {
+                    assert!(pair[0].1 < pair[1].1, "{:?} {:?}", face, pair);
+                }
+                assert!((pair[0].1 - pair[1].1).abs() < 0.2, "{:?} {:?}", face, pair);
+            }
+        }
+    }
+
+    #[test]
+    fn project_sh9_constant() {
+        let coefs = project_sh9(&CubeMap::filled(16, 2.0f32));
+
+        // `2 * Y00 * 4π`
+        let expected_dc = 2.0 * 0.282095 * 4.0 * f32::consts::PI;
+        assert!((coefs[0] - expected_dc).abs() < 0.05 * expected_dc, "{:?}", coefs);
+        assert!(coefs[1..].iter().all(|c| c.abs() < 1.0e-4), "{:?}", coefs);
+
+        let irradiance = eval_sh9_irradiance(&coefs, Vector3::new(0.3, -0.5, 0.8));
+        let expected = 2.0 * f32::consts::PI;
+        assert!((irradiance - expected).abs() < 0.05 * expected, "{}", irradiance);
+    }
+
+    #[test]
+    fn mip_pyramid_constant() {
+        let base = CubeMap::filled(32, 0.75f32);
+        let levels = generate_mip_pyramid(&base, 6, 4.0, 2.0);
+        assert_eq!(levels.len(), 6);
+        for (i, level) in levels.iter().enumerate() {
+            assert_eq!(level.size(), 32 >> i);
+            for (face, x, y, &texel) in level.iter_texels() {
+                assert!(
+                    (texel - 0.75).abs() < 1.0e-4,
+                    "level {}, {:?} ({}, {}): {}",
+                    i,
+                    face,
+                    x,
+                    y,
+                    texel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mip_pyramid_sizes_halve() {
+        let base = CubeMap::from_fn(64, |face, x, y| (face.as_ordinal() + x * y) as f32);
+        let levels = generate_mip_pyramid(&base, 4, 4.0, 2.0);
+        let sizes: Vec<usize> = levels.iter().map(|level| level.size()).collect();
+        assert_eq!(sizes, vec![64, 32, 16, 8]);
+    }
+
+    #[test]
+    fn mip_pyramid_progress() {
+        let (kernel_sigma, kernel_ratio) = (4.0, 2.0);
+        let base = CubeMap::filled(32, 1.0f32);
+
+        let mut reports = Vec::new();
+        generate_mip_pyramid_with_progress(&base, 6, kernel_sigma, kernel_ratio, &mut |p| {
+            reports.push(p)
+        });
+
+        let mut expected = 0;
+        for i in 0..6 {
+            let design = mip_level_design(i, 32 >> i, kernel_sigma, kernel_ratio);
+            assert!(design.num_passes > 0);
+
+            let level_reports: Vec<_> = reports.iter().filter(|p| p.level == i).collect();
+            assert_eq!(level_reports.len(), design.num_passes * 3, "level {}", i);
+            assert!(level_reports.iter().all(|p| p.total_passes == design.num_passes));
+            expected += design.num_passes * 3;
+        }
+        assert_eq!(reports.len(), expected);
+    }
+}
@@ -0,0 +1,487 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides traits describing the pixel types accepted by the library, and
+//! conversions from/to 8-bit pixel values.
+use std::ops;
+use cgmath::{num_traits, Vector3, Vector4};
+#[cfg(feature = "half")]
+use half::f16;
+
+/// Pixel types accepted by the blur functions.
+///
+/// The kernel taps of each output texel are summed in `Accumulator`, and the
+/// sum is converted back to the pixel type only once. Any type supporting the
+/// arithmetic operations used by the blur is its own accumulator. Compact
+/// storage types such as `Half4` use a wider accumulator so the rounding
+/// error does not build up over the taps.
+pub trait BlurPixel: Copy {
+    type Accumulator: Copy
+        + ops::Mul<f32, Output = Self::Accumulator>
+        + ops::Add<Output = Self::Accumulator>
+        + num_traits::Zero;
+
+    /// Convert a kernel tap to `Accumulator`.
+    fn to_accumulator(self) -> Self::Accumulator;
+
+    /// Convert the accumulated value back to the pixel type.
+    fn from_accumulator(x: Self::Accumulator) -> Self;
+}
+
+impl<T> BlurPixel for T
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    type Accumulator = T;
+
+    #[inline(always)]
+    fn to_accumulator(self) -> T {
+        self
+    }
+
+    #[inline(always)]
+    fn from_accumulator(x: T) -> T {
+        x
+    }
+}
+
+/// Pixel types having a well-defined luminance.
+pub trait Luminance {
+    /// Compute the relative luminance of a linear-space pixel value using the
+    /// Rec. 709 coefficients.
+    ///
+    /// RGBA pixels are assumed to be premultiplied, so the alpha channel is
+    /// ignored.
+    fn luminance(&self) -> f32;
+}
+
+impl Luminance for f32 {
+    fn luminance(&self) -> f32 {
+        *self
+    }
+}
+
+impl Luminance for Vector3<f32> {
+    fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+}
+
+impl Luminance for Vector4<f32> {
+    fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+}
+
+/// Pixel types consisting of `f32` components.
+pub trait Components: Sized {
+    /// Construct a new pixel value by applying `f` to every component.
+    fn map_components<F: FnMut(f32) -> f32>(&self, f: F) -> Self;
+
+    /// Check whether `f` returns `true` for every component.
+    fn all_components<F: FnMut(f32) -> bool>(&self, f: F) -> bool;
+}
+
+impl Components for f32 {
+    fn map_components<F: FnMut(f32) -> f32>(&self, mut f: F) -> Self {
+        f(*self)
+    }
+
+    fn all_components<F: FnMut(f32) -> bool>(&self, mut f: F) -> bool {
+        f(*self)
+    }
+}
+
+impl Components for Vector3<f32> {
+    fn map_components<F: FnMut(f32) -> f32>(&self, mut f: F) -> Self {
+        Vector3::new(f(self.x), f(self.y), f(self.z))
+    }
+
+    fn all_components<F: FnMut(f32) -> bool>(&self, mut f: F) -> bool {
+        f(self.x) && f(self.y) && f(self.z)
+    }
+}
+
+impl Components for Vector4<f32> {
+    fn map_components<F: FnMut(f32) -> f32>(&self, mut f: F) -> Self {
+        Vector4::new(f(self.x), f(self.y), f(self.z), f(self.w))
+    }
+
+    fn all_components<F: FnMut(f32) -> bool>(&self, mut f: F) -> bool {
+        f(self.x) && f(self.y) && f(self.z) && f(self.w)
+    }
+}
+
+/// Pixel types having RGB color channels.
+pub trait Rgb: Sized {
+    /// Retrieve the RGB channels.
+    fn rgb(&self) -> Vector3<f32>;
+
+    /// Construct a new pixel value by replacing the RGB channels. The other
+    /// channels (if any) are left unchanged.
+    fn with_rgb(&self, rgb: Vector3<f32>) -> Self;
+}
+
+impl Rgb for Vector3<f32> {
+    fn rgb(&self) -> Vector3<f32> {
+        *self
+    }
+
+    fn with_rgb(&self, rgb: Vector3<f32>) -> Self {
+        rgb
+    }
+}
+
+impl Rgb for Vector4<f32> {
+    fn rgb(&self) -> Vector3<f32> {
+        self.truncate()
+    }
+
+    fn with_rgb(&self, rgb: Vector3<f32>) -> Self {
+        rgb.extend(self.w)
+    }
+}
+
+/// Convert an 8-bit RGBA pixel value with straight alpha to a premultiplied,
+/// linear RGBA value, each channel ranging from `0` to `1`.
+///
+/// The sRGB transfer function is approximated with gamma 2.0 (i.e., the color
+/// channels are squared).
+pub fn srgb_u8_to_linear_f32(rgba: [u8; 4]) -> Vector4<f32> {
+    let c = |i: usize| rgba[i] as f32 * (1.0 / 255.0);
+    let alpha = c(3);
+    Vector4::new(c(0) * c(0) * alpha, c(1) * c(1) * alpha, c(2) * c(2) * alpha, alpha)
+}
+
+/// Convert a premultiplied RGBA value to straight alpha and return the color
+/// channels.
+///
+/// The color of a pixel with zero (or negative) alpha is undefined, in which
+/// case zero is returned instead of dividing by the alpha, which would produce
+/// NaN or infinity.
+pub fn unpremultiply(rgba: Vector4<f32>) -> Vector3<f32> {
+    if rgba.w > 0.0 {
+        rgba.truncate() * (1.0 / rgba.w)
+    } else {
+        Vector3::new(0.0, 0.0, 0.0)
+    }
+}
+
+/// The inverse of `srgb_u8_to_linear_f32`. The result is rounded to the
+/// nearest integers and clamped to `[0, 255]`. A pixel with zero (or negative)
+/// alpha is converted to `[0, 0, 0, 0]`.
+pub fn linear_f32_to_srgb_u8(rgba: Vector4<f32>) -> [u8; 4] {
+    if !(rgba.w > 0.0) {
+        return [0, 0, 0, 0];
+    }
+    let quantize = |x: f32| (x * 255.0).round().max(0.0).min(255.0) as u8;
+    let c = |x: f32| quantize(x.max(0.0).sqrt());
+    let rgb = unpremultiply(rgba);
+    [c(rgb.x), c(rgb.y), c(rgb.z), quantize(rgba.w)]
+}
+
+/// Specifies the transfer function with which color values are encoded.
+///
+/// The blur must be performed on linear values; blurring encoded values
+/// darkens the bright parts of the image, which matters the most for HDR
+/// highlights. Note that `srgb_u8_to_linear_f32` and `linear_f32_to_srgb_u8`
+/// use a cheaper approximation (gamma 2.0) instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    /// The exact sRGB transfer function, consisting of a linear segment near
+    /// zero and a power curve of the exponent 2.4.
+    Srgb,
+    /// A pure power curve of the exponent 2.2, a common approximation of
+    /// sRGB.
+    Gamma22,
+    /// No encoding.
+    Linear,
+}
+
+impl ColorSpace {
+    /// Decode a color channel value. Negative values are clamped to zero.
+    /// The values greater than one are decoded by extending the curve.
+    pub fn to_linear(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        match *self {
+            ColorSpace::Srgb => if x <= 0.04045 {
+                x * (1.0 / 12.92)
+            } else {
+                ((x + 0.055) * (1.0 / 1.055)).powf(2.4)
+            },
+            ColorSpace::Gamma22 => x.powf(2.2),
+            ColorSpace::Linear => x,
+        }
+    }
+
+    /// Encode a linear color channel value. This is the inverse of
+    /// `to_linear`.
+    pub fn from_linear(&self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        match *self {
+            ColorSpace::Srgb => if x <= 0.0031308 {
+                x * 12.92
+            } else {
+                x.powf(1.0 / 2.4) * 1.055 - 0.055
+            },
+            ColorSpace::Gamma22 => x.powf(1.0 / 2.2),
+            ColorSpace::Linear => x,
+        }
+    }
+}
+
+/// Specifies the tone mapping operator used by `tonemap_to_u8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ToneMap {
+    /// The simple Reinhard operator `x / (1 + x)`, which maps `[0, ∞)` to
+    /// `[0, 1)` and darkens the mid-tones.
+    Reinhard,
+    /// Krzysztof Narkowicz's curve fit of the ACES filmic tone mapping, which
+    /// preserves more contrast and saturates at about `x = 10`.
+    Aces,
+}
+
+impl ToneMap {
+    /// Apply the operator to a linear color channel value. The result lies in
+    /// `[0, 1]`. Negative values and NaN are mapped to zero.
+    pub fn apply(&self, x: f32) -> f32 {
+        // Also avoids `∞ / ∞`
+        let x = x.max(0.0).min(1.0e9);
+        match *self {
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Aces => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (x * (a * x + b) / (x * (c * x + d) + e)).min(1.0)
+            }
+        }
+    }
+}
+
+/// Tone-map a premultiplied, linear RGBA value and encode it in the same way
+/// as `linear_f32_to_srgb_u8`.
+///
+/// The color channels (converted to straight alpha) are multiplied by
+/// `exposure` and then mapped by `tone_map` into `[0, 1]`, so arbitrarily
+/// bright values do not clip abruptly.
+pub fn tonemap_to_u8(rgba: Vector4<f32>, tone_map: ToneMap, exposure: f32) -> [u8; 4] {
+    let rgb = unpremultiply(rgba) * exposure;
+    let c = |x: f32| tone_map.apply(x) * rgba.w;
+    linear_f32_to_srgb_u8(Vector4::new(c(rgb.x), c(rgb.y), c(rgb.z), rgba.w))
+}
+
+/// An RGBA pixel value made of four half-precision floating-point numbers.
+/// Requires the `half` feature.
+///
+/// This type halves the memory consumption compared to `Vector4<f32>` and can
+/// be used with the blur functions in the same way. The kernel taps are
+/// accumulated in `Vector4<f32>` (see `BlurPixel`), so each output texel is
+/// rounded to `f16` only once. The value range is limited to about `±65504`.
+#[cfg(feature = "half")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Half4(pub [f16; 4]);
+
+#[cfg(feature = "half")]
+impl Half4 {
+    pub fn from_f32(x: Vector4<f32>) -> Self {
+        Half4([
+            f16::from_f32(x.x),
+            f16::from_f32(x.y),
+            f16::from_f32(x.z),
+            f16::from_f32(x.w),
+        ])
+    }
+
+    pub fn to_f32(&self) -> Vector4<f32> {
+        let l = &self.0;
+        Vector4::new(l[0].to_f32(), l[1].to_f32(), l[2].to_f32(), l[3].to_f32())
+    }
+}
+
+#[cfg(feature = "half")]
+impl BlurPixel for Half4 {
+    type Accumulator = Vector4<f32>;
+
+    #[inline(always)]
+    fn to_accumulator(self) -> Vector4<f32> {
+        self.to_f32()
+    }
+
+    #[inline(always)]
+    fn from_accumulator(x: Vector4<f32>) -> Self {
+        Half4::from_f32(x)
+    }
+}
+
+#[cfg(feature = "half")]
+impl Luminance for Half4 {
+    fn luminance(&self) -> f32 {
+        self.to_f32().luminance()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_round_trip() {
+        for &alpha in [255u8, 128].iter() {
+            for i in 0..256 {
+                let c = i as u8;
+                let rgba = [c, 255 - c, c / 2, alpha];
+                let linear = srgb_u8_to_linear_f32(rgba);
+                assert!(linear.w > 0.0 && linear.w <= 1.0);
+                if alpha == 255 {
+                    assert_eq!(linear_f32_to_srgb_u8(linear), rgba);
+                } else {
+                    let result = linear_f32_to_srgb_u8(linear);
+                    assert_eq!(result[3], alpha);
+                    for ch in 0..3 {
+                        assert!((result[ch] as i32 - rgba[ch] as i32).abs() <= 1, "{:?}", result);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_zero_alpha() {
+        assert_eq!(linear_f32_to_srgb_u8(Vector4::new(0.0, 0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(srgb_u8_to_linear_f32([255, 0, 0, 0]), Vector4::new(0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn unpremultiply_zero_alpha() {
+        for &rgba in [
+            Vector4::new(0.0, 0.0, 0.0, 0.0),
+            Vector4::new(0.5, 0.2, 0.1, 0.0),
+            Vector4::new(0.0, 0.0, 0.0, -0.0),
+            Vector4::new(0.5, 0.2, 0.1, -0.1),
+        ].iter()
+        {
+            assert_eq!(unpremultiply(rgba), Vector3::new(0.0, 0.0, 0.0), "{:?}", rgba);
+        }
+        assert_eq!(
+            unpremultiply(Vector4::new(0.5, 0.25, 0.0, 0.5)),
+            Vector3::new(1.0, 0.5, 0.0)
+        );
+    }
+
+    #[test]
+    fn srgb_transfer_function() {
+        let srgb = ColorSpace::Srgb;
+        assert_eq!(srgb.to_linear(0.0), 0.0);
+        assert!((srgb.to_linear(0.04045) - 0.0031308).abs() < 1.0e-6);
+        assert!((srgb.to_linear(1.0) - 1.0).abs() < 1.0e-6);
+        assert!((srgb.to_linear(0.5) - 0.214041).abs() < 1.0e-5);
+
+        // Continuous at the junction of the linear segment
+        let (below, above) = (srgb.to_linear(0.04044), srgb.to_linear(0.04046));
+        assert!(below < above && above - below < 1.0e-5);
+    }
+
+    #[test]
+    fn color_space_round_trip() {
+        for &color_space in [ColorSpace::Srgb, ColorSpace::Gamma22, ColorSpace::Linear].iter() {
+            for i in 0..101 {
+                let x = i as f32 * 0.02;
+                let y = color_space.from_linear(color_space.to_linear(x));
+                assert!((x - y).abs() < 1.0e-5, "{:?}: {} != {}", color_space, x, y);
+            }
+            assert_eq!(color_space.to_linear(-1.0), 0.0);
+        }
+        assert!((ColorSpace::Gamma22.to_linear(0.5) - 0.217638).abs() < 1.0e-5);
+        assert_eq!(ColorSpace::Linear.to_linear(0.25), 0.25);
+    }
+
+    #[test]
+    fn tonemap() {
+        for &tone_map in [ToneMap::Reinhard, ToneMap::Aces].iter() {
+            // A mid-gray (18% reflectance) should end up in the middle of the range
+            let gray = tonemap_to_u8(Vector4::new(0.18, 0.18, 0.18, 1.0), tone_map, 1.0);
+            assert!(gray[0] > 80 && gray[0] < 160, "{:?}: {:?}", tone_map, gray);
+            assert_eq!(gray[0], gray[1]);
+            assert_eq!(gray[3], 255);
+
+            // Bright values stay in range and keep their ordering
+            let mut last = 0;
+            for &x in [1.0, 4.0, 100.0, 1.0e6, 1.0e30, ::std::f32::INFINITY].iter() {
+                let y = tonemap_to_u8(Vector4::new(x, 0.0, 0.0, 1.0), tone_map, 1.0)[0];
+                assert!(y >= last, "{:?}: {} → {}", tone_map, x, y);
+                last = y;
+            }
+            assert!(last >= 254, "{:?}: {}", tone_map, last);
+
+            assert_eq!(
+                tonemap_to_u8(Vector4::new(-1.0, ::std::f32::NAN, 0.0, 1.0), tone_map, 1.0),
+                [0, 0, 0, 255]
+            );
+        }
+    }
+
+    #[cfg(feature = "half")]
+    #[test]
+    fn half4_blur_matches_f32() {
+        use ltasgblur::{gaussian_kernel, ltasg, StandardCubeMapTrait};
+
+        let size = 16;
+        let kernel = gaussian_kernel(4, 2.0);
+        let input: Vec<Vec<Vector4<f32>>> = (0..6)
+            .map(|face| {
+                (0..size * size)
+                    .map(|i| {
+                        let x = ((i * 31 + face * 7) % 13) as f32 + 1.0;
+                        Vector4::new(x, x * 0.5, 20.0 - x, 1.0)
+                    })
+                    .collect()
+            })
+            .collect();
+        let input_half: Vec<Vec<Half4>> = input
+            .iter()
+            .map(|face| face.iter().map(|&x| Half4::from_f32(x)).collect())
+            .collect();
+
+        let mut expected = input.clone();
+        let mut scratch = input.clone();
+        ltasg(
+            &mut expected.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &input.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            &mut scratch.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+            0.5,
+            2,
+            StandardCubeMapTrait,
+        );
+
+        let mut actual = input_half.clone();
+        let mut scratch = input_half.clone();
+        ltasg(
+            &mut actual.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            &input_half.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            &mut scratch.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+            0.5,
+            2,
+            StandardCubeMapTrait,
+        );
+
+        // The kernel taps are accumulated in `f32`, so the only errors are
+        // from rounding the input and the output of each of the six phases
+        // to `f16` (whose relative rounding error is at most `2^-11`), and the
+        // blur, being a weighted average, does not amplify them
+        let tolerance = 7.0 / 2048.0;
+        let expected = expected.iter().flat_map(|face| face.iter());
+        let actual = actual.iter().flat_map(|face| face.iter());
+        for (e, a) in expected.zip(actual) {
+            let a = a.to_f32();
+            for i in 0..4 {
+                assert!((e[i] - a[i]).abs() <= e[i].abs() * tolerance, "{:?} vs {:?}", e, a);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides a rectangular raster image type.
+use std::ops;
+
+/// Specifies how the coordinates outside an image are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WrapMode {
+    /// Use the nearest texel on the edge.
+    Clamp,
+    /// Repeat the image.
+    Repeat,
+}
+
+impl WrapMode {
+    fn apply(&self, i: isize, size: usize) -> usize {
+        let size = size as isize;
+        match self {
+            &WrapMode::Clamp => i.max(0).min(size - 1) as usize,
+            &WrapMode::Repeat => (((i % size) + size) % size) as usize,
+        }
+    }
+}
+
+/// A `width`×`height` raster image stored in the row-major order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image<T> {
+    pub data: Vec<T>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<T> Image<T> {
+    /// Construct an `Image`. Panics if `data.len()` is not `width * height`.
+    pub fn new(width: usize, height: usize, data: Vec<T>) -> Self {
+        assert_eq!(data.len(), width * height);
+        Self {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// Construct an `Image` by evaluating `f(x, y)` for every pixel.
+    pub fn from_fn<F>(width: usize, height: usize, mut f: F) -> Self
+    where
+        F: FnMut(usize, usize) -> T,
+    {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+        Self::new(width, height, data)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &T {
+        assert!(x < self.width && y < self.height);
+        &self.data[x + y * self.width]
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut T {
+        assert!(x < self.width && y < self.height);
+        &mut self.data[x + y * self.width]
+    }
+
+    /// Return the pixel at the possibly out-of-bounds coordinates `(x, y)`.
+    pub fn get_wrapped(&self, x: isize, y: isize, wrap_x: WrapMode, wrap_y: WrapMode) -> &T {
+        self.get(wrap_x.apply(x, self.width), wrap_y.apply(y, self.height))
+    }
+
+    /// Sample the image with bilinear filtering. See `sample_bilinear` for
+    /// the parameters.
+    pub fn get_bilinear(&self, x: f32, y: f32, wrap_x: WrapMode, wrap_y: WrapMode) -> T
+    where
+        T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+    {
+        sample_bilinear(&self.data, self.width, self.height, x, y, wrap_x, wrap_y)
+    }
+}
+
+/// Sample a `width`×`height` raster image stored in the row-major order in
+/// `data` with bilinear filtering.
+///
+/// `(x, y)` are measured in pixels, and the center of the pixel `(i, j)` is
+/// located at `(i + 0.5, j + 0.5)` (the same convention as OpenGL's). The
+/// filter footprint outside the image is handled according to `wrap_x` and
+/// `wrap_y`.
+pub fn sample_bilinear<T>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    x: f32,
+    y: f32,
+    wrap_x: WrapMode,
+    wrap_y: WrapMode,
+) -> T
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T>,
+{
+    let data = &data[0..width * height];
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as isize, y0 as isize);
+    let fetch = |dx: isize, dy: isize| {
+        data[wrap_x.apply(x0 + dx, width) + wrap_y.apply(y0 + dy, height) * width]
+    };
+    let top = fetch(0, 0) * (1.0 - fx) + fetch(1, 0) * fx;
+    let bottom = fetch(0, 1) * (1.0 - fx) + fetch(1, 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Image<f32> {
+        // 0  1  2
+        // 3  4  5
+        Image::from_fn(3, 2, |x, y| (x + y * 3) as f32)
+    }
+
+    #[test]
+    fn bilinear_pixel_centers() {
+        let image = test_image();
+        for &wrap in [WrapMode::Clamp, WrapMode::Repeat].iter() {
+            for y in 0..2 {
+                for x in 0..3 {
+                    let value = image.get_bilinear(x as f32 + 0.5, y as f32 + 0.5, wrap, wrap);
+                    assert_eq!(value, *image.get(x, y));
+                }
+            }
+            // Midway between four pixels
+            assert_eq!(image.get_bilinear(1.0, 1.0, wrap, wrap), 2.0);
+        }
+    }
+
+    #[test]
+    fn bilinear_boundaries() {
+        let image = test_image();
+        let (clamp, repeat) = (WrapMode::Clamp, WrapMode::Repeat);
+
+        // The left edge of the top-left pixel
+        assert_eq!(image.get_bilinear(0.0, 0.5, clamp, clamp), 0.0);
+        assert_eq!(image.get_bilinear(0.0, 0.5, repeat, clamp), 1.0);
+
+        // The right edge of the bottom-right pixel
+        assert_eq!(image.get_bilinear(3.0, 1.5, clamp, clamp), 5.0);
+        assert_eq!(image.get_bilinear(3.0, 1.5, repeat, clamp), 4.0);
+
+        // The top edge
+        assert_eq!(image.get_bilinear(1.5, 0.0, clamp, clamp), 1.0);
+        assert_eq!(image.get_bilinear(1.5, 0.0, clamp, repeat), 2.5);
+
+        // Far outside
+        assert_eq!(image.get_bilinear(-10.0, -10.0, clamp, clamp), 0.0);
+        assert_eq!(image.get_bilinear(1.5 + 30.0, 1.5 - 20.0, repeat, repeat), 4.0);
+    }
+}
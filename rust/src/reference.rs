@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides a brute-force spherical Gaussian blur to validate the output of
+//! `ltasgblur` against.
+//!
+//! `ltasgblur` approximates a spherical Gaussian blur in linear time. The
+//! functions in this module evaluate the true angular Gaussian for every pair
+//! of output and input texels instead, which takes `O(size⁴)` time. They are
+//! intended for small sizes (e.g., `size ≤ 32`) in tests and accuracy
+//! benchmarks.
+use std::{f32, ops};
+use cgmath::{num_traits, Vector3};
+use cgmath::prelude::*;
+
+use container::CubeMap;
+use cubemap::{texel_direction, texel_solid_angle, CUBE_FACES};
+
+/// Blur `cube` by a spherical Gaussian of the standard deviation `sigma`.
+///
+/// `sigma` is measured in the face size, like `ltasgblur::BlurBuilder::sigma`
+/// and `pbr::mip_level_sigma`; i.e., the angular standard deviation is
+/// `2 * sigma` radians, which is the blur `ltasg` applies at the face centers.
+///
+/// Each output texel is the weighted average of all input texels, where the
+/// weight is the Gaussian of the angular distance between the texel centers
+/// multiplied by the solid angle of the input texel. Each input texel is
+/// split into `sample_count`×`sample_count` samples to integrate the Gaussian
+/// more accurately when `sigma` is not much larger than a texel. The inputs
+/// farther than `6 * sigma` are ignored. If `sigma` is so small that no
+/// sample gets a nonzero weight, the output texel is copied from the nearest
+/// input texel, i.e., the one at the same position.
+pub fn spherical_gaussian_reference<T>(
+    cube: &CubeMap<T>,
+    sigma: f32,
+    sample_count: usize,
+) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(sigma > 0.0);
+    assert!(sample_count > 0);
+    let size = cube.size();
+    let angular_sigma = sigma * 2.0;
+    let min_cos = (angular_sigma * 6.0).min(f32::consts::PI).cos();
+
+    // The direction and the solid angle of every input sample, and the texel
+    // it belongs to
+    let samples: Vec<(Vector3<f32>, f32, T)> = CUBE_FACES
+        .iter()
+        .flat_map(|&face| (0..size * size).map(move |i| (face, i % size, i / size)))
+        .flat_map(|(face, x, y)| {
+            let texel = cube.face(face)[x + y * size];
+            let d_omega = texel_solid_angle(x, y, size) / (sample_count * sample_count) as f32;
+            let uv = move |i: usize, j: usize| {
+                (i as f32 + (j as f32 + 0.5) / sample_count as f32) * 2.0 / size as f32 - 1.0
+            };
+            (0..sample_count * sample_count).map(move |j| {
+                let (u, v) = (uv(x, j % sample_count), uv(y, j / sample_count));
+                let dir = face.normal::<f32>() + face.u_vec::<f32>() * u +
+                    face.v_vec::<f32>() * v;
+                (dir.normalize(), d_omega, texel)
+            })
+        })
+        .collect();
+
+    CubeMap::from_fn(size, |face, x, y| {
+        let dir = texel_direction(face, x, y, size);
+        let mut sum = T::zero();
+        let mut weight_sum = 0.0f64;
+        for &(sample_dir, d_omega, texel) in samples.iter() {
+            let cos = dir.dot(sample_dir);
+            if cos < min_cos {
+                continue;
+            }
+            let angle = cos.min(1.0).acos();
+            let weight = (-0.5 * (angle / angular_sigma).powi(2)).exp() * d_omega;
+            sum = sum + texel * weight;
+            weight_sum += weight as f64;
+        }
+        if weight_sum == 0.0 {
+            cube.face(face)[x + y * size]
+        } else {
+            sum * (1.0 / weight_sum) as f32
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubemap::CubeFace;
+    use ltasgblur::BlurBuilder;
+
+    #[test]
+    fn constant_stays_constant() {
+        let cube = CubeMap::filled(8, 0.5f32);
+        let blurred = spherical_gaussian_reference(&cube, 0.1, 2);
+        for (_, _, _, &texel) in blurred.iter_texels() {
+            assert!((texel - 0.5).abs() < 1.0e-5, "{}", texel);
+        }
+    }
+
+    #[test]
+    fn tiny_sigma_keeps_texels() {
+        // The samples are a quarter texel away from the texel centers, where
+        // the Gaussian underflows to zero
+        let size = 8;
+        let cube = CubeMap::from_fn(size, |face, x, y| {
+            (face.as_ordinal() * 100 + x * 10 + y) as f32
+        });
+        let blurred = spherical_gaussian_reference(&cube, 1.0e-4, 2);
+        assert_eq!(blurred, cube);
+    }
+
+    #[test]
+    fn ltasg_matches_reference() {
+        // A spherical cap around +X, which is blurred into a smooth transition
+        let size = 32;
+        let sigma = 0.05;
+        let cube = CubeMap::from_fn(size, |face, x, y| {
+            if texel_direction(face, x, y, size).x > 0.5 {
+                1.0f32
+            } else {
+                0.0
+            }
+        });
+
+        let expected = spherical_gaussian_reference(&cube, sigma, 2);
+        let mut actual = cube.clone();
+        BlurBuilder::new().sigma(sigma).run(&mut actual);
+
+        let (mut sum_sq, mut count) = (0.0f32, 0);
+        for ((face, x, y, &a), (_, _, _, &e)) in
+            actual.iter_texels().zip(expected.iter_texels())
+        {
+            sum_sq += (a - e) * (a - e);
+            count += 1;
+            assert!((a - e).abs() < 0.15, "{:?} ({}, {}): {} != {}", face, x, y, a, e);
+        }
+        let rms = (sum_sq / count as f32).sqrt();
+        assert!(rms < 0.02, "RMS error: {}", rms);
+
+        // The transition is actually blurred
+        let face = actual.face(CubeFace::PositiveX);
+        assert!(face.iter().any(|&x| x > 0.1 && x < 0.9));
+    }
+}
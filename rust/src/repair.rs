@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides a function for detecting and repairing non-finite texels.
+use std::ops;
+use cgmath::num_traits;
+
+use cubemap::{gather_offset, CUBE_FACES};
+use pixel::Components;
+
+/// Specifies how `repair_faces` replaces a non-finite texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepairPolicy {
+    /// Replace the whole texel with zero.
+    Zero,
+
+    /// Replace each non-finite component: +∞ and NaN (both usually the result
+    /// of a saturated sensor) become the given value, and -∞ becomes its
+    /// negation. The finite components are kept.
+    ClampTo(f32),
+
+    /// Replace the whole texel with the average of its finite neighbors
+    /// among the eight surrounding texels (continuing across the face seams),
+    /// or zero if there is none.
+    NeighborAverage,
+}
+
+/// The result of `repair_faces`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RepairReport {
+    /// The number of the repaired texels in each face, in the order of
+    /// `CUBE_FACES`.
+    pub num_repaired: [usize; 6],
+}
+
+impl RepairReport {
+    /// Return the total number of the repaired texels.
+    pub fn total(&self) -> usize {
+        self.num_repaired.iter().sum()
+    }
+}
+
+fn is_finite<T: Components>(x: &T) -> bool {
+    x.all_components(f32::is_finite)
+}
+
+/// Find the texels containing a NaN or infinite component and replace them
+/// according to `policy`.
+///
+/// A single non-finite texel poisons the entire footprint of the blur (and
+/// eventually the whole output after a few passes), so captured environment
+/// maps should be passed through this function before processing them.
+/// `faces` is a slice of six `size`×`size` raster images.
+pub fn repair_faces<T>(faces: &mut [&mut [T]], size: usize, policy: RepairPolicy) -> RepairReport
+where
+    T: Copy + Components + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    let mut report = RepairReport::default();
+
+    // Compute the replacements first so that `NeighborAverage` only sees the
+    // original values
+    let replacements: Vec<(usize, usize, T)> = {
+        let faces: Vec<&[T]> = faces[0..6].iter().map(|image| &image[0..size * size]).collect();
+        let mut replacements = Vec::new();
+        for (&face, image) in CUBE_FACES.iter().zip(faces.iter()) {
+            for (i, texel) in image.iter().enumerate() {
+                if is_finite(texel) {
+                    continue;
+                }
+                let new_value = match policy {
+                    RepairPolicy::Zero => T::zero(),
+                    RepairPolicy::ClampTo(max) => texel.map_components(|x| if x.is_finite() {
+                        x
+                    } else if x == -::std::f32::INFINITY {
+                        -max
+                    } else {
+                        max
+                    }),
+                    RepairPolicy::NeighborAverage => {
+                        let (x, y) = (i % size, i / size);
+                        let mut sum = T::zero();
+                        let mut count = 0;
+                        for dy in -1..2 {
+                            for dx in -1..2 {
+                                let neighbor = gather_offset(&faces, size, face, x, y, dx, dy);
+                                if is_finite(&neighbor) {
+                                    sum = sum + neighbor;
+                                    count += 1;
+                                }
+                            }
+                        }
+                        if count > 0 {
+                            sum * (1.0 / count as f32)
+                        } else {
+                            T::zero()
+                        }
+                    }
+                };
+                replacements.push((face.as_ordinal(), i, new_value));
+            }
+        }
+        replacements
+    };
+
+    for (face_i, i, new_value) in replacements {
+        faces[face_i][i] = new_value;
+        report.num_repaired[face_i] += 1;
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32;
+    use cgmath::Vector4;
+    use cubemap::CubeFace;
+
+    #[test]
+    fn clean_reports_nothing() {
+        let size = 8;
+        let mut faces = vec![vec![Vector4::new(0.5f32, 1.0, 2.0, 1.0); size * size]; 6];
+        let expected = faces.clone();
+        let report = repair_faces(
+            &mut faces.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+            size,
+            RepairPolicy::NeighborAverage,
+        );
+        assert_eq!(report.total(), 0);
+        assert_eq!(faces, expected);
+    }
+
+    #[test]
+    fn repairs_injected_values() {
+        let size = 8;
+        let face_i = CubeFace::NegativeY.as_ordinal();
+        for &(policy, expected) in [
+            (RepairPolicy::Zero, Vector4::new(0.0, 0.0, 0.0, 0.0)),
+            (RepairPolicy::ClampTo(10.0), Vector4::new(10.0, 10.0, -10.0, 1.0)),
+            (RepairPolicy::NeighborAverage, Vector4::new(0.5, 0.5, 0.5, 1.0)),
+        ].iter()
+        {
+            let mut faces = vec![vec![Vector4::new(0.5f32, 0.5, 0.5, 1.0); size * size]; 6];
+            faces[face_i][0] = Vector4::new(f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1.0);
+            faces[face_i][1].y = f32::NAN;
+
+            let report = repair_faces(
+                &mut faces.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                size,
+                policy,
+            );
+
+            assert_eq!(report.num_repaired, [0, 0, 0, 2, 0, 0]);
+            assert_eq!(faces[face_i][0], expected, "{:?}", policy);
+            assert!(faces.iter().all(|image| image.iter().all(is_finite)));
+        }
+    }
+}
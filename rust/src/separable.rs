@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides a cube map blur built from per-face separable passes and a
+//! seam-correction pass.
+use std::{cmp, ops};
+use cgmath::num_traits;
+
+use container::CubeMap;
+use cubemap::{gather_offset, CUBE_FACES};
+
+/// Blur a cube map with a Gaussian kernel by processing each face as a flat
+/// image and then correcting the texels near the seams.
+///
+/// The blur is done in two steps:
+///
+///  1. Each face is blurred with the separable kernel `kernel` (measured in
+///     texels) in the horizontal and vertical directions, clamping the taps
+///     at the face boundary. This step has no branches or cross-face
+///     accesses and is trivially vectorizable.
+///  2. The texels within `kernel.len() / 2` texels of an edge, where step 1
+///     read clamped values, are recomputed by the same separable passes over
+///     a copy of the face padded with the texels of the adjacent faces
+///     (fetched via `gather_offset`).
+///
+/// This is an alternative to the three `ltasg_single` phases for small to
+/// moderate kernels. Unlike `ltasg_single`, the kernel is not adjusted for
+/// the cube-to-sphere distortion, so the blur measured on the sphere
+/// becomes up to about 1.7 (= √3) times narrower toward the corners, and the
+/// padding in step 2 unfolds the faces flat across each seam. Near the
+/// corners, where only three faces meet, the missing quarter of the padding
+/// is excluded and the kernel is renormalized over the rest, so an input
+/// texel near a corner is weighted up to 4/3 times as much as elsewhere. For
+/// smooth inputs, the output differs from that of `ltasg_single` by a few
+/// percent at most; the difference concentrates around the corners.
+///
+/// See the `separable_*` and `blur_all_phases_*` benchmarks for the
+/// performance comparison.
+///
+/// `faces` is a slice of six `size`×`size` raster images. `kernel.len()` must
+/// be odd and `kernel.len() / 2` must be less than `size`.
+pub fn separable_blur<T>(faces: &[&[T]], size: usize, kernel: &[f32]) -> CubeMap<T>
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add<Output = T> + num_traits::Zero,
+{
+    assert!(kernel.len() % 2 == 1);
+    let radius = kernel.len() / 2;
+    assert!(radius < size);
+
+    let clamp = |i: isize| cmp::max(0, cmp::min(size as isize - 1, i)) as usize;
+
+    // Step 1: per-face separable blur
+    let mut output = CubeMap::zeros(size);
+    let mut temp = vec![T::zero(); size * size];
+    for &face in CUBE_FACES.iter() {
+        let image = &faces[face.as_ordinal()][0..size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let mut sum = T::zero();
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let sx = clamp(x as isize + k as isize - radius as isize);
+                    sum = sum + image[sx + y * size] * weight;
+                }
+                temp[x + y * size] = sum;
+            }
+        }
+        let out = output.face_mut(face);
+        for y in 0..size {
+            for x in 0..size {
+                let mut sum = T::zero();
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let sy = clamp(y as isize + k as isize - radius as isize);
+                    sum = sum + temp[x + sy * size] * weight;
+                }
+                out[x + y * size] = sum;
+            }
+        }
+    }
+
+    // Step 2: seam correction. The face is padded with `radius` texels from
+    // the adjacent faces, and then the separable passes are evaluated again
+    // only where they read the padding. No texels exist in the padding
+    // diagonally adjacent to the corners, so they are excluded by giving them
+    // zero weight. The passes are applied to the weights as well to
+    // renormalize the kernel over the remaining taps.
+    let r = radius as isize;
+    let padded_size = size + radius * 2;
+    let in_band = |i: usize| i < radius || i >= size - radius;
+    let in_face = |i: isize| i >= 0 && i < size as isize;
+    let mut padded = vec![T::zero(); padded_size * padded_size];
+    let padded_weight: Vec<f32> = (0..padded_size * padded_size)
+        .map(|i| {
+            let (x, y) = ((i % padded_size) as isize - r, (i / padded_size) as isize - r);
+            if in_face(x) || in_face(y) { 1.0 } else { 0.0 }
+        })
+        .collect();
+    let mut temp = vec![T::zero(); size * padded_size];
+    let mut temp_weight = vec![0.0f32; size * padded_size];
+    let kernel_sum: f32 = kernel.iter().sum();
+    for &face in CUBE_FACES.iter() {
+        for py in 0..padded_size {
+            for px in 0..padded_size {
+                let (x, y) = (px as isize - r, py as isize - r);
+                if in_face(x) || in_face(y) {
+                    padded[px + py * padded_size] = gather_offset(faces, size, face, 0, 0, x, y);
+                }
+            }
+        }
+
+        // Horizontal pass. The vertical pass reads the rows within `radius`
+        // texels of the bands, so those are computed for all columns.
+        for py in 0..padded_size {
+            let full_row = py < radius * 3 || py >= size - radius;
+            for x in 0..size {
+                if !full_row && !in_band(x) {
+                    continue;
+                }
+                let mut sum = T::zero();
+                let mut weight_sum = 0.0;
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let i = x + k + py * padded_size;
+                    sum = sum + padded[i] * weight;
+                    weight_sum += padded_weight[i] * weight;
+                }
+                temp[x + py * size] = sum;
+                temp_weight[x + py * size] = weight_sum;
+            }
+        }
+
+        // Vertical pass
+        let out = output.face_mut(face);
+        for y in 0..size {
+            for x in 0..size {
+                if !in_band(x) && !in_band(y) {
+                    continue;
+                }
+                let mut sum = T::zero();
+                let mut weight_sum = 0.0;
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let i = x + (y + k) * size;
+                    sum = sum + temp[i] * weight;
+                    weight_sum += temp_weight[i] * weight;
+                }
+                // Scale to the total weight of step 1 (which is not
+                // necessarily one) so the seams blend with the interior
+                out[x + y * size] = sum * (kernel_sum * kernel_sum / weight_sum);
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::swap;
+    use cgmath::Vector3;
+    use cgmath::prelude::*;
+    use cubemap::{wrap_texel, CubeFace};
+    use ltasgblur::{gaussian_kernel, ltasg_single, StandardCubeMapTrait};
+
+    #[test]
+    fn constant_stays_constant() {
+        let size = 16;
+        let kernel = gaussian_kernel(6, 3.0);
+        let faces = vec![vec![0.3f32; size * size]; 6];
+        let faces: Vec<_> = faces.iter().map(Vec::as_slice).collect();
+        let out = separable_blur(&faces, size, &kernel);
+        for (_, _, _, &texel) in out.iter_texels() {
+            assert!((texel - 0.3).abs() < 1.0e-5, "{}", texel);
+        }
+    }
+
+    /// Blur a single bright texel at `(x0, y0)` on `face` and return the
+    /// output texels in the unfolded `kernel.len()`×`kernel.len()` window
+    /// around it, along with the values expected on a flat plane. The
+    /// offsets leaving the face in both directions, which have no
+    /// counterparts on the cube, are omitted.
+    fn impulse_response(
+        face: CubeFace,
+        x0: usize,
+        y0: usize,
+        size: usize,
+        kernel: &[f32],
+    ) -> (CubeMap<f32>, Vec<(f32, f32)>) {
+        let mut src = vec![vec![0f32; size * size]; 6];
+        src[face.as_ordinal()][x0 + y0 * size] = 1.0;
+        let src: Vec<_> = src.iter().map(Vec::as_slice).collect();
+        let out = separable_blur(&src, size, kernel);
+
+        let r = (kernel.len() / 2) as isize;
+        let in_face = |i: isize| i >= 0 && i < size as isize;
+        let mut pairs = Vec::new();
+        for dy in -r..r + 1 {
+            for dx in -r..r + 1 {
+                let (x, y) = (x0 as isize + dx, y0 as isize + dy);
+                if in_face(x) || in_face(y) {
+                    let (out_face, i) = wrap_texel(face, x, y, size);
+                    let expected = kernel[(dx + r) as usize] * kernel[(dy + r) as usize];
+                    pairs.push((out.face(out_face)[i], expected));
+                }
+            }
+        }
+        (out, pairs)
+    }
+
+    #[test]
+    fn seams_unfold_flat() {
+        // A bright texel on an edge (away from the corners) must spread
+        // across the seam exactly as on a flat plane. The window includes
+        // both sides of the seam, so this checks the seam texels directly.
+        let size = 32;
+        let kernel = gaussian_kernel(6, 3.0);
+        let mid = size / 2;
+        for &face in CUBE_FACES.iter() {
+            for &(x0, y0) in [(0, mid), (size - 1, mid), (mid, 0), (mid, size - 1)].iter() {
+                let (out, pairs) = impulse_response(face, x0, y0, size, &kernel);
+                for &(actual, expected) in pairs.iter() {
+                    assert!(
+                        (actual - expected).abs() < 1.0e-6,
+                        "{:?} {:?}: {} {}",
+                        face,
+                        (x0, y0),
+                        actual,
+                        expected
+                    );
+                }
+                let total: f32 = out.iter_texels().map(|(_, _, _, &x)| x).sum();
+                assert!((total - 1.0).abs() < 1.0e-5, "{}", total);
+            }
+        }
+    }
+
+    #[test]
+    fn corners_renormalized() {
+        // Near a corner, the missing quarter of the padding increases the
+        // weights of the other taps by up to 4/3. The bright texel must not
+        // be duplicated into the missing quarter.
+        let size = 32;
+        let kernel = gaussian_kernel(6, 3.0);
+        let last = size - 1;
+        for &face in CUBE_FACES.iter() {
+            for &(x0, y0) in [(0, 0), (last, 0), (0, last), (last, last), (2, 1)].iter() {
+                let (out, pairs) = impulse_response(face, x0, y0, size, &kernel);
+                for &(actual, expected) in pairs.iter() {
+                    assert!(
+                        actual >= expected * (1.0 - 1.0e-5) &&
+                            actual <= expected * (4.0 / 3.0 + 1.0e-5),
+                        "{:?} {:?}: {} {}",
+                        face,
+                        (x0, y0),
+                        actual,
+                        expected
+                    );
+                }
+
+                // Nothing leaks outside the window. (The taps falling in the
+                // missing quarter are lost, so the total may fall below one.)
+                let total: f32 = out.iter_texels().map(|(_, _, _, &x)| x).sum();
+                let window_total: f32 = pairs.iter().map(|&(actual, _)| actual).sum();
+                assert!((total - window_total).abs() < 1.0e-5, "{} {}", total, window_total);
+            }
+        }
+    }
+
+    #[test]
+    fn close_to_ltasg() {
+        let size = 64;
+        let kernel = gaussian_kernel(12, 4.0);
+
+        // A smooth function of the direction
+        let uv = |c: usize| (c as f32 * 2.0 + 1.0) / size as f32 - 1.0;
+        let src = CubeMap::from_fn(size, |face: CubeFace, x, y| {
+            let dir = (face.normal::<f32>() + face.u_vec::<f32>() * uv(x) +
+                           face.v_vec::<f32>() * uv(y))
+                .normalize();
+            1.0 + 0.5 * dir.dot(Vector3::new(0.3, 0.8, -0.5)) + 0.3 * dir.x * dir.z
+        });
+        let src_faces: Vec<Vec<f32>> = (&src).into_iter().map(|(_, f)| f.to_vec()).collect();
+
+        let actual = separable_blur(
+            &src_faces.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            size,
+            &kernel,
+        );
+
+        let mut expected = src_faces.clone();
+        let mut temp = src_faces.clone();
+        for phase in 0..3 {
+            ltasg_single(
+                &mut temp.iter_mut().map(Vec::as_mut_slice).collect::<Vec<_>>(),
+                &expected.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                size,
+                &kernel,
+                1.0,
+                phase,
+                StandardCubeMapTrait,
+            );
+            swap(&mut expected, &mut temp);
+        }
+
+        let mut max_diff = 0.0f32;
+        for (face, x, y, &value) in actual.iter_texels() {
+            let diff = (value - expected[face.as_ordinal()][x + y * size]).abs();
+            max_diff = max_diff.max(diff);
+        }
+        assert!(max_diff < 0.02, "{}", max_diff);
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright (c) 2017 yvt
+ *
+ * This file is a part of hyper3d-envmapgen. Please read the license text that
+ * comes with the source code for use conditions.
+ */
+//! Provides functions for analyzing the contents of cube maps.
+use std::cmp::Ordering;
+
+use cgmath::Vector3;
+use cgmath::prelude::*;
+use container::CubeMap;
+use cubemap::{texel_solid_angle, wrap_texel, CUBE_FACES};
+use pixel::Luminance;
+
+/// Estimate the amount of high-frequency detail in a cube map.
+///
+/// Computes the variance of the discrete Laplacian (the 4-neighbor stencil,
+/// crossing the face boundaries into the adjacent faces) of the luminance,
+/// normalized by the variance of the luminance itself. The result is
+/// independent of the overall brightness and ranges from `0` (a constant or
+/// very smooth environment) to approximately `1` (a checkerboard with a
+/// period of two texels). Note that texels are not weighted by their solid
+/// angles.
+///
+/// `faces` is a slice of six `size`×`size` raster images.
+pub fn estimate_detail<T>(faces: &[&[T]], size: usize) -> f32
+where
+    T: Luminance,
+{
+    let faces = &faces[0..6];
+    let count = (6 * size * size) as f64;
+    let lum = |face: usize, i: usize| faces[face][i].luminance() as f64;
+
+    let mut lum_sum = 0.0f64;
+    let mut lum_sq_sum = 0.0f64;
+    let mut lap_sum = 0.0f64;
+    let mut lap_sq_sum = 0.0f64;
+
+    for &face in CUBE_FACES.iter() {
+        let image = &faces[face.as_ordinal()][0..size * size];
+        for y in 0..size as isize {
+            for x in 0..size as isize {
+                let center = image[(x + y * size as isize) as usize].luminance() as f64;
+                let neighbors: f64 = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .map(|&(dx, dy)| {
+                        let (face, i) = wrap_texel(face, x + dx, y + dy, size);
+                        lum(face.as_ordinal(), i)
+                    })
+                    .sum();
+                let lap = center * 4.0 - neighbors;
+
+                lum_sum += center;
+                lum_sq_sum += center * center;
+                lap_sum += lap;
+                lap_sq_sum += lap * lap;
+            }
+        }
+    }
+
+    let lum_var = lum_sq_sum / count - (lum_sum / count).powi(2);
+    let lap_var = lap_sq_sum / count - (lap_sum / count).powi(2);
+
+    // The eigenvalues of the 4-neighbor Laplacian lie in `[0, 8]`, so
+    // `lap_var` is at most `64 * lum_var`
+    if lum_var <= lum_sq_sum / count * 1.0e-9 {
+        0.0
+    } else {
+        (lap_var / (64.0 * lum_var)).max(0.0) as f32
+    }
+}
+
+/// Compute the dominant light direction of a cube map, which is the mean of
+/// the texel directions weighted by their luminance and solid angle.
+///
+/// Returns `None` if the weighted directions cancel out (e.g., the cube map
+/// is black or uniform). Texels with a negative luminance are ignored.
+pub fn dominant_direction<T>(cube_map: &CubeMap<T>) -> Option<Vector3<f32>>
+where
+    T: Luminance,
+{
+    let size = cube_map.size();
+    let mut sum = Vector3::zero();
+    let mut weight_sum = 0.0f32;
+    for (_, i, dir, texel) in cube_map.iter_faces_with_dir() {
+        let weight = texel.luminance().max(0.0) * texel_solid_angle(i % size, i / size, size);
+        sum += dir * weight;
+        weight_sum += weight;
+    }
+
+    // Allow for the rounding errors in the sum of a uniform cube map
+    if sum.magnitude() > weight_sum * 1.0e-4 {
+        Some(sum.normalize())
+    } else {
+        None
+    }
+}
+
+/// Specifies the statistic `auto_exposure` adjusts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExposureTarget {
+    /// Map the maximum luminance to the given value.
+    Max(f32),
+    /// Map the given percentile (in the range `[0, 100]`) of the luminance
+    /// distribution to the given value. Less sensitive to small, very bright
+    /// light sources than `Max`.
+    Percentile { percentile: f32, value: f32 },
+    /// Map the mean luminance to the given value.
+    Mean(f32),
+}
+
+/// Compute the factor by which the cube map must be scaled to achieve the
+/// given exposure target.
+///
+/// The cube map is not modified. Apply the returned factor with, e.g.,
+/// `CubeMap::map`, and store it if it must be undone at render time. Returns
+/// `1` if the chosen statistic is zero or not finite (e.g., for an all-black
+/// environment map). Texels are not weighted by their solid angles.
+///
+/// `faces` is a slice of six `size`×`size` raster images.
+pub fn auto_exposure<T>(faces: &[&[T]], size: usize, target: ExposureTarget) -> f32
+where
+    T: Luminance,
+{
+    let texels = faces[0..6].iter().flat_map(|image| image[0..size * size].iter());
+
+    let (statistic, value) = match target {
+        ExposureTarget::Max(value) => (
+            texels.map(Luminance::luminance).fold(0.0f32, |x, y| x.max(y)),
+            value,
+        ),
+        ExposureTarget::Percentile { percentile, value } => {
+            let mut lums: Vec<f32> = texels.map(Luminance::luminance).collect();
+            if lums.is_empty() {
+                return 1.0;
+            }
+            let rank = (percentile.max(0.0).min(100.0) / 100.0 * (lums.len() - 1) as f32)
+                .round() as usize;
+            let (_, &mut nth, _) = lums.select_nth_unstable_by(rank, |x, y| {
+                x.partial_cmp(y).unwrap_or(Ordering::Equal)
+            });
+            (nth, value)
+        }
+        ExposureTarget::Mean(value) => {
+            let (sum, count) = texels.fold((0.0f64, 0usize), |(sum, count), x| {
+                (sum + x.luminance() as f64, count + 1)
+            });
+            ((sum / count as f64) as f32, value)
+        }
+    };
+
+    let scale = value / statistic;
+    if statistic > 0.0 && scale.is_finite() {
+        scale
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate(faces: &Vec<Vec<f32>>, size: usize) -> f32 {
+        let faces: Vec<_> = faces.iter().map(Vec::as_slice).collect();
+        estimate_detail(&faces, size)
+    }
+
+    #[test]
+    fn dominant_direction_single_texel() {
+        use cubemap::{texel_direction, CubeFace};
+        let size = 8;
+        for &(face, x, y) in [
+            (CubeFace::PositiveX, 0, 0),
+            (CubeFace::NegativeY, 3, 5),
+            (CubeFace::PositiveZ, 7, 2),
+        ].iter()
+        {
+            let mut cube_map = CubeMap::zeros(size);
+            cube_map.face_mut(face)[x + y * size] = 10.0f32;
+            let dir = dominant_direction(&cube_map).unwrap();
+            let expected = texel_direction(face, x, y, size);
+            assert!((dir - expected).magnitude() < 1.0e-5, "{:?} vs {:?}", dir, expected);
+        }
+
+        assert_eq!(dominant_direction(&CubeMap::<f32>::zeros(size)), None);
+        assert_eq!(dominant_direction(&CubeMap::filled(size, 3.0f32)), None);
+    }
+
+    #[test]
+    fn constant_has_no_detail() {
+        let size = 16;
+        assert_eq!(estimate(&vec![vec![0.0; size * size]; 6], size), 0.0);
+        assert_eq!(estimate(&vec![vec![42.0; size * size]; 6], size), 0.0);
+    }
+
+    #[test]
+    fn checkerboard_has_high_detail() {
+        let size = 16;
+        let faces = vec![(0..size * size).map(|i| ((i + i / size) % 2) as f32).collect(); 6];
+        let detail = estimate(&faces, size);
+        assert!(detail > 0.8, "{}", detail);
+
+        // A smooth gradient
+        let faces = vec![(0..size * size).map(|i| (i % size) as f32).collect(); 6];
+        let smooth_detail = estimate(&faces, size);
+        assert!(smooth_detail < detail * 0.1, "{}", smooth_detail);
+    }
+
+    #[test]
+    fn auto_exposure_targets() {
+        use container::CubeMap;
+
+        let size = 8;
+        let cube_map = CubeMap::from_fn(size, |face, x, y| {
+            (face.as_ordinal() * size * size + x + y * size) as f32 * 0.01
+        });
+        let faces: Vec<_> = ::cubemap::CUBE_FACES.iter().map(|&f| cube_map.face(f)).collect();
+
+        let scale = auto_exposure(&faces, size, ExposureTarget::Max(2.0));
+        let scaled = cube_map.map(|x| x * scale);
+        let max = scaled.iter_texels().fold(0.0f32, |x, (_, _, _, &y)| x.max(y));
+        assert!((max - 2.0).abs() < 1.0e-5, "{}", max);
+
+        let scale = auto_exposure(&faces, size, ExposureTarget::Mean(0.5));
+        let scaled = cube_map.map(|x| x * scale);
+        let mean =
+            scaled.iter_texels().map(|(_, _, _, &x)| x).sum::<f32>() / (6 * size * size) as f32;
+        assert!((mean - 0.5).abs() < 1.0e-5, "{}", mean);
+
+        let scale = auto_exposure(
+            &faces,
+            size,
+            ExposureTarget::Percentile {
+                percentile: 50.0,
+                value: 1.0,
+            },
+        );
+        let scaled = cube_map.map(|x| x * scale);
+        let num_below = scaled.iter_texels().filter(|&(_, _, _, &x)| x < 1.0).count();
+        assert!((num_below as isize - (3 * size * size) as isize).abs() <= 1);
+
+        // Nothing to adjust
+        let black = vec![vec![0.0f32; size * size]; 6];
+        let black: Vec<_> = black.iter().map(Vec::as_slice).collect();
+        assert_eq!(auto_exposure(&black, size, ExposureTarget::Max(1.0)), 1.0);
+    }
+}
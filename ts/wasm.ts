@@ -44,7 +44,58 @@ export interface CoreExports {
         kernel_size: number,
         kernel_scale: number,
         phase: number,
-    ): void;
+    ): EmgStatus;
+
+    emg_ltasg_single_rgb(
+        out_faces: Ptr,
+        in_faces: Ptr,
+        size: number,
+        kernel: Ptr,
+        kernel_size: number,
+        kernel_scale: number,
+        phase: number,
+    ): EmgStatus;
+
+    /** The final result is always stored in `out_faces`. */
+    emg_ltasg(
+        out_faces: Ptr,
+        in_faces: Ptr,
+        scratch_faces: Ptr,
+        size: number,
+        kernel: Ptr,
+        kernel_size: number,
+        kernel_scale: number,
+        num_passes: number,
+    ): EmgStatus;
+
+    /**
+     * Returns the kernel length (`2 * radius + 1`), or `0` on failure. Pass
+     * a null pointer as `out` to only query the length.
+     */
+    emg_gaussian_kernel(out: Ptr, radius: number, sigma: number): number;
+
+    /**
+     * Returns a pointer to `EmgKernelDesign` (five 4-byte fields: `kernel`,
+     * `kernel_size`, `kernel_scale`, `num_passes`, and `sigma1`), or a null
+     * pointer on failure. Release it with `emg_kernel_design_free`.
+     */
+    emg_kernel_design(
+        target_sigma: number,
+        size: number,
+        kernel_ratio: number,
+        kernel_upsample: number,
+    ): Ptr;
+    emg_kernel_design_free(design: Ptr): void;
+}
+
+/** Status codes returned by the core functions. */
+export const enum EmgStatus {
+    Ok = 0,
+    NullPointer = 1,
+    Misaligned = 2,
+    SizeOverflow = 3,
+    InvalidParameter = 4,
+    Panicked = 5,
 }
 
 let globalModule: WebAssembly.Module | null = null;
@@ -186,11 +237,11 @@ export class CoreInstance implements Readonly<CoreOptions> {
         }
 
         // Allocate buffers
-        //  - `elements * sizeof::<f32>() * 6 * 2` bytes for input/output passing
-        //    and temporary (ping-pong buffer)
+        //  - `elements * sizeof::<f32>() * 6 * 3` bytes for input, output, and
+        //    temporary (ping-pong buffer)
         //  - `kernel.length * sizeof::<f32>()` for kernel
         const emg: CoreExports = this.instance.exports;
-        const bufferLen = elements * 48 + kernel.length * 4;
+        const bufferLen = elements * 72 + kernel.length * 4;
         const pBuffer = emg.emg_malloc(bufferLen);
 
         {
@@ -201,33 +252,28 @@ export class CoreInstance implements Readonly<CoreOptions> {
             }
         }
 
-        let pImages1 = pBuffer;
-        let pImages2 = pBuffer + elements * 4 * 6;
-        const pKernel = pImages2 + elements * 4 * 6;
+        const pInput = pBuffer;
+        const pOutput = pInput + elements * 4 * 6;
+        const pScratch = pOutput + elements * 4 * 6;
+        const pKernel = pScratch + elements * 4 * 6;
 
         // Upload the inputs
         for (let i = 0; i < 6; ++i) {
-            new Float32Array(emg.memory.buffer, pImages1 + i * (elements * 4))
+            new Float32Array(emg.memory.buffer, pInput + i * (elements * 4))
                 .set(inFaces[i]);
         }
         new Float32Array(emg.memory.buffer, pKernel).set(kernel);
 
         // Let's get this show on the road 🍎
-        for (let i = 0; i < numPasses; ++i) {
-            for (let k = 0; k < 3; ++k) {
-                // `pImages1` → `pImages2`
-                emg.emg_ltasg_single(pImages2, pImages1, size, pKernel, kernel.length, kernelScale, k);
-
-                // Swap buffers
-                let t = pImages1;
-                pImages1 = pImages2;
-                pImages2 = t;
-            }
+        const status = emg.emg_ltasg(pOutput, pInput, pScratch, size, pKernel, kernel.length, kernelScale, numPasses);
+        if (status !== EmgStatus.Ok) {
+            emg.emg_free(pBuffer);
+            throw new Error(`emg_ltasg failed with status ${status}`);
         }
 
         // Retrieve the outputs
         for (let i = 0; i < 6; ++i) {
-            outFaces[i].set(new Float32Array(emg.memory.buffer, pImages1 + i * (elements * 4), elements));
+            outFaces[i].set(new Float32Array(emg.memory.buffer, pOutput + i * (elements * 4), elements));
         }
         emg.emg_free(pBuffer);
     }
@@ -4,17 +4,16 @@
  * This file is a part of hyper3d-envmapgen. Please read the license text that
  * comes with the source code for use conditions.
  */
-#![feature(allocator_api)]
 extern crate cgmath;
 extern crate hyperenvmap;
 extern crate smallvec;
 
-use std::heap::{Heap, Alloc, Layout};
-use std::{ptr, mem};
+use std::alloc::{alloc, dealloc, Layout};
+use std::{ops, ptr, mem, panic};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use smallvec::SmallVec;
-use cgmath::Vector4;
+use cgmath::{num_traits, Vector3, Vector4};
 
 use hyperenvmap::ltasgblur;
 
@@ -26,58 +25,519 @@ pub unsafe fn emg_init() {
     &*hyperenvmap::cubemap::CUBE_FACE_INFOS;
 }
 
+/// Run `f`, returning `on_panic` if it panics instead of letting the unwind
+/// cross the FFI boundary.
+///
+/// This only has an effect when the crate is built with `panic = "unwind"`.
+/// With `panic = "abort"` (the workspace's release profile, and the only
+/// strategy `wasm32-unknown-unknown` supports at the time of writing), a panic
+/// aborts the program before reaching here, which is safe but unrecoverable.
+/// The exported functions therefore also validate their parameters up front
+/// wherever possible.
+fn catch_panic<R, F: FnOnce() -> R>(on_panic: R, f: F) -> R {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// Allocate `size` bytes. Returns a null pointer on failure.
+///
+/// In debug builds, the allocated memory is filled with `0xdeadbeef`.
+/// Otherwise, its contents are unspecified.
 #[no_mangle]
 pub unsafe fn emg_malloc(size: usize) -> *mut u8 {
-    let layout = Layout::from_size_align(size + mem::size_of::<Layout>(), 4).unwrap();
-    let p = Heap.alloc(layout.clone()).unwrap();
-    ptr::write(p as *mut Layout, layout);
-    for i in 0..size / 4 {
-        ptr::write(
-            p.offset(mem::size_of::<Layout>() as isize + (i * 4) as isize) as *mut u32,
-            0xdeadbeef,
-        );
-    }
-    p.offset(mem::size_of::<Layout>() as isize)
+    catch_panic(ptr::null_mut(), || {
+        let layout = match size.checked_add(mem::size_of::<Layout>())
+            .and_then(|x| Layout::from_size_align(x, 4).ok())
+        {
+            Some(x) => x,
+            None => return ptr::null_mut(),
+        };
+        let p = alloc(layout.clone());
+        if p.is_null() {
+            return ptr::null_mut();
+        }
+        ptr::write(p as *mut Layout, layout);
+        if cfg!(debug_assertions) {
+            // Poison the allocation to make uninitialized reads stand out
+            for i in 0..size / 4 {
+                ptr::write(
+                    p.offset(mem::size_of::<Layout>() as isize + (i * 4) as isize) as *mut u32,
+                    0xdeadbeef,
+                );
+            }
+        }
+        p.offset(mem::size_of::<Layout>() as isize)
+    })
 }
 
 #[no_mangle]
 pub unsafe fn emg_free(p: *mut u8) {
     let p = p.offset(-(mem::size_of::<Layout>() as isize));
     let layout = ptr::read(p as *mut _);
-    Heap.dealloc(p, layout);
+    dealloc(p, layout);
 }
 
-#[no_mangle]
-pub unsafe fn emg_ltasg_single(
-    mut out_faces: *mut Vector4<f32>,
-    mut in_faces: *const Vector4<f32>,
+/// Status codes returned by the exported functions.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmgStatus {
+    Ok = 0,
+    /// One of the pointer parameters was null.
+    NullPointer = 1,
+    /// One of the pointer parameters was not suitably aligned.
+    Misaligned = 2,
+    /// The size of a buffer computed from the parameters overflowed.
+    SizeOverflow = 3,
+    /// One of the non-pointer parameters was out of range.
+    InvalidParameter = 4,
+    /// The operation panicked. Only returned by builds with
+    /// `panic = "unwind"`; see `catch_panic`.
+    Panicked = 5,
+}
+
+/// Validate a pointer to `len` elements of type `T` before constructing a
+/// slice from it.
+///
+/// This only catches the errors that can be detected without knowing the
+/// memory layout of the caller. The caller is still responsible for providing
+/// a valid allocation of at least `len` elements.
+fn validate_raw_parts<T>(p: *const T, len: usize) -> Result<(), EmgStatus> {
+    if p.is_null() {
+        Err(EmgStatus::NullPointer)
+    } else if (p as usize) % mem::align_of::<T>() != 0 {
+        Err(EmgStatus::Misaligned)
+    } else if len.checked_mul(mem::size_of::<T>())
+        .map(|bytes| bytes > isize::max_value() as usize)
+        .unwrap_or(true)
+    {
+        Err(EmgStatus::SizeOverflow)
+    } else {
+        Ok(())
+    }
+}
+
+/// Construct slices for six consecutive `size`×`size` cube faces starting at
+/// `p`.
+unsafe fn try_faces_from_raw_parts<'a, T>(
+    mut p: *const T,
     size: usize,
-    kernel: *const f32,
-    kernel_size: usize,
-    kernel_scale: f32,
-    phase: usize,
-) {
-    ltasgblur::ltasg_single(
+) -> Result<SmallVec<[&'a [T]; 6]>, EmgStatus> {
+    let face_len = size.checked_mul(size).ok_or(EmgStatus::SizeOverflow)?;
+    validate_raw_parts(p, face_len.checked_mul(6).ok_or(EmgStatus::SizeOverflow)?)?;
+    Ok(
         (0..6)
             .map(|_| {
-                let slice = from_raw_parts_mut(out_faces, size * size);
-                out_faces = out_faces.offset((size * size) as isize);
+                let slice = from_raw_parts(p, face_len);
+                p = p.offset(face_len as isize);
                 slice
             })
-            .collect::<SmallVec<[_; 6]>>()
-            .as_mut_slice(),
+            .collect(),
+    )
+}
+
+/// The mutable version of `try_faces_from_raw_parts`.
+unsafe fn try_faces_from_raw_parts_mut<'a, T>(
+    mut p: *mut T,
+    size: usize,
+) -> Result<SmallVec<[&'a mut [T]; 6]>, EmgStatus> {
+    let face_len = size.checked_mul(size).ok_or(EmgStatus::SizeOverflow)?;
+    validate_raw_parts(p, face_len.checked_mul(6).ok_or(EmgStatus::SizeOverflow)?)?;
+    Ok(
         (0..6)
             .map(|_| {
-                let slice = from_raw_parts(in_faces, size * size);
-                in_faces = in_faces.offset((size * size) as isize);
+                let slice = from_raw_parts_mut(p, face_len);
+                p = p.offset(face_len as isize);
                 slice
             })
-            .collect::<SmallVec<[_; 6]>>()
-            .as_slice(),
-        size,
-        from_raw_parts(kernel, kernel_size),
-        kernel_scale,
-        phase,
-        ltasgblur::StandardCubeMapTrait,
-    );
+            .collect(),
+    )
+}
+
+/// The implementation of `emg_ltasg_single` and `emg_ltasg_single_rgb`.
+unsafe fn ltasg_single_raw<T>(
+    out_faces: *mut T,
+    in_faces: *const T,
+    size: usize,
+    kernel: *const f32,
+    kernel_size: usize,
+    kernel_scale: f32,
+    phase: usize,
+) -> EmgStatus
+where
+    T: Copy + ops::Mul<f32, Output = T> + ops::Add + num_traits::Zero,
+{
+    catch_panic(EmgStatus::Panicked, || {
+        let mut out_faces = match try_faces_from_raw_parts_mut(out_faces, size) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        let in_faces = match try_faces_from_raw_parts(in_faces, size) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        if let Err(e) = validate_raw_parts(kernel, kernel_size) {
+            return e;
+        }
+        let result = ltasgblur::try_ltasg_single(
+            out_faces.as_mut_slice(),
+            in_faces.as_slice(),
+            size,
+            from_raw_parts(kernel, kernel_size),
+            kernel_scale,
+            phase,
+            ltasgblur::StandardCubeMapTrait,
+        );
+        if result.is_err() {
+            return EmgStatus::InvalidParameter;
+        }
+
+        EmgStatus::Ok
+    })
+}
+
+/// Perform a single pass of LTASG blur. See `ltasgblur::ltasg_single` for the
+/// parameters.
+///
+/// `out_faces` and `in_faces` each point to six consecutive `size`×`size`
+/// images. Returns an `EmgStatus` value. The pointers are checked for null and
+/// alignment, and the buffer sizes for overflow, before any slice is
+/// constructed. The other parameters are validated by
+/// `ltasgblur::try_ltasg_single`. A panic that slips through this validation
+/// is reported as `EmgStatus::Panicked`. The caller must still uphold the
+/// following preconditions:
+///
+///  - `out_faces` and `in_faces` point to valid allocations of at least
+///    `6 * size * size` elements each, and `kernel` to one of at least
+///    `kernel_size` elements.
+///  - The output buffer does not overlap with the input buffer or the kernel.
+#[no_mangle]
+pub unsafe fn emg_ltasg_single(
+    out_faces: *mut Vector4<f32>,
+    in_faces: *const Vector4<f32>,
+    size: usize,
+    kernel: *const f32,
+    kernel_size: usize,
+    kernel_scale: f32,
+    phase: usize,
+) -> EmgStatus {
+    ltasg_single_raw(out_faces, in_faces, size, kernel, kernel_size, kernel_scale, phase)
+}
+
+/// The version of `emg_ltasg_single` for RGB images without an alpha channel.
+/// Each element consists of three `f32` values.
+#[no_mangle]
+pub unsafe fn emg_ltasg_single_rgb(
+    out_faces: *mut Vector3<f32>,
+    in_faces: *const Vector3<f32>,
+    size: usize,
+    kernel: *const f32,
+    kernel_size: usize,
+    kernel_scale: f32,
+    phase: usize,
+) -> EmgStatus {
+    ltasg_single_raw(out_faces, in_faces, size, kernel, kernel_size, kernel_scale, phase)
+}
+
+/// Perform `num_passes` iterations of LTASG blur, each consisting of the
+/// three phases of `emg_ltasg_single`. See `ltasgblur::ltasg` for the
+/// parameters.
+///
+/// `out_faces`, `in_faces`, and `scratch_faces` each point to six consecutive
+/// `size`×`size` images, which must not overlap with each other. The final
+/// result is always stored in `out_faces`; the contents of `scratch_faces` are
+/// unspecified after the call, and `in_faces` is left unmodified. The
+/// parameters are validated in the same way as `emg_ltasg_single`.
+#[no_mangle]
+pub unsafe fn emg_ltasg(
+    out_faces: *mut Vector4<f32>,
+    in_faces: *const Vector4<f32>,
+    scratch_faces: *mut Vector4<f32>,
+    size: usize,
+    kernel: *const f32,
+    kernel_size: usize,
+    kernel_scale: f32,
+    num_passes: usize,
+) -> EmgStatus {
+    catch_panic(EmgStatus::Panicked, || {
+        let mut out_faces = match try_faces_from_raw_parts_mut(out_faces, size) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        let in_faces = match try_faces_from_raw_parts(in_faces, size) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        let mut scratch_faces = match try_faces_from_raw_parts_mut(scratch_faces, size) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        if let Err(e) = validate_raw_parts(kernel, kernel_size) {
+            return e;
+        }
+        if num_passes.checked_mul(3).is_none() {
+            return EmgStatus::InvalidParameter;
+        }
+        let result = ltasgblur::try_ltasg(
+            out_faces.as_mut_slice(),
+            in_faces.as_slice(),
+            scratch_faces.as_mut_slice(),
+            size,
+            from_raw_parts(kernel, kernel_size),
+            kernel_scale,
+            num_passes,
+            ltasgblur::StandardCubeMapTrait,
+        );
+        if result.is_err() {
+            return EmgStatus::InvalidParameter;
+        }
+
+        EmgStatus::Ok
+    })
+}
+
+/// Compute a Gaussian kernel with `ltasgblur::gaussian_kernel`.
+///
+/// The kernel consists of `2 * radius + 1` elements, which is the returned
+/// value. If `out` is null, the kernel is not computed, which can be used to
+/// query the required length of the output buffer. Otherwise, `out` must
+/// point to a buffer of at least that many elements. Returns `0` if the
+/// parameters are invalid (`sigma` is not a positive number, the length
+/// overflows, or `out` is misaligned).
+#[no_mangle]
+pub unsafe fn emg_gaussian_kernel(out: *mut f32, radius: usize, sigma: f32) -> usize {
+    catch_panic(0, || {
+        let len = match radius.checked_mul(2).and_then(|x| x.checked_add(1)) {
+            Some(x) => x,
+            None => return 0,
+        };
+        if !(sigma > 0.0) {
+            return 0;
+        }
+        if out.is_null() {
+            return len;
+        }
+        if validate_raw_parts(out, len).is_err() {
+            return 0;
+        }
+        from_raw_parts_mut(out, len).copy_from_slice(&ltasgblur::gaussian_kernel(radius, sigma));
+        len
+    })
+}
+
+/// The result of `emg_kernel_design`. Mirrors `ltasgblur::KernelDesign`.
+///
+/// On `wasm32`, every field occupies four bytes, so the fields are located at
+/// the offsets 0, 4, 8, 12, and 16 in this order.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EmgKernelDesign {
+    /// Points to `kernel_size` elements owned by this object.
+    pub kernel: *mut f32,
+    pub kernel_size: usize,
+    pub kernel_scale: f32,
+    pub num_passes: usize,
+    pub sigma1: f32,
+}
+
+/// Design a blur with `ltasgblur::KernelDesign::for_sigma`.
+///
+/// Returns a pointer to `EmgKernelDesign`, which must be released with
+/// `emg_kernel_design_free`, or a null pointer if the parameters are invalid
+/// (`target_sigma` is negative, `kernel_ratio` or `kernel_upsample` is not
+/// a positive number, or `size` is too small for the kernel).
+#[no_mangle]
+pub unsafe fn emg_kernel_design(
+    target_sigma: f32,
+    size: usize,
+    kernel_ratio: f32,
+    kernel_upsample: f32,
+) -> *mut EmgKernelDesign {
+    catch_panic(ptr::null_mut(), || {
+        if !(target_sigma >= 0.0 && target_sigma.is_finite() && kernel_ratio > 0.0
+            && kernel_upsample > 0.0)
+        {
+            return ptr::null_mut();
+        }
+        let max_sigma1 =
+            ltasgblur::KernelDesign::max_sigma1(size, kernel_ratio, kernel_upsample);
+        if !(max_sigma1 > 0.0) {
+            return ptr::null_mut();
+        }
+
+        let design =
+            ltasgblur::KernelDesign::for_sigma(target_sigma, size, kernel_ratio, kernel_upsample);
+        let mut kernel = design.kernel.into_boxed_slice();
+        let (kernel_ptr, kernel_size) = (kernel.as_mut_ptr(), kernel.len());
+        mem::forget(kernel);
+        Box::into_raw(Box::new(EmgKernelDesign {
+            kernel: kernel_ptr,
+            kernel_size,
+            kernel_scale: design.kernel_scale,
+            num_passes: design.num_passes,
+            sigma1: design.sigma1,
+        }))
+    })
+}
+
+/// Release an `EmgKernelDesign` returned by `emg_kernel_design`. Does nothing
+/// if `p` is null.
+#[no_mangle]
+pub unsafe fn emg_kernel_design_free(p: *mut EmgKernelDesign) {
+    if p.is_null() {
+        return;
+    }
+    let design = Box::from_raw(p);
+    drop(Box::from_raw(from_raw_parts_mut(design.kernel, design.kernel_size)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::prelude::*;
+
+    fn run(size: usize, kernel: &[f32], kernel_scale: f32, phase: usize) -> EmgStatus {
+        let input = vec![Vector4::new(0.0, 0.0, 0.0, 0.0); size * size * 6];
+        let mut output = input.clone();
+        unsafe {
+            emg_ltasg_single(
+                output.as_mut_ptr(),
+                input.as_ptr(),
+                size,
+                kernel.as_ptr(),
+                kernel.len(),
+                kernel_scale,
+                phase,
+            )
+        }
+    }
+
+    #[test]
+    fn valid_parameters() {
+        assert_eq!(run(16, &[0.25, 0.5, 0.25], 1.0, 0), EmgStatus::Ok);
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let kernel = [0.25, 0.5, 0.25];
+        assert_eq!(run(16, &kernel, 1.0, 3), EmgStatus::InvalidParameter);
+        assert_eq!(run(16, &kernel[0..2], 1.0, 0), EmgStatus::InvalidParameter);
+        assert_eq!(run(16, &kernel, -1.0, 0), EmgStatus::InvalidParameter);
+        assert_eq!(run(16, &kernel, 100.0, 0), EmgStatus::InvalidParameter);
+    }
+
+    #[test]
+    fn rgb() {
+        let size = 16;
+        let kernel = [0.25, 0.5, 0.25];
+        let input = vec![Vector3::new(1.0, 0.5, 0.25); size * size * 6];
+        let mut output = vec![Vector3::new(0.0, 0.0, 0.0); size * size * 6];
+        let status = unsafe {
+            emg_ltasg_single_rgb(
+                output.as_mut_ptr(),
+                input.as_ptr(),
+                size,
+                kernel.as_ptr(),
+                kernel.len(),
+                1.0,
+                0,
+            )
+        };
+        assert_eq!(status, EmgStatus::Ok);
+        for x in output.iter() {
+            assert!((x - input[0]).magnitude() < 1.0e-4, "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn multi_pass_matches_single() {
+        let size = 16;
+        let kernel = [0.25, 0.5, 0.25];
+        let input: Vec<_> = (0..size * size * 6)
+            .map(|i| Vector4::new((i % 7) as f32, (i % 11) as f32, 0.0, 1.0))
+            .collect();
+        let num_passes = 2;
+
+        let mut expected = input.clone();
+        let mut temp = input.clone();
+        for _ in 0..num_passes {
+            for phase in 0..3 {
+                let status = unsafe {
+                    emg_ltasg_single(
+                        temp.as_mut_ptr(),
+                        expected.as_ptr(),
+                        size,
+                        kernel.as_ptr(),
+                        kernel.len(),
+                        1.0,
+                        phase,
+                    )
+                };
+                assert_eq!(status, EmgStatus::Ok);
+                mem::swap(&mut expected, &mut temp);
+            }
+        }
+
+        let mut output = vec![Vector4::new(0.0, 0.0, 0.0, 0.0); size * size * 6];
+        let mut scratch = output.clone();
+        let status = unsafe {
+            emg_ltasg(
+                output.as_mut_ptr(),
+                input.as_ptr(),
+                scratch.as_mut_ptr(),
+                size,
+                kernel.as_ptr(),
+                kernel.len(),
+                1.0,
+                num_passes,
+            )
+        };
+        assert_eq!(status, EmgStatus::Ok);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn gaussian_kernel() {
+        unsafe {
+            assert_eq!(emg_gaussian_kernel(ptr::null_mut(), 4, 2.0), 9);
+            assert_eq!(emg_gaussian_kernel(ptr::null_mut(), 4, 0.0), 0);
+
+            let mut kernel = vec![0.0f32; 9];
+            assert_eq!(emg_gaussian_kernel(kernel.as_mut_ptr(), 4, 2.0), 9);
+            assert_eq!(kernel, ltasgblur::gaussian_kernel(4, 2.0));
+        }
+    }
+
+    #[test]
+    fn kernel_design() {
+        unsafe {
+            let expected = ltasgblur::KernelDesign::for_sigma(0.3, 64, 2.0, 2.0);
+            let p = emg_kernel_design(0.3, 64, 2.0, 2.0);
+            assert!(!p.is_null());
+            {
+                let design = &*p;
+                assert_eq!(
+                    from_raw_parts(design.kernel, design.kernel_size),
+                    &expected.kernel[..]
+                );
+                assert_eq!(design.kernel_scale, expected.kernel_scale);
+                assert_eq!(design.num_passes, expected.num_passes);
+                assert_eq!(design.sigma1, expected.sigma1);
+            }
+            emg_kernel_design_free(p);
+
+            assert!(emg_kernel_design(0.3, 0, 2.0, 2.0).is_null());
+            assert!(emg_kernel_design(-1.0, 64, 2.0, 2.0).is_null());
+        }
+    }
+
+    #[test]
+    fn panic_is_caught() {
+        assert_eq!(catch_panic(EmgStatus::Panicked, || panic!()), EmgStatus::Panicked);
+    }
+
+    #[test]
+    fn malloc_overflow() {
+        unsafe {
+            assert!(emg_malloc(usize::max_value()).is_null());
+        }
+    }
 }